@@ -116,13 +116,7 @@ fn find_readable_endpoint(device: &mut libusb::Device, device_desc: &libusb::Dev
 fn read_endpoint(handle: &mut libusb::DeviceHandle, endpoint: Endpoint, transfer_type: libusb::TransferType) {
     println!("Reading from endpoint: {:?}", endpoint);
 
-    let has_kernel_driver = match handle.kernel_driver_active(endpoint.iface) {
-        Ok(true) => {
-            handle.detach_kernel_driver(endpoint.iface).ok();
-            true
-        },
-        _ => false
-    };
+    let has_kernel_driver = handle.kernel_driver_active(endpoint.iface).unwrap_or(false);
 
     println!(" - kernel driver? {}", has_kernel_driver);
 
@@ -158,14 +152,13 @@ fn read_endpoint(handle: &mut libusb::DeviceHandle, endpoint: Endpoint, transfer
         Err(err) => println!("could not configure endpoint: {}", err)
     }
 
-    if has_kernel_driver {
-        handle.attach_kernel_driver(endpoint.iface).ok();
-    }
+    // re-attaches the kernel driver if it was detached
+    handle.release_interface(endpoint.iface).ok();
 }
 
 fn configure_endpoint<'a>(handle: &'a mut libusb::DeviceHandle, endpoint: &Endpoint) -> libusb::Result<()> {
     try!(handle.set_active_configuration(endpoint.config));
-    try!(handle.claim_interface(endpoint.iface));
+    try!(handle.claim_interface_detached(endpoint.iface));
     try!(handle.set_alternate_setting(endpoint.iface, endpoint.setting));
     Ok(())
 }
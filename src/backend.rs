@@ -0,0 +1,180 @@
+use std::cmp;
+use std::sync::Arc;
+use std::time::Duration;
+
+use libc::c_uint;
+use libusb::*;
+
+use error::{Error, ErrorKind};
+use fields::{Speed, TransferType};
+use trace::SetupPacket;
+
+/// A provider of USB devices other than `libusb`.
+///
+/// A context that is created with [`Context::with_backend()`](struct.Context.html#method.with_backend)
+/// enumerates, opens, and communicates with devices through its backend instead of `libusb`.
+/// Descriptors are exchanged as the raw bytes that a device returns for a `GET_DESCRIPTOR`
+/// request and are parsed by this crate.
+///
+/// Backends support the synchronous parts of the API: enumerating devices, reading descriptors,
+/// claiming interfaces, and control, bulk, and interrupt transfers, including the cancellable and
+/// flagged variants and the endpoint readers and writers. A [`BulkStream`](struct.BulkStream.html)
+/// or [`BulkSink`](struct.BulkSink.html) performs each of its transfers when it waits for the
+/// transfer. Kernel drivers can be detached if the backend implements the kernel driver methods of
+/// [`BackendHandle`](trait.BackendHandle.html). The parts of the API that rely on `libusb`'s
+/// asynchronous transfers, such as [`InterruptPoller`](struct.InterruptPoller.html) and bulk
+/// streams, return `NotSupported`.
+///
+/// The crate provides [`MockBackend`](struct.MockBackend.html) for testing applications without
+/// hardware.
+pub trait Backend: Send + Sync {
+    /// Returns the devices that are currently connected.
+    ///
+    /// Devices compare equal when they share the same `Arc`, so a backend should return the same
+    /// `Arc` for a device each time that it lists the device.
+    fn devices(&self) -> ::Result<Vec<Arc<BackendDevice>>>;
+}
+
+/// A device provided by a [`Backend`](trait.Backend.html).
+pub trait BackendDevice: Send + Sync {
+    /// Returns the number of the bus that the device is connected to.
+    fn bus_number(&self) -> u8;
+
+    /// Returns the device's address on the bus that it's connected to.
+    fn address(&self) -> u8;
+
+    /// Returns the number of the port that the device is connected to, or 0 if it's not available.
+    fn port_number(&self) -> u8 {
+        0
+    }
+
+    /// Returns the numbers of the ports from the root hub to the device.
+    fn port_numbers(&self) -> Vec<u8> {
+        match self.port_number() {
+            0 => Vec::new(),
+            port_number => vec![port_number],
+        }
+    }
+
+    /// Returns the device's connection speed.
+    fn speed(&self) -> Speed;
+
+    /// Returns the bytes of the device descriptor.
+    fn device_descriptor(&self) -> ::Result<Vec<u8>>;
+
+    /// Returns the bytes of a configuration descriptor and the descriptors that follow it.
+    ///
+    /// `config_index` is the index of the configuration, not its configuration number.
+    fn config_descriptor(&self, config_index: u8) -> ::Result<Vec<u8>>;
+
+    /// Returns the bytes of the active configuration's descriptor and the descriptors that follow
+    /// it.
+    fn active_config_descriptor(&self) -> ::Result<Vec<u8>>;
+
+    /// Opens the device.
+    fn open(&self) -> ::Result<Box<BackendHandle>>;
+}
+
+/// An open device provided by a [`BackendDevice`](trait.BackendDevice.html).
+///
+/// Timeouts are given as `None` for transfers that should not time out.
+pub trait BackendHandle: Send + Sync {
+    /// Returns the active configuration number.
+    fn active_configuration(&self) -> ::Result<u8>;
+
+    /// Sets the active configuration, or puts the device in an unconfigured state if `config` is
+    /// `None`.
+    fn set_active_configuration(&self, config: Option<u8>) -> ::Result<()>;
+
+    /// Resets the device.
+    fn reset(&self) -> ::Result<()>;
+
+    /// Claims an interface.
+    fn claim_interface(&self, iface: u8) -> ::Result<()>;
+
+    /// Releases a claimed interface.
+    fn release_interface(&self, iface: u8) -> ::Result<()>;
+
+    /// Sets an interface's active setting.
+    fn set_alternate_setting(&self, iface: u8, setting: u8) -> ::Result<()>;
+
+    /// Clears the halt condition of an endpoint.
+    fn clear_halt(&self, endpoint: u8) -> ::Result<()>;
+
+    /// Indicates whether a kernel driver is attached to an interface.
+    ///
+    /// The default implementation reports that no kernel driver is attached.
+    fn kernel_driver_active(&self, _iface: u8) -> ::Result<bool> {
+        Ok(false)
+    }
+
+    /// Indicates whether kernel drivers can be detached from the device's interfaces.
+    ///
+    /// The default implementation returns `false`.
+    fn supports_detach_kernel_driver(&self) -> bool {
+        false
+    }
+
+    /// Detaches the kernel driver from an interface.
+    ///
+    /// The default implementation returns `NotSupported`.
+    fn detach_kernel_driver(&self, _iface: u8) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    /// Re-attaches the kernel driver of an interface.
+    ///
+    /// The default implementation returns `NotSupported`.
+    fn attach_kernel_driver(&self, _iface: u8) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    /// Performs a control transfer that reads into `buf`.
+    fn read_control(&self, setup: &SetupPacket, buf: &mut [u8], timeout: Option<Duration>) -> ::Result<usize>;
+
+    /// Performs a control transfer that writes `buf`.
+    fn write_control(&self, setup: &SetupPacket, buf: &[u8], timeout: Option<Duration>) -> ::Result<usize>;
+
+    /// Reads from a bulk or interrupt endpoint into `buf`.
+    fn read(&self, transfer_type: TransferType, endpoint: u8, buf: &mut [u8], timeout: Option<Duration>) -> ::Result<usize>;
+
+    /// Writes `buf` to a bulk or interrupt endpoint.
+    fn write(&self, transfer_type: TransferType, endpoint: u8, buf: &[u8], timeout: Option<Duration>) -> ::Result<usize>;
+}
+
+
+/// Converts a `libusb` timeout in milliseconds to a backend timeout.
+#[doc(hidden)]
+pub fn timeout_from_libusb(timeout_ms: c_uint) -> Option<Duration> {
+    match timeout_ms {
+        0 => None,
+        ms => Some(Duration::from_millis(ms as u64)),
+    }
+}
+
+/// Returns the descriptor type, descriptor index, and language ID of a standard `GET_DESCRIPTOR`
+/// request, or `None` if the request is something else.
+#[doc(hidden)]
+pub fn descriptor_request(setup: &SetupPacket) -> Option<(u8, u8, u16)> {
+    if setup.request_type() != LIBUSB_ENDPOINT_IN || setup.request() != LIBUSB_REQUEST_GET_DESCRIPTOR {
+        return None;
+    }
+
+    Some(((setup.value() >> 8) as u8, setup.value() as u8, setup.index()))
+}
+
+/// Answers a `GET_DESCRIPTOR` request with as much of a descriptor as fits in `buf`.
+///
+/// Stalls with `Pipe` if the device doesn't have the descriptor.
+#[doc(hidden)]
+pub fn reply_descriptor(bytes: Option<&[u8]>, buf: &mut [u8]) -> ::Result<usize> {
+    match bytes {
+        Some(bytes) => {
+            let len = cmp::min(bytes.len(), buf.len());
+
+            buf[..len].copy_from_slice(&bytes[..len]);
+            Ok(len)
+        },
+        None => Err(Error::new(ErrorKind::Pipe)),
+    }
+}
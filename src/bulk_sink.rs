@@ -0,0 +1,435 @@
+use std::collections::VecDeque;
+
+use libusb::*;
+
+use device_handle::{self, DeviceHandle};
+use error::{self, Error, ErrorKind};
+use fields::TransferType;
+use timeout::{self, Timeout};
+use transfer::{self, Transfer, TransferFlags, TransferStats};
+
+/// A sink for data sent to a bulk OUT endpoint.
+///
+/// A bulk sink keeps several transfers queued on an endpoint, so that the device can keep
+/// receiving data while the application prepares the next buffer. Buffers are sent in the order in
+/// which they were queued. When the maximum number of transfers is queued,
+/// [`send()`](#method.send) blocks until the oldest transfer completes.
+///
+/// The sink stops at the first transfer that fails. The transfers queued after it are cancelled,
+/// and every later call returns the same error. [`committed()`](#method.committed) reports how
+/// many bytes the device accepted before the failure.
+///
+/// Dropping the sink cancels the transfers that are still queued. Call [`flush()`](#method.flush)
+/// to wait for them to complete first.
+///
+/// Devices provided by a [`Backend`](trait.Backend.html) perform transfers synchronously, so each
+/// transfer of a sink on such a device is performed when the sink waits for it.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// # fn load(handle: &libusb::DeviceHandle, image: &[u8]) -> libusb::Result<()> {
+/// let mut sink = try!(handle.bulk_sink(0x02, 8, Duration::from_secs(1)));
+///
+/// for chunk in image.chunks(64 * 1024) {
+///     let mut buf = sink.take_buffer();
+///     buf.extend_from_slice(chunk);
+///
+///     if let Err(err) = sink.send(buf) {
+///         println!("failed after {} bytes: {}", sink.committed(), err);
+///         return Err(err);
+///     }
+/// }
+///
+/// try!(sink.flush());
+/// # Ok(())
+/// # }
+/// ```
+pub struct BulkSink<'h, 'a: 'h> {
+    handle: &'h DeviceHandle<'a>,
+    endpoint: u8,
+    num_transfers: usize,
+    timeout: Timeout,
+    queue: VecDeque<Slot>,
+    pool: Vec<Vec<u8>>,
+    idle: Vec<Transfer>,
+    committed: u64,
+    flags: TransferFlags,
+    stats: TransferStats,
+    error: Option<Error>,
+}
+
+struct Slot {
+    // declared before `buf` so that the transfer is completed before its buffer is freed; devices
+    // provided by a backend have no `libusb` transfers
+    transfer: Option<Transfer>,
+    buf: Vec<u8>,
+    flags: TransferFlags,
+    timeout: Timeout,
+}
+
+impl Slot {
+    fn cancel(&self) {
+        if let Some(ref transfer) = self.transfer {
+            transfer.cancel();
+        }
+    }
+
+    fn is_completed(&self) -> bool {
+        self.transfer.as_ref().map_or(false, |transfer| transfer.is_completed())
+    }
+}
+
+impl<'h, 'a> Drop for BulkSink<'h, 'a> {
+    /// Cancels the queued transfers.
+    fn drop(&mut self) {
+        for slot in self.queue.iter() {
+            slot.cancel();
+        }
+    }
+}
+
+impl<'h, 'a> BulkSink<'h, 'a> {
+    /// Returns the address of the endpoint.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Returns the maximum number of transfers that the sink keeps queued.
+    pub fn num_transfers(&self) -> usize {
+        self.num_transfers
+    }
+
+    /// Returns the number of transfers that are queued.
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns the number of bytes that the device has accepted.
+    ///
+    /// Only bytes sent before the first failed transfer are counted, including the part of the
+    /// failed transfer that the device accepted.
+    pub fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    /// Returns the sink's statistics.
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Returns the timeout for each transfer.
+    pub fn timeout(&self) -> Timeout {
+        self.timeout
+    }
+
+    /// Sets the timeout for each transfer.
+    ///
+    /// The timeout applies to transfers that are queued after it is changed.
+    pub fn set_timeout<T: Into<Timeout>>(&mut self, timeout: T) {
+        self.timeout = timeout.into();
+    }
+
+    /// Returns the flags for each transfer.
+    pub fn flags(&self) -> TransferFlags {
+        self.flags
+    }
+
+    /// Sets the flags for each transfer.
+    ///
+    /// The flags apply to transfers that are queued after they are changed. For example, adding a
+    /// zero-length packet terminates each buffer whose length is a multiple of the endpoint's
+    /// maximum packet size.
+    pub fn set_flags(&mut self, flags: TransferFlags) {
+        self.flags = flags;
+    }
+
+    /// Returns an empty buffer, reusing the buffer of a completed transfer if one is available.
+    pub fn take_buffer(&mut self) -> Vec<u8> {
+        self.pool.pop().unwrap_or_else(Vec::new)
+    }
+
+    /// Queues a buffer to be sent to the endpoint.
+    ///
+    /// If the maximum number of transfers is already queued, this method blocks until the oldest
+    /// transfer completes. An empty buffer sends a zero-length packet.
+    ///
+    /// Returns the error of the first failed transfer if the sink has stopped. The buffer is not
+    /// sent in that case.
+    pub fn send(&mut self, buf: Vec<u8>) -> ::Result<()> {
+        try!(self.check());
+
+        self.reap();
+
+        while self.error.is_none() && self.queue.len() >= self.num_transfers {
+            self.complete();
+        }
+
+        try!(self.check());
+
+        let transfer = match self.idle.pop() {
+            Some(transfer) => Some(transfer),
+            // devices provided by a backend have no `libusb` handle
+            None if device_handle::as_raw(self.handle).is_null() => None,
+            None => Some(try!(Transfer::new(device_handle::context_raw(self.handle)).map_err(|err| self.error(err)))),
+        };
+
+        self.submit(Slot { transfer: transfer, buf: buf, flags: self.flags, timeout: self.timeout });
+        self.check()
+    }
+
+    /// Waits for all queued transfers to complete.
+    ///
+    /// Returns the error of the first failed transfer if the sink has stopped.
+    pub fn flush(&mut self) -> ::Result<()> {
+        while self.error.is_none() && !self.queue.is_empty() {
+            self.complete();
+        }
+
+        self.check()
+    }
+
+    fn check(&self) -> ::Result<()> {
+        match self.error {
+            Some(ref err) => Err(self.error(error::without_context(err))),
+            None => Ok(()),
+        }
+    }
+
+    fn error(&self, err: Error) -> Error {
+        device_handle::endpoint_error(self.handle, err, "bulk_sink", self.endpoint)
+    }
+
+    fn submit(&mut self, mut slot: Slot) {
+        let timeout_ms = match timeout::to_libusb(&slot.timeout) {
+            Some(timeout_ms) => timeout_ms,
+            None => return self.stop(Error::new(ErrorKind::Timeout)),
+        };
+
+        if let Some(ref mut transfer) = slot.transfer {
+            unsafe {
+                transfer.fill(device_handle::as_raw(self.handle), LIBUSB_TRANSFER_TYPE_BULK, self.endpoint, slot.buf.as_mut_ptr(), slot.buf.len(), timeout_ms, transfer::flags_to_libusb(&slot.flags));
+            }
+
+            match transfer.submit() {
+                0 => (),
+                err => return self.stop(error::from_libusb(err)),
+            }
+        }
+
+        self.queue.push_back(slot);
+    }
+
+    /// Completes the transfers at the front of the queue that have already completed.
+    ///
+    /// Backends perform transfers only when they're waited for, so no transfer of a sink on a
+    /// device provided by a backend completes before then.
+    fn reap(&mut self) {
+        // devices provided by a backend have no `libusb` handle
+        if device_handle::as_raw(self.handle).is_null() {
+            return;
+        }
+
+        transfer::poll_events(device_handle::context_raw(self.handle));
+
+        while self.error.is_none() && self.queue.front().map_or(false, |slot| slot.is_completed()) {
+            self.complete();
+        }
+    }
+
+    /// Waits for the oldest transfer to complete.
+    fn complete(&mut self) {
+        let mut slot = match self.queue.pop_front() {
+            Some(slot) => slot,
+            None => return,
+        };
+
+        let (res, len) = match slot.transfer {
+            Some(ref mut transfer) => {
+                transfer.wait();
+
+                match transfer.result() {
+                    0 => (Ok(()), transfer.actual_length()),
+                    err => (Err(error::from_libusb(err)), transfer.actual_length()),
+                }
+            },
+            None => unsafe {
+                device_handle::backend_transfer(self.handle, TransferType::Bulk, self.endpoint, slot.buf.as_mut_ptr(), slot.buf.len(), slot.timeout, transfer::flags_to_libusb(&slot.flags))
+            },
+        };
+
+        self.committed += len as u64;
+
+        match res {
+            Ok(()) => {
+                transfer::record_transfer(&mut self.stats, len);
+
+                if self.pool.len() < self.num_transfers {
+                    slot.buf.clear();
+                    self.pool.push(slot.buf);
+                }
+
+                if let Some(transfer) = slot.transfer {
+                    self.idle.push(transfer);
+                }
+            },
+            Err(err) => self.stop(err),
+        }
+    }
+
+    fn stop(&mut self, err: Error) {
+        transfer::record_error(&mut self.stats);
+
+        for slot in self.queue.iter() {
+            slot.cancel();
+        }
+
+        self.queue.clear();
+        self.error = Some(err);
+    }
+}
+
+
+#[doc(hidden)]
+pub fn new<'h, 'a>(handle: &'h DeviceHandle<'a>, endpoint: u8, num_transfers: usize, timeout: Timeout) -> BulkSink<'h, 'a> {
+    BulkSink {
+        handle: handle,
+        endpoint: endpoint,
+        num_transfers: num_transfers,
+        timeout: timeout,
+        queue: VecDeque::with_capacity(num_transfers),
+        pool: Vec::with_capacity(num_transfers),
+        idle: Vec::with_capacity(num_transfers),
+        committed: 0,
+        flags: TransferFlags::new(),
+        stats: transfer::new_stats(),
+        error: None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use context::Context;
+    use error::ErrorKind;
+    use test_helpers::{context_with, mock_device, open};
+
+    fn context() -> Context {
+        context_with(&[&mock_device(512)])
+    }
+
+    fn sink_error(context: &Context, endpoint: u8, num_transfers: usize) -> ErrorKind {
+        open(context).bulk_sink(endpoint, num_transfers, Duration::from_secs(1)).err().unwrap().kind()
+    }
+
+    #[test]
+    fn it_rejects_zero_transfers() {
+        assert_eq!(ErrorKind::InvalidParam, sink_error(&context(), 0x02, 0));
+    }
+
+    #[test]
+    fn it_rejects_input_endpoint() {
+        assert_eq!(ErrorKind::InvalidParam, sink_error(&context(), 0x81, 4));
+    }
+
+    #[test]
+    fn it_rejects_non_bulk_endpoint() {
+        assert_eq!(ErrorKind::WrongTransferType, sink_error(&context(), 0x04, 4));
+    }
+
+    #[test]
+    fn it_rejects_unknown_endpoint() {
+        assert_eq!(ErrorKind::UnknownEndpoint, sink_error(&context(), 0x05, 4));
+    }
+
+    #[test]
+    fn it_sends_buffers_in_order() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut sink = handle.bulk_sink(0x02, 4, Duration::from_secs(1)).unwrap();
+
+        sink.send(vec![1; 512]).unwrap();
+        sink.send(vec![2; 10]).unwrap();
+        sink.send(vec![]).unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(vec![vec![1; 512], vec![2; 10], vec![]], device.take_out(0x02));
+        assert_eq!(522, sink.committed());
+        assert_eq!(3, sink.stats().transfers());
+        assert_eq!(0, sink.pending());
+    }
+
+    #[test]
+    fn it_waits_for_oldest_transfer_when_queue_is_full() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut sink = handle.bulk_sink(0x02, 2, Duration::from_secs(1)).unwrap();
+
+        sink.send(vec![1]).unwrap();
+        sink.send(vec![2]).unwrap();
+        assert_eq!(2, sink.pending());
+        assert!(device.take_out(0x02).is_empty());
+
+        sink.send(vec![3]).unwrap();
+        assert_eq!(2, sink.pending());
+        assert_eq!(vec![vec![1]], device.take_out(0x02));
+
+        sink.flush().unwrap();
+        assert_eq!(vec![vec![2], vec![3]], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_reuses_buffers_of_completed_transfers() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut sink = handle.bulk_sink(0x02, 2, Duration::from_secs(1)).unwrap();
+
+        let mut buf = sink.take_buffer();
+        buf.extend_from_slice(&[1, 2, 3]);
+        let ptr = buf.as_ptr();
+
+        sink.send(buf).unwrap();
+        sink.flush().unwrap();
+
+        let buf = sink.take_buffer();
+        assert!(buf.is_empty());
+        assert_eq!(ptr, buf.as_ptr());
+    }
+
+    #[test]
+    fn it_reports_committed_bytes_after_failed_transfer() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut sink = handle.bulk_sink(0x02, 2, Duration::from_secs(1)).unwrap();
+
+        sink.send(vec![1; 10]).unwrap();
+        sink.send(vec![2; 20]).unwrap();
+        sink.send(vec![3; 30]).unwrap();
+        assert_eq!(10, sink.committed());
+
+        device.disconnect();
+
+        let err = sink.flush().unwrap_err();
+        assert_eq!(ErrorKind::NoDevice, err.kind());
+        assert_eq!(Some("bulk_sink"), err.operation());
+        assert_eq!(Some(0x02), err.endpoint());
+        assert_eq!(10, sink.committed());
+        assert_eq!(0, sink.pending());
+        assert_eq!(1, sink.stats().transfers());
+        assert_eq!(1, sink.stats().errors());
+
+        // the sink stays stopped and doesn't send later buffers
+        assert_eq!(ErrorKind::NoDevice, sink.send(vec![4; 40]).unwrap_err().kind());
+        assert_eq!(ErrorKind::NoDevice, sink.flush().unwrap_err().kind());
+        assert_eq!(10, sink.committed());
+        assert_eq!(vec![vec![1; 10]], device.take_out(0x02));
+    }
+}
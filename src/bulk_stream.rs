@@ -0,0 +1,495 @@
+use std::collections::VecDeque;
+use std::mem;
+
+use libusb::*;
+
+use device_handle::{self, DeviceHandle};
+use error::{self, Error, ErrorKind};
+use fields::TransferType;
+use timeout::Timeout;
+use transfer::{self, Transfer, TransferFlags, TransferStats};
+
+/// A stream of data from a bulk IN endpoint.
+///
+/// A bulk stream keeps several transfers queued on an endpoint, so that the device can keep
+/// sending data while the application processes the data that it has already received. Completed
+/// transfers are handed to the application in the order in which they were queued, and each
+/// completed transfer is immediately queued again.
+///
+/// `BulkStream` implements `Iterator`. Each item is the data received by one transfer. After an
+/// error is returned, the stream is stopped and the iterator ends. Buffers that are no longer
+/// needed can be returned to the stream with [`recycle()`](#method.recycle) to avoid allocating
+/// new buffers.
+///
+/// Dropping the stream cancels the transfers that are still queued.
+///
+/// Devices provided by a [`Backend`](trait.Backend.html) perform transfers synchronously, so each
+/// transfer of a stream on such a device is performed when the stream waits for it.
+///
+/// ## Example
+///
+/// ```no_run
+/// # fn stream(handle: &libusb::DeviceHandle) -> libusb::Result<()> {
+/// let mut stream = try!(handle.bulk_stream(0x81, 8, 64 * 1024));
+///
+/// for _ in 0..1000 {
+///     match stream.next() {
+///         Some(Ok(buf)) => {
+///             println!("received {} bytes", buf.len());
+///             stream.recycle(buf);
+///         },
+///         Some(Err(err)) => return Err(err),
+///         None => break,
+///     }
+/// }
+///
+/// println!("{:.0} bytes/s", stream.stats().throughput());
+/// # Ok(())
+/// # }
+/// ```
+pub struct BulkStream<'h, 'a: 'h> {
+    handle: &'h DeviceHandle<'a>,
+    endpoint: u8,
+    num_transfers: usize,
+    transfer_size: usize,
+    queue: VecDeque<Slot>,
+    pool: Vec<Vec<u8>>,
+    flags: TransferFlags,
+    stats: TransferStats,
+    error: Option<Error>,
+    stopped: bool,
+}
+
+struct Slot {
+    // declared before `buf` so that the transfer is completed before its buffer is freed; devices
+    // provided by a backend have no `libusb` transfers
+    transfer: Option<Transfer>,
+    buf: Vec<u8>,
+    flags: TransferFlags,
+}
+
+impl Slot {
+    fn cancel(&self) {
+        if let Some(ref transfer) = self.transfer {
+            transfer.cancel();
+        }
+    }
+
+    fn is_completed(&self) -> bool {
+        self.transfer.as_ref().map_or(false, |transfer| transfer.is_completed())
+    }
+}
+
+impl<'h, 'a> Drop for BulkStream<'h, 'a> {
+    /// Cancels the queued transfers.
+    fn drop(&mut self) {
+        for slot in self.queue.iter() {
+            slot.cancel();
+        }
+    }
+}
+
+impl<'h, 'a> BulkStream<'h, 'a> {
+    /// Returns the address of the endpoint.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Returns the number of transfers that the stream keeps queued.
+    pub fn num_transfers(&self) -> usize {
+        self.num_transfers
+    }
+
+    /// Returns the size of each transfer.
+    pub fn transfer_size(&self) -> usize {
+        self.transfer_size
+    }
+
+    /// Returns the stream's statistics.
+    pub fn stats(&self) -> TransferStats {
+        self.stats
+    }
+
+    /// Returns the flags for each transfer.
+    pub fn flags(&self) -> TransferFlags {
+        self.flags
+    }
+
+    /// Sets the flags for each transfer.
+    ///
+    /// The flags apply to transfers that are queued after they are changed. If the flags don't
+    /// allow short transfers, a transfer that receives less than the transfer size stops the
+    /// stream with `ShortTransfer`.
+    pub fn set_flags(&mut self, flags: TransferFlags) {
+        self.flags = flags;
+    }
+
+    /// Returns a buffer to the stream, so that it can be reused for a later transfer.
+    pub fn recycle(&mut self, buf: Vec<u8>) {
+        if self.pool.len() < self.num_transfers && buf.capacity() >= self.transfer_size {
+            self.pool.push(buf);
+        }
+    }
+
+    /// Waits for the next transfer to complete.
+    ///
+    /// This method blocks up to the amount of time specified by `timeout`. If the next transfer
+    /// doesn't complete in time, `Timeout` is returned and the stream keeps running.
+    ///
+    /// Returns `None` if the stream has stopped because of an earlier error.
+    pub fn next_timeout<T: Into<Timeout>>(&mut self, timeout: T) -> Option<::Result<Vec<u8>>> {
+        let timeout = timeout.into();
+
+        if let Some(err) = self.error.take() {
+            return Some(Err(err));
+        }
+
+        if self.stopped || self.queue.is_empty() {
+            return None;
+        }
+
+        // the endpoint ran out of transfers if every transfer completed before it was waited for
+        if self.is_overrun() {
+            transfer::record_overrun(&mut self.stats);
+        }
+
+        let (res, len) = {
+            let slot = self.queue.front_mut().unwrap();
+
+            match slot.transfer {
+                Some(ref mut transfer) => {
+                    if !transfer.wait_timeout(timeout) {
+                        return Some(Err(device_handle::endpoint_error(self.handle, ErrorKind::Timeout, "bulk_stream", self.endpoint)));
+                    }
+
+                    match transfer.result() {
+                        0 => (Ok(()), transfer.actual_length()),
+                        err => (Err(error::from_libusb(err)), transfer.actual_length()),
+                    }
+                },
+                None => {
+                    let ptr = slot.buf.as_mut_ptr();
+
+                    match unsafe { device_handle::backend_transfer(self.handle, TransferType::Bulk, self.endpoint, ptr, slot.buf.len(), timeout, transfer::flags_to_libusb(&slot.flags)) } {
+                        // the transfer is performed again by the next wait
+                        (Err(ref err), 0) if err.kind() == ErrorKind::Timeout => return Some(Err(device_handle::endpoint_error(self.handle, ErrorKind::Timeout, "bulk_stream", self.endpoint))),
+                        res => res,
+                    }
+                },
+            }
+        };
+
+        let mut slot = self.queue.pop_front().unwrap();
+
+        match res {
+            Ok(()) => {
+                let buf = self.pool.pop().unwrap_or_else(Vec::new);
+
+                let mut data = mem::replace(&mut slot.buf, buf);
+                data.truncate(len);
+
+                transfer::record_transfer(&mut self.stats, len);
+
+                if let Err(err) = self.submit(slot) {
+                    self.stop(err);
+                }
+
+                Some(Ok(data))
+            },
+            Err(err) => {
+                let err = transfer::transfer_error(&slot.flags, err, len, self.transfer_size);
+                self.stop(err);
+                self.error.take().map(Err)
+            },
+        }
+    }
+
+    /// Indicates if every queued transfer has completed.
+    ///
+    /// Backends perform transfers only when they're waited for, so a stream on a device provided by
+    /// a backend never runs out of transfers.
+    fn is_overrun(&self) -> bool {
+        if self.queue.iter().any(|slot| slot.transfer.is_none()) {
+            return false;
+        }
+
+        // reap the transfers that have completed since the last wait
+        transfer::poll_events(device_handle::context_raw(self.handle));
+
+        self.queue.iter().all(|slot| slot.is_completed())
+    }
+
+    fn submit(&mut self, mut slot: Slot) -> ::Result<()> {
+        slot.buf.resize(self.transfer_size, 0);
+        slot.flags = self.flags;
+
+        if let Some(ref mut transfer) = slot.transfer {
+            unsafe {
+                transfer.fill(device_handle::as_raw(self.handle), LIBUSB_TRANSFER_TYPE_BULK, self.endpoint, slot.buf.as_mut_ptr(), slot.buf.len(), 0, transfer::flags_to_libusb(&slot.flags));
+            }
+
+            match transfer.submit() {
+                0 => (),
+                err => return Err(error::from_libusb(err)),
+            }
+        }
+
+        self.queue.push_back(slot);
+        Ok(())
+    }
+
+    fn stop(&mut self, err: Error) {
+        let err = device_handle::endpoint_error(self.handle, err, "bulk_stream", self.endpoint);
+
+        transfer::record_error(&mut self.stats);
+
+        for slot in self.queue.iter() {
+            slot.cancel();
+        }
+
+        self.queue.clear();
+        self.error = Some(err);
+        self.stopped = true;
+    }
+}
+
+impl<'h, 'a> Iterator for BulkStream<'h, 'a> {
+    type Item = ::Result<Vec<u8>>;
+
+    /// Waits for the next transfer to complete.
+    ///
+    /// This method blocks until the next transfer completes.
+    fn next(&mut self) -> Option<::Result<Vec<u8>>> {
+        self.next_timeout(Timeout::Infinite)
+    }
+}
+
+
+#[doc(hidden)]
+pub fn new<'h, 'a>(handle: &'h DeviceHandle<'a>, endpoint: u8, num_transfers: usize, transfer_size: usize) -> ::Result<BulkStream<'h, 'a>> {
+    let mut stream = BulkStream {
+        handle: handle,
+        endpoint: endpoint,
+        num_transfers: num_transfers,
+        transfer_size: transfer_size,
+        queue: VecDeque::with_capacity(num_transfers),
+        pool: Vec::with_capacity(num_transfers),
+        flags: TransferFlags::new(),
+        stats: transfer::new_stats(),
+        error: None,
+        stopped: false,
+    };
+
+    // devices provided by a backend have no `libusb` handle
+    let backend = device_handle::as_raw(handle).is_null();
+
+    for _ in 0..num_transfers {
+        let transfer = if backend {
+            None
+        }
+        else {
+            Some(try!(Transfer::new(device_handle::context_raw(handle)).map_err(|err| device_handle::endpoint_error(handle, err, "bulk_stream", endpoint))))
+        };
+
+        let slot = Slot {
+            transfer: transfer,
+            buf: Vec::with_capacity(transfer_size),
+            flags: TransferFlags::new(),
+        };
+
+        try!(stream.submit(slot).map_err(|err| device_handle::endpoint_error(handle, err, "bulk_stream", endpoint)));
+    }
+
+    Ok(stream)
+}
+
+#[doc(hidden)]
+pub fn round_transfer_size(transfer_size: usize, packet_size: usize) -> usize {
+    (transfer_size + packet_size - 1) / packet_size * packet_size
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use context::Context;
+    use error::ErrorKind;
+    use test_helpers::{context_with, mock_device, open};
+    use transfer::TransferFlags;
+
+    use super::round_transfer_size;
+
+    fn context() -> Context {
+        context_with(&[&mock_device(512)])
+    }
+
+    fn stream_error(context: &Context, endpoint: u8, num_transfers: usize, transfer_size: usize) -> ErrorKind {
+        open(context).bulk_stream(endpoint, num_transfers, transfer_size).err().unwrap().kind()
+    }
+
+    #[test]
+    fn it_rejects_zero_transfers() {
+        assert_eq!(ErrorKind::InvalidParam, stream_error(&context(), 0x81, 0, 512));
+    }
+
+    #[test]
+    fn it_rejects_zero_transfer_size() {
+        assert_eq!(ErrorKind::InvalidParam, stream_error(&context(), 0x81, 4, 0));
+    }
+
+    #[test]
+    fn it_rejects_output_endpoint() {
+        assert_eq!(ErrorKind::InvalidParam, stream_error(&context(), 0x02, 4, 512));
+    }
+
+    #[test]
+    fn it_rejects_non_bulk_endpoint() {
+        assert_eq!(ErrorKind::WrongTransferType, stream_error(&context(), 0x83, 4, 512));
+    }
+
+    #[test]
+    fn it_rejects_unknown_endpoint() {
+        assert_eq!(ErrorKind::UnknownEndpoint, stream_error(&context(), 0x85, 4, 512));
+    }
+
+    #[test]
+    fn it_streams_transfers_in_order() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 4, 1000).unwrap();
+
+        device.push_in(0x81, vec![1; 1024]);
+        device.push_in(0x81, vec![2; 512]);
+        device.push_in(0x81, vec![3; 10]);
+
+        assert_eq!(1024, stream.transfer_size());
+        assert_eq!(vec![1; 1024], stream.next().unwrap().unwrap());
+        assert_eq!(vec![2; 512], stream.next().unwrap().unwrap());
+        assert_eq!(vec![3; 10], stream.next().unwrap().unwrap());
+    }
+
+    #[test]
+    fn it_keeps_running_after_timeout() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 2, 512).unwrap();
+
+        assert_eq!(ErrorKind::Timeout, stream.next_timeout(Duration::from_millis(10)).unwrap().unwrap_err().kind());
+
+        device.push_in(0x81, vec![1, 2, 3]);
+        assert_eq!(vec![1, 2, 3], stream.next_timeout(Duration::from_millis(10)).unwrap().unwrap());
+        assert_eq!(0, stream.stats().errors());
+    }
+
+    #[test]
+    fn it_reuses_recycled_buffers() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 1, 512).unwrap();
+
+        for i in 0..3 {
+            device.push_in(0x81, vec![i; 512]);
+        }
+
+        let first = stream.next().unwrap().unwrap();
+        let ptr = first.as_ptr();
+        stream.recycle(first);
+
+        // the recycled buffer receives the transfer after the one that's already queued
+        assert_eq!(vec![1; 512], stream.next().unwrap().unwrap());
+
+        let third = stream.next().unwrap().unwrap();
+        assert_eq!(vec![2; 512], third);
+        assert_eq!(ptr, third.as_ptr());
+    }
+
+    #[test]
+    fn it_records_stats() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 2, 512).unwrap();
+
+        device.push_in(0x81, vec![1; 512]);
+        device.push_in(0x81, vec![2; 100]);
+
+        stream.next().unwrap().unwrap();
+        stream.next().unwrap().unwrap();
+
+        let stats = stream.stats();
+        assert_eq!(612, stats.bytes());
+        assert_eq!(2, stats.transfers());
+        assert_eq!(0, stats.errors());
+        assert!(stats.throughput() > 0.0);
+    }
+
+    #[test]
+    fn it_does_not_count_overruns_with_single_transfer() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 1, 512).unwrap();
+
+        for i in 0..3 {
+            device.push_in(0x81, vec![i; 512]);
+            stream.next().unwrap().unwrap();
+        }
+
+        assert_eq!(3, stream.stats().transfers());
+        assert_eq!(0, stream.stats().overruns());
+    }
+
+    #[test]
+    fn it_stops_at_first_error() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 2, 512).unwrap();
+
+        device.push_in(0x81, vec![1; 512]);
+        device.push_in_error(0x81, ErrorKind::Pipe);
+        device.push_in(0x81, vec![2; 512]);
+
+        assert_eq!(vec![1; 512], stream.next().unwrap().unwrap());
+
+        let err = stream.next().unwrap().unwrap_err();
+        assert_eq!(ErrorKind::Pipe, err.kind());
+        assert_eq!(Some("bulk_stream"), err.operation());
+        assert_eq!(Some(0x81), err.endpoint());
+
+        assert!(stream.next().is_none());
+        assert_eq!(1, stream.stats().transfers());
+        assert_eq!(1, stream.stats().errors());
+    }
+
+    #[test]
+    fn it_stops_at_short_transfer_with_short_not_ok_flag() {
+        let device = mock_device(512);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut stream = handle.bulk_stream(0x81, 1, 512).unwrap();
+
+        device.push_in(0x81, vec![1; 100]);
+        device.push_in(0x81, vec![2; 100]);
+
+        // the flags apply to the transfer that's queued after the first one completes
+        stream.set_flags(TransferFlags::new().short_not_ok(true));
+
+        assert_eq!(vec![1; 100], stream.next().unwrap().unwrap());
+        assert_eq!(ErrorKind::ShortTransfer, stream.next().unwrap().unwrap_err().kind());
+        assert!(stream.next().is_none());
+    }
+
+    #[test]
+    fn it_rounds_transfer_size_up_to_packet_size() {
+        assert_eq!(512, round_transfer_size(1, 512));
+        assert_eq!(512, round_transfer_size(512, 512));
+        assert_eq!(1024, round_transfer_size(513, 512));
+        assert_eq!(65024, round_transfer_size(65000, 512));
+        assert_eq!(7, round_transfer_size(7, 1));
+    }
+}
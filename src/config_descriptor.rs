@@ -1,20 +1,39 @@
+use std::cmp;
 use std::fmt;
 use std::mem;
+use std::ptr;
 use std::slice;
 
+use libc::c_int;
 use libusb::*;
 
+use error::{Error, ErrorKind};
 use interface_descriptor::{self, Interface};
 
 /// Describes a configuration.
 pub struct ConfigDescriptor {
     descriptor: *const libusb_config_descriptor,
+    parsed: Option<Box<ParsedConfig>>,
+}
+
+/// A configuration descriptor that was parsed by this crate instead of `libusb`.
+///
+/// The `libusb` structures point into the vectors, whose heap allocations don't move when the
+/// parsed configuration is moved.
+struct ParsedConfig {
+    config: libusb_config_descriptor,
+    interfaces: Vec<libusb_interface>,
+    settings: Vec<Vec<libusb_interface_descriptor>>,
+    endpoints: Vec<Vec<libusb_endpoint_descriptor>>,
+    extra: Vec<Vec<u8>>,
 }
 
 impl Drop for ConfigDescriptor {
     fn drop(&mut self) {
-        unsafe {
-            libusb_free_config_descriptor(self.descriptor);
+        if self.parsed.is_none() {
+            unsafe {
+                libusb_free_config_descriptor(self.descriptor);
+            }
         }
     }
 }
@@ -124,7 +143,229 @@ impl<'a> Iterator for Interfaces<'a> {
 
 #[doc(hidden)]
 pub unsafe fn from_libusb(config: *const libusb_config_descriptor) -> ConfigDescriptor {
-    ConfigDescriptor { descriptor: config }
+    ConfigDescriptor {
+        descriptor: config,
+        parsed: None,
+    }
+}
+
+/// Parses a configuration descriptor and the descriptors that follow it, as returned by a
+/// `GET_DESCRIPTOR` request.
+///
+/// Descriptors that are not interface or endpoint descriptors are kept as extra bytes of the
+/// preceding endpoint, interface, or configuration descriptor, as `libusb` does.
+#[doc(hidden)]
+pub fn from_bytes(bytes: &[u8]) -> ::Result<ConfigDescriptor> {
+    if bytes.len() < 9 || bytes[0] < 9 || bytes[1] != LIBUSB_DT_CONFIG {
+        return Err(Error::new(ErrorKind::Io));
+    }
+
+    let total_length = cmp::min(bytes[2] as usize | (bytes[3] as usize) << 8, bytes.len());
+
+    let mut parsed = Box::new(ParsedConfig {
+        config: libusb_config_descriptor {
+            bLength: bytes[0],
+            bDescriptorType: bytes[1],
+            wTotalLength: bytes[2] as u16 | (bytes[3] as u16) << 8,
+            bNumInterfaces: 0,
+            bConfigurationValue: bytes[5],
+            iConfiguration: bytes[6],
+            bmAttributes: bytes[7],
+            bMaxPower: bytes[8],
+            interface: ptr::null(),
+            extra: ptr::null(),
+            extra_length: 0,
+        },
+        interfaces: Vec::new(),
+        settings: Vec::new(),
+        endpoints: Vec::new(),
+        extra: Vec::new(),
+    });
+
+    // (interface index, setting index) of each setting in the order in which they're parsed
+    let mut setting_positions: Vec<(usize, usize)> = Vec::new();
+    let mut config_extra = Vec::new();
+    let mut setting_extra: Vec<Vec<u8>> = Vec::new();
+    let mut endpoint_extra: Vec<Vec<Vec<u8>>> = Vec::new();
+
+    let mut offset = bytes[0] as usize;
+
+    while offset + 2 <= total_length {
+        let len = bytes[offset] as usize;
+
+        if len < 2 || offset + len > total_length {
+            break;
+        }
+
+        let descriptor = &bytes[offset..offset + len];
+
+        match descriptor[1] {
+            LIBUSB_DT_INTERFACE if len >= 9 => {
+                let setting = libusb_interface_descriptor {
+                    bLength: descriptor[0],
+                    bDescriptorType: descriptor[1],
+                    bInterfaceNumber: descriptor[2],
+                    bAlternateSetting: descriptor[3],
+                    bNumEndpoints: 0,
+                    bInterfaceClass: descriptor[5],
+                    bInterfaceSubClass: descriptor[6],
+                    bInterfaceProtocol: descriptor[7],
+                    iInterface: descriptor[8],
+                    endpoint: ptr::null(),
+                    extra: ptr::null(),
+                    extra_length: 0,
+                };
+
+                let position = parsed.settings.iter().position(|settings| settings[0].bInterfaceNumber == setting.bInterfaceNumber);
+
+                let i = match position {
+                    Some(i) => i,
+                    None => {
+                        parsed.settings.push(Vec::new());
+                        parsed.settings.len() - 1
+                    },
+                };
+
+                parsed.settings[i].push(setting);
+                setting_positions.push((i, parsed.settings[i].len() - 1));
+                parsed.endpoints.push(Vec::new());
+                setting_extra.push(Vec::new());
+                endpoint_extra.push(Vec::new());
+            },
+            LIBUSB_DT_ENDPOINT if len >= 7 && !setting_positions.is_empty() => {
+                let endpoint = libusb_endpoint_descriptor {
+                    bLength: descriptor[0],
+                    bDescriptorType: descriptor[1],
+                    bEndpointAddress: descriptor[2],
+                    bmAttributes: descriptor[3],
+                    wMaxPacketSize: descriptor[4] as u16 | (descriptor[5] as u16) << 8,
+                    bInterval: descriptor[6],
+                    bRefresh: if len >= 9 { descriptor[7] } else { 0 },
+                    bSynchAddress: if len >= 9 { descriptor[8] } else { 0 },
+                    extra: ptr::null(),
+                    extra_length: 0,
+                };
+
+                parsed.endpoints.last_mut().unwrap().push(endpoint);
+                endpoint_extra.last_mut().unwrap().push(Vec::new());
+            },
+            _ => {
+                let extra = match endpoint_extra.last_mut().and_then(|extra| extra.last_mut()) {
+                    Some(extra) => extra,
+                    None => match setting_extra.last_mut() {
+                        Some(extra) => extra,
+                        None => &mut config_extra,
+                    },
+                };
+
+                extra.extend_from_slice(descriptor);
+            },
+        }
+
+        offset += len;
+    }
+
+    // the vectors are complete, so the pointers into them remain valid
+    {
+        let ParsedConfig { ref mut config, ref mut interfaces, ref mut settings, ref mut endpoints, ref mut extra } = *parsed;
+
+        let (config_extra_ptr, config_extra_len) = keep_extra(extra, config_extra);
+        config.extra = config_extra_ptr;
+        config.extra_length = config_extra_len;
+
+        for (k, (&(i, j), setting_extra)) in setting_positions.iter().zip(setting_extra.into_iter()).enumerate() {
+            for (endpoint, endpoint_extra) in endpoints[k].iter_mut().zip(endpoint_extra[k].drain(..)) {
+                let (extra_ptr, extra_len) = keep_extra(extra, endpoint_extra);
+                endpoint.extra = extra_ptr;
+                endpoint.extra_length = extra_len;
+            }
+
+            let (extra_ptr, extra_len) = keep_extra(extra, setting_extra);
+            let setting = &mut settings[i][j];
+
+            setting.bNumEndpoints = endpoints[k].len() as u8;
+            setting.endpoint = endpoints[k].as_ptr();
+            setting.extra = extra_ptr;
+            setting.extra_length = extra_len;
+        }
+
+        for setting in settings.iter() {
+            interfaces.push(libusb_interface {
+                altsetting: setting.as_ptr(),
+                num_altsetting: setting.len() as c_int,
+            });
+        }
+
+        config.bNumInterfaces = interfaces.len() as u8;
+        config.interface = interfaces.as_ptr();
+    }
+
+    Ok(ConfigDescriptor {
+        descriptor: &parsed.config,
+        parsed: Some(parsed),
+    })
+}
+
+/// Returns the bytes of a configuration descriptor and the descriptors that follow it, as returned
+/// by a `GET_DESCRIPTOR` request.
+///
+/// Extra descriptors are written after the descriptor that they belong to. Alternate settings are
+/// written grouped by interface.
+#[doc(hidden)]
+pub fn to_bytes(config: &ConfigDescriptor) -> Vec<u8> {
+    let c = unsafe { &*config.descriptor };
+
+    let mut bytes = vec![9, LIBUSB_DT_CONFIG, 0, 0, c.bNumInterfaces, c.bConfigurationValue, c.iConfiguration, c.bmAttributes, c.bMaxPower];
+    push_extra(&mut bytes, c.extra, c.extra_length);
+
+    let interfaces = unsafe { slice::from_raw_parts(c.interface, c.bNumInterfaces as usize) };
+
+    for interface in interfaces {
+        let settings = unsafe { slice::from_raw_parts(interface.altsetting, interface.num_altsetting as usize) };
+
+        for s in settings {
+            bytes.extend_from_slice(&[9, LIBUSB_DT_INTERFACE, s.bInterfaceNumber, s.bAlternateSetting, s.bNumEndpoints, s.bInterfaceClass, s.bInterfaceSubClass, s.bInterfaceProtocol, s.iInterface]);
+            push_extra(&mut bytes, s.extra, s.extra_length);
+
+            let endpoints = unsafe { slice::from_raw_parts(s.endpoint, s.bNumEndpoints as usize) };
+
+            for e in endpoints {
+                // audio endpoint descriptors have two more fields
+                let len = if e.bLength >= 9 { 9 } else { 7 };
+
+                bytes.extend_from_slice(&[len, LIBUSB_DT_ENDPOINT, e.bEndpointAddress, e.bmAttributes, e.wMaxPacketSize as u8, (e.wMaxPacketSize >> 8) as u8, e.bInterval]);
+
+                if len == 9 {
+                    bytes.extend_from_slice(&[e.bRefresh, e.bSynchAddress]);
+                }
+
+                push_extra(&mut bytes, e.extra, e.extra_length);
+            }
+        }
+    }
+
+    let total_length = bytes.len();
+    bytes[2] = total_length as u8;
+    bytes[3] = (total_length >> 8) as u8;
+
+    bytes
+}
+
+fn push_extra(bytes: &mut Vec<u8>, extra: *const u8, extra_length: c_int) {
+    if !extra.is_null() && extra_length > 0 {
+        bytes.extend_from_slice(unsafe { slice::from_raw_parts(extra, extra_length as usize) });
+    }
+}
+
+fn keep_extra(extra: &mut Vec<Vec<u8>>, bytes: Vec<u8>) -> (*const u8, c_int) {
+    if bytes.is_empty() {
+        return (ptr::null(), 0);
+    }
+
+    let len = bytes.len() as c_int;
+    extra.push(bytes);
+
+    (extra.last().unwrap().as_ptr(), len)
 }
 
 
@@ -220,4 +461,44 @@ mod test {
             assert_eq!(vec![1], interface_numbers);
         });
     }
+
+    #[test]
+    fn it_parses_bytes() {
+        let bytes = [
+            9, 0x02, 46, 0, 2, 3, 0, 0x80, 50,
+            9, 0x04, 0, 0, 1, 0xFF, 0, 0, 0,
+            7, 0x05, 0x81, 0x02, 0x00, 0x02, 0,
+            9, 0x04, 0, 1, 0, 0xFF, 0, 0, 0,
+            9, 0x04, 1, 0, 0, 0x03, 0, 0, 0,
+            3, 0x21, 0xAA,
+        ];
+
+        let config = super::from_bytes(&bytes).unwrap();
+        assert_eq!(3, config.number());
+        assert_eq!(2, config.num_interfaces());
+
+        let interfaces = config.interfaces().collect::<Vec<_>>();
+        assert_eq!(2, interfaces[0].descriptors().count());
+        assert_eq!(0x81, interfaces[0].descriptors().next().unwrap().endpoint_descriptors().next().unwrap().address());
+        assert_eq!(0x03, interfaces[1].descriptors().next().unwrap().class_code());
+    }
+
+    #[test]
+    fn it_converts_parsed_bytes_back_to_bytes() {
+        let bytes = [
+            9, 0x02, 40, 0, 2, 1, 0, 0x80, 50,
+            4, 0x30, 0x01, 0x02,
+            9, 0x04, 0, 0, 1, 0x01, 0x02, 0, 0,
+            9, 0x05, 0x81, 0x01, 0xC0, 0x00, 1, 0, 0,
+            9, 0x04, 1, 0, 0, 0x03, 0, 0, 0,
+        ];
+
+        assert_eq!(&bytes[..], &super::to_bytes(&super::from_bytes(&bytes).unwrap())[..]);
+    }
+
+    #[test]
+    fn it_rejects_invalid_bytes() {
+        assert!(super::from_bytes(&[9, 0x02, 9, 0, 0, 1]).is_err());
+        assert!(super::from_bytes(&[9, 0x01, 9, 0, 0, 1, 0, 0x80, 50]).is_err());
+    }
 }
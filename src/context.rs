@@ -1,27 +1,48 @@
-use std::marker::PhantomData;
+use std::cmp;
 use std::mem;
+use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
-use libc::c_int;
+use libc::{c_int, c_void};
 use libusb::*;
 
+use backend::Backend;
+use dispatch::ContextSource;
+use device::Device;
 use device_list::{self, DeviceList};
-use device_handle::{self, DeviceHandle};
-use error;
+use device_handle::DeviceHandle;
+use error::{self, Error, ErrorKind};
+use filter::{self, DeviceFilter};
+use timeout::Timeout;
+use transfer;
 
-/// A `libusb` context.
-pub struct Context {
-    context: *mut libusb_context,
-}
+mod ffi {
+    use libc::{c_int, c_void};
+    use libusb::{libusb_context, libusb_device};
 
-impl Drop for Context {
-    /// Closes the `libusb` context.
-    fn drop(&mut self) {
-        unsafe {
-            libusb_exit(self.context);
-        }
+    pub const LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED: c_int = 0x01;
+    pub const LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT: c_int = 0x02;
+    pub const LIBUSB_HOTPLUG_MATCH_ANY: c_int = -1;
+
+    // available since libusb 1.0.16
+    extern "C" {
+        pub fn libusb_hotplug_register_callback(ctx: *mut libusb_context, events: c_int, flags: c_int, vendor_id: c_int, product_id: c_int, dev_class: c_int, cb_fn: extern "C" fn(*mut libusb_context, *mut libusb_device, c_int, *mut c_void) -> c_int, user_data: *mut c_void, handle: *mut c_int) -> c_int;
+        pub fn libusb_hotplug_deregister_callback(ctx: *mut libusb_context, handle: c_int);
     }
 }
 
+/// How often the device list is polled while waiting for devices without hotplug support.
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// A `libusb` context.
+///
+/// A context can also be created with a [`Backend`](trait.Backend.html) that provides devices
+/// instead of `libusb`.
+pub struct Context {
+    source: ContextSource,
+}
+
 unsafe impl Sync for Context {}
 unsafe impl Send for Context {}
 
@@ -32,14 +53,32 @@ impl Context {
 
         try_unsafe!(libusb_init(&mut context));
 
-        Ok(Context { context: context })
+        Ok(Context {
+            source: ContextSource::Libusb(context),
+        })
+    }
+
+    /// Creates a context whose devices are provided by a backend instead of `libusb`.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// let backend = libusb::MockBackend::new();
+    /// backend.add_device(libusb::MockDevice::new(0x1234, 0x5678));
+    ///
+    /// let context = libusb::Context::with_backend(backend);
+    /// ```
+    pub fn with_backend<B: Backend + 'static>(backend: B) -> Self {
+        Context {
+            source: ContextSource::Backend(Box::new(backend)),
+        }
     }
 
     /// Sets the log level of a `libusb` context.
+    ///
+    /// The log level has no effect on contexts that are created with a backend.
     pub fn set_log_level(&mut self, level: LogLevel) {
-        unsafe {
-            libusb_set_debug(self.context, level.as_c_int());
-        }
+        self.source.set_log_level(level.as_c_int());
     }
 
     pub fn has_capability(&self) -> bool {
@@ -71,15 +110,172 @@ impl Context {
 
     /// Returns a list of the current USB devices. The context must outlive the device list.
     pub fn devices<'a>(&'a self) -> ::Result<DeviceList<'a>> {
-        let mut list: *const *mut libusb_device = unsafe { mem::uninitialized() };
+        Ok(device_list::new(self, try!(self.source.devices())))
+    }
+
+    /// Returns the devices that match a filter.
+    ///
+    /// Devices that can't be checked against the filter, e.g., because they can't be opened to
+    /// read their serial numbers, are left out.
+    pub fn find_devices<'a>(&'a self, filter: &DeviceFilter) -> ::Result<Vec<Device<'a>>> {
+        let devices = try!(self.devices());
+
+        Ok(devices.iter().filter(|device| filter.matches(device).unwrap_or(false)).collect())
+    }
+
+    /// Opens the first device that matches a filter.
+    ///
+    /// Matching devices are opened in the order in which they're listed until one of them opens.
+    ///
+    /// ## Errors
+    ///
+    /// * `NotFound` if no device matches the filter.
+    /// * The error of the last matching device if none of the matching devices could be opened,
+    ///   e.g., `Access`.
+    pub fn open_first<'a>(&'a self, filter: &DeviceFilter) -> ::Result<DeviceHandle<'a>> {
+        let mut result = Err(Error::new(ErrorKind::NotFound));
+
+        for device in try!(self.find_devices(filter)) {
+            result = device.open();
+
+            if result.is_ok() {
+                break;
+            }
+        }
+
+        result
+    }
+
+    /// Opens the device with the given vendor ID, product ID, and serial number.
+    ///
+    /// Devices with matching vendor and product IDs are opened one at a time to read their serial
+    /// number string in the first language that they support. Devices whose serial number doesn't
+    /// match are closed immediately, and devices without a serial number string are never opened.
+    /// `timeout` applies to each of the requests that read a device's languages and serial number
+    /// string.
+    ///
+    /// ## Errors
+    ///
+    /// * `Ambiguous` if more than one device has the serial number.
+    /// * `NotFound` if no device has the serial number.
+    /// * The error of the last candidate that couldn't be opened or couldn't report its serial
+    ///   number if none of the other candidates has the serial number, e.g., `Access`.
+    pub fn open_by_serial<'a, T: Into<Timeout>>(&'a self, vendor_id: u16, product_id: u16, serial_number: &str, timeout: T) -> ::Result<DeviceHandle<'a>> {
+        let timeout = timeout.into();
+        let candidates = try!(self.find_devices(&DeviceFilter::new().vendor_id(vendor_id).product_id(product_id)));
+
+        let mut found = None;
+        let mut error = None;
+
+        for device in candidates {
+            let descriptor = match device.device_descriptor() {
+                Ok(descriptor) => descriptor,
+                Err(err) => { error = Some(err); continue },
+            };
+
+            if descriptor.serial_number_string_index().is_none() {
+                continue;
+            }
+
+            let handle = match device.open() {
+                Ok(handle) => handle,
+                Err(err) => { error = Some(err); continue },
+            };
+
+            match filter::read_serial_number(&handle, &descriptor, timeout) {
+                Ok(Some(ref serial)) if serial == serial_number => {
+                    if found.is_some() {
+                        return Err(Error::new(ErrorKind::Ambiguous));
+                    }
+
+                    found = Some(handle);
+                },
+                Ok(_) => (),
+                Err(err) => error = Some(err),
+            }
+        }
+
+        match found {
+            Some(handle) => Ok(handle),
+            None => Err(error.unwrap_or(Error::new(ErrorKind::NotFound))),
+        }
+    }
+
+    /// Waits until a device that matches a filter is connected.
+    ///
+    /// Returns the first matching device as soon as one exists, which may be immediately. If the
+    /// running `libusb` library supports hotplug, the device list is checked again whenever a
+    /// device arrives or leaves. Otherwise, the device list is polled periodically.
+    ///
+    /// ## Errors
+    ///
+    /// * `Timeout` if no matching device is connected before the timeout expires.
+    ///
+    /// ## Example
+    ///
+    /// ```no_run
+    /// use std::time::Duration;
+    ///
+    /// # fn reset(context: &libusb::Context) -> libusb::Result<()> {
+    /// let bootloader = libusb::DeviceFilter::new().vendor_id(0x0483).product_id(0xdf11);
+    ///
+    /// let device = try!(context.wait_for_device(&bootloader, Duration::from_secs(10)));
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn wait_for_device<'a, T: Into<Timeout>>(&'a self, filter: &DeviceFilter, timeout: T) -> ::Result<Device<'a>> {
+        self.wait_until(timeout.into(), || {
+            Ok(try!(self.find_devices(filter)).into_iter().next())
+        })
+    }
+
+    /// Waits until no device that matches a filter is connected.
+    ///
+    /// Returns immediately if no matching device exists. Otherwise, the device list is checked
+    /// again in the same way as [`wait_for_device()`](#method.wait_for_device).
+    ///
+    /// ## Errors
+    ///
+    /// * `Timeout` if a matching device is still connected when the timeout expires.
+    pub fn wait_for_device_removal<T: Into<Timeout>>(&self, filter: &DeviceFilter, timeout: T) -> ::Result<()> {
+        self.wait_until(timeout.into(), || {
+            if try!(self.find_devices(filter)).is_empty() {
+                Ok(Some(()))
+            }
+            else {
+                Ok(None)
+            }
+        })
+    }
 
-        let n = unsafe { libusb_get_device_list(self.context, &mut list) };
+    fn wait_until<T, F>(&self, timeout: Timeout, mut check: F) -> ::Result<T>
+        where F: FnMut() -> ::Result<Option<T>>
+    {
+        let deadline = timeout.to_deadline();
 
-        if n < 0 {
-            Err(error::from_libusb(n as c_int))
+        let watch = if self.source.is_libusb() && self.has_hotplug() {
+            Some(try!(HotplugWatch::register(self)))
         }
         else {
-            Ok(unsafe { device_list::from_libusb(self, list, n as usize) })
+            None
+        };
+
+        loop {
+            if let Some(value) = try!(check()) {
+                return Ok(value);
+            }
+
+            if deadline.is_expired() {
+                return Err(Error::new(ErrorKind::Timeout));
+            }
+
+            match watch {
+                Some(ref watch) => try!(watch.wait(&deadline)),
+                None => {
+                    let interval = Duration::from_millis(POLL_INTERVAL_MS);
+                    thread::sleep(deadline.remaining().map_or(interval, |remaining| cmp::min(remaining, interval)));
+                },
+            }
         }
     }
 
@@ -87,22 +283,93 @@ impl Context {
     ///
     /// This function is provided as a convenience for building prototypes without having to
     /// iterate a [`DeviceList`](struct.DeviceList.html). It is not meant for production
-    /// applications.
+    /// applications, which can use [`open_first()`](#method.open_first) instead.
     ///
     /// Returns a device handle for the first device found matching `vendor_id` and `product_id`.
     /// On error, or if the device could not be found, it returns `None`.
     pub fn open_device_with_vid_pid<'a>(&'a self, vendor_id: u16, product_id: u16) -> Option<DeviceHandle<'a>> {
-        let handle = unsafe { libusb_open_device_with_vid_pid(self.context, vendor_id, product_id) };
+        let devices = match self.devices() {
+            Ok(devices) => devices,
+            Err(_) => return None,
+        };
 
-        if handle.is_null() {
-            None
+        devices.iter()
+            .find(|device| device.device_descriptor().map(|descriptor| descriptor.vendor_id() == vendor_id && descriptor.product_id() == product_id).unwrap_or(false))
+            .and_then(|device| device.open().ok())
+    }
+}
+
+
+#[doc(hidden)]
+pub fn as_raw(context: &Context) -> *mut libusb_context {
+    context.source.as_raw()
+}
+
+
+/// A hotplug callback that records whether any device arrived or left.
+struct HotplugWatch<'a> {
+    context: &'a Context,
+    handle: c_int,
+    changed: Box<AtomicBool>,
+}
+
+impl<'a> Drop for HotplugWatch<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::libusb_hotplug_deregister_callback(as_raw(self.context), self.handle);
         }
-        else {
-            Some(unsafe { device_handle::from_libusb(PhantomData, handle) })
+    }
+}
+
+impl<'a> HotplugWatch<'a> {
+    fn register(context: &'a Context) -> ::Result<Self> {
+        let changed = Box::new(AtomicBool::new(false));
+        let mut handle: c_int = 0;
+
+        try_unsafe!(ffi::libusb_hotplug_register_callback(
+            as_raw(context),
+            ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_ARRIVED | ffi::LIBUSB_HOTPLUG_EVENT_DEVICE_LEFT,
+            0,
+            ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+            ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+            ffi::LIBUSB_HOTPLUG_MATCH_ANY,
+            hotplug_callback,
+            &*changed as *const AtomicBool as *mut c_void,
+            &mut handle));
+
+        Ok(HotplugWatch {
+            context: context,
+            handle: handle,
+            changed: changed,
+        })
+    }
+
+    /// Handles events until a device arrives or leaves or until the deadline passes.
+    fn wait(&self, deadline: &Timeout) -> ::Result<()> {
+        loop {
+            let res = transfer::handle_events(as_raw(self.context), deadline.remaining());
+
+            if res < 0 && res != LIBUSB_ERROR_INTERRUPTED {
+                return Err(error::from_libusb(res));
+            }
+
+            if self.changed.swap(false, Ordering::SeqCst) || deadline.is_expired() {
+                return Ok(());
+            }
         }
     }
 }
 
+extern "C" fn hotplug_callback(_context: *mut libusb_context, _device: *mut libusb_device, _event: c_int, user_data: *mut c_void) -> c_int {
+    let changed = user_data as *const AtomicBool;
+
+    unsafe {
+        (*changed).store(true, Ordering::SeqCst);
+    }
+
+    0
+}
+
 
 /// Library logging levels.
 pub enum LogLevel {
@@ -135,3 +402,88 @@ impl LogLevel {
         }
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use error::ErrorKind;
+    use filter::DeviceFilter;
+    use mock::{MockBackend, MockDevice};
+
+    use super::Context;
+
+    #[test]
+    fn it_opens_device_by_serial_number() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice::new(0x1234, 0x0001).serial_number("A1"));
+        backend.add_device(MockDevice::new(0x1234, 0x0002).serial_number("B2"));
+        backend.add_device(MockDevice::new(0x5678, 0x0001));
+        let context = Context::with_backend(backend);
+
+        assert!(context.open_by_serial(0x1234, 0x0002, "B2", Duration::from_secs(1)).is_ok());
+        assert_eq!(ErrorKind::NotFound, context.open_by_serial(0x1234, 0x0002, "A1", Duration::from_secs(1)).err().unwrap().kind());
+        assert_eq!(ErrorKind::NotFound, context.open_by_serial(0x5678, 0x0001, "", Duration::from_secs(1)).err().unwrap().kind());
+    }
+
+    #[test]
+    fn it_reports_ambiguous_serial_numbers() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice::new(0x1234, 0x0001).address(1).serial_number("A1"));
+        backend.add_device(MockDevice::new(0x1234, 0x0001).address(2).serial_number("A1"));
+        backend.add_device(MockDevice::new(0x1234, 0x0001).address(3).serial_number("B2"));
+        let context = Context::with_backend(backend);
+
+        assert!(context.open_by_serial(0x1234, 0x0001, "B2", Duration::from_secs(1)).is_ok());
+        assert_eq!(ErrorKind::Ambiguous, context.open_by_serial(0x1234, 0x0001, "A1", Duration::from_secs(1)).err().unwrap().kind());
+    }
+
+    #[test]
+    fn it_reports_serial_number_timeout() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice::new(0x1234, 0x0001).serial_number("A1"));
+        let context = Context::with_backend(backend);
+
+        assert_eq!(ErrorKind::Timeout, context.open_by_serial(0x1234, 0x0001, "A1", Instant::now()).err().unwrap().kind());
+    }
+
+    #[test]
+    fn it_waits_for_device_to_appear() {
+        let backend = MockBackend::new();
+        let context = Context::with_backend(backend.clone());
+        let filter = DeviceFilter::new().vendor_id(0x1234);
+
+        assert_eq!(ErrorKind::Timeout, context.wait_for_device(&filter, Duration::from_millis(10)).err().unwrap().kind());
+
+        let arrival = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            backend.add_device(MockDevice::new(0x1234, 0x0001));
+        });
+
+        let device = context.wait_for_device(&filter, Duration::from_secs(5)).unwrap();
+        assert_eq!(0x1234, device.device_descriptor().unwrap().vendor_id());
+        arrival.join().unwrap();
+    }
+
+    #[test]
+    fn it_waits_for_device_to_be_removed() {
+        let backend = MockBackend::new();
+        let device = MockDevice::new(0x1234, 0x0001);
+        backend.add_device(device.clone());
+        let context = Context::with_backend(backend.clone());
+        let filter = DeviceFilter::new().vendor_id(0x1234);
+
+        assert!(context.wait_for_device_removal(&DeviceFilter::new().vendor_id(0x5678), Duration::from_millis(10)).is_ok());
+        assert_eq!(ErrorKind::Timeout, context.wait_for_device_removal(&filter, Duration::from_millis(10)).err().unwrap().kind());
+
+        let removal = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            backend.remove_device(&device);
+        });
+
+        assert!(context.wait_for_device_removal(&filter, Duration::from_secs(5)).is_ok());
+        removal.join().unwrap();
+    }
+}
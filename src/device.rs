@@ -1,99 +1,155 @@
-use std::marker::PhantomData;
-use std::mem;
-
-use libusb::*;
+use std::hash::{Hash, Hasher};
 
 use context::Context;
 use device_handle::{self, DeviceHandle};
 use device_descriptor::{self, DeviceDescriptor};
 use config_descriptor::{self, ConfigDescriptor};
-use fields::{self, Speed};
+use device_id::{self, DeviceId};
+use dispatch::DeviceSource;
+use error::ErrorKind;
+use fields::Speed;
+use filter;
+use timeout::Timeout;
 
 
 /// A reference to a USB device.
 pub struct Device<'a> {
-    context: PhantomData<&'a Context>,
-    device: *mut libusb_device,
+    context: &'a Context,
+    source: DeviceSource,
 }
 
-impl<'a> Drop for Device<'a> {
-    /// Releases the device reference.
-    fn drop(&mut self) {
-        unsafe {
-            libusb_unref_device(self.device);
-        }
+unsafe impl<'a> Send for Device<'a> {}
+unsafe impl<'a> Sync for Device<'a> {}
+
+impl<'a> PartialEq for Device<'a> {
+    /// Tests whether two references refer to the same device.
+    ///
+    /// References from different device lists are equal if they refer to the same `libusb` device,
+    /// which stays the same for as long as the device remains connected.
+    fn eq(&self, other: &Self) -> bool {
+        self.identity() == other.identity()
     }
 }
 
-unsafe impl<'a> Send for Device<'a> {}
-unsafe impl<'a> Sync for Device<'a> {}
+impl<'a> Eq for Device<'a> {}
+
+impl<'a> Hash for Device<'a> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.identity().hash(state);
+    }
+}
 
 impl<'a> Device<'a> {
     /// Reads the device descriptor.
     pub fn device_descriptor(&self) -> ::Result<DeviceDescriptor> {
-        let mut descriptor: libusb_device_descriptor = unsafe { mem::uninitialized() };
-
-        // since libusb 1.0.16, this function always succeeds
-        try_unsafe!(libusb_get_device_descriptor(self.device, &mut descriptor));
-
-        Ok(device_descriptor::from_libusb(descriptor))
+        self.source.device_descriptor()
     }
 
     /// Reads a configuration descriptor.
     pub fn config_descriptor(&self, config_index: u8) -> ::Result<ConfigDescriptor> {
-        let mut config: *const libusb_config_descriptor = unsafe { mem::uninitialized() };
-
-        try_unsafe!(libusb_get_config_descriptor(self.device, config_index, &mut config));
-
-        Ok(unsafe { config_descriptor::from_libusb(config) })
+        self.source.config_descriptor(config_index)
     }
 
     /// Reads the configuration descriptor for the current configuration.
     pub fn active_config_descriptor(&self) -> ::Result<ConfigDescriptor> {
-        let mut config: *const libusb_config_descriptor = unsafe { mem::uninitialized() };
-
-        try_unsafe!(libusb_get_active_config_descriptor(self.device, &mut config));
-
-        Ok(unsafe { config_descriptor::from_libusb(config) })
+        self.source.active_config_descriptor()
     }
 
     /// Returns the number of the bus that the device is connected to.
     pub fn bus_number(&self) -> u8 {
-        unsafe {
-            libusb_get_bus_number(self.device)
-        }
+        self.source.bus_number()
     }
 
     /// Returns the device's address on the bus that it's connected to.
     pub fn address(&self) -> u8 {
-        unsafe {
-            libusb_get_device_address(self.device)
-        }
+        self.source.address()
+    }
+
+    /// Returns the number of the port that the device is connected to.
+    ///
+    /// Returns 0 if the port number is not available.
+    pub fn port_number(&self) -> u8 {
+        self.source.port_number()
+    }
+
+    /// Returns the numbers of the ports from the root hub to the device, which is the device's
+    /// port path.
+    pub fn port_numbers(&self) -> ::Result<Vec<u8>> {
+        self.source.port_numbers()
     }
 
     /// Returns the device's connection speed.
     pub fn speed(&self) -> Speed {
-        fields::speed_from_libusb(unsafe {
-            libusb_get_device_speed(self.device)
-        })
+        self.source.speed()
+    }
+
+    /// Returns an identifier for the device that doesn't include its serial number.
+    ///
+    /// The device isn't opened. See [`DeviceId`](struct.DeviceId.html).
+    pub fn id(&self) -> ::Result<DeviceId> {
+        self.id_with_serial_number(None)
+    }
+
+    /// Returns an identifier for the device that includes its serial number.
+    ///
+    /// The device is opened to read its serial number string in the first language that it
+    /// supports. The serial number is left unknown if the device doesn't have one. See
+    /// [`DeviceId`](struct.DeviceId.html).
+    pub fn read_id<T: Into<Timeout>>(&self, timeout: T) -> ::Result<DeviceId> {
+        let descriptor = try!(self.device_descriptor());
+        let handle = try!(self.open());
+        let serial_number = try!(filter::read_serial_number(&handle, &descriptor, timeout));
+
+        self.id_with_serial_number(serial_number)
     }
 
     /// Opens the device.
     pub fn open(&self) -> ::Result<DeviceHandle<'a>> {
-        let mut handle: *mut libusb_device_handle = unsafe { mem::uninitialized() };
+        Ok(device_handle::new(self.context, try!(self.source.open())))
+    }
+
+    fn id_with_serial_number(&self, serial_number: Option<String>) -> ::Result<DeviceId> {
+        let descriptor = try!(self.device_descriptor());
+        let port_numbers = try!(self.port_numbers());
 
-        try_unsafe!(libusb_open(self.device, &mut handle));
+        Ok(device_id::new(self.bus_number(), port_numbers, descriptor.vendor_id(), descriptor.product_id(), serial_number))
+    }
 
-        Ok(unsafe { device_handle::from_libusb(self.context, handle) })
+    fn identity(&self) -> usize {
+        self.source.identity()
     }
 }
 
+/// Returns the bytes of a device's device descriptor and configuration descriptors and the number
+/// of its active configuration, which is 0 if the device is unconfigured.
 #[doc(hidden)]
-pub unsafe fn from_libusb<'a>(context: PhantomData<&'a Context>, device: *mut libusb_device) -> Device<'a> {
-    libusb_ref_device(device);
+pub fn descriptor_bytes(device: &Device) -> ::Result<(Vec<u8>, Vec<Vec<u8>>, u8)> {
+    let device_descriptor = try!(device.device_descriptor());
+    let mut config_descriptors = Vec::new();
 
+    for i in 0..device_descriptor.num_configurations() {
+        config_descriptors.push(config_descriptor::to_bytes(&try!(device.config_descriptor(i))));
+    }
+
+    let active_config = match device.active_config_descriptor() {
+        Ok(config) => config.number(),
+        Err(ref err) if err.kind() == ErrorKind::NotFound => 0,
+        Err(err) => return Err(err),
+    };
+
+    Ok((device_descriptor::to_bytes(&device_descriptor), config_descriptors, active_config))
+}
+
+/// Returns another reference to the same device.
+#[doc(hidden)]
+pub fn duplicate<'a>(device: &Device<'a>) -> Device<'a> {
+    new(device.context, device.source.duplicate())
+}
+
+#[doc(hidden)]
+pub fn new<'a>(context: &'a Context, source: DeviceSource) -> Device<'a> {
     Device {
         context: context,
-        device: device,
+        source: source,
     }
 }
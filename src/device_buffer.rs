@@ -0,0 +1,213 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+use std::ptr;
+use std::slice;
+
+use libusb::libusb_device_handle;
+
+use device_handle::{self, DeviceHandle};
+
+#[cfg(feature = "dev-mem")]
+mod ffi {
+    use libc::{c_int, c_uchar, size_t};
+    use libusb::libusb_device_handle;
+
+    // available since libusb 1.0.21
+    extern "C" {
+        pub fn libusb_dev_mem_alloc(dev_handle: *mut libusb_device_handle, length: size_t) -> *mut c_uchar;
+        pub fn libusb_dev_mem_free(dev_handle: *mut libusb_device_handle, buffer: *mut c_uchar, length: size_t) -> c_int;
+    }
+}
+
+/// A buffer for transfers to and from a device.
+///
+/// On platforms that support it, the buffer is allocated from memory that the kernel maps for
+/// DMA, so that bulk transfers to and from the buffer avoid copying data through the kernel.
+/// Otherwise, the buffer is allocated on the heap. The buffer derefs to `[u8]` and can be used
+/// with any of the transfer methods of [`DeviceHandle`](struct.DeviceHandle.html).
+///
+/// Device memory is only requested from `libusb` when the crate is built with the `dev-mem`
+/// feature, which requires `libusb` 1.0.21 or later.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// # fn capture(handle: &libusb::DeviceHandle) -> libusb::Result<()> {
+/// let mut buf = handle.alloc_buffer(1024 * 1024);
+///
+/// let len = try!(handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)));
+/// println!("received {} bytes in {} memory", len, if buf.is_device_memory() { "device" } else { "heap" });
+/// # Ok(())
+/// # }
+/// ```
+pub struct DeviceBuffer<'h, 'a: 'h> {
+    handle: &'h DeviceHandle<'a>,
+    memory: Memory,
+}
+
+enum Memory {
+    Device(*mut u8, usize),
+    Heap(Vec<u8>),
+}
+
+unsafe impl<'h, 'a> Send for DeviceBuffer<'h, 'a> {}
+unsafe impl<'h, 'a> Sync for DeviceBuffer<'h, 'a> {}
+
+impl<'h, 'a> Drop for DeviceBuffer<'h, 'a> {
+    /// Frees the buffer.
+    fn drop(&mut self) {
+        if let Memory::Device(ptr, len) = self.memory {
+            free_device_memory(device_handle::as_raw(self.handle), ptr, len);
+        }
+    }
+}
+
+impl<'h, 'a> DeviceBuffer<'h, 'a> {
+    /// Indicates whether the buffer was allocated from device memory.
+    ///
+    /// Returns `false` if the buffer was allocated on the heap because device memory isn't
+    /// supported.
+    pub fn is_device_memory(&self) -> bool {
+        match self.memory {
+            Memory::Device(..) => true,
+            Memory::Heap(_) => false,
+        }
+    }
+}
+
+impl<'h, 'a> Deref for DeviceBuffer<'h, 'a> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self.memory {
+            Memory::Device(ptr, len) => unsafe { slice::from_raw_parts(ptr, len) },
+            Memory::Heap(ref vec) => vec,
+        }
+    }
+}
+
+impl<'h, 'a> DerefMut for DeviceBuffer<'h, 'a> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self.memory {
+            Memory::Device(ptr, len) => unsafe { slice::from_raw_parts_mut(ptr, len) },
+            Memory::Heap(ref mut vec) => vec,
+        }
+    }
+}
+
+impl<'h, 'a> fmt::Debug for DeviceBuffer<'h, 'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DeviceBuffer")
+            .field("len", &self.len())
+            .field("device_memory", &self.is_device_memory())
+            .finish()
+    }
+}
+
+#[cfg(feature = "dev-mem")]
+fn alloc_device_memory(handle: *mut libusb_device_handle, len: usize) -> *mut u8 {
+    unsafe { ffi::libusb_dev_mem_alloc(handle, len) }
+}
+
+#[cfg(not(feature = "dev-mem"))]
+fn alloc_device_memory(_handle: *mut libusb_device_handle, _len: usize) -> *mut u8 {
+    ptr::null_mut()
+}
+
+#[cfg(feature = "dev-mem")]
+fn free_device_memory(handle: *mut libusb_device_handle, ptr: *mut u8, len: usize) {
+    unsafe {
+        ffi::libusb_dev_mem_free(handle, ptr, len);
+    }
+}
+
+#[cfg(not(feature = "dev-mem"))]
+fn free_device_memory(_handle: *mut libusb_device_handle, _ptr: *mut u8, _len: usize) {
+    unreachable!();
+}
+
+
+#[doc(hidden)]
+pub fn new<'h, 'a>(handle: &'h DeviceHandle<'a>, len: usize) -> DeviceBuffer<'h, 'a> {
+    let raw = device_handle::as_raw(handle);
+
+    // devices provided by a backend have no `libusb` handle
+    let ptr = if len > 0 && !raw.is_null() {
+        alloc_device_memory(raw, len)
+    }
+    else {
+        ptr::null_mut()
+    };
+
+    let memory = if ptr.is_null() {
+        Memory::Heap(vec![0; len])
+    }
+    else {
+        Memory::Device(ptr, len)
+    };
+
+    DeviceBuffer {
+        handle: handle,
+        memory: memory,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use test_helpers::{context_with, mock_device, open};
+
+    #[test]
+    fn it_allocates_heap_buffer_for_backend_devices() {
+        let context = context_with(&[&mock_device(64)]);
+        let handle = open(&context);
+        let buf = handle.alloc_buffer(16);
+
+        assert!(!buf.is_device_memory());
+        assert_eq!(&[0; 16], &buf[..]);
+        assert_eq!("DeviceBuffer { len: 16, device_memory: false }", format!("{:?}", buf));
+    }
+
+    #[test]
+    fn it_allocates_zero_length_buffer() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let buf = handle.alloc_buffer(0);
+
+        assert!(buf.is_empty());
+        assert!(!buf.is_device_memory());
+        assert_eq!(0, handle.write_bulk(0x02, &buf, Duration::from_secs(1)).unwrap());
+        assert_eq!(vec![Vec::<u8>::new()], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_reads_bulk_into_buffer() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut buf = handle.alloc_buffer(64);
+
+        device.push_in(0x81, vec![1, 2, 3]);
+
+        assert_eq!(3, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap());
+        assert_eq!(&[1, 2, 3], &buf[..3]);
+    }
+
+    #[test]
+    fn it_writes_bulk_from_buffer() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut buf = handle.alloc_buffer(4);
+
+        buf.copy_from_slice(&[4, 5, 6, 7]);
+
+        assert_eq!(4, handle.write_bulk(0x02, &buf, Duration::from_secs(1)).unwrap());
+        assert_eq!(vec![vec![4, 5, 6, 7]], device.take_out(0x02));
+    }
+}
@@ -2,6 +2,7 @@ use std::fmt;
 
 use libusb::*;
 
+use error::{Error, ErrorKind};
 use fields::Version;
 
 /// Describes a device.
@@ -108,11 +109,62 @@ pub fn from_libusb(device: libusb_device_descriptor) -> DeviceDescriptor {
     DeviceDescriptor { descriptor: device }
 }
 
+/// Parses a device descriptor, as returned by a `GET_DESCRIPTOR` request.
+#[doc(hidden)]
+pub fn from_bytes(bytes: &[u8]) -> ::Result<DeviceDescriptor> {
+    if bytes.len() < 18 || bytes[1] != LIBUSB_DT_DEVICE {
+        return Err(Error::new(ErrorKind::Io));
+    }
+
+    let word = |i: usize| bytes[i] as u16 | (bytes[i + 1] as u16) << 8;
+
+    Ok(from_libusb(libusb_device_descriptor {
+        bLength: bytes[0],
+        bDescriptorType: bytes[1],
+        bcdUSB: word(2),
+        bDeviceClass: bytes[4],
+        bDeviceSubClass: bytes[5],
+        bDeviceProtocol: bytes[6],
+        bMaxPacketSize0: bytes[7],
+        idVendor: word(8),
+        idProduct: word(10),
+        bcdDevice: word(12),
+        iManufacturer: bytes[14],
+        iProduct: bytes[15],
+        iSerialNumber: bytes[16],
+        bNumConfigurations: bytes[17],
+    }))
+}
+
+
+/// Returns the bytes of a device descriptor, as returned by a `GET_DESCRIPTOR` request.
+#[doc(hidden)]
+pub fn to_bytes(descriptor: &DeviceDescriptor) -> Vec<u8> {
+    let d = &descriptor.descriptor;
+
+    vec![
+        18, LIBUSB_DT_DEVICE,
+        d.bcdUSB as u8, (d.bcdUSB >> 8) as u8,
+        d.bDeviceClass, d.bDeviceSubClass, d.bDeviceProtocol, d.bMaxPacketSize0,
+        d.idVendor as u8, (d.idVendor >> 8) as u8,
+        d.idProduct as u8, (d.idProduct >> 8) as u8,
+        d.bcdDevice as u8, (d.bcdDevice >> 8) as u8,
+        d.iManufacturer, d.iProduct, d.iSerialNumber,
+        d.bNumConfigurations,
+    ]
+}
+
 
 #[cfg(test)]
 mod test {
     use fields::Version;
 
+    #[test]
+    fn it_converts_to_and_from_bytes() {
+        let bytes = [18, 0x01, 0x00, 0x02, 0xFF, 1, 2, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 3, 1];
+        assert_eq!(&bytes[..], &super::to_bytes(&super::from_bytes(&bytes).unwrap())[..]);
+    }
+
     #[test]
     fn it_has_usb_version() {
         assert_eq!(Version::from_bcd(0x1234), super::from_libusb(device_descriptor!(bcdUSB: 0x1234)).usb_version());
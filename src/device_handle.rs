@@ -1,36 +1,58 @@
-use std::marker::PhantomData;
-use std::mem;
+use std::cmp;
 use std::slice;
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::Instant;
 
 use bit_set::BitSet;
 use libc::{c_int, c_uint, c_uchar};
 use libusb::*;
 
-use context::Context;
-use error::{self, Error};
+use context::{self, Context};
+use dispatch::HandleSource;
+use error::{self, Error, ErrorKind};
 use device_descriptor::DeviceDescriptor;
 use config_descriptor::ConfigDescriptor;
 use interface_descriptor::InterfaceDescriptor;
-use fields::{Direction, RequestType, Recipient, request_type};
+use endpoint_descriptor::EndpointDescriptor;
+use fields::{Direction, RequestType, Recipient, TransferType, request_type};
 use language::Language;
+use timeout::{self, Timeout};
+use transfer::{self, CancellationToken, Transfer, TransferFlags};
+use endpoint_io::{self, EndpointReader, EndpointWriter};
+use bulk_stream::{self, BulkStream};
+use bulk_sink::{self, BulkSink};
+use device_buffer::{self, DeviceBuffer};
+use interrupt_poller::{self, InterruptPoller};
+use trace::{self, SetupPacket, TransferObserver};
+use metrics::{self, MetricsSnapshot};
+use fault::{self, FaultInjector};
 
 /// A handle to an open USB device.
+///
+/// The methods that block while communicating with the device accept a timeout, which can be
+/// given either as a `Duration` or as a [`Timeout`](enum.Timeout.html). A `Timeout` can also
+/// specify an absolute deadline or no timeout at all.
 pub struct DeviceHandle<'a> {
-    _context: PhantomData<&'a Context>,
-    handle: *mut libusb_device_handle,
+    context: &'a Context,
+    source: HandleSource,
     interfaces: BitSet,
+    detached: BitSet,
+    settings: Vec<u8>,
+    validate_endpoints: bool,
+    observer: Option<Box<TransferObserver>>,
+    metrics: Option<Mutex<MetricsSnapshot>>,
+    faults: Option<FaultInjector>,
 }
 
 impl<'a> Drop for DeviceHandle<'a> {
-    /// Closes the device.
+    /// Releases the claimed interfaces and closes the device.
     fn drop(&mut self) {
-        unsafe {
-            for iface in self.interfaces.iter() {
-                libusb_release_interface(self.handle, iface as c_int);
-            }
+        for iface in self.interfaces.iter() {
+            let _ = self.source.release_interface(iface as u8);
+        }
 
-            libusb_close(self.handle);
+        for iface in self.detached.iter() {
+            let _ = self.source.attach_kernel_driver(iface as u8);
         }
     }
 }
@@ -41,80 +63,233 @@ unsafe impl<'a> Sync for DeviceHandle<'a> {}
 impl<'a> DeviceHandle<'a> {
     /// Returns the active configuration number.
     pub fn active_configuration(&self) -> ::Result<u8> {
-        let mut config = unsafe { mem::uninitialized() };
-
-        try_unsafe!(libusb_get_configuration(self.handle, &mut config));
-        Ok(config as u8)
+        self.source.active_configuration()
     }
 
     /// Sets the device's active configuration.
     pub fn set_active_configuration(&mut self, config: u8) -> ::Result<()> {
-        try_unsafe!(libusb_set_configuration(self.handle, config as c_int));
-        Ok(())
+        self.source.set_active_configuration(Some(config))
     }
 
     /// Puts the device in an unconfigured state.
     pub fn unconfigure(&mut self) -> ::Result<()> {
-        try_unsafe!(libusb_set_configuration(self.handle, -1));
-        Ok(())
+        self.source.set_active_configuration(None)
     }
 
     /// Resets the device.
     pub fn reset(&mut self) -> ::Result<()> {
-        try_unsafe!(libusb_reset_device(self.handle));
-        Ok(())
+        self.source.reset()
     }
 
     /// Indicates whether the device has an attached kernel driver.
     ///
     /// This method is not supported on all platforms.
     pub fn kernel_driver_active(&self, iface: u8) -> ::Result<bool> {
-        match unsafe { libusb_kernel_driver_active(self.handle, iface as c_int) } {
-            0 => Ok(false),
-            1 => Ok(true),
-            err => Err(error::from_libusb(err)),
-        }
+        self.source.kernel_driver_active(iface)
     }
 
     /// Detaches an attached kernel driver from the device.
     ///
     /// This method is not supported on all platforms.
     pub fn detach_kernel_driver(&mut self, iface: u8) -> ::Result<()> {
-        try_unsafe!(libusb_detach_kernel_driver(self.handle, iface as c_int));
-        Ok(())
+        self.source.detach_kernel_driver(iface)
     }
 
     /// Attaches a kernel driver to the device.
     ///
     /// This method is not supported on all platforms.
     pub fn attach_kernel_driver(&mut self, iface: u8) -> ::Result<()> {
-        try_unsafe!(libusb_attach_kernel_driver(self.handle, iface as c_int));
+        try!(self.source.attach_kernel_driver(iface));
+        self.detached.remove(&(iface as usize));
         Ok(())
     }
 
+    /// Enables or disables automatic kernel driver detachment.
+    ///
+    /// When enabled, `libusb` detaches the kernel driver from an interface when it is claimed and
+    /// re-attaches it when the interface is released.
+    ///
+    /// This method is not supported on all platforms. It returns `NotSupported` on platforms where
+    /// [`Context::supports_detach_kernel_driver()`](struct.Context.html#method.supports_detach_kernel_driver)
+    /// is false.
+    pub fn set_auto_detach_kernel_driver(&mut self, auto_detach: bool) -> ::Result<()> {
+        self.source.set_auto_detach_kernel_driver(auto_detach)
+    }
+
     /// Claims one of the device's interfaces.
     ///
     /// An interface must be claimed before operating on it. All claimed interfaces are released
     /// when the device handle goes out of scope.
     pub fn claim_interface(&mut self, iface: u8) -> ::Result<()> {
-        try_unsafe!(libusb_claim_interface(self.handle, iface as c_int));
+        try!(self.source.claim_interface(iface));
+
         self.interfaces.insert(iface as usize);
+        self.settings[iface as usize] = 0;
         Ok(())
     }
 
+    /// Claims one of the device's interfaces, detaching its kernel driver if necessary.
+    ///
+    /// If a kernel driver is attached to the interface, it is detached before the interface is
+    /// claimed. The handle remembers which interfaces had their kernel driver detached and
+    /// re-attaches the kernel driver when the interface is released or when the device handle
+    /// goes out of scope.
+    ///
+    /// On platforms that don't support detaching kernel drivers, this method behaves the same as
+    /// [`claim_interface()`](#method.claim_interface).
+    pub fn claim_interface_detached(&mut self, iface: u8) -> ::Result<()> {
+        if self.source.supports_detach_kernel_driver() {
+            match self.kernel_driver_active(iface) {
+                Ok(true) => {
+                    try!(self.detach_kernel_driver(iface));
+                    self.detached.insert(iface as usize);
+                },
+                Ok(false) => (),
+                Err(ref err) if err.kind() == ErrorKind::NotSupported => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        match self.claim_interface(iface) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                if self.detached.remove(&(iface as usize)) {
+                    let _ = self.source.attach_kernel_driver(iface);
+                }
+
+                Err(err)
+            },
+        }
+    }
+
     /// Releases a claimed interface.
+    ///
+    /// If the interface's kernel driver was detached by
+    /// [`claim_interface_detached()`](#method.claim_interface_detached), the kernel driver is
+    /// re-attached after the interface is released.
     pub fn release_interface(&mut self, iface: u8) -> ::Result<()> {
-        try_unsafe!(libusb_release_interface(self.handle, iface as c_int));
+        try!(self.source.release_interface(iface));
+
         self.interfaces.remove(&(iface as usize));
+
+        if self.detached.remove(&(iface as usize)) {
+            try!(self.source.attach_kernel_driver(iface));
+        }
+
         Ok(())
     }
 
+    /// Clears the halt condition of an endpoint.
+    ///
+    /// A halted endpoint fails transfers with `Pipe` until its halt condition is cleared.
+    pub fn clear_halt(&mut self, endpoint: u8) -> ::Result<()> {
+        self.source.clear_halt(endpoint)
+    }
+
     /// Sets an interface's active setting.
     pub fn set_alternate_setting(&mut self, iface: u8, setting: u8) -> ::Result<()> {
-        try_unsafe!(libusb_set_interface_alt_setting(self.handle, iface as c_int, setting as c_int));
+        try!(self.source.set_alternate_setting(iface, setting));
+
+        self.settings[iface as usize] = setting;
         Ok(())
     }
 
+    /// Enables or disables endpoint validation.
+    ///
+    /// When endpoint validation is enabled, the bulk and interrupt transfer methods check that the
+    /// endpoint belongs to the active setting of a claimed interface in the device's active
+    /// configuration and that the endpoint supports the requested transfer type. The check is made
+    /// before the transfer is submitted, so a misaddressed transfer fails with a specific error
+    /// instead of an error from the operating system.
+    ///
+    /// Validation reads the active configuration descriptor for each transfer. It is disabled by
+    /// default.
+    pub fn set_endpoint_validation(&mut self, validate: bool) {
+        self.validate_endpoints = validate;
+    }
+
+    /// Sets an observer that is notified of each transfer made through the handle.
+    ///
+    /// The observer replaces any observer that was set before. See
+    /// [`TransferObserver`](trait.TransferObserver.html) for the transfers that are observed.
+    pub fn set_observer<O: TransferObserver + 'static>(&mut self, observer: O) {
+        self.observer = Some(Box::new(observer));
+    }
+
+    /// Removes the handle's transfer observer.
+    pub fn clear_observer(&mut self) {
+        self.observer = None;
+    }
+
+    /// Enables or disables collecting metrics of the transfers made through the handle.
+    ///
+    /// Enabling metrics starts collecting them from zero. Disabling metrics discards the metrics
+    /// that have been collected. Metrics are disabled by default.
+    pub fn set_metrics_enabled(&mut self, enabled: bool) {
+        self.metrics = if enabled { Some(Mutex::new(metrics::new_snapshot())) } else { None };
+    }
+
+    /// Returns a snapshot of the metrics collected by the handle.
+    ///
+    /// Returns `None` if metrics are disabled.
+    pub fn metrics(&self) -> Option<MetricsSnapshot> {
+        self.metrics.as_ref().map(|metrics| metrics.lock().unwrap().clone())
+    }
+
+    /// Resets the metrics collected by the handle to zero.
+    pub fn reset_metrics(&self) {
+        if let Some(ref metrics) = self.metrics {
+            *metrics.lock().unwrap() = metrics::new_snapshot();
+        }
+    }
+
+    /// Sets a fault injector that injects faults into the transfers made through the handle.
+    ///
+    /// The injector replaces any injector that was set before. See
+    /// [`FaultInjector`](struct.FaultInjector.html) for the faults that can be injected.
+    pub fn set_fault_injector(&mut self, injector: FaultInjector) {
+        self.faults = Some(injector);
+    }
+
+    /// Removes the handle's fault injector.
+    pub fn clear_fault_injector(&mut self) {
+        self.faults = None;
+    }
+
+    fn check_endpoint(&self, endpoint: u8, transfer_type: TransferType) -> ::Result<()> {
+        if !self.validate_endpoints {
+            return Ok(());
+        }
+
+        let config = try!(self.active_config_descriptor());
+
+        validate_endpoint(&config, &self.interfaces, &self.settings, endpoint, transfer_type)
+    }
+
+    fn active_config_descriptor(&self) -> ::Result<ConfigDescriptor> {
+        self.source.active_config_descriptor()
+    }
+
+    /// Attaches the operation and the device's bus number and address to an error.
+    fn device_error<E: Into<Error>>(&self, err: E, operation: &'static str) -> Error {
+        let (bus_number, address) = self.source.location();
+
+        err.into().with_operation(operation).with_device(bus_number, address)
+    }
+
+    /// Returns `NotSupported` if the device is provided by a backend instead of `libusb`.
+    fn require_libusb(&self) -> ::Result<()> {
+        self.source.require_libusb()
+    }
+
+    fn endpoint_error<E: Into<Error>>(&self, err: E, operation: &'static str, endpoint: u8) -> Error {
+        self.device_error(err, operation).with_endpoint(endpoint)
+    }
+
+    fn request_error<E: Into<Error>>(&self, err: E, operation: &'static str, request_type: u8, request: u8) -> Error {
+        self.device_error(err, operation).with_request(request_type, request)
+    }
+
     /// Reads from an interrupt endpoint.
     ///
     /// This function attempts to read from the interrupt endpoint with the address given by the
@@ -137,29 +312,23 @@ impl<'a> DeviceHandle<'a> {
     /// * `Overflow` if the device offered more data.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn read_interrupt(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
+    ///
+    /// If endpoint validation is enabled, this function can also return `UnknownEndpoint`,
+    /// `WrongTransferType`, or `InterfaceNotClaimed`. See
+    /// [`set_endpoint_validation()`](#method.set_endpoint_validation).
+    pub fn read_interrupt<T: Into<Timeout>>(&self, endpoint: u8, buf: &mut [u8], timeout: T) -> ::Result<usize> {
         if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
-            return Err(Error::InvalidParam);
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_interrupt", endpoint));
         }
 
-        let mut transferred: c_int = unsafe { mem::uninitialized() };
+        try!(self.check_endpoint(endpoint, TransferType::Interrupt).map_err(|err| self.endpoint_error(err, "read_interrupt", endpoint)));
 
         let ptr = buf.as_mut_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
 
-        match unsafe { libusb_interrupt_transfer(self.handle, endpoint, ptr, len, &mut transferred, timeout_ms) } {
-            0 => {
-                Ok(transferred as usize)
-            },
-            err => {
-                if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
-                }
-                else {
-                    Err(error::from_libusb(err))
-                }
-            },
+        match unsafe { sync_transfer(self, TransferType::Interrupt, endpoint, ptr, buf.len(), timeout.into()) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "read_interrupt", endpoint)),
         }
     }
 
@@ -183,29 +352,23 @@ impl<'a> DeviceHandle<'a> {
     /// * `Pipe` if the endpoint halted.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn write_interrupt(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> ::Result<usize> {
+    ///
+    /// If endpoint validation is enabled, this function can also return `UnknownEndpoint`,
+    /// `WrongTransferType`, or `InterfaceNotClaimed`. See
+    /// [`set_endpoint_validation()`](#method.set_endpoint_validation).
+    pub fn write_interrupt<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T) -> ::Result<usize> {
         if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
-            return Err(Error::InvalidParam);
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_interrupt", endpoint));
         }
 
-        let mut transferred: c_int = unsafe { mem::uninitialized() };
+        try!(self.check_endpoint(endpoint, TransferType::Interrupt).map_err(|err| self.endpoint_error(err, "write_interrupt", endpoint)));
 
         let ptr = buf.as_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
 
-        match unsafe { libusb_interrupt_transfer(self.handle, endpoint, ptr, len, &mut transferred, timeout_ms) } {
-            0 => {
-                Ok(transferred as usize)
-            },
-            err => {
-                if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
-                }
-                else {
-                    Err(error::from_libusb(err))
-                }
-            },
+        match unsafe { sync_transfer(self, TransferType::Interrupt, endpoint, ptr, buf.len(), timeout.into()) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "write_interrupt", endpoint)),
         }
     }
 
@@ -231,29 +394,23 @@ impl<'a> DeviceHandle<'a> {
     /// * `Overflow` if the device offered more data.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn read_bulk(&self, endpoint: u8, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
+    ///
+    /// If endpoint validation is enabled, this function can also return `UnknownEndpoint`,
+    /// `WrongTransferType`, or `InterfaceNotClaimed`. See
+    /// [`set_endpoint_validation()`](#method.set_endpoint_validation).
+    pub fn read_bulk<T: Into<Timeout>>(&self, endpoint: u8, buf: &mut [u8], timeout: T) -> ::Result<usize> {
         if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
-            return Err(Error::InvalidParam);
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_bulk", endpoint));
         }
 
-        let mut transferred: c_int = unsafe { mem::uninitialized() };
+        try!(self.check_endpoint(endpoint, TransferType::Bulk).map_err(|err| self.endpoint_error(err, "read_bulk", endpoint)));
 
         let ptr = buf.as_mut_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
 
-        match unsafe { libusb_bulk_transfer(self.handle, endpoint, ptr, len, &mut transferred, timeout_ms) } {
-            0 => {
-                Ok(transferred as usize)
-            },
-            err => {
-                if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
-                }
-                else {
-                    Err(error::from_libusb(err))
-                }
-            },
+        match unsafe { sync_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len(), timeout.into()) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "read_bulk", endpoint)),
         }
     }
 
@@ -277,227 +434,1552 @@ impl<'a> DeviceHandle<'a> {
     /// * `Pipe` if the endpoint halted.
     /// * `NoDevice` if the device has been disconnected.
     /// * `Io` if the transfer encountered an I/O error.
-    pub fn write_bulk(&self, endpoint: u8, buf: &[u8], timeout: Duration) -> ::Result<usize> {
+    ///
+    /// If endpoint validation is enabled, this function can also return `UnknownEndpoint`,
+    /// `WrongTransferType`, or `InterfaceNotClaimed`. See
+    /// [`set_endpoint_validation()`](#method.set_endpoint_validation).
+    pub fn write_bulk<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T) -> ::Result<usize> {
         if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
-            return Err(Error::InvalidParam);
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_bulk", endpoint));
         }
 
-        let mut transferred: c_int = unsafe { mem::uninitialized() };
+        try!(self.check_endpoint(endpoint, TransferType::Bulk).map_err(|err| self.endpoint_error(err, "write_bulk", endpoint)));
 
         let ptr = buf.as_ptr() as *mut c_uchar;
-        let len = buf.len() as c_int;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
 
-        match unsafe { libusb_bulk_transfer(self.handle, endpoint, ptr, len, &mut transferred, timeout_ms) } {
-            0 => {
-                Ok(transferred as usize)
-            },
-            err => {
-                if err == LIBUSB_ERROR_INTERRUPTED && transferred > 0 {
-                    Ok(transferred as usize)
-                }
-                else {
-                    Err(error::from_libusb(err))
-                }
-            },
+        match unsafe { sync_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len(), timeout.into()) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "write_bulk", endpoint)),
         }
     }
 
-    /// Reads data using a control transfer.
-    ///
-    /// This function attempts to read data from the device using a control transfer and fills
-    /// `buf` with any data received during the transfer. The function blocks up to the amount of
-    /// time specified by `timeout`.
-    ///
-    /// The parameters `request_type`, `request`, `value`, and `index` specify the fields of the
-    /// control transfer setup packet (`bmRequestType`, `bRequest`, `wValue`, and `wIndex`
-    /// respectively). The values for each of these parameters shall be given in host-endian byte
-    /// order. The value for the `request_type` parameter can be built with the helper function,
-    /// [request_type()](fn.request_type.html). The meaning of the other parameters depends on the
-    /// type of control request.
-    ///
-    /// If the return value is `Ok(n)`, then `buf` is populated with `n` bytes of data.
-    ///
-    /// ## Errors
-    ///
-    /// If this function encounters any form of error while fulfilling the transfer request, an
-    /// error variant will be returned. If an error variant is returned, no bytes were read.
+    /// Reads from an interrupt endpoint with a cancellation token.
     ///
-    /// The errors returned by this function include:
+    /// This function behaves like [`read_interrupt()`](#method.read_interrupt), except that the transfer can be
+    /// cancelled from another thread by calling [`cancel()`](struct.CancellationToken.html#method.cancel)
+    /// on a clone of `cancel`.
     ///
-    /// * `InvalidParam` if `request_type` does not specify a read transfer.
-    /// * `Timeout` if the transfer timed out.
-    /// * `Pipe` if the control request was not supported by the device.
-    /// * `NoDevice` if the device has been disconnected.
-    /// * `Io` if the transfer encountered an I/O error.
-    pub fn read_control(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8], timeout: Duration) -> ::Result<usize> {
-        if request_type & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
-            return Err(Error::InvalidParam);
+    /// If the transfer is cancelled before any data was received, `Interrupted` is returned. If the
+    /// transfer is cancelled after some data was received, the return value is `Ok(n)`, where `n` is
+    /// the number of bytes that were received. If the token was already cancelled, no transfer is
+    /// made.
+    pub fn read_interrupt_cancellable<T: Into<Timeout>>(&self, endpoint: u8, buf: &mut [u8], timeout: T, cancel: &CancellationToken) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_interrupt_cancellable", endpoint));
         }
 
-        let ptr = buf.as_mut_ptr() as *mut c_uchar;
-        let len = buf.len() as u16;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
+        try!(self.check_endpoint(endpoint, TransferType::Interrupt).map_err(|err| self.endpoint_error(err, "read_interrupt_cancellable", endpoint)));
 
-        let res = unsafe {
-            libusb_control_transfer(self.handle, request_type, request, value, index, ptr, len, timeout_ms)
-        };
+        let ptr = buf.as_mut_ptr();
 
-        if res < 0 {
-            Err(error::from_libusb(res))
-        } else {
-            Ok(res as usize)
+        match unsafe { async_transfer(self, TransferType::Interrupt, endpoint, ptr, buf.len(), timeout.into(), 0, 0, Some(cancel)) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "read_interrupt_cancellable", endpoint)),
         }
     }
 
-    /// Writes data using a control transfer.
+    /// Writes to an interrupt endpoint with a cancellation token.
     ///
-    /// This function attempts to write the contents of `buf` to the device using a control
-    /// transfer. The function blocks up to the amount of time specified by `timeout`.
-    ///
-    /// The parameters `request_type`, `request`, `value`, and `index` specify the fields of the
-    /// control transfer setup packet (`bmRequestType`, `bRequest`, `wValue`, and `wIndex`
-    /// respectively). The values for each of these parameters shall be given in host-endian byte
-    /// order. The value for the `request_type` parameter can be built with the helper function,
-    /// [request_type()](fn.request_type.html). The meaning of the other parameters depends on the
-    /// type of control request.
+    /// This function behaves like [`write_interrupt()`](#method.write_interrupt), except that the transfer can be
+    /// cancelled from another thread by calling [`cancel()`](struct.CancellationToken.html#method.cancel)
+    /// on a clone of `cancel`.
     ///
-    /// If the return value is `Ok(n)`, then `n` bytes of `buf` were transfered.
+    /// If the transfer is cancelled before any data was sent, `Interrupted` is returned. If the
+    /// transfer is cancelled after some data was sent, the return value is `Ok(n)`, where `n` is
+    /// the number of bytes that were sent. If the token was already cancelled, no transfer is
+    /// made.
+    pub fn write_interrupt_cancellable<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T, cancel: &CancellationToken) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_interrupt_cancellable", endpoint));
+        }
+
+        try!(self.check_endpoint(endpoint, TransferType::Interrupt).map_err(|err| self.endpoint_error(err, "write_interrupt_cancellable", endpoint)));
+
+        let ptr = buf.as_ptr() as *mut c_uchar;
+
+        match unsafe { async_transfer(self, TransferType::Interrupt, endpoint, ptr, buf.len(), timeout.into(), 0, 0, Some(cancel)) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "write_interrupt_cancellable", endpoint)),
+        }
+    }
+
+    /// Reads from a bulk endpoint with a cancellation token.
     ///
-    /// ## Errors
+    /// This function behaves like [`read_bulk()`](#method.read_bulk), except that the transfer can be
+    /// cancelled from another thread by calling [`cancel()`](struct.CancellationToken.html#method.cancel)
+    /// on a clone of `cancel`.
     ///
-    /// If this function encounters any form of error while fulfilling the transfer request, an
-    /// error variant will be returned. If an error variant is returned, no bytes were read.
+    /// If the transfer is cancelled before any data was received, `Interrupted` is returned. If the
+    /// transfer is cancelled after some data was received, the return value is `Ok(n)`, where `n` is
+    /// the number of bytes that were received. If the token was already cancelled, no transfer is
+    /// made.
+    pub fn read_bulk_cancellable<T: Into<Timeout>>(&self, endpoint: u8, buf: &mut [u8], timeout: T, cancel: &CancellationToken) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_bulk_cancellable", endpoint));
+        }
+
+        try!(self.check_endpoint(endpoint, TransferType::Bulk).map_err(|err| self.endpoint_error(err, "read_bulk_cancellable", endpoint)));
+
+        let ptr = buf.as_mut_ptr();
+
+        match unsafe { async_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len(), timeout.into(), 0, 0, Some(cancel)) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "read_bulk_cancellable", endpoint)),
+        }
+    }
+
+    /// Writes to a bulk endpoint with a cancellation token.
     ///
-    /// The errors returned by this function include:
+    /// This function behaves like [`write_bulk()`](#method.write_bulk), except that the transfer can be
+    /// cancelled from another thread by calling [`cancel()`](struct.CancellationToken.html#method.cancel)
+    /// on a clone of `cancel`.
     ///
-    /// * `InvalidParam` if `request_type` does not specify a write transfer.
-    /// * `Timeout` if the transfer timed out.
-    /// * `Pipe` if the control request was not supported by the device.
-    /// * `NoDevice` if the device has been disconnected.
-    /// * `Io` if the transfer encountered an I/O error.
-    pub fn write_control(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8], timeout: Duration) -> ::Result<usize> {
-        if request_type & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
-            return Err(Error::InvalidParam);
+    /// If the transfer is cancelled before any data was sent, `Interrupted` is returned. If the
+    /// transfer is cancelled after some data was sent, the return value is `Ok(n)`, where `n` is
+    /// the number of bytes that were sent. If the token was already cancelled, no transfer is
+    /// made.
+    pub fn write_bulk_cancellable<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T, cancel: &CancellationToken) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_bulk_cancellable", endpoint));
         }
 
+        try!(self.check_endpoint(endpoint, TransferType::Bulk).map_err(|err| self.endpoint_error(err, "write_bulk_cancellable", endpoint)));
+
         let ptr = buf.as_ptr() as *mut c_uchar;
-        let len = buf.len() as u16;
-        let timeout_ms = (timeout.as_secs() * 1000 + timeout.subsec_nanos() as u64 / 1_000_000) as c_uint;
 
-        let res = unsafe {
-            libusb_control_transfer(self.handle, request_type, request, value, index, ptr, len, timeout_ms)
-        };
+        match unsafe { async_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len(), timeout.into(), 0, 0, Some(cancel)) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "write_bulk_cancellable", endpoint)),
+        }
+    }
 
-        if res < 0 {
-            Err(error::from_libusb(res))
-        } else {
-            Ok(res as usize)
+    /// Reads from an interrupt endpoint with transfer flags.
+    ///
+    /// This function behaves like [`read_interrupt()`](#method.read_interrupt), except that the
+    /// transfer is made with the given [`TransferFlags`](struct.TransferFlags.html). If the flags
+    /// don't allow short transfers and the device sends less data than fits in `buf`,
+    /// `ShortTransfer` is returned.
+    pub fn read_interrupt_with_flags<T: Into<Timeout>>(&self, endpoint: u8, buf: &mut [u8], timeout: T, flags: TransferFlags) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_interrupt_with_flags", endpoint));
         }
+
+        self.flagged_transfer("read_interrupt_with_flags", TransferType::Interrupt, endpoint, buf.as_mut_ptr(), buf.len(), timeout.into(), flags)
     }
 
-    /// Reads the languages supported by the device's string descriptors.
+    /// Writes to an interrupt endpoint with transfer flags.
     ///
-    /// This function returns a list of languages that can be used to read the device's string
-    /// descriptors.
-    pub fn read_languages(&self, timeout: Duration) -> ::Result<Vec<Language>> {
-        let mut buf = Vec::<u8>::with_capacity(256);
+    /// This function behaves like [`write_interrupt()`](#method.write_interrupt), except that the
+    /// transfer is made with the given [`TransferFlags`](struct.TransferFlags.html).
+    pub fn write_interrupt_with_flags<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T, flags: TransferFlags) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_interrupt_with_flags", endpoint));
+        }
 
-        let mut buf_slice = unsafe {
-            slice::from_raw_parts_mut((&mut buf[..]).as_mut_ptr(), buf.capacity())
-        };
+        self.flagged_transfer("write_interrupt_with_flags", TransferType::Interrupt, endpoint, buf.as_ptr() as *mut c_uchar, buf.len(), timeout.into(), flags)
+    }
 
-        let len = try!(self.read_control(request_type(Direction::In, RequestType::Standard, Recipient::Device),
-                                         LIBUSB_REQUEST_GET_DESCRIPTOR,
-                                         (LIBUSB_DT_STRING as u16) << 8,
-                                         0,
-                                         buf_slice,
-                                         timeout));
+    /// Reads from a bulk endpoint with transfer flags.
+    ///
+    /// This function behaves like [`read_bulk()`](#method.read_bulk), except that the transfer is
+    /// made with the given [`TransferFlags`](struct.TransferFlags.html). If the flags don't allow
+    /// short transfers and the device sends less data than fits in `buf`, `ShortTransfer` is
+    /// returned.
+    pub fn read_bulk_with_flags<T: Into<Timeout>>(&self, endpoint: u8, buf: &mut [u8], timeout: T, flags: TransferFlags) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_bulk_with_flags", endpoint));
+        }
 
-        unsafe {
-            buf.set_len(len);
+        self.flagged_transfer("read_bulk_with_flags", TransferType::Bulk, endpoint, buf.as_mut_ptr(), buf.len(), timeout.into(), flags)
+    }
+
+    /// Writes to a bulk endpoint with transfer flags.
+    ///
+    /// This function behaves like [`write_bulk()`](#method.write_bulk), except that the transfer is
+    /// made with the given [`TransferFlags`](struct.TransferFlags.html). If the flags add a
+    /// zero-length packet, a transfer whose length is a multiple of the endpoint's maximum packet
+    /// size is followed by a zero-length packet.
+    pub fn write_bulk_with_flags<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T, flags: TransferFlags) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_bulk_with_flags", endpoint));
         }
 
-        Ok(buf.chunks(2).skip(1).map(|chunk| {
-            let lang_id = chunk[0] as u16 | (chunk[1] as u16) << 8;
-            ::language::from_lang_id(lang_id)
-        }).collect())
+        self.flagged_transfer("write_bulk_with_flags", TransferType::Bulk, endpoint, buf.as_ptr() as *mut c_uchar, buf.len(), timeout.into(), flags)
     }
 
-    /// Reads a string descriptor from the device.
+    fn flagged_transfer(&self, operation: &'static str, transfer_type: TransferType, endpoint: u8, ptr: *mut c_uchar, len: usize, timeout: Timeout, flags: TransferFlags) -> ::Result<usize> {
+        try!(self.check_endpoint(endpoint, transfer_type).map_err(|err| self.endpoint_error(err, operation, endpoint)));
+
+        match unsafe { async_transfer(self, transfer_type, endpoint, ptr, len, timeout, transfer::flags_to_libusb(&flags), 0, None) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(err), transferred) => Err(self.endpoint_error(transfer::transfer_error(&flags, err, transferred, len), operation, endpoint)),
+        }
+    }
+
+    /// Allocates USB 3 bulk streams on a set of endpoints.
     ///
-    /// `language` should be one of the languages returned from [`read_languages`](#method.read_languages).
-    pub fn read_string_descriptor(&self, language: Language, index: u8, timeout: Duration) -> ::Result<String> {
-        let mut buf = Vec::<u8>::with_capacity(256);
+    /// Streams are allocated with IDs from 1 up to the returned number of streams, which may be
+    /// less than `num_streams`. All endpoints must belong to the same interface. Streams can then
+    /// be used with [`read_stream()`](#method.read_stream) and
+    /// [`write_stream()`](#method.write_stream).
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if `endpoints` is empty, if `num_streams` is zero, or if `num_streams`
+    ///   exceeds the number of streams supported by one of the endpoints.
+    /// * `UnknownEndpoint` if an endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if an endpoint is not a bulk endpoint.
+    /// * `NotSupported` if an endpoint does not support streams, e.g., because the device is not
+    ///   operating at SuperSpeed.
+    pub fn alloc_streams(&mut self, num_streams: u32, endpoints: &[u8]) -> ::Result<u32> {
+        if endpoints.is_empty() || num_streams == 0 {
+            return Err(self.device_error(ErrorKind::InvalidParam, "alloc_streams"));
+        }
 
-        let mut buf_slice = unsafe {
-            slice::from_raw_parts_mut((&mut buf[..]).as_mut_ptr(), buf.capacity())
-        };
+        try!(self.require_libusb().map_err(|err| self.device_error(err, "alloc_streams")));
 
-        let len = try!(self.read_control(request_type(Direction::In, RequestType::Standard, Recipient::Device),
-                                         LIBUSB_REQUEST_GET_DESCRIPTOR,
-                                         (LIBUSB_DT_STRING as u16) << 8 | index as u16,
-                                         language.lang_id(),
-                                         buf_slice,
-                                         timeout));
+        let config = try!(self.active_config_descriptor().map_err(|err| self.device_error(err, "alloc_streams")));
 
-        unsafe {
-            buf.set_len(len);
+        for &endpoint in endpoints {
+            try!(check_streams(&config, &self.settings, endpoint, num_streams).map_err(|err| self.endpoint_error(err, "alloc_streams", endpoint)));
         }
 
-        let utf16: Vec<u16> = buf.chunks(2).skip(1).map(|chunk| {
-            chunk[0] as u16 | (chunk[1] as u16) << 8
-        }).collect();
+        let mut raw_endpoints = endpoints.to_vec();
 
-        String::from_utf16(&utf16[..]).map_err(|_| Error::Other)
+        match unsafe { libusb_alloc_streams(self.source.as_raw(), num_streams, raw_endpoints.as_mut_ptr(), raw_endpoints.len() as c_int) } {
+            n if n >= 0 => Ok(n as u32),
+            err => Err(self.streams_error(error::from_libusb(err), "alloc_streams", endpoints)),
+        }
     }
 
-    /// Reads the device's manufacturer string descriptor.
-    pub fn read_manufacturer_string(&self, language: Language, device: &DeviceDescriptor, timeout: Duration) -> ::Result<String> {
-        match device.manufacturer_string_index() {
-            None => Err(Error::InvalidParam),
-            Some(n) => self.read_string_descriptor(language, n, timeout)
+    /// Frees the USB 3 bulk streams allocated on a set of endpoints.
+    pub fn free_streams(&mut self, endpoints: &[u8]) -> ::Result<()> {
+        if endpoints.is_empty() {
+            return Err(self.device_error(ErrorKind::InvalidParam, "free_streams"));
         }
-    }
 
-    /// Reads the device's product string descriptor.
-    pub fn read_product_string(&self, language: Language, device: &DeviceDescriptor, timeout: Duration) -> ::Result<String> {
-        match device.product_string_index() {
-            None => Err(Error::InvalidParam),
-            Some(n) => self.read_string_descriptor(language, n, timeout)
+        try!(self.require_libusb().map_err(|err| self.device_error(err, "free_streams")));
+
+        let mut raw_endpoints = endpoints.to_vec();
+
+        match unsafe { libusb_free_streams(self.source.as_raw(), raw_endpoints.as_mut_ptr(), raw_endpoints.len() as c_int) } {
+            0 => Ok(()),
+            err => Err(self.streams_error(error::from_libusb(err), "free_streams", endpoints)),
         }
     }
 
-    /// Reads the device's serial number string descriptor.
-    pub fn read_serial_number_string(&self, language: Language, device: &DeviceDescriptor, timeout: Duration) -> ::Result<String> {
-        match device.serial_number_string_index() {
-            None => Err(Error::InvalidParam),
-            Some(n) => self.read_string_descriptor(language, n, timeout)
+    /// Adds context to an error of an operation on the streams of a set of endpoints. The endpoint
+    /// is included if there is only one.
+    fn streams_error(&self, err: Error, operation: &'static str, endpoints: &[u8]) -> Error {
+        match *endpoints {
+            [endpoint] => self.endpoint_error(err, operation, endpoint),
+            _ => self.device_error(err, operation),
         }
     }
 
-    /// Reads the string descriptor for a configuration's description.
-    pub fn read_configuration_string(&self, language: Language, configuration: &ConfigDescriptor, timeout: Duration) -> ::Result<String> {
-        match configuration.description_string_index() {
-            None => Err(Error::InvalidParam),
-            Some(n) => self.read_string_descriptor(language, n, timeout)
+    /// Reads from a USB 3 bulk stream.
+    ///
+    /// This function behaves like [`read_bulk()`](#method.read_bulk), except that the transfer is
+    /// made on the stream with the ID given by `stream_id`. Streams must be allocated with
+    /// [`alloc_streams()`](#method.alloc_streams) first.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if the endpoint is not an input endpoint or if `stream_id` is zero.
+    /// * `Timeout` if the transfer timed out.
+    /// * `Pipe` if the endpoint halted.
+    /// * `Overflow` if the device offered more data.
+    /// * `NoDevice` if the device has been disconnected.
+    /// * `Io` if the transfer encountered an I/O error.
+    pub fn read_stream<T: Into<Timeout>>(&self, endpoint: u8, stream_id: u32, buf: &mut [u8], timeout: T) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN || stream_id == 0 {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_stream", endpoint));
         }
-    }
 
-    /// Reads the string descriptor for a interface's description.
-    pub fn read_interface_string(&self, language: Language, interface: &InterfaceDescriptor, timeout: Duration) -> ::Result<String> {
-        match interface.description_string_index() {
-            None => Err(Error::InvalidParam),
-            Some(n) => self.read_string_descriptor(language, n, timeout)
+        try!(self.check_endpoint(endpoint, TransferType::Bulk).map_err(|err| self.endpoint_error(err, "read_stream", endpoint)));
+
+        let ptr = buf.as_mut_ptr();
+
+        match unsafe { async_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len(), timeout.into(), 0, stream_id, None) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "read_stream", endpoint)),
         }
     }
-}
+
+    /// Writes to a USB 3 bulk stream.
+    ///
+    /// This function behaves like [`write_bulk()`](#method.write_bulk), except that the transfer
+    /// is made on the stream with the ID given by `stream_id`. Streams must be allocated with
+    /// [`alloc_streams()`](#method.alloc_streams) first.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if the endpoint is not an output endpoint or if `stream_id` is zero.
+    /// * `Timeout` if the transfer timed out.
+    /// * `Pipe` if the endpoint halted.
+    /// * `NoDevice` if the device has been disconnected.
+    /// * `Io` if the transfer encountered an I/O error.
+    pub fn write_stream<T: Into<Timeout>>(&self, endpoint: u8, stream_id: u32, buf: &[u8], timeout: T) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT || stream_id == 0 {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_stream", endpoint));
+        }
+
+        try!(self.check_endpoint(endpoint, TransferType::Bulk).map_err(|err| self.endpoint_error(err, "write_stream", endpoint)));
+
+        let ptr = buf.as_ptr() as *mut c_uchar;
+
+        match unsafe { async_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len(), timeout.into(), 0, stream_id, None) } {
+            (Ok(()), transferred) => Ok(transferred),
+            (Err(err), _) => Err(self.endpoint_error(err, "write_stream", endpoint)),
+        }
+    }
+
+    /// Reads a message from a bulk endpoint.
+    ///
+    /// This function reads from the bulk endpoint with the address given by the `endpoint`
+    /// parameter until the device ends the message with a short packet or a zero-length packet.
+    /// The message can span any number of transfers. The function blocks up to the amount of time
+    /// specified by `timeout`, which applies to the message as a whole.
+    ///
+    /// The endpoint's maximum packet size is looked up in the active configuration. A message that
+    /// is an exact multiple of the maximum packet size and shorter than `max_len` must be
+    /// terminated by a zero-length packet. A message that is exactly `max_len` bytes long ends
+    /// without one, unless `zero_packet` is true. When `zero_packet` is true, the device must
+    /// follow such a message with a zero-length packet, which is read to check that the message
+    /// isn't longer than `max_len`. Otherwise, nothing after the message is read, so that the
+    /// first packet of the next message stays queued on the device.
+    ///
+    /// If the return value is `Ok(data)`, then `data` contains the message, which is at most
+    /// `max_len` bytes long.
+    ///
+    /// ## Errors
+    ///
+    /// If an error variant is returned, the data that was received before the error occurred is
+    /// discarded.
+    ///
+    /// The errors returned by this function include:
+    ///
+    /// * `InvalidParam` if the endpoint is not an input endpoint.
+    /// * `UnknownEndpoint` if the endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if the endpoint is not a bulk endpoint.
+    /// * `Overflow` if the message is longer than `max_len` bytes, or if `zero_packet` is true and
+    ///   a message of `max_len` bytes isn't followed by a zero-length packet.
+    /// * `Timeout` if the message was not complete before the timeout expired.
+    /// * `Pipe` if the endpoint halted.
+    /// * `NoDevice` if the device has been disconnected.
+    /// * `Io` if the transfer encountered an I/O error.
+    pub fn read_bulk_message<T: Into<Timeout>>(&self, endpoint: u8, max_len: usize, zero_packet: bool, timeout: T) -> ::Result<Vec<u8>> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "read_bulk_message", endpoint));
+        }
+
+        let packet_size = try!(self.bulk_packet_size(endpoint).map_err(|err| self.endpoint_error(err, "read_bulk_message", endpoint)));
+        let timeout = timeout.into().to_deadline();
+
+        // round up to a whole number of packets so that the device can't overflow the buffer
+        let capacity = (max_len + packet_size - 1) / packet_size * packet_size;
+
+        let mut buf = vec![0u8; capacity];
+        let mut len = 0;
+
+        while len < capacity {
+            let ptr = buf[len..].as_mut_ptr();
+
+            let n = match unsafe { sync_transfer(self, TransferType::Bulk, endpoint, ptr, capacity - len, timeout) } {
+                (Ok(()), transferred) => transferred,
+                (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => transferred,
+                (Err(err), _) => return Err(self.endpoint_error(err, "read_bulk_message", endpoint)),
+            };
+
+            len += n;
+
+            if n == 0 || n % packet_size != 0 {
+                break;
+            }
+        }
+
+        if len == capacity && len == max_len && zero_packet {
+            // the buffer is full, so the message must end with a zero-length packet
+            let mut packet = vec![0u8; packet_size];
+            let ptr = packet.as_mut_ptr();
+
+            match unsafe { sync_transfer(self, TransferType::Bulk, endpoint, ptr, packet_size, timeout) } {
+                (Ok(()), 0) => (),
+                (Ok(()), _) => return Err(self.endpoint_error(ErrorKind::Overflow, "read_bulk_message", endpoint)),
+                (Err(err), _) => return Err(self.endpoint_error(err, "read_bulk_message", endpoint)),
+            }
+        }
+
+        if len > max_len {
+            return Err(self.endpoint_error(ErrorKind::Overflow, "read_bulk_message", endpoint));
+        }
+
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    /// Writes a message to a bulk endpoint.
+    ///
+    /// This function writes the contents of `buf` to the bulk endpoint with the address given by
+    /// the `endpoint` parameter. If the length of `buf` is a multiple of the endpoint's maximum
+    /// packet size, the message is terminated with a zero-length packet, so that the device can
+    /// detect the end of the message. The function blocks up to the amount of time specified by
+    /// `timeout`, which applies to the message as a whole.
+    ///
+    /// If the return value is `Ok(n)`, then all `n` bytes of `buf` were written to the endpoint.
+    ///
+    /// ## Errors
+    ///
+    /// If an error variant is returned, part of the message may have been written.
+    ///
+    /// The errors returned by this function include:
+    ///
+    /// * `InvalidParam` if the endpoint is not an output endpoint.
+    /// * `UnknownEndpoint` if the endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if the endpoint is not a bulk endpoint.
+    /// * `Timeout` if the message was not written before the timeout expired.
+    /// * `Pipe` if the endpoint halted.
+    /// * `NoDevice` if the device has been disconnected.
+    /// * `Io` if the transfer encountered an I/O error or the device accepted none of the
+    ///   remaining data.
+    pub fn write_bulk_message<T: Into<Timeout>>(&self, endpoint: u8, buf: &[u8], timeout: T) -> ::Result<usize> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(self.endpoint_error(ErrorKind::InvalidParam, "write_bulk_message", endpoint));
+        }
+
+        let packet_size = try!(self.bulk_packet_size(endpoint).map_err(|err| self.endpoint_error(err, "write_bulk_message", endpoint)));
+        let timeout = timeout.into().to_deadline();
+
+        let mut len = 0;
+
+        while len < buf.len() {
+            let ptr = buf[len..].as_ptr() as *mut c_uchar;
+
+            match unsafe { sync_transfer(self, TransferType::Bulk, endpoint, ptr, buf.len() - len, timeout) } {
+                // a device that accepts no data would otherwise be retried forever
+                (Ok(()), 0) => return Err(self.endpoint_error(ErrorKind::Io, "write_bulk_message", endpoint)),
+                (Ok(()), transferred) => len += transferred,
+                (Err(ref err), transferred) if err.kind() == ErrorKind::Interrupted && transferred > 0 => len += transferred,
+                (Err(err), _) => return Err(self.endpoint_error(err, "write_bulk_message", endpoint)),
+            }
+        }
+
+        if buf.len() % packet_size == 0 {
+            let ptr = buf.as_ptr() as *mut c_uchar;
+
+            match unsafe { sync_transfer(self, TransferType::Bulk, endpoint, ptr, 0, timeout) } {
+                (Ok(()), _) => (),
+                (Err(err), _) => return Err(self.endpoint_error(err, "write_bulk_message", endpoint)),
+            }
+        }
+
+        Ok(len)
+    }
+
+    fn bulk_packet_size(&self, endpoint: u8) -> ::Result<usize> {
+        match try!(self.stream_endpoint(endpoint)) {
+            (TransferType::Bulk, max_packet_size) => Ok(endpoint_io::packet_size(max_packet_size)),
+            _ => Err(Error::new(ErrorKind::WrongTransferType)),
+        }
+    }
+
+    /// Returns a poller for a set of interrupt IN endpoints.
+    ///
+    /// The returned [`InterruptPoller`](struct.InterruptPoller.html) keeps a transfer queued on
+    /// each endpoint and delivers the reports it receives to subscribed callbacks. Each transfer
+    /// is sized for the largest report that the endpoint can send in one service interval.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if `endpoints` is empty or if an endpoint is not an input endpoint.
+    /// * `UnknownEndpoint` if an endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if an endpoint is not an interrupt endpoint.
+    pub fn interrupt_poller<'h>(&'h self, endpoints: &[u8]) -> ::Result<InterruptPoller<'h, 'a>> {
+        if endpoints.is_empty() || endpoints.iter().any(|&endpoint| endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN) {
+            return Err(Error::new(ErrorKind::InvalidParam));
+        }
+
+        try!(self.require_libusb());
+
+        let config = try!(self.active_config_descriptor());
+        let mut polled = Vec::with_capacity(endpoints.len());
+
+        for &endpoint in endpoints {
+            let found = inspect_endpoint(&config, &self.settings, endpoint, |_, endpoint_desc| {
+                (endpoint_desc.transfer_type(), endpoint_desc.max_packet_size(), endpoint_desc.interval())
+            });
+
+            match found {
+                Some((TransferType::Interrupt, max_packet_size, interval)) => {
+                    // bits 11 and 12 of wMaxPacketSize encode additional transactions per microframe
+                    let transactions = 1 + ((max_packet_size as usize >> 11) & 0x03);
+                    polled.push((endpoint, endpoint_io::packet_size(max_packet_size) * transactions, interval));
+                },
+                Some(_) => return Err(Error::new(ErrorKind::WrongTransferType)),
+                None => return Err(Error::new(ErrorKind::UnknownEndpoint)),
+            }
+        }
+
+        interrupt_poller::new(self, self.source.speed(), &polled)
+    }
+
+    /// Allocates a buffer of `len` bytes for transfers to and from the device.
+    ///
+    /// The buffer is allocated from device memory if the platform supports it, which avoids
+    /// copying data through the kernel during bulk transfers. Otherwise, the buffer is allocated
+    /// on the heap. See [`DeviceBuffer`](struct.DeviceBuffer.html).
+    pub fn alloc_buffer<'h>(&'h self, len: usize) -> DeviceBuffer<'h, 'a> {
+        device_buffer::new(self, len)
+    }
+
+    /// Starts streaming from a bulk IN endpoint.
+    ///
+    /// The returned [`BulkStream`](struct.BulkStream.html) keeps `num_transfers` transfers of
+    /// `transfer_size` bytes each queued on the endpoint, so that the device can keep sending data
+    /// while the application processes the data it has already received. The transfer size is
+    /// rounded up to a multiple of the endpoint's maximum packet size.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if the endpoint is not an input endpoint or if `num_transfers` or
+    ///   `transfer_size` is zero.
+    /// * `UnknownEndpoint` if the endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if the endpoint is not a bulk endpoint.
+    /// * `NoDevice` if the device has been disconnected.
+    pub fn bulk_stream<'h>(&'h self, endpoint: u8, num_transfers: usize, transfer_size: usize) -> ::Result<BulkStream<'h, 'a>> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN || num_transfers == 0 || transfer_size == 0 {
+            return Err(Error::new(ErrorKind::InvalidParam));
+        }
+
+        let packet_size = try!(self.bulk_packet_size(endpoint));
+        let transfer_size = bulk_stream::round_transfer_size(transfer_size, packet_size);
+
+        bulk_stream::new(self, endpoint, num_transfers, transfer_size)
+    }
+
+    /// Starts queuing writes to a bulk OUT endpoint.
+    ///
+    /// The returned [`BulkSink`](struct.BulkSink.html) keeps up to `num_transfers` transfers
+    /// queued on the endpoint. Each transfer times out after the amount of time specified by
+    /// `timeout`.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if the endpoint is not an output endpoint or if `num_transfers` is zero.
+    /// * `UnknownEndpoint` if the endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if the endpoint is not a bulk endpoint.
+    pub fn bulk_sink<'h, T: Into<Timeout>>(&'h self, endpoint: u8, num_transfers: usize, timeout: T) -> ::Result<BulkSink<'h, 'a>> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT || num_transfers == 0 {
+            return Err(Error::new(ErrorKind::InvalidParam));
+        }
+
+        try!(self.bulk_packet_size(endpoint));
+
+        Ok(bulk_sink::new(self, endpoint, num_transfers, timeout.into()))
+    }
+
+    /// Returns a reader for a bulk or interrupt IN endpoint.
+    ///
+    /// The returned reader implements `std::io::Read`. Each read blocks up to the amount of time
+    /// specified by `timeout`, which can be changed later with
+    /// [`EndpointReader::set_timeout()`](struct.EndpointReader.html#method.set_timeout).
+    ///
+    /// The endpoint's transfer type and maximum packet size are looked up in the active
+    /// configuration, taking into account the interface's active alternate setting.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if the endpoint is not an input endpoint.
+    /// * `UnknownEndpoint` if the endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if the endpoint is not a bulk or interrupt endpoint.
+    pub fn endpoint_reader<'h, T: Into<Timeout>>(&'h self, endpoint: u8, timeout: T) -> ::Result<EndpointReader<'h, 'a>> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(Error::new(ErrorKind::InvalidParam));
+        }
+
+        let (transfer_type, max_packet_size) = try!(self.stream_endpoint(endpoint));
+
+        Ok(endpoint_io::reader(self, endpoint, transfer_type, max_packet_size, timeout.into()))
+    }
+
+    /// Returns a writer for a bulk or interrupt OUT endpoint.
+    ///
+    /// The returned writer implements `std::io::Write`. Each write blocks up to the amount of time
+    /// specified by `timeout`, which can be changed later with
+    /// [`EndpointWriter::set_timeout()`](struct.EndpointWriter.html#method.set_timeout).
+    ///
+    /// The endpoint's transfer type and maximum packet size are looked up in the active
+    /// configuration, taking into account the interface's active alternate setting.
+    ///
+    /// ## Errors
+    ///
+    /// * `InvalidParam` if the endpoint is not an output endpoint.
+    /// * `UnknownEndpoint` if the endpoint does not exist in the active configuration.
+    /// * `WrongTransferType` if the endpoint is not a bulk or interrupt endpoint.
+    pub fn endpoint_writer<'h, T: Into<Timeout>>(&'h self, endpoint: u8, timeout: T) -> ::Result<EndpointWriter<'h, 'a>> {
+        if endpoint & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(Error::new(ErrorKind::InvalidParam));
+        }
+
+        let (transfer_type, max_packet_size) = try!(self.stream_endpoint(endpoint));
+
+        Ok(endpoint_io::writer(self, endpoint, transfer_type, max_packet_size, timeout.into()))
+    }
+
+    fn stream_endpoint(&self, endpoint: u8) -> ::Result<(TransferType, u16)> {
+        let config = try!(self.active_config_descriptor());
+
+        match find_endpoint(&config, &self.settings, endpoint) {
+            None => Err(Error::new(ErrorKind::UnknownEndpoint)),
+            Some((_, transfer_type @ TransferType::Bulk, max_packet_size)) |
+            Some((_, transfer_type @ TransferType::Interrupt, max_packet_size)) => Ok((transfer_type, max_packet_size)),
+            Some(_) => Err(Error::new(ErrorKind::WrongTransferType)),
+        }
+    }
+
+    /// Reads data using a control transfer.
+    ///
+    /// This function attempts to read data from the device using a control transfer and fills
+    /// `buf` with any data received during the transfer. The function blocks up to the amount of
+    /// time specified by `timeout`.
+    ///
+    /// The parameters `request_type`, `request`, `value`, and `index` specify the fields of the
+    /// control transfer setup packet (`bmRequestType`, `bRequest`, `wValue`, and `wIndex`
+    /// respectively). The values for each of these parameters shall be given in host-endian byte
+    /// order. The value for the `request_type` parameter can be built with the helper function,
+    /// [request_type()](fn.request_type.html). The meaning of the other parameters depends on the
+    /// type of control request.
+    ///
+    /// If the return value is `Ok(n)`, then `buf` is populated with `n` bytes of data.
+    ///
+    /// ## Errors
+    ///
+    /// If this function encounters any form of error while fulfilling the transfer request, an
+    /// error variant will be returned. If an error variant is returned, no bytes were read.
+    ///
+    /// The errors returned by this function include:
+    ///
+    /// * `InvalidParam` if `request_type` does not specify a read transfer.
+    /// * `Timeout` if the transfer timed out.
+    /// * `Pipe` if the control request was not supported by the device.
+    /// * `NoDevice` if the device has been disconnected.
+    /// * `Io` if the transfer encountered an I/O error.
+    pub fn read_control<T: Into<Timeout>>(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &mut [u8], timeout: T) -> ::Result<usize> {
+        if request_type & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_IN {
+            return Err(self.request_error(ErrorKind::InvalidParam, "read_control", request_type, request));
+        }
+
+        let ptr = buf.as_mut_ptr() as *mut c_uchar;
+        let len = buf.len() as u16;
+        let timeout_ms = match timeout::to_libusb(&timeout.into()) {
+            Some(timeout_ms) => timeout_ms,
+            None => return Err(self.request_error(ErrorKind::Timeout, "read_control", request_type, request)),
+        };
+
+        let start = Instant::now();
+        let setup = SetupPacket::new(request_type, request, value, index, len);
+
+        let (res, transferred) = with_faults(self, TransferType::Control, request_type & LIBUSB_ENDPOINT_DIR_MASK, len as usize, |len| {
+            control_status(unsafe { self.source.control_transfer(request_type, request, value, index, ptr, len, timeout_ms) })
+        });
+
+        unsafe { trace_transfer(self, TransferType::Control, request_type & LIBUSB_ENDPOINT_DIR_MASK, Some(setup), ptr, len as usize, &res, transferred, start) };
+
+        match res {
+            Ok(()) => Ok(transferred),
+            Err(err) => Err(self.request_error(err, "read_control", request_type, request)),
+        }
+    }
+
+    /// Writes data using a control transfer.
+    ///
+    /// This function attempts to write the contents of `buf` to the device using a control
+    /// transfer. The function blocks up to the amount of time specified by `timeout`.
+    ///
+    /// The parameters `request_type`, `request`, `value`, and `index` specify the fields of the
+    /// control transfer setup packet (`bmRequestType`, `bRequest`, `wValue`, and `wIndex`
+    /// respectively). The values for each of these parameters shall be given in host-endian byte
+    /// order. The value for the `request_type` parameter can be built with the helper function,
+    /// [request_type()](fn.request_type.html). The meaning of the other parameters depends on the
+    /// type of control request.
+    ///
+    /// If the return value is `Ok(n)`, then `n` bytes of `buf` were transfered.
+    ///
+    /// ## Errors
+    ///
+    /// If this function encounters any form of error while fulfilling the transfer request, an
+    /// error variant will be returned. If an error variant is returned, no bytes were read.
+    ///
+    /// The errors returned by this function include:
+    ///
+    /// * `InvalidParam` if `request_type` does not specify a write transfer.
+    /// * `Timeout` if the transfer timed out.
+    /// * `Pipe` if the control request was not supported by the device.
+    /// * `NoDevice` if the device has been disconnected.
+    /// * `Io` if the transfer encountered an I/O error.
+    pub fn write_control<T: Into<Timeout>>(&self, request_type: u8, request: u8, value: u16, index: u16, buf: &[u8], timeout: T) -> ::Result<usize> {
+        if request_type & LIBUSB_ENDPOINT_DIR_MASK != LIBUSB_ENDPOINT_OUT {
+            return Err(self.request_error(ErrorKind::InvalidParam, "write_control", request_type, request));
+        }
+
+        let ptr = buf.as_ptr() as *mut c_uchar;
+        let len = buf.len() as u16;
+        let timeout_ms = match timeout::to_libusb(&timeout.into()) {
+            Some(timeout_ms) => timeout_ms,
+            None => return Err(self.request_error(ErrorKind::Timeout, "write_control", request_type, request)),
+        };
+
+        let start = Instant::now();
+        let setup = SetupPacket::new(request_type, request, value, index, len);
+
+        let (res, transferred) = with_faults(self, TransferType::Control, request_type & LIBUSB_ENDPOINT_DIR_MASK, len as usize, |len| {
+            control_status(unsafe { self.source.control_transfer(request_type, request, value, index, ptr, len, timeout_ms) })
+        });
+
+        unsafe { trace_transfer(self, TransferType::Control, request_type & LIBUSB_ENDPOINT_DIR_MASK, Some(setup), ptr, len as usize, &res, transferred, start) };
+
+        match res {
+            Ok(()) => Ok(transferred),
+            Err(err) => Err(self.request_error(err, "write_control", request_type, request)),
+        }
+    }
+
+    /// Reads the languages supported by the device's string descriptors.
+    ///
+    /// This function returns a list of languages that can be used to read the device's string
+    /// descriptors.
+    pub fn read_languages<T: Into<Timeout>>(&self, timeout: T) -> ::Result<Vec<Language>> {
+        let mut buf = Vec::<u8>::with_capacity(256);
+
+        let mut buf_slice = unsafe {
+            slice::from_raw_parts_mut((&mut buf[..]).as_mut_ptr(), buf.capacity())
+        };
+
+        let len = try!(self.read_control(request_type(Direction::In, RequestType::Standard, Recipient::Device),
+                                         LIBUSB_REQUEST_GET_DESCRIPTOR,
+                                         (LIBUSB_DT_STRING as u16) << 8,
+                                         0,
+                                         buf_slice,
+                                         timeout));
+
+        unsafe {
+            buf.set_len(len);
+        }
+
+        Ok(buf.chunks(2).skip(1).map(|chunk| {
+            let lang_id = chunk[0] as u16 | (chunk[1] as u16) << 8;
+            ::language::from_lang_id(lang_id)
+        }).collect())
+    }
+
+    /// Reads a string descriptor from the device.
+    ///
+    /// `language` should be one of the languages returned from [`read_languages`](#method.read_languages).
+    pub fn read_string_descriptor<T: Into<Timeout>>(&self, language: Language, index: u8, timeout: T) -> ::Result<String> {
+        let mut buf = Vec::<u8>::with_capacity(256);
+
+        let mut buf_slice = unsafe {
+            slice::from_raw_parts_mut((&mut buf[..]).as_mut_ptr(), buf.capacity())
+        };
+
+        let len = try!(self.read_control(request_type(Direction::In, RequestType::Standard, Recipient::Device),
+                                         LIBUSB_REQUEST_GET_DESCRIPTOR,
+                                         (LIBUSB_DT_STRING as u16) << 8 | index as u16,
+                                         language.lang_id(),
+                                         buf_slice,
+                                         timeout));
+
+        unsafe {
+            buf.set_len(len);
+        }
+
+        let utf16: Vec<u16> = buf.chunks(2).skip(1).map(|chunk| {
+            chunk[0] as u16 | (chunk[1] as u16) << 8
+        }).collect();
+
+        String::from_utf16(&utf16[..]).map_err(|_| Error::new(ErrorKind::Other))
+    }
+
+    /// Reads the device's manufacturer string descriptor.
+    pub fn read_manufacturer_string<T: Into<Timeout>>(&self, language: Language, device: &DeviceDescriptor, timeout: T) -> ::Result<String> {
+        match device.manufacturer_string_index() {
+            None => Err(Error::new(ErrorKind::InvalidParam)),
+            Some(n) => self.read_string_descriptor(language, n, timeout)
+        }
+    }
+
+    /// Reads the device's product string descriptor.
+    pub fn read_product_string<T: Into<Timeout>>(&self, language: Language, device: &DeviceDescriptor, timeout: T) -> ::Result<String> {
+        match device.product_string_index() {
+            None => Err(Error::new(ErrorKind::InvalidParam)),
+            Some(n) => self.read_string_descriptor(language, n, timeout)
+        }
+    }
+
+    /// Reads the device's serial number string descriptor.
+    pub fn read_serial_number_string<T: Into<Timeout>>(&self, language: Language, device: &DeviceDescriptor, timeout: T) -> ::Result<String> {
+        match device.serial_number_string_index() {
+            None => Err(Error::new(ErrorKind::InvalidParam)),
+            Some(n) => self.read_string_descriptor(language, n, timeout)
+        }
+    }
+
+    /// Reads the string descriptor for a configuration's description.
+    pub fn read_configuration_string<T: Into<Timeout>>(&self, language: Language, configuration: &ConfigDescriptor, timeout: T) -> ::Result<String> {
+        match configuration.description_string_index() {
+            None => Err(Error::new(ErrorKind::InvalidParam)),
+            Some(n) => self.read_string_descriptor(language, n, timeout)
+        }
+    }
+
+    /// Reads the string descriptor for a interface's description.
+    pub fn read_interface_string<T: Into<Timeout>>(&self, language: Language, interface: &InterfaceDescriptor, timeout: T) -> ::Result<String> {
+        match interface.description_string_index() {
+            None => Err(Error::new(ErrorKind::InvalidParam)),
+            Some(n) => self.read_string_descriptor(language, n, timeout)
+        }
+    }
+}
+
+/// Performs a synchronous bulk or interrupt transfer.
+///
+/// Returns the status of the transfer and the number of bytes transferred, which may be non-zero
+/// even if the transfer failed.
+#[doc(hidden)]
+pub unsafe fn sync_transfer(handle: &DeviceHandle, transfer_type: TransferType, endpoint: u8, ptr: *mut c_uchar, len: usize, timeout: Timeout) -> (::Result<()>, usize) {
+    let timeout_ms = match timeout::to_libusb(&timeout) {
+        Some(timeout_ms) => timeout_ms,
+        None => return (Err(Error::new(ErrorKind::Timeout)), 0),
+    };
+
+    let start = Instant::now();
+
+    let (res, transferred) = with_faults(handle, transfer_type, endpoint, len, |len| {
+        handle.source.transfer(transfer_type, endpoint, ptr, len, timeout_ms)
+    });
+
+    trace_transfer(handle, transfer_type, endpoint, None, ptr, len, &res, transferred, start);
+
+    (res, transferred)
+}
+
+/// Performs a bulk or interrupt transfer asynchronously and blocks until it completes.
+///
+/// The transfer is submitted with the given transfer flags. If `cancel` is given, the transfer can
+/// be cancelled with the cancellation token. Returns the status of the transfer and the number of
+/// bytes transferred, which may be non-zero even if the transfer failed.
+#[doc(hidden)]
+pub unsafe fn async_transfer(handle: &DeviceHandle, transfer_type: TransferType, endpoint: u8, ptr: *mut c_uchar, len: usize, timeout: Timeout, flags: u8, stream_id: u32, cancel: Option<&CancellationToken>) -> (::Result<()>, usize) {
+    let timeout_ms = match timeout::to_libusb(&timeout) {
+        Some(timeout_ms) => timeout_ms,
+        None => return (Err(Error::new(ErrorKind::Timeout)), 0),
+    };
+
+    if handle.source.require_libusb().is_err() {
+        return backend_async_transfer(handle, transfer_type, endpoint, ptr, len, timeout_ms, flags, stream_id, cancel);
+    }
+
+    let mut transfer = match Transfer::new(context_raw(handle)) {
+        Ok(transfer) => transfer,
+        Err(err) => return (Err(err), 0),
+    };
+
+    let libusb_transfer_type = match transfer_type {
+        TransferType::Interrupt => LIBUSB_TRANSFER_TYPE_INTERRUPT,
+        _ => LIBUSB_TRANSFER_TYPE_BULK,
+    };
+
+    let start = Instant::now();
+
+    let (res, transferred) = with_faults(handle, transfer_type, endpoint, len, |len| {
+        transfer.fill(handle.source.as_raw(), libusb_transfer_type, endpoint, ptr, len, timeout_ms, flags);
+
+        if stream_id != 0 {
+            transfer.set_stream_id(stream_id);
+        }
+
+        let res = match cancel {
+            Some(cancel) => transfer.submit_cancellable(cancel),
+            None => transfer.submit(),
+        };
+
+        if res != 0 {
+            return (Err(error::from_libusb(res)), 0);
+        }
+
+        transfer.wait();
+
+        if let Some(cancel) = cancel {
+            transfer.unregister(cancel);
+        }
+
+        match transfer.result() {
+            0 => (Ok(()), transfer.actual_length()),
+            err => (Err(error::from_libusb(err)), transfer.actual_length()),
+        }
+    });
+
+    trace_transfer(handle, transfer_type, endpoint, None, ptr, len, &res, transferred, start);
+
+    (res, transferred)
+}
+
+/// Performs the equivalent of an asynchronous transfer through a backend.
+///
+/// Backends perform transfers synchronously, so a cancellation token only cancels transfers that
+/// haven't started yet. Streams are not supported.
+unsafe fn backend_async_transfer(handle: &DeviceHandle, transfer_type: TransferType, endpoint: u8, ptr: *mut c_uchar, len: usize, timeout_ms: c_uint, flags: u8, stream_id: u32, cancel: Option<&CancellationToken>) -> (::Result<()>, usize) {
+    if stream_id != 0 {
+        return (Err(Error::new(ErrorKind::NotSupported)), 0);
+    }
+
+    if cancel.map_or(false, |cancel| cancel.is_cancelled()) {
+        return (Err(Error::new(ErrorKind::Interrupted)), 0);
+    }
+
+    let start = Instant::now();
+
+    let (res, transferred) = with_faults(handle, transfer_type, endpoint, len, |limit| {
+        match handle.source.transfer(transfer_type, endpoint, ptr, limit, timeout_ms) {
+            (Ok(()), n) if flags & LIBUSB_TRANSFER_SHORT_NOT_OK != 0 && n < len => (Err(Error::new(ErrorKind::Io)), n),
+            result => result,
+        }
+    });
+
+    trace_transfer(handle, transfer_type, endpoint, None, ptr, len, &res, transferred, start);
+
+    (res, transferred)
+}
+
+/// Performs a bulk or interrupt transfer that was queued on a device provided by a backend.
+///
+/// Backends perform transfers synchronously, so a queued transfer is performed when it's waited
+/// for. Like transfers queued through `libusb`, the transfer isn't seen by the handle's observer,
+/// metrics, or fault injector.
+#[doc(hidden)]
+pub unsafe fn backend_transfer(handle: &DeviceHandle, transfer_type: TransferType, endpoint: u8, ptr: *mut c_uchar, len: usize, timeout: Timeout, flags: u8) -> (::Result<()>, usize) {
+    let timeout_ms = match timeout::to_libusb(&timeout) {
+        Some(timeout_ms) => timeout_ms,
+        None => return (Err(Error::new(ErrorKind::Timeout)), 0),
+    };
+
+    match handle.source.transfer(transfer_type, endpoint, ptr, len, timeout_ms) {
+        (Ok(()), n) if flags & LIBUSB_TRANSFER_SHORT_NOT_OK != 0 && n < len => (Err(Error::new(ErrorKind::Io)), n),
+        result => result,
+    }
+}
+
+/// Performs a transfer of `len` bytes with `transfer`, injecting the faults of the handle's fault
+/// injector.
+fn with_faults<F>(handle: &DeviceHandle, transfer_type: TransferType, endpoint: u8, len: usize, transfer: F) -> (::Result<()>, usize)
+    where F: FnOnce(usize) -> (::Result<()>, usize)
+{
+    match handle.faults {
+        Some(ref faults) => fault::inject(faults, transfer_type, endpoint, len, transfer),
+        None => transfer(len),
+    }
+}
+
+/// Splits the result of a control transfer into a status and a byte count.
+fn control_status(res: ::Result<usize>) -> (::Result<()>, usize) {
+    match res {
+        Ok(transferred) => (Ok(()), transferred),
+        Err(err) => (Err(err), 0),
+    }
+}
+
+/// Records a transfer that started at `start` in the handle's metrics and notifies the handle's
+/// observer.
+unsafe fn trace_transfer(handle: &DeviceHandle, transfer_type: TransferType, endpoint: u8, setup: Option<SetupPacket>, ptr: *const c_uchar, len: usize, res: &::Result<()>, transferred: usize, start: Instant) {
+    if handle.metrics.is_none() && handle.observer.is_none() {
+        return;
+    }
+
+    let latency = start.elapsed();
+
+    if let Some(ref metrics) = handle.metrics {
+        let kind = res.as_ref().err().map(|err| err.kind());
+
+        metrics::record_transfer(&mut metrics.lock().unwrap(), transfer_type, endpoint, transferred, kind, latency);
+    }
+
+    if let Some(ref observer) = handle.observer {
+        let error = res.as_ref().err().map(error::without_context);
+
+        let data = if observer.capture_data() {
+            let data_len = match endpoint & LIBUSB_ENDPOINT_DIR_MASK {
+                LIBUSB_ENDPOINT_IN => cmp::min(transferred, len),
+                _ => len,
+            };
+
+            Some(slice::from_raw_parts(ptr, data_len))
+        }
+        else {
+            None
+        };
+
+        observer.on_transfer(&trace::new_record(transfer_type, endpoint, setup, len, transferred, error, latency, data));
+    }
+}
+
+/// Looks up an endpoint in the active settings of a configuration.
+///
+/// Returns the endpoint's interface number, transfer type, and maximum packet size.
+fn find_endpoint(config: &ConfigDescriptor, settings: &[u8], endpoint: u8) -> Option<(u8, TransferType, u16)> {
+    inspect_endpoint(config, settings, endpoint, |iface, endpoint_desc| {
+        (iface, endpoint_desc.transfer_type(), endpoint_desc.max_packet_size())
+    })
+}
+
+/// Looks up an endpoint in the active settings of a configuration and applies `f` to the
+/// endpoint's interface number and descriptor.
+fn inspect_endpoint<F, R>(config: &ConfigDescriptor, settings: &[u8], endpoint: u8, f: F) -> Option<R>
+    where F: Fn(u8, &EndpointDescriptor) -> R
+{
+    for interface in config.interfaces() {
+        let iface = interface.number();
+
+        for setting in interface.descriptors() {
+            if setting.setting_number() != settings[iface as usize] {
+                continue;
+            }
+
+            for endpoint_desc in setting.endpoint_descriptors() {
+                if endpoint_desc.address() == endpoint {
+                    return Some(f(iface, &endpoint_desc));
+                }
+            }
+        }
+    }
+
+    None
+}
+
+fn validate_endpoint(config: &ConfigDescriptor, claimed: &BitSet, settings: &[u8], endpoint: u8, transfer_type: TransferType) -> ::Result<()> {
+    match find_endpoint(config, settings, endpoint) {
+        None => Err(Error::new(ErrorKind::UnknownEndpoint)),
+        Some((iface, _, _)) if !claimed.contains(&(iface as usize)) => Err(Error::new(ErrorKind::InterfaceNotClaimed)),
+        Some((_, endpoint_type, _)) if endpoint_type != transfer_type => Err(Error::new(ErrorKind::WrongTransferType)),
+        Some(_) => Ok(()),
+    }
+}
+
+fn check_streams(config: &ConfigDescriptor, settings: &[u8], endpoint: u8, num_streams: u32) -> ::Result<()> {
+    match inspect_endpoint(config, settings, endpoint, |_, endpoint_desc| (endpoint_desc.transfer_type(), endpoint_desc.max_streams())) {
+        None => Err(Error::new(ErrorKind::UnknownEndpoint)),
+        Some((TransferType::Bulk, None)) | Some((TransferType::Bulk, Some(0))) => Err(Error::new(ErrorKind::NotSupported)),
+        Some((TransferType::Bulk, Some(max_streams))) if num_streams > max_streams => Err(Error::new(ErrorKind::InvalidParam)),
+        Some((TransferType::Bulk, Some(_))) => Ok(()),
+        Some(_) => Err(Error::new(ErrorKind::WrongTransferType)),
+    }
+}
+
+/// Adds the operation, the endpoint, and the device to an error of a transfer on an endpoint.
+#[doc(hidden)]
+pub fn endpoint_error<E: Into<Error>>(handle: &DeviceHandle, err: E, operation: &'static str, endpoint: u8) -> Error {
+    handle.endpoint_error(err, operation, endpoint)
+}
 
 #[doc(hidden)]
-pub unsafe fn from_libusb<'a>(context: PhantomData<&'a Context>, handle: *mut libusb_device_handle) -> DeviceHandle<'a> {
+pub fn as_raw(handle: &DeviceHandle) -> *mut libusb_device_handle {
+    handle.source.as_raw()
+}
+
+#[doc(hidden)]
+pub fn context_raw(handle: &DeviceHandle) -> *mut libusb_context {
+    context::as_raw(handle.context)
+}
+
+#[doc(hidden)]
+pub fn new<'a>(context: &'a Context, source: HandleSource) -> DeviceHandle<'a> {
     DeviceHandle {
-        _context: context,
-        handle: handle,
+        context: context,
+        source: source,
         interfaces: BitSet::with_capacity(u8::max_value() as usize + 1),
+        detached: BitSet::with_capacity(u8::max_value() as usize + 1),
+        settings: vec![0; u8::max_value() as usize + 1],
+        validate_endpoints: false,
+        observer: None,
+        metrics: None,
+        faults: None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use std::time::Duration;
+
+    use bit_set::BitSet;
+
+    use config_descriptor;
+    use error::ErrorKind;
+    use fault::{FaultInjector, FaultRule};
+    use fields::TransferType;
+    use test_helpers::{context_with, mock_device, open};
+    use transfer::{CancellationToken, TransferFlags};
+
+    use super::{check_streams, validate_endpoint};
+
+    fn claimed(ifaces: &[usize]) -> BitSet {
+        let mut set = BitSet::new();
+
+        for &iface in ifaces {
+            set.insert(iface);
+        }
+
+        set
+    }
+
+    macro_rules! validate {
+        ($config:expr, $claimed:expr, $settings:expr, $endpoint:expr, $transfer_type:expr) => {
+            {
+                let config = unsafe { config_descriptor::from_libusb(&$config) };
+                let result = validate_endpoint(&config, &claimed($claimed), &$settings, $endpoint, $transfer_type).map_err(|err| err.kind());
+                mem::forget(config);
+                result
+            }
+        }
+    }
+
+    #[test]
+    fn it_accepts_endpoint_of_claimed_interface() {
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02))));
+
+        assert!(validate!(config, &[0], [0; 256], 0x81, TransferType::Bulk).is_ok());
+    }
+
+    #[test]
+    fn it_rejects_unknown_endpoint() {
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02))));
+
+        match validate!(config, &[0], [0; 256], 0x82, TransferType::Bulk) {
+            Err(ErrorKind::UnknownEndpoint) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_wrong_transfer_type() {
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x03))));
+
+        match validate!(config, &[0], [0; 256], 0x81, TransferType::Bulk) {
+            Err(ErrorKind::WrongTransferType) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_endpoint_of_unclaimed_interface() {
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02))));
+
+        match validate!(config, &[], [0; 256], 0x81, TransferType::Bulk) {
+            Err(ErrorKind::InterfaceNotClaimed) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_checks_active_alternate_setting() {
+        let setting0 = interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02));
+        let setting1 = merge!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x82, bmAttributes: 0x02)) => bAlternateSetting: 1);
+        let config = config_descriptor!(interface!(setting0, setting1));
+
+        let mut settings = [0; 256];
+
+        match validate!(config, &[0], settings, 0x82, TransferType::Bulk) {
+            Err(ErrorKind::UnknownEndpoint) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        settings[0] = 1;
+
+        assert!(validate!(config, &[0], settings, 0x82, TransferType::Bulk).is_ok());
+    }
+
+    macro_rules! check_streams {
+        ($config:expr, $endpoint:expr, $num_streams:expr) => {
+            {
+                let config = unsafe { config_descriptor::from_libusb(&$config) };
+                let result = check_streams(&config, &[0; 256], $endpoint, $num_streams).map_err(|err| err.kind());
+                mem::forget(config);
+                result
+            }
+        }
+    }
+
+    #[test]
+    fn it_accepts_streams_up_to_companion_max_streams() {
+        let companion = [0x06, 0x30, 0x0F, 0x04, 0x00, 0x00];
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02, extra: companion.as_ptr(), extra_length: 6))));
+
+        assert!(check_streams!(config, 0x81, 16).is_ok());
+
+        match check_streams!(config, 0x81, 17) {
+            Err(ErrorKind::InvalidParam) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_streams_without_companion_descriptor() {
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x02))));
+
+        match check_streams!(config, 0x81, 1) {
+            Err(ErrorKind::NotSupported) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_rejects_streams_on_non_bulk_endpoint() {
+        let config = config_descriptor!(interface!(interface_descriptor!(endpoint_descriptor!(bEndpointAddress: 0x81, bmAttributes: 0x03))));
+
+        match check_streams!(config, 0x81, 1) {
+            Err(ErrorKind::WrongTransferType) => (),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_reads_bulk_message_ending_with_short_packet() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        device.push_in(0x81, vec![1; 64]);
+        device.push_in(0x81, vec![2; 10]);
+
+        let message = handle.read_bulk_message(0x81, 200, false, Duration::from_secs(1)).unwrap();
+        assert_eq!(74, message.len());
+        assert_eq!(&[2; 10], &message[64..]);
+    }
+
+    #[test]
+    fn it_reads_bulk_message_ending_with_zero_length_packet() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        device.push_in(0x81, vec![1; 64]);
+        device.push_in(0x81, vec![2; 64]);
+        device.push_in(0x81, vec![]);
+
+        assert_eq!(128, handle.read_bulk_message(0x81, 200, false, Duration::from_secs(1)).unwrap().len());
+    }
+
+    #[test]
+    fn it_reads_zero_length_packet_after_full_message_when_requested() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        device.push_in(0x81, vec![1; 64]);
+        device.push_in(0x81, vec![2; 64]);
+        device.push_in(0x81, vec![]);
+        device.push_in(0x81, vec![3; 10]);
+
+        assert_eq!(128, handle.read_bulk_message(0x81, 128, true, Duration::from_secs(1)).unwrap().len());
+        assert_eq!(vec![3; 10], handle.read_bulk_message(0x81, 128, true, Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn it_leaves_next_message_queued_after_full_message() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        device.push_in(0x81, vec![1; 64]);
+        device.push_in(0x81, vec![2; 64]);
+        device.push_in(0x81, vec![3; 10]);
+
+        assert_eq!(128, handle.read_bulk_message(0x81, 128, false, Duration::from_secs(1)).unwrap().len());
+        assert_eq!(vec![3; 10], handle.read_bulk_message(0x81, 128, false, Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_full_message_without_zero_length_packet_when_requested() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        device.push_in(0x81, vec![1; 64]);
+        device.push_in(0x81, vec![2; 64]);
+        device.push_in(0x81, vec![3; 10]);
+
+        assert_eq!(ErrorKind::Overflow, handle.read_bulk_message(0x81, 128, true, Duration::from_secs(1)).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_rejects_bulk_message_longer_than_max_len() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        device.push_in(0x81, vec![1; 64]);
+        device.push_in(0x81, vec![2; 64]);
+        device.push_in(0x81, vec![3; 10]);
+
+        assert_eq!(ErrorKind::Overflow, handle.read_bulk_message(0x81, 100, false, Duration::from_secs(1)).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_writes_bulk_message_ending_with_short_packet() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        assert_eq!(100, handle.write_bulk_message(0x02, &[1; 100], Duration::from_secs(1)).unwrap());
+        assert_eq!(vec![vec![1; 100]], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_terminates_bulk_message_with_zero_length_packet() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        assert_eq!(128, handle.write_bulk_message(0x02, &[1; 128], Duration::from_secs(1)).unwrap());
+        assert_eq!(vec![vec![1; 128], vec![]], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_fails_bulk_message_when_device_accepts_no_data() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let mut handle = open(&context);
+
+        handle.set_fault_injector(FaultInjector::new().rule(FaultRule::truncate(0).endpoint(0x02)));
+
+        assert_eq!(ErrorKind::Io, handle.write_bulk_message(0x02, &[1; 100], Duration::from_secs(1)).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_transfers_with_cancellation_token() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let cancel = CancellationToken::new();
+        let mut buf = [0u8; 64];
+
+        device.push_in(0x81, vec![1, 2, 3]);
+        device.push_in(0x83, vec![4, 5]);
+
+        assert_eq!(3, handle.read_bulk_cancellable(0x81, &mut buf, Duration::from_secs(1), &cancel).unwrap());
+        assert_eq!(&[1, 2, 3], &buf[..3]);
+        assert_eq!(2, handle.read_interrupt_cancellable(0x83, &mut buf, Duration::from_secs(1), &cancel).unwrap());
+        assert_eq!(&[4, 5], &buf[..2]);
+        assert_eq!(2, handle.write_bulk_cancellable(0x02, &[6, 7], Duration::from_secs(1), &cancel).unwrap());
+        assert_eq!(1, handle.write_interrupt_cancellable(0x04, &[8], Duration::from_secs(1), &cancel).unwrap());
+        assert_eq!(vec![vec![6, 7]], device.take_out(0x02));
+        assert_eq!(vec![vec![8]], device.take_out(0x04));
+    }
+
+    #[test]
+    fn it_interrupts_transfers_with_cancelled_token() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let cancel = CancellationToken::new();
+        let mut buf = [0u8; 64];
+
+        cancel.clone().cancel();
+        device.push_in(0x81, vec![1, 2, 3]);
+        device.push_in(0x83, vec![4, 5]);
+
+        assert_eq!(ErrorKind::Interrupted, handle.read_bulk_cancellable(0x81, &mut buf, Duration::from_secs(1), &cancel).unwrap_err().kind());
+        assert_eq!(ErrorKind::Interrupted, handle.read_interrupt_cancellable(0x83, &mut buf, Duration::from_secs(1), &cancel).unwrap_err().kind());
+        assert_eq!(ErrorKind::Interrupted, handle.write_bulk_cancellable(0x02, &[6, 7], Duration::from_secs(1), &cancel).unwrap_err().kind());
+        assert_eq!(ErrorKind::Interrupted, handle.write_interrupt_cancellable(0x04, &[8], Duration::from_secs(1), &cancel).unwrap_err().kind());
+
+        // no transfer is made, so the queued data is still there
+        assert!(device.take_out(0x02).is_empty());
+        assert!(device.take_out(0x04).is_empty());
+        assert_eq!(3, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap());
+        assert_eq!(2, handle.read_interrupt(0x83, &mut buf, Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_short_reads_with_short_not_ok_flag() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let flags = TransferFlags::new().short_not_ok(true);
+        let mut buf = [0u8; 8];
+
+        device.push_in(0x81, vec![1, 2, 3]);
+        device.push_in(0x83, vec![4, 5]);
+
+        assert_eq!(ErrorKind::ShortTransfer, handle.read_bulk_with_flags(0x81, &mut buf, Duration::from_secs(1), flags).unwrap_err().kind());
+        assert_eq!(ErrorKind::ShortTransfer, handle.read_interrupt_with_flags(0x83, &mut buf, Duration::from_secs(1), flags).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_accepts_full_reads_with_short_not_ok_flag() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let flags = TransferFlags::new().short_not_ok(true);
+        let mut buf = [0u8; 8];
+
+        device.push_in(0x81, vec![1; 8]);
+        device.push_in(0x83, vec![2; 8]);
+
+        assert_eq!(8, handle.read_bulk_with_flags(0x81, &mut buf, Duration::from_secs(1), flags).unwrap());
+        assert_eq!(8, handle.read_interrupt_with_flags(0x83, &mut buf, Duration::from_secs(1), flags).unwrap());
+        assert_eq!([2; 8], buf);
+    }
+
+    #[test]
+    fn it_accepts_short_reads_without_short_not_ok_flag() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut buf = [0u8; 8];
+
+        device.push_in(0x81, vec![1, 2, 3]);
+
+        assert_eq!(3, handle.read_bulk_with_flags(0x81, &mut buf, Duration::from_secs(1), TransferFlags::new()).unwrap());
+        assert_eq!(2, handle.write_bulk_with_flags(0x02, &[4, 5], Duration::from_secs(1), TransferFlags::new()).unwrap());
+        assert_eq!(1, handle.write_interrupt_with_flags(0x04, &[6], Duration::from_secs(1), TransferFlags::new()).unwrap());
+        assert_eq!(vec![vec![4, 5]], device.take_out(0x02));
+        assert_eq!(vec![vec![6]], device.take_out(0x04));
+    }
+
+    #[test]
+    fn it_adds_context_to_stream_errors() {
+        let context = context_with(&[&mock_device(64)]);
+        let mut handle = open(&context);
+
+        let err = handle.alloc_streams(4, &[]).unwrap_err();
+        assert_eq!(ErrorKind::InvalidParam, err.kind());
+        assert_eq!(Some("alloc_streams"), err.operation());
+
+        let err = handle.free_streams(&[0x81]).unwrap_err();
+        assert_eq!(ErrorKind::NotSupported, err.kind());
+        assert_eq!(Some("free_streams"), err.operation());
+        assert!(err.device().is_some());
+    }
+
+    #[test]
+    fn it_returns_backend_errors_without_libusb_code() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut buf = [0u8; 8];
+
+        device.push_in_error(0x81, ErrorKind::ShortTransfer);
+        device.push_in_error(0x81, ErrorKind::UnknownEndpoint);
+        device.push_in_error(0x83, ErrorKind::ShortTransfer);
+
+        assert_eq!(ErrorKind::ShortTransfer, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(ErrorKind::UnknownEndpoint, handle.read_bulk_cancellable(0x81, &mut buf, Duration::from_secs(1), &CancellationToken::new()).unwrap_err().kind());
+        assert_eq!(ErrorKind::ShortTransfer, handle.read_interrupt_with_flags(0x83, &mut buf, Duration::from_secs(1), TransferFlags::new()).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_injects_faults_without_libusb_code() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let mut handle = open(&context);
+        let mut buf = [0u8; 8];
+
+        handle.set_fault_injector(FaultInjector::new().rule(FaultRule::fail(ErrorKind::ShortTransfer).endpoint(0x81)));
+
+        let err = handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(ErrorKind::ShortTransfer, err.kind());
+        assert_eq!(None, err.code());
+    }
+
+    #[test]
+    fn it_detaches_kernel_driver_when_claiming() {
+        let device = mock_device(64).kernel_driver(0);
+        let context = context_with(&[&device]);
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        assert_eq!(ErrorKind::Busy, handle.claim_interface(0).unwrap_err().kind());
+
+        handle.claim_interface_detached(0).unwrap();
+        assert_eq!(false, handle.kernel_driver_active(0).unwrap());
+        assert_eq!(claimed(&[0]), handle.detached);
+        assert_eq!(vec![0], device.claimed_interfaces());
+    }
+
+    #[test]
+    fn it_reattaches_kernel_driver_when_releasing() {
+        let device = mock_device(64).kernel_driver(0);
+        let context = context_with(&[&device]);
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        handle.claim_interface_detached(0).unwrap();
+        handle.release_interface(0).unwrap();
+        assert_eq!(true, handle.kernel_driver_active(0).unwrap());
+        assert!(handle.detached.is_empty());
+
+        handle.detach_kernel_driver(0).unwrap();
+        handle.claim_interface(0).unwrap();
+        handle.release_interface(0).unwrap();
+        assert_eq!(false, handle.kernel_driver_active(0).unwrap());
+    }
+
+    #[test]
+    fn it_reattaches_kernel_driver_when_dropped() {
+        let device = mock_device(64).kernel_driver(0);
+        let context = context_with(&[&device]);
+
+        {
+            let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+            handle.claim_interface_detached(0).unwrap();
+        }
+
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        assert_eq!(true, handle.kernel_driver_active(0).unwrap());
+        assert!(device.claimed_interfaces().is_empty());
+    }
+
+    #[test]
+    fn it_reattaches_kernel_driver_when_claiming_fails() {
+        let device = mock_device(64).kernel_driver(1);
+        let context = context_with(&[&device]);
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        assert_eq!(ErrorKind::NotFound, handle.claim_interface_detached(1).unwrap_err().kind());
+        assert_eq!(true, handle.kernel_driver_active(1).unwrap());
+        assert!(handle.detached.is_empty());
+    }
+
+    #[test]
+    fn it_claims_without_detaching_when_no_kernel_driver_is_attached() {
+        let device = mock_device(64);
+        let context = context_with(&[&device]);
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        handle.claim_interface_detached(0).unwrap();
+        assert!(handle.detached.is_empty());
+
+        handle.release_interface(0).unwrap();
+        assert_eq!(false, handle.kernel_driver_active(0).unwrap());
+    }
+
+    #[test]
+    fn it_claims_without_detaching_when_detaching_is_not_supported() {
+        let device = mock_device(64).detach_kernel_driver(false);
+        let context = context_with(&[&device]);
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        assert_eq!(ErrorKind::NotSupported, handle.detach_kernel_driver(0).unwrap_err().kind());
+
+        handle.claim_interface_detached(0).unwrap();
+        assert!(handle.detached.is_empty());
+        assert_eq!(vec![0], device.claimed_interfaces());
+
+        handle.release_interface(0).unwrap();
+        assert!(device.claimed_interfaces().is_empty());
     }
 }
@@ -0,0 +1,302 @@
+/// An identifier for a physical device that stays the same across device lists.
+///
+/// A device ID combines the port that the device is connected to with the vendor ID and product
+/// ID from its device descriptor and, when it's known, its serial number. Unlike the device's
+/// address, which changes every time the device is enumerated, the device ID stays the same while
+/// the device stays connected to the same port, which makes it suitable as a key in maps and sets.
+///
+/// A device ID is returned by [`Device::id()`](struct.Device.html#method.id), which doesn't open
+/// the device and leaves the serial number unknown, or by
+/// [`Device::read_id()`](struct.Device.html#method.read_id), which opens the device to read its
+/// serial number. IDs with and without a serial number don't compare equal, so IDs that are used
+/// as keys should be obtained in the same way.
+///
+/// With the `serde` feature, device IDs implement `serde::Serialize`.
+#[derive(Debug,PartialEq,Eq,Clone,Hash)]
+pub struct DeviceId {
+    bus_number: u8,
+    port_numbers: Vec<u8>,
+    vendor_id: u16,
+    product_id: u16,
+    serial_number: Option<String>,
+}
+
+impl DeviceId {
+    /// Returns the number of the bus that the device is connected to.
+    pub fn bus_number(&self) -> u8 {
+        self.bus_number
+    }
+
+    /// Returns the port numbers from the root hub to the device.
+    ///
+    /// The list is empty if the port numbers aren't known.
+    pub fn port_numbers(&self) -> &[u8] {
+        &self.port_numbers
+    }
+
+    /// Returns the device's vendor ID.
+    pub fn vendor_id(&self) -> u16 {
+        self.vendor_id
+    }
+
+    /// Returns the device's product ID.
+    pub fn product_id(&self) -> u16 {
+        self.product_id
+    }
+
+    /// Returns the device's serial number.
+    ///
+    /// Returns `None` if the serial number wasn't read or the device doesn't have one.
+    pub fn serial_number(&self) -> Option<&str> {
+        self.serial_number.as_ref().map(|serial_number| &serial_number[..])
+    }
+}
+
+#[cfg(feature = "serde")]
+impl ::serde::Serialize for DeviceId {
+    fn serialize<S: ::serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+
+        let mut state = try!(serializer.serialize_struct("DeviceId", 5));
+        try!(state.serialize_field("bus_number", &self.bus_number));
+        try!(state.serialize_field("port_numbers", &self.port_numbers));
+        try!(state.serialize_field("vendor_id", &self.vendor_id));
+        try!(state.serialize_field("product_id", &self.product_id));
+        try!(state.serialize_field("serial_number", &self.serial_number));
+        state.end()
+    }
+}
+
+#[doc(hidden)]
+pub fn new(bus_number: u8, port_numbers: Vec<u8>, vendor_id: u16, product_id: u16, serial_number: Option<String>) -> DeviceId {
+    DeviceId {
+        bus_number: bus_number,
+        port_numbers: port_numbers,
+        vendor_id: vendor_id,
+        product_id: product_id,
+        serial_number: serial_number,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use context::Context;
+    use mock::{MockBackend, MockDevice};
+
+    #[test]
+    fn it_compares_devices_across_device_lists() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice::new(0x1234, 0x0001));
+        backend.add_device(MockDevice::new(0x1234, 0x0001));
+        let context = Context::with_backend(backend);
+
+        let first = context.devices().unwrap().iter().collect::<Vec<_>>();
+        let second = context.devices().unwrap().iter().collect::<HashSet<_>>();
+
+        assert!(first[0] == first[0]);
+        assert!(first[0] != first[1]);
+        assert_eq!(2, second.len());
+        assert!(first.iter().all(|device| second.contains(device)));
+    }
+
+    #[test]
+    fn it_identifies_devices() {
+        let backend = MockBackend::new();
+        backend.add_device(MockDevice::new(0x1234, 0x0001).bus_number(3).port_number(2).serial_number("A1"));
+        let context = Context::with_backend(backend);
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+
+        let id = device.id().unwrap();
+        assert_eq!(3, id.bus_number());
+        assert_eq!(&[2], id.port_numbers());
+        assert_eq!((0x1234, 0x0001), (id.vendor_id(), id.product_id()));
+        assert_eq!(None, id.serial_number());
+        assert_eq!(id, context.devices().unwrap().iter().next().unwrap().id().unwrap());
+
+        let id = device.read_id(Duration::from_secs(1)).unwrap();
+        assert_eq!(Some("A1"), id.serial_number());
+        assert!(id != device.id().unwrap());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn it_serializes_device_ids() {
+        let id = super::new(3, vec![2, 1], 0x1234, 0x0001, Some("A1".to_owned()));
+
+        assert_eq!(vec!["struct DeviceId 5",
+                        "field bus_number", "u8 3",
+                        "field port_numbers", "seq 2", "u8 2", "u8 1", "end",
+                        "field vendor_id", "u16 4660",
+                        "field product_id", "u16 1",
+                        "field serial_number", "some", "str A1",
+                        "end"], tokens::of(&id));
+
+        let id = super::new(3, vec![], 0x1234, 0x0001, None);
+
+        assert_eq!(vec!["struct DeviceId 5",
+                        "field bus_number", "u8 3",
+                        "field port_numbers", "seq 0", "end",
+                        "field vendor_id", "u16 4660",
+                        "field product_id", "u16 1",
+                        "field serial_number", "none",
+                        "end"], tokens::of(&id));
+    }
+
+    /// A serializer that records the calls made to it as a list of tokens, so that serialization
+    /// can be tested without depending on a data format.
+    #[cfg(feature = "serde")]
+    mod tokens {
+        use std::error;
+        use std::fmt;
+
+        use serde::ser::{self, Impossible, Serialize};
+
+        pub fn of<T: Serialize>(value: &T) -> Vec<String> {
+            let mut tokens = Tokens(Vec::new());
+            value.serialize(&mut tokens).unwrap();
+            tokens.0
+        }
+
+        struct Tokens(Vec<String>);
+
+        #[derive(Debug)]
+        pub struct Error(String);
+
+        impl fmt::Display for Error {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl error::Error for Error {
+            fn description(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl ser::Error for Error {
+            fn custom<T: fmt::Display>(msg: T) -> Self {
+                Error(msg.to_string())
+            }
+        }
+
+        macro_rules! unexpected {
+            ($($name:ident($($ty:ty),*) -> $ok:ty;)*) => {
+                $(fn $name(self, $(_: $ty),*) -> Result<$ok, Error> {
+                    Err(Error(stringify!($name).to_owned()))
+                })*
+            }
+        }
+
+        impl<'a> ser::Serializer for &'a mut Tokens {
+            type Ok = ();
+            type Error = Error;
+            type SerializeSeq = Self;
+            type SerializeTuple = Impossible<(), Error>;
+            type SerializeTupleStruct = Impossible<(), Error>;
+            type SerializeTupleVariant = Impossible<(), Error>;
+            type SerializeMap = Impossible<(), Error>;
+            type SerializeStruct = Self;
+            type SerializeStructVariant = Impossible<(), Error>;
+
+            fn serialize_u8(self, v: u8) -> Result<(), Error> {
+                self.0.push(format!("u8 {}", v));
+                Ok(())
+            }
+
+            fn serialize_u16(self, v: u16) -> Result<(), Error> {
+                self.0.push(format!("u16 {}", v));
+                Ok(())
+            }
+
+            fn serialize_str(self, v: &str) -> Result<(), Error> {
+                self.0.push(format!("str {}", v));
+                Ok(())
+            }
+
+            fn serialize_none(self) -> Result<(), Error> {
+                self.0.push("none".to_owned());
+                Ok(())
+            }
+
+            fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), Error> {
+                self.0.push("some".to_owned());
+                value.serialize(self)
+            }
+
+            fn serialize_seq(self, len: Option<usize>) -> Result<Self, Error> {
+                self.0.push(format!("seq {}", len.unwrap()));
+                Ok(self)
+            }
+
+            fn serialize_struct(self, name: &'static str, len: usize) -> Result<Self, Error> {
+                self.0.push(format!("struct {} {}", name, len));
+                Ok(self)
+            }
+
+            fn serialize_newtype_struct<T: ?Sized + Serialize>(self, _: &'static str, _: &T) -> Result<(), Error> {
+                Err(Error("serialize_newtype_struct".to_owned()))
+            }
+
+            fn serialize_newtype_variant<T: ?Sized + Serialize>(self, _: &'static str, _: u32, _: &'static str, _: &T) -> Result<(), Error> {
+                Err(Error("serialize_newtype_variant".to_owned()))
+            }
+
+            unexpected! {
+                serialize_bool(bool) -> ();
+                serialize_i8(i8) -> ();
+                serialize_i16(i16) -> ();
+                serialize_i32(i32) -> ();
+                serialize_i64(i64) -> ();
+                serialize_u32(u32) -> ();
+                serialize_u64(u64) -> ();
+                serialize_f32(f32) -> ();
+                serialize_f64(f64) -> ();
+                serialize_char(char) -> ();
+                serialize_bytes(&[u8]) -> ();
+                serialize_unit() -> ();
+                serialize_unit_struct(&'static str) -> ();
+                serialize_unit_variant(&'static str, u32, &'static str) -> ();
+                serialize_tuple(usize) -> Impossible<(), Error>;
+                serialize_tuple_struct(&'static str, usize) -> Impossible<(), Error>;
+                serialize_tuple_variant(&'static str, u32, &'static str, usize) -> Impossible<(), Error>;
+                serialize_map(Option<usize>) -> Impossible<(), Error>;
+                serialize_struct_variant(&'static str, u32, &'static str, usize) -> Impossible<(), Error>;
+            }
+        }
+
+        impl<'a> ser::SerializeSeq for &'a mut Tokens {
+            type Ok = ();
+            type Error = Error;
+
+            fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), Error> {
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), Error> {
+                self.0.push("end".to_owned());
+                Ok(())
+            }
+        }
+
+        impl<'a> ser::SerializeStruct for &'a mut Tokens {
+            type Ok = ();
+            type Error = Error;
+
+            fn serialize_field<T: ?Sized + Serialize>(&mut self, key: &'static str, value: &T) -> Result<(), Error> {
+                self.0.push(format!("field {}", key));
+                value.serialize(&mut **self)
+            }
+
+            fn end(self) -> Result<(), Error> {
+                self.0.push("end".to_owned());
+                Ok(())
+            }
+        }
+    }
+}
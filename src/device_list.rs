@@ -1,31 +1,17 @@
-use std::marker::PhantomData;
-use std::slice;
-
-use libusb::*;
-
 use context::Context;
 use device::{self, Device};
+use dispatch::ListSource;
 
 /// A list of detected USB devices.
 pub struct DeviceList<'a> {
-    context: PhantomData<&'a Context>,
-    list: *const *mut libusb_device,
-    len: usize,
-}
-
-impl<'a> Drop for DeviceList<'a> {
-    /// Frees the device list.
-    fn drop(&mut self) {
-        unsafe {
-            libusb_free_device_list(self.list, 1);
-        }
-    }
+    context: &'a Context,
+    source: ListSource,
 }
 
 impl<'a> DeviceList<'a> {
     /// Returns the number of devices in the list.
     pub fn len(&self) -> usize {
-        self.len
+        self.source.len()
     }
 
     /// Returns an iterator over the devices in the list.
@@ -34,7 +20,7 @@ impl<'a> DeviceList<'a> {
     pub fn iter<'b>(&'b self) -> Devices<'a, 'b> {
         Devices {
             context: self.context,
-            devices: unsafe { slice::from_raw_parts(self.list, self.len) },
+            source: &self.source,
             index: 0,
         }
     }
@@ -42,8 +28,8 @@ impl<'a> DeviceList<'a> {
 
 /// Iterator over detected USB devices.
 pub struct Devices<'a, 'b> {
-    context: PhantomData<&'a Context>,
-    devices: &'b [*mut libusb_device],
+    context: &'a Context,
+    source: &'b ListSource,
     index: usize,
 }
 
@@ -51,29 +37,26 @@ impl<'a, 'b> Iterator for Devices<'a, 'b> {
     type Item = Device<'a>;
 
     fn next(&mut self) -> Option<Device<'a>> {
-        if self.index < self.devices.len() {
-            let device = self.devices[self.index];
+        let device = self.source.get(self.index);
 
+        if device.is_some() {
             self.index += 1;
-            Some(unsafe { device::from_libusb(self.context, device) })
-        }
-        else {
-            None
         }
+
+        device.map(|device| device::new(self.context, device))
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let remaining = self.devices.len() - self.index;
+        let remaining = self.source.len() - self.index;
         (remaining, Some(remaining))
     }
 }
 
 
 #[doc(hidden)]
-pub unsafe fn from_libusb<'a>(_context: &'a Context, list: *const *mut libusb_device, len: usize,) -> DeviceList<'a> {
+pub fn new<'a>(context: &'a Context, source: ListSource) -> DeviceList<'a> {
     DeviceList {
-        context: PhantomData,
-        list: list,
-        len: len,
+        context: context,
+        source: source,
     }
 }
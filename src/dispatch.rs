@@ -0,0 +1,506 @@
+use std::mem;
+use std::ptr;
+use std::slice;
+use std::sync::Arc;
+
+use libc::{c_int, c_uchar, c_uint};
+use libusb::*;
+
+use backend::{self, Backend, BackendDevice, BackendHandle};
+use config_descriptor::{self, ConfigDescriptor};
+use device_descriptor::{self, DeviceDescriptor};
+use error::{self, Error, ErrorKind};
+use fields::{self, Speed, TransferType};
+use trace::SetupPacket;
+
+// The types in this module dispatch each operation either to `libusb` or to a backend, so that
+// `Context`, `DeviceList`, `Device`, and `DeviceHandle` don't have to. Operations that only
+// `libusb` supports fail with `NotSupported` for backends.
+
+/// The provider of a context's devices.
+#[doc(hidden)]
+pub enum ContextSource {
+    Libusb(*mut libusb_context),
+    Backend(Box<Backend>),
+}
+
+impl Drop for ContextSource {
+    fn drop(&mut self) {
+        if let ContextSource::Libusb(context) = *self {
+            unsafe {
+                libusb_exit(context);
+            }
+        }
+    }
+}
+
+impl ContextSource {
+    /// Returns the `libusb` context, or a null pointer for a backend.
+    pub fn as_raw(&self) -> *mut libusb_context {
+        match *self {
+            ContextSource::Libusb(context) => context,
+            ContextSource::Backend(_) => ptr::null_mut(),
+        }
+    }
+
+    pub fn is_libusb(&self) -> bool {
+        match *self {
+            ContextSource::Libusb(_) => true,
+            ContextSource::Backend(_) => false,
+        }
+    }
+
+    pub fn set_log_level(&self, level: c_int) {
+        if let ContextSource::Libusb(context) = *self {
+            unsafe {
+                libusb_set_debug(context, level);
+            }
+        }
+    }
+
+    pub fn devices(&self) -> ::Result<ListSource> {
+        match *self {
+            ContextSource::Libusb(context) => {
+                let mut list: *const *mut libusb_device = unsafe { mem::uninitialized() };
+
+                let n = unsafe { libusb_get_device_list(context, &mut list) };
+
+                if n < 0 {
+                    Err(error::from_libusb(n as c_int))
+                }
+                else {
+                    Ok(ListSource::Libusb(list, n as usize))
+                }
+            },
+            ContextSource::Backend(ref backend) => Ok(ListSource::Backend(try!(backend.devices()))),
+        }
+    }
+}
+
+
+/// The devices of a device list.
+#[doc(hidden)]
+pub enum ListSource {
+    Libusb(*const *mut libusb_device, usize),
+    Backend(Vec<Arc<BackendDevice>>),
+}
+
+impl Drop for ListSource {
+    fn drop(&mut self) {
+        if let ListSource::Libusb(list, _) = *self {
+            unsafe {
+                libusb_free_device_list(list, 1);
+            }
+        }
+    }
+}
+
+impl ListSource {
+    pub fn len(&self) -> usize {
+        match *self {
+            ListSource::Libusb(_, len) => len,
+            ListSource::Backend(ref devices) => devices.len(),
+        }
+    }
+
+    /// Returns a new reference to the device at `index`.
+    pub fn get(&self, index: usize) -> Option<DeviceSource> {
+        if index >= self.len() {
+            return None;
+        }
+
+        match *self {
+            ListSource::Libusb(list, _) => Some(unsafe { DeviceSource::from_libusb(*list.offset(index as isize)) }),
+            ListSource::Backend(ref devices) => Some(DeviceSource::Backend(devices[index].clone())),
+        }
+    }
+}
+
+
+/// A reference to a device.
+#[doc(hidden)]
+pub enum DeviceSource {
+    Libusb(*mut libusb_device),
+    Backend(Arc<BackendDevice>),
+}
+
+impl Drop for DeviceSource {
+    fn drop(&mut self) {
+        if let DeviceSource::Libusb(device) = *self {
+            unsafe {
+                libusb_unref_device(device);
+            }
+        }
+    }
+}
+
+impl DeviceSource {
+    /// Takes a new reference to a `libusb` device.
+    pub unsafe fn from_libusb(device: *mut libusb_device) -> DeviceSource {
+        libusb_ref_device(device);
+
+        DeviceSource::Libusb(device)
+    }
+
+    /// Returns another reference to the same device.
+    pub fn duplicate(&self) -> DeviceSource {
+        match *self {
+            DeviceSource::Libusb(device) => unsafe { DeviceSource::from_libusb(device) },
+            DeviceSource::Backend(ref device) => DeviceSource::Backend(device.clone()),
+        }
+    }
+
+    /// Returns a value that is the same for all references to the same device.
+    pub fn identity(&self) -> usize {
+        match *self {
+            DeviceSource::Libusb(device) => device as usize,
+            DeviceSource::Backend(ref device) => &**device as *const BackendDevice as *const u8 as usize,
+        }
+    }
+
+    pub fn device_descriptor(&self) -> ::Result<DeviceDescriptor> {
+        match *self {
+            DeviceSource::Libusb(device) => {
+                let mut descriptor: libusb_device_descriptor = unsafe { mem::uninitialized() };
+
+                // since libusb 1.0.16, this function always succeeds
+                try_unsafe!(libusb_get_device_descriptor(device, &mut descriptor));
+
+                Ok(device_descriptor::from_libusb(descriptor))
+            },
+            DeviceSource::Backend(ref device) => device_descriptor::from_bytes(&try!(device.device_descriptor())),
+        }
+    }
+
+    pub fn config_descriptor(&self, config_index: u8) -> ::Result<ConfigDescriptor> {
+        match *self {
+            DeviceSource::Libusb(device) => {
+                let mut config: *const libusb_config_descriptor = unsafe { mem::uninitialized() };
+
+                try_unsafe!(libusb_get_config_descriptor(device, config_index, &mut config));
+
+                Ok(unsafe { config_descriptor::from_libusb(config) })
+            },
+            DeviceSource::Backend(ref device) => config_descriptor::from_bytes(&try!(device.config_descriptor(config_index))),
+        }
+    }
+
+    pub fn active_config_descriptor(&self) -> ::Result<ConfigDescriptor> {
+        match *self {
+            DeviceSource::Libusb(device) => active_config_descriptor(device),
+            DeviceSource::Backend(ref device) => config_descriptor::from_bytes(&try!(device.active_config_descriptor())),
+        }
+    }
+
+    pub fn bus_number(&self) -> u8 {
+        match *self {
+            DeviceSource::Libusb(device) => unsafe { libusb_get_bus_number(device) },
+            DeviceSource::Backend(ref device) => device.bus_number(),
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        match *self {
+            DeviceSource::Libusb(device) => unsafe { libusb_get_device_address(device) },
+            DeviceSource::Backend(ref device) => device.address(),
+        }
+    }
+
+    pub fn port_number(&self) -> u8 {
+        match *self {
+            DeviceSource::Libusb(device) => unsafe { libusb_get_port_number(device) },
+            DeviceSource::Backend(ref device) => device.port_number(),
+        }
+    }
+
+    pub fn port_numbers(&self) -> ::Result<Vec<u8>> {
+        match *self {
+            DeviceSource::Libusb(device) => {
+                // the USB specification limits the depth of hubs to 7
+                let mut port_numbers = vec![0; 7];
+
+                let n = unsafe { libusb_get_port_numbers(device, port_numbers.as_mut_ptr(), port_numbers.len() as c_int) };
+
+                if n < 0 {
+                    return Err(error::from_libusb(n));
+                }
+
+                port_numbers.truncate(n as usize);
+                Ok(port_numbers)
+            },
+            DeviceSource::Backend(ref device) => Ok(device.port_numbers()),
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        match *self {
+            DeviceSource::Libusb(device) => fields::speed_from_libusb(unsafe { libusb_get_device_speed(device) }),
+            DeviceSource::Backend(ref device) => device.speed(),
+        }
+    }
+
+    pub fn open(&self) -> ::Result<HandleSource> {
+        match *self {
+            DeviceSource::Libusb(device) => {
+                let mut handle: *mut libusb_device_handle = unsafe { mem::uninitialized() };
+
+                try_unsafe!(libusb_open(device, &mut handle));
+
+                Ok(HandleSource::Libusb(handle))
+            },
+            DeviceSource::Backend(ref device) => Ok(HandleSource::Backend(device.clone(), try!(device.open()))),
+        }
+    }
+}
+
+
+/// An open device.
+#[doc(hidden)]
+pub enum HandleSource {
+    Libusb(*mut libusb_device_handle),
+    Backend(Arc<BackendDevice>, Box<BackendHandle>),
+}
+
+impl Drop for HandleSource {
+    fn drop(&mut self) {
+        if let HandleSource::Libusb(handle) = *self {
+            unsafe {
+                libusb_close(handle);
+            }
+        }
+    }
+}
+
+impl HandleSource {
+    /// Returns the `libusb` device handle, or a null pointer for a backend.
+    pub fn as_raw(&self) -> *mut libusb_device_handle {
+        match *self {
+            HandleSource::Libusb(handle) => handle,
+            HandleSource::Backend(..) => ptr::null_mut(),
+        }
+    }
+
+    /// Returns `NotSupported` if the device is provided by a backend instead of `libusb`.
+    pub fn require_libusb(&self) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(_) => Ok(()),
+            HandleSource::Backend(..) => Err(Error::new(ErrorKind::NotSupported)),
+        }
+    }
+
+    pub fn active_configuration(&self) -> ::Result<u8> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                let mut config = unsafe { mem::uninitialized() };
+
+                try_unsafe!(libusb_get_configuration(handle, &mut config));
+                Ok(config as u8)
+            },
+            HandleSource::Backend(_, ref handle) => handle.active_configuration(),
+        }
+    }
+
+    /// Sets the active configuration, or puts the device in an unconfigured state for `None`.
+    pub fn set_active_configuration(&self, config: Option<u8>) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_set_configuration(handle, config.map_or(-1, |config| config as c_int)));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.set_active_configuration(config),
+        }
+    }
+
+    pub fn reset(&self) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_reset_device(handle));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.reset(),
+        }
+    }
+
+    /// Indicates whether a kernel driver is attached.
+    pub fn kernel_driver_active(&self, iface: u8) -> ::Result<bool> {
+        match *self {
+            HandleSource::Libusb(handle) => match unsafe { libusb_kernel_driver_active(handle, iface as c_int) } {
+                0 => Ok(false),
+                1 => Ok(true),
+                err => Err(error::from_libusb(err)),
+            },
+            HandleSource::Backend(_, ref handle) => handle.kernel_driver_active(iface),
+        }
+    }
+
+    pub fn supports_detach_kernel_driver(&self) -> bool {
+        match *self {
+            HandleSource::Libusb(_) => unsafe { libusb_has_capability(LIBUSB_CAP_SUPPORTS_DETACH_KERNEL_DRIVER) != 0 },
+            HandleSource::Backend(_, ref handle) => handle.supports_detach_kernel_driver(),
+        }
+    }
+
+    pub fn detach_kernel_driver(&self, iface: u8) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_detach_kernel_driver(handle, iface as c_int));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.detach_kernel_driver(iface),
+        }
+    }
+
+    pub fn attach_kernel_driver(&self, iface: u8) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_attach_kernel_driver(handle, iface as c_int));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.attach_kernel_driver(iface),
+        }
+    }
+
+    pub fn set_auto_detach_kernel_driver(&self, auto_detach: bool) -> ::Result<()> {
+        try_unsafe!(libusb_set_auto_detach_kernel_driver(try!(self.libusb()), auto_detach as c_int));
+        Ok(())
+    }
+
+    pub fn claim_interface(&self, iface: u8) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_claim_interface(handle, iface as c_int));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.claim_interface(iface),
+        }
+    }
+
+    pub fn release_interface(&self, iface: u8) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_release_interface(handle, iface as c_int));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.release_interface(iface),
+        }
+    }
+
+    pub fn set_alternate_setting(&self, iface: u8, setting: u8) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_set_interface_alt_setting(handle, iface as c_int, setting as c_int));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.set_alternate_setting(iface, setting),
+        }
+    }
+
+    pub fn clear_halt(&self, endpoint: u8) -> ::Result<()> {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                try_unsafe!(libusb_clear_halt(handle, endpoint));
+                Ok(())
+            },
+            HandleSource::Backend(_, ref handle) => handle.clear_halt(endpoint),
+        }
+    }
+
+    pub fn active_config_descriptor(&self) -> ::Result<ConfigDescriptor> {
+        match *self {
+            HandleSource::Libusb(handle) => active_config_descriptor(unsafe { libusb_get_device(handle) }),
+            HandleSource::Backend(ref device, _) => config_descriptor::from_bytes(&try!(device.active_config_descriptor())),
+        }
+    }
+
+    /// Returns the device's bus number and address.
+    pub fn location(&self) -> (u8, u8) {
+        match *self {
+            HandleSource::Libusb(handle) => unsafe {
+                let device = libusb_get_device(handle);
+                (libusb_get_bus_number(device), libusb_get_device_address(device))
+            },
+            HandleSource::Backend(ref device, _) => (device.bus_number(), device.address()),
+        }
+    }
+
+    pub fn speed(&self) -> Speed {
+        match *self {
+            HandleSource::Libusb(handle) => fields::speed_from_libusb(unsafe { libusb_get_device_speed(libusb_get_device(handle)) }),
+            HandleSource::Backend(ref device, _) => device.speed(),
+        }
+    }
+
+    /// Performs a control transfer of `len` bytes at `ptr` in the direction given by
+    /// `request_type`.
+    ///
+    /// Returns the number of bytes transferred. Errors of a backend are returned as the backend
+    /// reported them.
+    pub unsafe fn control_transfer(&self, request_type: u8, request: u8, value: u16, index: u16, ptr: *mut c_uchar, len: usize, timeout_ms: c_uint) -> ::Result<usize> {
+        match *self {
+            HandleSource::Libusb(handle) => match libusb_control_transfer(handle, request_type, request, value, index, ptr, len as u16, timeout_ms) {
+                n if n >= 0 => Ok(n as usize),
+                err => Err(error::from_libusb(err)),
+            },
+            HandleSource::Backend(_, ref handle) => {
+                let setup = SetupPacket::new(request_type, request, value, index, len as u16);
+                let timeout = backend::timeout_from_libusb(timeout_ms);
+
+                match request_type & LIBUSB_ENDPOINT_DIR_MASK {
+                    LIBUSB_ENDPOINT_IN => handle.read_control(&setup, slice::from_raw_parts_mut(ptr, len), timeout),
+                    _ => handle.write_control(&setup, slice::from_raw_parts(ptr, len), timeout),
+                }
+            },
+        }
+    }
+
+    /// Performs a synchronous bulk or interrupt transfer of `len` bytes at `ptr`.
+    ///
+    /// Returns the status of the transfer and the number of bytes transferred, which may be
+    /// non-zero even if the transfer failed. Errors of a backend are returned as the backend
+    /// reported them.
+    pub unsafe fn transfer(&self, transfer_type: TransferType, endpoint: u8, ptr: *mut c_uchar, len: usize, timeout_ms: c_uint) -> (::Result<()>, usize) {
+        match *self {
+            HandleSource::Libusb(handle) => {
+                let mut transferred: c_int = 0;
+
+                let res = match transfer_type {
+                    TransferType::Interrupt => libusb_interrupt_transfer(handle, endpoint, ptr, len as c_int, &mut transferred, timeout_ms),
+                    _ => libusb_bulk_transfer(handle, endpoint, ptr, len as c_int, &mut transferred, timeout_ms),
+                };
+
+                match res {
+                    0 => (Ok(()), transferred as usize),
+                    err => (Err(error::from_libusb(err)), transferred as usize),
+                }
+            },
+            HandleSource::Backend(_, ref handle) => {
+                let timeout = backend::timeout_from_libusb(timeout_ms);
+
+                let res = match endpoint & LIBUSB_ENDPOINT_DIR_MASK {
+                    LIBUSB_ENDPOINT_IN => handle.read(transfer_type, endpoint, slice::from_raw_parts_mut(ptr, len), timeout),
+                    _ => handle.write(transfer_type, endpoint, slice::from_raw_parts(ptr, len), timeout),
+                };
+
+                match res {
+                    Ok(transferred) => (Ok(()), transferred),
+                    Err(err) => (Err(err), 0),
+                }
+            },
+        }
+    }
+
+    fn libusb(&self) -> ::Result<*mut libusb_device_handle> {
+        try!(self.require_libusb());
+        Ok(self.as_raw())
+    }
+}
+
+
+fn active_config_descriptor(device: *mut libusb_device) -> ::Result<ConfigDescriptor> {
+    let mut config: *const libusb_config_descriptor = ptr::null();
+
+    try_unsafe!(libusb_get_active_config_descriptor(device, &mut config));
+
+    Ok(unsafe { config_descriptor::from_libusb(config) })
+}
+
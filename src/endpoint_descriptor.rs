@@ -1,4 +1,6 @@
 use std::fmt;
+use std::cmp;
+use std::slice;
 
 use libusb::*;
 
@@ -71,6 +73,49 @@ impl<'a> EndpointDescriptor<'a> {
     pub fn interval(&self) -> u8 {
         self.descriptor.bInterval
     }
+
+    /// Returns the maximum number of streams supported by a SuperSpeed bulk endpoint.
+    ///
+    /// The value is read from the endpoint's SuperSpeed endpoint companion descriptor. Returns
+    /// `None` if the endpoint doesn't have a companion descriptor, which is the case for devices
+    /// operating below SuperSpeed. Returns `Some(0)` if the endpoint doesn't support streams.
+    ///
+    /// The return value of this method is only valid for bulk endpoints.
+    pub fn max_streams(&self) -> Option<u32> {
+        if self.descriptor.extra.is_null() || self.descriptor.extra_length <= 0 {
+            return None;
+        }
+
+        let extra = unsafe {
+            slice::from_raw_parts(self.descriptor.extra, self.descriptor.extra_length as usize)
+        };
+
+        companion_max_streams(extra)
+    }
+}
+
+/// Finds the SuperSpeed endpoint companion descriptor in an endpoint's extra descriptors and
+/// returns the number of streams that it advertises.
+fn companion_max_streams(mut extra: &[u8]) -> Option<u32> {
+    while extra.len() >= 2 {
+        let length = extra[0] as usize;
+
+        if length < 2 || length > extra.len() {
+            return None;
+        }
+
+        if extra[1] == LIBUSB_DT_SS_ENDPOINT_COMPANION && length >= 6 {
+            // bits 4:0 of bmAttributes are the base-2 logarithm of the number of streams
+            return match extra[3] & 0x1F {
+                0 => Some(0),
+                exponent => Some(1 << cmp::min(exponent, 16)),
+            };
+        }
+
+        extra = &extra[length..];
+    }
+
+    None
 }
 
 impl<'a> fmt::Debug for EndpointDescriptor<'a> {
@@ -162,4 +207,30 @@ mod test {
         assert_eq!(20,  super::from_libusb(&endpoint_descriptor!(bInterval: 20)).interval());
         assert_eq!(255, super::from_libusb(&endpoint_descriptor!(bInterval: 255)).interval());
     }
+
+    #[test]
+    fn it_has_no_max_streams_without_companion_descriptor() {
+        assert_eq!(None, super::from_libusb(&endpoint_descriptor!(bmAttributes: 0x02)).max_streams());
+        assert_eq!(None, super::companion_max_streams(&[0x07, 0x25, 0x01, 0x00, 0x00, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_interprets_max_streams_in_companion_descriptor() {
+        let extra = [0x06, 0x30, 0x0F, 0x04, 0x00, 0x00];
+
+        assert_eq!(Some(16), super::from_libusb(&endpoint_descriptor!(extra: extra.as_ptr(), extra_length: 6)).max_streams());
+        assert_eq!(Some(0), super::companion_max_streams(&[0x06, 0x30, 0x0F, 0x00, 0x00, 0x00]));
+        assert_eq!(Some(65536), super::companion_max_streams(&[0x06, 0x30, 0x0F, 0x1F, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_skips_other_extra_descriptors_before_companion_descriptor() {
+        assert_eq!(Some(2), super::companion_max_streams(&[0x03, 0x25, 0x01, 0x06, 0x30, 0x00, 0x01, 0x00, 0x00]));
+    }
+
+    #[test]
+    fn it_ignores_truncated_extra_descriptors() {
+        assert_eq!(None, super::companion_max_streams(&[0x06, 0x30, 0x0F, 0x04]));
+        assert_eq!(None, super::companion_max_streams(&[0x00, 0x30]));
+    }
 }
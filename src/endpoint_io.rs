@@ -0,0 +1,399 @@
+use std::cmp;
+use std::io::{self, Read, Write};
+
+use libc::c_uchar;
+use libusb::*;
+
+use device_handle::{self, DeviceHandle};
+use fields::TransferType;
+use timeout::Timeout;
+
+/// A reader for a bulk or interrupt IN endpoint.
+///
+/// `EndpointReader` implements `std::io::Read`, so that an endpoint can be used with `BufReader`,
+/// `io::copy()`, and other code written against the standard I/O traits. Each read performs at
+/// most one transfer, which blocks up to the reader's timeout.
+///
+/// Transfers are sized to a multiple of the endpoint's maximum packet size, so that the device can
+/// never send more data than fits in the buffer. Reads into a buffer smaller than a packet are
+/// served from an internal buffer.
+///
+/// A zero-length packet from the device results in a read of zero bytes.
+pub struct EndpointReader<'h, 'a: 'h> {
+    handle: &'h DeviceHandle<'a>,
+    endpoint: u8,
+    transfer_type: TransferType,
+    max_packet_size: usize,
+    timeout: Timeout,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'h, 'a> EndpointReader<'h, 'a> {
+    /// Returns the address of the endpoint.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Returns the endpoint's maximum packet size.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Returns the timeout for each read.
+    pub fn timeout(&self) -> Timeout {
+        self.timeout
+    }
+
+    /// Sets the timeout for each read.
+    pub fn set_timeout<T: Into<Timeout>>(&mut self, timeout: T) {
+        self.timeout = timeout.into();
+    }
+}
+
+impl<'h, 'a> Read for EndpointReader<'h, 'a> {
+    /// Reads from the endpoint.
+    ///
+    /// If `buf` holds at least one packet and no data is left over from an earlier read, the
+    /// transfer reads directly into `buf`, with its length rounded down to a multiple of the
+    /// endpoint's maximum packet size. Otherwise, one packet is read into the reader's internal
+    /// buffer and as much of it as fits is copied to `buf`. The rest of the packet is returned by
+    /// the following reads before another transfer is made.
+    ///
+    /// If the transfer fails after some data was received, the data is returned and the error is
+    /// discarded. If the transfer times out before any data is received, an error of kind
+    /// `io::ErrorKind::TimedOut` is returned.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() && buf.len() >= self.max_packet_size {
+            let len = buf.len() - buf.len() % self.max_packet_size;
+
+            return transfer(self.handle, "endpoint_reader", self.endpoint, self.transfer_type, self.timeout, buf[..len].as_mut_ptr(), len);
+        }
+
+        if self.pos == self.buf.len() {
+            self.buf.resize(self.max_packet_size, 0);
+            self.pos = 0;
+
+            let ptr = self.buf.as_mut_ptr();
+
+            match transfer(self.handle, "endpoint_reader", self.endpoint, self.transfer_type, self.timeout, ptr, self.max_packet_size) {
+                Ok(n) => self.buf.truncate(n),
+                Err(err) => {
+                    self.buf.clear();
+                    return Err(err);
+                },
+            }
+        }
+
+        let n = cmp::min(buf.len(), self.buf.len() - self.pos);
+
+        buf[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+
+        Ok(n)
+    }
+}
+
+
+/// A writer for a bulk or interrupt OUT endpoint.
+///
+/// `EndpointWriter` implements `std::io::Write`, so that an endpoint can be used with
+/// `BufWriter`, `io::copy()`, and other code written against the standard I/O traits. Each write
+/// performs one transfer, which blocks up to the writer's timeout. If the transfer times out after
+/// part of the buffer was sent, the write returns the number of bytes that were sent.
+///
+/// Some devices expect a transfer that ends on a packet boundary to be terminated by a zero-length
+/// packet. The writer can be configured to send a zero-length packet when it's flushed. See
+/// [`set_zero_length_packet()`](#method.set_zero_length_packet).
+pub struct EndpointWriter<'h, 'a: 'h> {
+    handle: &'h DeviceHandle<'a>,
+    endpoint: u8,
+    transfer_type: TransferType,
+    max_packet_size: usize,
+    timeout: Timeout,
+    zero_length_packet: bool,
+    packet_boundary: bool,
+}
+
+impl<'h, 'a> EndpointWriter<'h, 'a> {
+    /// Returns the address of the endpoint.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Returns the endpoint's maximum packet size.
+    pub fn max_packet_size(&self) -> usize {
+        self.max_packet_size
+    }
+
+    /// Returns the timeout for each write.
+    pub fn timeout(&self) -> Timeout {
+        self.timeout
+    }
+
+    /// Sets the timeout for each write.
+    pub fn set_timeout<T: Into<Timeout>>(&mut self, timeout: T) {
+        self.timeout = timeout.into();
+    }
+
+    /// Sets whether a zero-length packet is sent when the writer is flushed.
+    ///
+    /// When enabled, flushing the writer sends a zero-length packet if the last write ended on a
+    /// packet boundary, i.e., the number of bytes written was a multiple of the endpoint's maximum
+    /// packet size. This is disabled by default.
+    pub fn set_zero_length_packet(&mut self, zero_length_packet: bool) {
+        self.zero_length_packet = zero_length_packet;
+    }
+}
+
+impl<'h, 'a> Write for EndpointWriter<'h, 'a> {
+    /// Writes to the endpoint.
+    ///
+    /// If the transfer times out before any data is sent, an error of kind
+    /// `io::ErrorKind::TimedOut` is returned.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = try!(transfer(self.handle, "endpoint_writer", self.endpoint, self.transfer_type, self.timeout, buf.as_ptr() as *mut c_uchar, buf.len()));
+
+        if n > 0 {
+            self.packet_boundary = n % self.max_packet_size == 0;
+        }
+
+        Ok(n)
+    }
+
+    /// Flushes the writer.
+    ///
+    /// Sends a zero-length packet if the writer is configured to do so and the last write ended on
+    /// a packet boundary.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.zero_length_packet && self.packet_boundary {
+            let mut empty = [0u8; 0];
+
+            try!(transfer(self.handle, "endpoint_writer", self.endpoint, self.transfer_type, self.timeout, empty.as_mut_ptr(), 0));
+            self.packet_boundary = false;
+        }
+
+        Ok(())
+    }
+}
+
+
+fn transfer(handle: &DeviceHandle, operation: &'static str, endpoint: u8, transfer_type: TransferType, timeout: Timeout, ptr: *mut c_uchar, len: usize) -> io::Result<usize> {
+    match unsafe { device_handle::sync_transfer(handle, transfer_type, endpoint, ptr, len, timeout) } {
+        (Ok(()), transferred) => Ok(transferred),
+        (_, transferred) if transferred > 0 => Ok(transferred),
+        (Err(err), _) => Err(io::Error::from(device_handle::endpoint_error(handle, err, operation, endpoint))),
+    }
+}
+
+#[doc(hidden)]
+pub fn packet_size(max_packet_size: u16) -> usize {
+    // bits 11 and 12 of wMaxPacketSize encode additional transactions per microframe
+    cmp::max(max_packet_size as usize & 0x07FF, 1)
+}
+
+
+#[doc(hidden)]
+pub fn reader<'h, 'a>(handle: &'h DeviceHandle<'a>, endpoint: u8, transfer_type: TransferType, max_packet_size: u16, timeout: Timeout) -> EndpointReader<'h, 'a> {
+    debug_assert!(endpoint & LIBUSB_ENDPOINT_DIR_MASK == LIBUSB_ENDPOINT_IN);
+
+    EndpointReader {
+        handle: handle,
+        endpoint: endpoint,
+        transfer_type: transfer_type,
+        max_packet_size: packet_size(max_packet_size),
+        timeout: timeout,
+        buf: Vec::new(),
+        pos: 0,
+    }
+}
+
+#[doc(hidden)]
+pub fn writer<'h, 'a>(handle: &'h DeviceHandle<'a>, endpoint: u8, transfer_type: TransferType, max_packet_size: u16, timeout: Timeout) -> EndpointWriter<'h, 'a> {
+    debug_assert!(endpoint & LIBUSB_ENDPOINT_DIR_MASK == LIBUSB_ENDPOINT_OUT);
+
+    EndpointWriter {
+        handle: handle,
+        endpoint: endpoint,
+        transfer_type: transfer_type,
+        max_packet_size: packet_size(max_packet_size),
+        timeout: timeout,
+        zero_length_packet: false,
+        packet_boundary: false,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+    use std::time::{Duration, Instant};
+
+    use error::{Error, ErrorKind};
+    use test_helpers::{context_with, mock_device, open};
+
+    use super::packet_size;
+
+    #[test]
+    fn it_uses_max_packet_size_as_packet_size() {
+        assert_eq!(64, packet_size(64));
+        assert_eq!(512, packet_size(512));
+    }
+
+    #[test]
+    fn it_ignores_additional_transactions_in_packet_size() {
+        assert_eq!(1024, packet_size(0x1400));
+    }
+
+    #[test]
+    fn it_handles_zero_max_packet_size() {
+        assert_eq!(1, packet_size(0));
+    }
+
+    #[test]
+    fn it_reads_from_endpoint() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut reader = handle.endpoint_reader(0x81, Duration::from_secs(1)).unwrap();
+        let mut buf = [0u8; 16];
+
+        device.push_in(0x81, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        device.push_in(0x81, vec![9, 10]);
+
+        assert_eq!(8, reader.read(&mut buf).unwrap());
+        assert_eq!(&[1, 2, 3, 4, 5, 6, 7, 8], &buf[..8]);
+        assert_eq!(2, reader.read(&mut buf).unwrap());
+        assert_eq!(&[9, 10], &buf[..2]);
+    }
+
+    #[test]
+    fn it_buffers_packets_for_small_reads() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut reader = handle.endpoint_reader(0x83, Duration::from_secs(1)).unwrap();
+        let mut buf = [0u8; 3];
+
+        device.push_in(0x83, vec![1, 2, 3, 4, 5]);
+
+        assert_eq!(3, reader.read(&mut buf).unwrap());
+        assert_eq!([1, 2, 3], buf);
+        assert_eq!(2, reader.read(&mut buf).unwrap());
+        assert_eq!(&[4, 5], &buf[..2]);
+    }
+
+    #[test]
+    fn it_reads_zero_length_packet_as_zero_bytes() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut reader = handle.endpoint_reader(0x81, Duration::from_secs(1)).unwrap();
+        let mut buf = [0u8; 8];
+
+        device.push_in(0x81, vec![]);
+
+        assert_eq!(0, reader.read(&mut buf).unwrap());
+    }
+
+    #[test]
+    fn it_reports_read_timeout_as_io_error() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut reader = handle.endpoint_reader(0x81, Instant::now()).unwrap();
+        let mut buf = [0u8; 8];
+
+        assert_eq!(io::ErrorKind::TimedOut, reader.read(&mut buf).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_adds_context_to_transfer_errors() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut reader = handle.endpoint_reader(0x81, Duration::from_secs(1)).unwrap();
+        let mut buf = [0u8; 8];
+
+        device.push_in_error(0x81, ErrorKind::Pipe);
+
+        let err = reader.read(&mut buf).unwrap_err();
+        let err = err.get_ref().unwrap().downcast_ref::<Error>().unwrap();
+        assert_eq!(ErrorKind::Pipe, err.kind());
+        assert_eq!(Some("endpoint_reader"), err.operation());
+        assert_eq!(Some(0x81), err.endpoint());
+    }
+
+    #[test]
+    fn it_writes_to_endpoint() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut writer = handle.endpoint_writer(0x02, Duration::from_secs(1)).unwrap();
+
+        writer.write_all(&[1, 2, 3]).unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(vec![vec![1, 2, 3]], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_sends_zero_length_packet_on_flush_after_full_packet() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut writer = handle.endpoint_writer(0x02, Duration::from_secs(1)).unwrap();
+
+        writer.set_zero_length_packet(true);
+        writer.write_all(&[1; 16]).unwrap();
+        writer.flush().unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(vec![vec![1; 16], vec![]], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_doesnt_send_zero_length_packet_after_short_packet() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut writer = handle.endpoint_writer(0x02, Duration::from_secs(1)).unwrap();
+
+        writer.set_zero_length_packet(true);
+        writer.write_all(&[1; 10]).unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(vec![vec![1; 10]], device.take_out(0x02));
+    }
+
+    #[test]
+    fn it_round_trips_through_io_copy() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+        let mut writer = handle.endpoint_writer(0x02, Duration::from_secs(1)).unwrap();
+
+        device.push_in(0x81, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        device.push_in(0x81, vec![9, 10]);
+        device.push_in(0x81, vec![]);
+
+        let mut reader = handle.endpoint_reader(0x81, Duration::from_secs(1)).unwrap();
+
+        assert_eq!(10, io::copy(&mut reader, &mut writer).unwrap());
+        assert_eq!(vec![1, 2, 3, 4, 5, 6, 7, 8, 9, 10], device.take_out(0x02).concat());
+    }
+
+    #[test]
+    fn it_rejects_endpoints_in_wrong_direction() {
+        let device = mock_device(8);
+        let context = context_with(&[&device]);
+        let handle = open(&context);
+
+        assert_eq!(ErrorKind::InvalidParam, handle.endpoint_reader(0x02, Duration::from_secs(1)).err().unwrap().kind());
+        assert_eq!(ErrorKind::InvalidParam, handle.endpoint_writer(0x81, Duration::from_secs(1)).err().unwrap().kind());
+    }
+}
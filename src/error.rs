@@ -1,17 +1,20 @@
+use std::ffi::{CStr, CString};
 use std::fmt;
+use std::io;
+use std::str;
 use std::error::Error as StdError;
 use std::result::Result as StdResult;
 
-use libc::c_int;
+use libc::{c_char, c_int};
 use libusb::*;
 
 /// A result of a function that may return a `Error`.
 pub type Result<T> = StdResult<T, Error>;
 
 
-/// Errors returned by the `libusb` library.
-#[derive(Debug)]
-pub enum Error {
+/// Kinds of errors returned by the `libusb` library.
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum ErrorKind {
     /// Success (no error).
     Success,
 
@@ -51,35 +54,317 @@ pub enum Error {
     /// Operation not supported or unimplemented on this platform.
     NotSupported,
 
+    /// The endpoint does not exist in the active configuration.
+    UnknownEndpoint,
+
+    /// The endpoint does not support the requested transfer type.
+    WrongTransferType,
+
+    /// The interface that the endpoint belongs to has not been claimed.
+    InterfaceNotClaimed,
+
+    /// The device sent less data than requested by a transfer that doesn't allow short
+    /// transfers.
+    ShortTransfer,
+
+    /// More than one device matches when exactly one was expected.
+    Ambiguous,
+
     /// Other error.
     Other
 }
 
-impl Error {
+impl ErrorKind {
     /// Returns a description of an error suitable for display to an end user.
     pub fn strerror(&self) -> &'static str {
         match *self {
-            Error::Success      => "Success",
-            Error::Io           => "Input/Output Error",
-            Error::InvalidParam => "Invalid parameter",
-            Error::Access       => "Access denied (insufficient permissions)",
-            Error::NoDevice     => "No such device (it may have been disconnected)",
-            Error::NotFound     => "Entity not found",
-            Error::Busy         => "Resource busy",
-            Error::Timeout      => "Operation timed out",
-            Error::Overflow     => "Overflow",
-            Error::Pipe         => "Pipe error",
-            Error::Interrupted  => "System call interrupted (perhaps due to signal)",
-            Error::NoMem        => "Insufficient memory",
-            Error::NotSupported => "Operation not supported or unimplemented on this platform",
-            Error::Other        => "Other error",
+            ErrorKind::Success             => "Success",
+            ErrorKind::Io                  => "Input/Output Error",
+            ErrorKind::InvalidParam        => "Invalid parameter",
+            ErrorKind::Access              => "Access denied (insufficient permissions)",
+            ErrorKind::NoDevice            => "No such device (it may have been disconnected)",
+            ErrorKind::NotFound            => "Entity not found",
+            ErrorKind::Busy                => "Resource busy",
+            ErrorKind::Timeout             => "Operation timed out",
+            ErrorKind::Overflow            => "Overflow",
+            ErrorKind::Pipe                => "Pipe error",
+            ErrorKind::Interrupted         => "System call interrupted (perhaps due to signal)",
+            ErrorKind::NoMem               => "Insufficient memory",
+            ErrorKind::NotSupported        => "Operation not supported or unimplemented on this platform",
+            ErrorKind::UnknownEndpoint     => "Endpoint not found in the active configuration",
+            ErrorKind::WrongTransferType   => "Endpoint does not support the transfer type",
+            ErrorKind::InterfaceNotClaimed => "Interface has not been claimed",
+            ErrorKind::ShortTransfer       => "Transfer ended with a short packet",
+            ErrorKind::Ambiguous           => "More than one device matches",
+            ErrorKind::Other               => "Other error",
+        }
+    }
+
+    /// Returns the `libusb` error code that corresponds to this kind of error.
+    ///
+    /// Returns `None` for kinds of errors that are detected by this crate and have no equivalent
+    /// `libusb` error code.
+    pub fn code(&self) -> Option<i32> {
+        match *self {
+            ErrorKind::Success             => Some(LIBUSB_SUCCESS),
+            ErrorKind::Io                  => Some(LIBUSB_ERROR_IO),
+            ErrorKind::InvalidParam        => Some(LIBUSB_ERROR_INVALID_PARAM),
+            ErrorKind::Access              => Some(LIBUSB_ERROR_ACCESS),
+            ErrorKind::NoDevice            => Some(LIBUSB_ERROR_NO_DEVICE),
+            ErrorKind::NotFound            => Some(LIBUSB_ERROR_NOT_FOUND),
+            ErrorKind::Busy                => Some(LIBUSB_ERROR_BUSY),
+            ErrorKind::Timeout             => Some(LIBUSB_ERROR_TIMEOUT),
+            ErrorKind::Overflow            => Some(LIBUSB_ERROR_OVERFLOW),
+            ErrorKind::Pipe                => Some(LIBUSB_ERROR_PIPE),
+            ErrorKind::Interrupted         => Some(LIBUSB_ERROR_INTERRUPTED),
+            ErrorKind::NoMem               => Some(LIBUSB_ERROR_NO_MEM),
+            ErrorKind::NotSupported        => Some(LIBUSB_ERROR_NOT_SUPPORTED),
+            ErrorKind::UnknownEndpoint     => None,
+            ErrorKind::WrongTransferType   => None,
+            ErrorKind::InterfaceNotClaimed => None,
+            ErrorKind::ShortTransfer       => None,
+            ErrorKind::Ambiguous           => None,
+            ErrorKind::Other               => Some(LIBUSB_ERROR_OTHER),
         }
     }
+
+    /// Returns the `std::io::ErrorKind` that best describes this kind of error.
+    pub fn io_error_kind(&self) -> io::ErrorKind {
+        match *self {
+            ErrorKind::Timeout                             => io::ErrorKind::TimedOut,
+            ErrorKind::Access                              => io::ErrorKind::PermissionDenied,
+            ErrorKind::NoDevice                            => io::ErrorKind::NotConnected,
+            ErrorKind::NotFound | ErrorKind::UnknownEndpoint => io::ErrorKind::NotFound,
+            ErrorKind::InvalidParam | ErrorKind::WrongTransferType |
+            ErrorKind::InterfaceNotClaimed                 => io::ErrorKind::InvalidInput,
+            ErrorKind::Pipe                                => io::ErrorKind::BrokenPipe,
+            ErrorKind::Interrupted                         => io::ErrorKind::Interrupted,
+            ErrorKind::Overflow                            => io::ErrorKind::InvalidData,
+            ErrorKind::ShortTransfer                       => io::ErrorKind::UnexpectedEof,
+            _                                              => io::ErrorKind::Other,
+        }
+    }
+}
+
+
+/// Errors returned by the `libusb` library.
+///
+/// An error has a [`kind()`](#method.kind), which identifies what went wrong. Errors returned by
+/// methods that communicate with a device also describe the context in which the error occurred,
+/// such as the operation, the endpoint or control request, and the device's bus number and
+/// address. The context is included when the error is displayed:
+///
+/// ```text
+/// Pipe error (read_bulk, endpoint 0x81, bus 001 device 004)
+/// ```
+///
+/// Errors convert into `std::io::Error`, so they can be returned from code that uses the
+/// standard I/O traits.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::ErrorKind;
+///
+/// # fn read(handle: &libusb::DeviceHandle) -> libusb::Result<()> {
+/// let mut buf = [0u8; 64];
+///
+/// loop {
+///     match handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)) {
+///         Ok(n) => println!("read {} bytes", n),
+///         Err(ref err) if err.is_retryable() => continue,
+///         Err(ref err) if err.kind() == ErrorKind::Pipe => println!("endpoint {:?} stalled", err.endpoint()),
+///         Err(err) => return Err(err),
+///     }
+/// }
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct Error {
+    kind: ErrorKind,
+    code: Option<c_int>,
+    context: Option<Box<Context>>,
+}
+
+#[derive(Debug,Default)]
+struct Context {
+    operation: Option<&'static str>,
+    endpoint: Option<u8>,
+    request: Option<(u8, u8)>,
+    device: Option<(u8, u8)>,
+}
+
+impl Error {
+    /// Creates an error of the given kind without context.
+    pub fn new(kind: ErrorKind) -> Self {
+        Error {
+            kind: kind,
+            code: None,
+            context: None,
+        }
+    }
+
+    /// Returns the kind of error.
+    pub fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns a description of an error suitable for display to an end user.
+    ///
+    /// The description doesn't include the error's context.
+    pub fn strerror(&self) -> &'static str {
+        self.kind.strerror()
+    }
+
+    /// Returns the `libusb` error code of the error.
+    ///
+    /// For errors returned by `libusb`, this is the original error code, including codes that
+    /// this crate doesn't recognize and reports as `Other`. For errors detected by this crate, it's
+    /// the code that corresponds to the error's kind, if there is one.
+    pub fn code(&self) -> Option<i32> {
+        self.code.or_else(|| self.kind.code())
+    }
+
+    /// Returns the symbolic name of the error, e.g., `"LIBUSB_ERROR_PIPE"`.
+    ///
+    /// The name is returned by `libusb_error_name()` and doesn't depend on the locale. Errors that
+    /// have no `libusb` error code are named by this crate, e.g., `"UNKNOWN_ENDPOINT"`.
+    pub fn name(&self) -> &'static str {
+        match self.code() {
+            Some(code) => static_str(unsafe { libusb_error_name(code) }),
+            None => match self.kind {
+                ErrorKind::UnknownEndpoint     => "UNKNOWN_ENDPOINT",
+                ErrorKind::WrongTransferType   => "WRONG_TRANSFER_TYPE",
+                ErrorKind::InterfaceNotClaimed => "INTERFACE_NOT_CLAIMED",
+                ErrorKind::ShortTransfer       => "SHORT_TRANSFER",
+                ErrorKind::Ambiguous           => "AMBIGUOUS",
+                _                              => "UNKNOWN",
+            },
+        }
+    }
+
+    /// Returns a description of the error in the locale selected with
+    /// [`setlocale()`](fn.setlocale.html).
+    ///
+    /// The description is returned by `libusb_strerror()`. Errors that have no `libusb` error code
+    /// are described in English, as with [`strerror()`](#method.strerror).
+    pub fn message(&self) -> &'static str {
+        match self.code() {
+            Some(code) => static_str(unsafe { libusb_strerror(code) }),
+            None => self.strerror(),
+        }
+    }
+
+    /// Returns the name of the operation that failed, e.g., `"read_bulk"`.
+    pub fn operation(&self) -> Option<&'static str> {
+        self.context.as_ref().and_then(|context| context.operation)
+    }
+
+    /// Returns the address of the endpoint involved in the failed operation.
+    pub fn endpoint(&self) -> Option<u8> {
+        self.context.as_ref().and_then(|context| context.endpoint)
+    }
+
+    /// Returns the `bmRequestType` and `bRequest` fields of the control request that failed.
+    pub fn request(&self) -> Option<(u8, u8)> {
+        self.context.as_ref().and_then(|context| context.request)
+    }
+
+    /// Returns the bus number and address of the device involved in the failed operation.
+    pub fn device(&self) -> Option<(u8, u8)> {
+        self.context.as_ref().and_then(|context| context.device)
+    }
+
+    /// Sets the name of the operation that failed.
+    pub fn with_operation(mut self, operation: &'static str) -> Self {
+        self.context_mut().operation = Some(operation);
+        self
+    }
+
+    /// Sets the address of the endpoint involved in the failed operation.
+    pub fn with_endpoint(mut self, endpoint: u8) -> Self {
+        self.context_mut().endpoint = Some(endpoint);
+        self
+    }
+
+    /// Sets the `bmRequestType` and `bRequest` fields of the control request that failed.
+    pub fn with_request(mut self, request_type: u8, request: u8) -> Self {
+        self.context_mut().request = Some((request_type, request));
+        self
+    }
+
+    /// Sets the bus number and address of the device involved in the failed operation.
+    pub fn with_device(mut self, bus_number: u8, address: u8) -> Self {
+        self.context_mut().device = Some((bus_number, address));
+        self
+    }
+
+    /// Indicates whether the failed operation may succeed if it's retried.
+    ///
+    /// Timeouts, busy resources, and interrupted operations are retryable.
+    pub fn is_retryable(&self) -> bool {
+        match self.kind {
+            ErrorKind::Timeout | ErrorKind::Busy | ErrorKind::Interrupted => true,
+            _ => false,
+        }
+    }
+
+    /// Indicates whether the error was caused by the device being disconnected.
+    pub fn is_disconnect(&self) -> bool {
+        self.kind == ErrorKind::NoDevice
+    }
+
+    fn context_mut(&mut self) -> &mut Context {
+        if self.context.is_none() {
+            self.context = Some(Box::new(Context::default()));
+        }
+
+        self.context.as_mut().unwrap()
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error::new(kind)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        io::Error::new(err.kind.io_error_kind(), err)
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> StdResult<(), fmt::Error> {
-        fmt.write_str(self.strerror())
+        try!(fmt.write_str(self.strerror()));
+
+        if let Some(ref context) = self.context {
+            let mut parts = Vec::new();
+
+            if let Some(operation) = context.operation {
+                parts.push(operation.to_owned());
+            }
+
+            if let Some(endpoint) = context.endpoint {
+                parts.push(format!("endpoint {:#04x}", endpoint));
+            }
+
+            if let Some((request_type, request)) = context.request {
+                parts.push(format!("request {:#04x}/{:#04x}", request_type, request));
+            }
+
+            if let Some((bus_number, address)) = context.device {
+                parts.push(format!("bus {:03} device {:03}", bus_number, address));
+            }
+
+            if !parts.is_empty() {
+                try!(write!(fmt, " ({})", parts.join(", ")));
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -92,21 +377,68 @@ impl StdError for Error {
 
 #[doc(hidden)]
 pub fn from_libusb(err: c_int) -> Error {
+    Error {
+        kind: kind_from_libusb(err),
+        code: Some(err),
+        context: None,
+    }
+}
+
+/// Returns a copy of an error without its context.
+#[doc(hidden)]
+pub fn without_context(err: &Error) -> Error {
+    Error {
+        kind: err.kind,
+        code: err.code,
+        context: None,
+    }
+}
+
+/// Looks up a kind of error by the name of its variant, e.g., `"Pipe"`.
+#[doc(hidden)]
+pub fn kind_from_name(name: &str) -> Option<ErrorKind> {
+    let kind = match name {
+        "Success"             => ErrorKind::Success,
+        "Io"                  => ErrorKind::Io,
+        "InvalidParam"        => ErrorKind::InvalidParam,
+        "Access"              => ErrorKind::Access,
+        "NoDevice"            => ErrorKind::NoDevice,
+        "NotFound"            => ErrorKind::NotFound,
+        "Busy"                => ErrorKind::Busy,
+        "Timeout"             => ErrorKind::Timeout,
+        "Overflow"            => ErrorKind::Overflow,
+        "Pipe"                => ErrorKind::Pipe,
+        "Interrupted"         => ErrorKind::Interrupted,
+        "NoMem"               => ErrorKind::NoMem,
+        "NotSupported"        => ErrorKind::NotSupported,
+        "UnknownEndpoint"     => ErrorKind::UnknownEndpoint,
+        "WrongTransferType"   => ErrorKind::WrongTransferType,
+        "InterfaceNotClaimed" => ErrorKind::InterfaceNotClaimed,
+        "ShortTransfer"       => ErrorKind::ShortTransfer,
+        "Ambiguous"           => ErrorKind::Ambiguous,
+        "Other"               => ErrorKind::Other,
+        _                     => return None,
+    };
+
+    Some(kind)
+}
+
+fn kind_from_libusb(err: c_int) -> ErrorKind {
     match err {
-        LIBUSB_SUCCESS             => Error::Success,
-        LIBUSB_ERROR_IO            => Error::Io,
-        LIBUSB_ERROR_INVALID_PARAM => Error::InvalidParam,
-        LIBUSB_ERROR_ACCESS        => Error::Access,
-        LIBUSB_ERROR_NO_DEVICE     => Error::NoDevice,
-        LIBUSB_ERROR_NOT_FOUND     => Error::NotFound,
-        LIBUSB_ERROR_BUSY          => Error::Busy,
-        LIBUSB_ERROR_TIMEOUT       => Error::Timeout,
-        LIBUSB_ERROR_OVERFLOW      => Error::Overflow,
-        LIBUSB_ERROR_PIPE          => Error::Pipe,
-        LIBUSB_ERROR_INTERRUPTED   => Error::Interrupted,
-        LIBUSB_ERROR_NO_MEM        => Error::NoMem,
-        LIBUSB_ERROR_NOT_SUPPORTED => Error::NotSupported,
-        LIBUSB_ERROR_OTHER | _     => Error::Other,
+        LIBUSB_SUCCESS             => ErrorKind::Success,
+        LIBUSB_ERROR_IO            => ErrorKind::Io,
+        LIBUSB_ERROR_INVALID_PARAM => ErrorKind::InvalidParam,
+        LIBUSB_ERROR_ACCESS        => ErrorKind::Access,
+        LIBUSB_ERROR_NO_DEVICE     => ErrorKind::NoDevice,
+        LIBUSB_ERROR_NOT_FOUND     => ErrorKind::NotFound,
+        LIBUSB_ERROR_BUSY          => ErrorKind::Busy,
+        LIBUSB_ERROR_TIMEOUT       => ErrorKind::Timeout,
+        LIBUSB_ERROR_OVERFLOW      => ErrorKind::Overflow,
+        LIBUSB_ERROR_PIPE          => ErrorKind::Pipe,
+        LIBUSB_ERROR_INTERRUPTED   => ErrorKind::Interrupted,
+        LIBUSB_ERROR_NO_MEM        => ErrorKind::NoMem,
+        LIBUSB_ERROR_NOT_SUPPORTED => ErrorKind::NotSupported,
+        LIBUSB_ERROR_OTHER | _     => ErrorKind::Other,
     }
 }
 
@@ -119,3 +451,148 @@ macro_rules! try_unsafe {
         }
     }
 }
+
+/// Sets the locale of the error messages returned by [`Error::message()`](struct.Error.html#method.message).
+///
+/// The locale is given as an IETF language tag, e.g., `"de"` or `"fr-FR"`. Only the language part
+/// of the tag is used. The locale applies to the whole process, so it should be set once, before
+/// any other thread uses `libusb`.
+///
+/// ## Errors
+///
+/// * `InvalidParam` if the locale is malformed.
+/// * `NotFound` if `libusb` has no messages for the requested language.
+///
+/// ## Example
+///
+/// ```no_run
+/// # fn localize() -> libusb::Result<()> {
+/// try!(libusb::setlocale("de"));
+/// # Ok(())
+/// # }
+/// ```
+pub fn setlocale(locale: &str) -> ::Result<()> {
+    let locale = match CString::new(locale) {
+        Ok(locale) => locale,
+        Err(_) => return Err(Error::new(ErrorKind::InvalidParam)),
+    };
+
+    try_unsafe!(libusb_setlocale(locale.as_ptr()));
+
+    Ok(())
+}
+
+fn static_str(ptr: *const c_char) -> &'static str {
+    let cstr = unsafe { CStr::from_ptr(ptr) };
+
+    str::from_utf8(cstr.to_bytes()).unwrap_or("")
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use libusb::*;
+
+    use super::{Error, ErrorKind, from_libusb};
+
+    #[test]
+    fn it_converts_libusb_error_codes_to_kinds() {
+        assert_eq!(ErrorKind::Pipe, from_libusb(LIBUSB_ERROR_PIPE).kind());
+        assert_eq!(ErrorKind::NoDevice, from_libusb(LIBUSB_ERROR_NO_DEVICE).kind());
+        assert_eq!(ErrorKind::Other, from_libusb(-1000).kind());
+    }
+
+    #[test]
+    fn it_preserves_libusb_error_codes() {
+        assert_eq!(Some(LIBUSB_ERROR_PIPE), from_libusb(LIBUSB_ERROR_PIPE).code());
+        assert_eq!(Some(-1000), from_libusb(-1000).code());
+    }
+
+    #[test]
+    fn it_derives_error_codes_from_kinds() {
+        assert_eq!(Some(LIBUSB_ERROR_TIMEOUT), Error::new(ErrorKind::Timeout).code());
+        assert_eq!(None, Error::new(ErrorKind::UnknownEndpoint).code());
+    }
+
+    #[test]
+    fn it_looks_up_kinds_by_name() {
+        for kind in [ErrorKind::Pipe, ErrorKind::ShortTransfer, ErrorKind::Other].iter() {
+            assert_eq!(Some(*kind), super::kind_from_name(&format!("{:?}", kind)));
+        }
+
+        assert_eq!(None, super::kind_from_name("Stall"));
+    }
+
+    #[test]
+    fn it_names_errors_without_libusb_code() {
+        assert_eq!("SHORT_TRANSFER", Error::new(ErrorKind::ShortTransfer).name());
+    }
+
+    #[test]
+    fn it_describes_errors_without_libusb_code_in_english() {
+        assert_eq!("Interface has not been claimed", Error::new(ErrorKind::InterfaceNotClaimed).message());
+    }
+
+    #[test]
+    fn it_displays_error_without_context() {
+        assert_eq!("Pipe error", Error::new(ErrorKind::Pipe).to_string());
+    }
+
+    #[test]
+    fn it_displays_error_with_context() {
+        let err = Error::new(ErrorKind::Pipe).with_operation("read_bulk").with_endpoint(0x81).with_device(1, 4);
+
+        assert_eq!("Pipe error (read_bulk, endpoint 0x81, bus 001 device 004)", err.to_string());
+    }
+
+    #[test]
+    fn it_displays_control_request_in_context() {
+        let err = Error::new(ErrorKind::Timeout).with_operation("read_control").with_request(0xC0, 0x01);
+
+        assert_eq!("Operation timed out (read_control, request 0xc0/0x01)", err.to_string());
+    }
+
+    #[test]
+    fn it_exposes_context() {
+        let err = Error::new(ErrorKind::Pipe).with_operation("write_bulk").with_endpoint(0x02).with_device(3, 7);
+
+        assert_eq!(ErrorKind::Pipe, err.kind());
+        assert_eq!(Some("write_bulk"), err.operation());
+        assert_eq!(Some(0x02), err.endpoint());
+        assert_eq!(None, err.request());
+        assert_eq!(Some((3, 7)), err.device());
+    }
+
+    #[test]
+    fn it_classifies_retryable_errors() {
+        assert!(Error::new(ErrorKind::Timeout).is_retryable());
+        assert!(Error::new(ErrorKind::Busy).is_retryable());
+        assert!(Error::new(ErrorKind::Interrupted).is_retryable());
+        assert!(!Error::new(ErrorKind::Pipe).is_retryable());
+        assert!(!Error::new(ErrorKind::NoDevice).is_retryable());
+    }
+
+    #[test]
+    fn it_classifies_disconnect_errors() {
+        assert!(Error::new(ErrorKind::NoDevice).is_disconnect());
+        assert!(!Error::new(ErrorKind::Io).is_disconnect());
+    }
+
+    #[test]
+    fn it_converts_into_io_error() {
+        assert_eq!(io::ErrorKind::TimedOut, io::Error::from(Error::new(ErrorKind::Timeout)).kind());
+        assert_eq!(io::ErrorKind::PermissionDenied, io::Error::from(Error::new(ErrorKind::Access)).kind());
+        assert_eq!(io::ErrorKind::NotConnected, io::Error::from(Error::new(ErrorKind::NoDevice)).kind());
+        assert_eq!(io::ErrorKind::BrokenPipe, io::Error::from(Error::new(ErrorKind::Pipe)).kind());
+        assert_eq!(io::ErrorKind::Other, io::Error::from(Error::new(ErrorKind::Io)).kind());
+    }
+
+    #[test]
+    fn it_keeps_context_when_converting_into_io_error() {
+        let err = io::Error::from(Error::new(ErrorKind::Pipe).with_endpoint(0x81));
+
+        assert_eq!("Pipe error (endpoint 0x81)", err.to_string());
+    }
+}
@@ -0,0 +1,412 @@
+use std::cmp;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use error::{Error, ErrorKind};
+use fields::TransferType;
+
+/// Injects faults into the transfers made through a device handle.
+///
+/// A fault injector holds a list of [`FaultRule`](struct.FaultRule.html)s and is set on a handle
+/// with [`DeviceHandle::set_fault_injector()`](struct.DeviceHandle.html#method.set_fault_injector).
+/// Before each control, bulk, or interrupt transfer, every rule that matches the transfer is
+/// applied, whether the handle communicates with a device through `libusb` or through a
+/// [`Backend`](trait.Backend.html). Injected faults are seen by the handle's observer and metrics
+/// like faults of the device. Transfers that are queued by [`BulkStream`](struct.BulkStream.html),
+/// [`BulkSink`](struct.BulkSink.html), and [`InterruptPoller`](struct.InterruptPoller.html) are not
+/// affected.
+///
+/// Cloning an injector returns a new reference to the same injector, so that a test can inspect
+/// it after setting it on a handle.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::{ErrorKind, FaultInjector, FaultRule, TransferType};
+///
+/// # fn inject(handle: &mut libusb::DeviceHandle) {
+/// let faults = FaultInjector::new()
+///     .rule(FaultRule::fail(ErrorKind::Pipe).transfer_type(TransferType::Bulk).endpoint(0x81).nth(3))
+///     .rule(FaultRule::disconnect().endpoint(0x02).after_bytes(4096))
+///     .rule(FaultRule::delay(Duration::from_millis(200)));
+///
+/// handle.set_fault_injector(faults.clone());
+/// # }
+/// ```
+#[derive(Clone,Default)]
+pub struct FaultInjector {
+    state: Arc<Mutex<InjectorState>>,
+}
+
+#[derive(Default)]
+struct InjectorState {
+    rules: Vec<RuleState>,
+    disconnected: bool,
+    injected: usize,
+}
+
+struct RuleState {
+    rule: FaultRule,
+    count: usize,
+    bytes: usize,
+}
+
+impl FaultInjector {
+    /// Creates a fault injector without rules.
+    pub fn new() -> Self {
+        FaultInjector::default()
+    }
+
+    /// Adds a rule to the injector.
+    pub fn rule(self, rule: FaultRule) -> Self {
+        self.state.lock().unwrap().rules.push(RuleState {
+            rule: rule,
+            count: 0,
+            bytes: 0,
+        });
+
+        self
+    }
+
+    /// Returns the number of faults that have been injected.
+    pub fn injected(&self) -> usize {
+        self.state.lock().unwrap().injected
+    }
+
+    /// Indicates if a rule has disconnected the device.
+    ///
+    /// Once the device is disconnected, every transfer fails with `NoDevice`.
+    pub fn is_disconnected(&self) -> bool {
+        self.state.lock().unwrap().disconnected
+    }
+}
+
+impl fmt::Debug for FaultInjector {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state.lock().unwrap();
+
+        fmt.debug_struct("FaultInjector")
+            .field("rules", &state.rules.iter().map(|rule| &rule.rule).collect::<Vec<_>>())
+            .field("disconnected", &state.disconnected)
+            .field("injected", &state.injected)
+            .finish()
+    }
+}
+
+
+/// A rule that injects a fault into matching transfers.
+///
+/// A rule is created with the fault that it injects and then narrowed down to the transfers
+/// that it applies to. By default, a rule applies to every transfer.
+///
+/// Rules that are limited with [`after_bytes()`](#method.after_bytes) limit the transfer that
+/// could cross the limit to the bytes up to the limit. If the transfer reaches the limit, the
+/// fault is injected after the bytes were transferred, so that faults can occur in the middle of
+/// a transfer.
+#[derive(Debug,Clone)]
+pub struct FaultRule {
+    action: FaultAction,
+    transfer_type: Option<TransferType>,
+    endpoint: Option<u8>,
+    nth: Option<usize>,
+    after_bytes: Option<usize>,
+}
+
+#[derive(Debug,Clone,Copy)]
+enum FaultAction {
+    Fail(ErrorKind),
+    Disconnect,
+    Delay(Duration),
+    Truncate(usize),
+}
+
+impl FaultRule {
+    /// Creates a rule that fails transfers with the given kind of error without performing them.
+    pub fn fail(kind: ErrorKind) -> Self {
+        FaultRule::new(FaultAction::Fail(kind))
+    }
+
+    /// Creates a rule that disconnects the device.
+    ///
+    /// The transfer that triggers the rule and every later transfer through the handle fail with
+    /// `NoDevice`.
+    pub fn disconnect() -> Self {
+        FaultRule::new(FaultAction::Disconnect)
+    }
+
+    /// Creates a rule that delays transfers before performing them.
+    pub fn delay(delay: Duration) -> Self {
+        FaultRule::new(FaultAction::Delay(delay))
+    }
+
+    /// Creates a rule that limits transfers to at most `len` bytes, so that reads and writes are
+    /// short.
+    pub fn truncate(len: usize) -> Self {
+        FaultRule::new(FaultAction::Truncate(len))
+    }
+
+    /// Limits the rule to transfers of the given type.
+    pub fn transfer_type(mut self, transfer_type: TransferType) -> Self {
+        self.transfer_type = Some(transfer_type);
+        self
+    }
+
+    /// Limits the rule to transfers on the given endpoint.
+    ///
+    /// Control transfers are on endpoint `0x80` when they read from the device and on endpoint
+    /// `0x00` when they write to the device.
+    pub fn endpoint(mut self, endpoint: u8) -> Self {
+        self.endpoint = Some(endpoint);
+        self
+    }
+
+    /// Limits the rule to the `n`th transfer that it matches, counting from 1.
+    pub fn nth(mut self, n: usize) -> Self {
+        self.nth = Some(n);
+        self
+    }
+
+    /// Limits the rule to the transfer that reaches a total of `len` bytes in the transfers that the
+    /// rule matches, and to the transfers after it.
+    pub fn after_bytes(mut self, len: usize) -> Self {
+        self.after_bytes = Some(len);
+        self
+    }
+
+    fn new(action: FaultAction) -> Self {
+        FaultRule {
+            action: action,
+            transfer_type: None,
+            endpoint: None,
+            nth: None,
+            after_bytes: None,
+        }
+    }
+
+    fn matches(&self, transfer_type: TransferType, endpoint: u8) -> bool {
+        self.transfer_type.map_or(true, |t| t == transfer_type) && self.endpoint.map_or(true, |e| e == endpoint)
+    }
+}
+
+
+/// Performs a transfer of `len` bytes with the faults that an injector's rules apply to it.
+///
+/// `transfer` performs the transfer with the given length and returns the status of the transfer
+/// and the number of bytes transferred. Returns the status and number of bytes after injecting
+/// faults.
+#[doc(hidden)]
+pub fn inject<F>(injector: &FaultInjector, transfer_type: TransferType, endpoint: u8, len: usize, transfer: F) -> (::Result<()>, usize)
+    where F: FnOnce(usize) -> (::Result<()>, usize)
+{
+    let mut delay = Duration::from_secs(0);
+    let mut limit = len;
+    let mut matched = Vec::new();
+
+    // errors to inject, with the number of bytes that the transfer must reach to inject them
+    let mut errors: Vec<(ErrorKind, bool, usize)> = Vec::new();
+
+    {
+        let mut state = injector.state.lock().unwrap();
+
+        if state.disconnected {
+            state.injected += 1;
+            return (Err(Error::new(ErrorKind::NoDevice)), 0);
+        }
+
+        let mut injected = 0;
+
+        for (i, rule) in state.rules.iter_mut().enumerate() {
+            if !rule.rule.matches(transfer_type, endpoint) {
+                continue;
+            }
+
+            rule.count += 1;
+            matched.push(i);
+
+            if rule.rule.nth.map_or(false, |n| n != rule.count) {
+                continue;
+            }
+
+            // the number of bytes that may be transferred before the fault
+            let remaining = match rule.rule.after_bytes {
+                Some(after) if rule.bytes + len < after => continue,
+                Some(after) => after.saturating_sub(rule.bytes),
+                None => 0,
+            };
+
+            match rule.rule.action {
+                FaultAction::Delay(d) => {
+                    delay += d;
+                    injected += 1;
+                },
+                FaultAction::Truncate(n) => {
+                    limit = cmp::min(limit, n);
+                    injected += 1;
+                },
+                FaultAction::Fail(kind) => errors.push((kind, false, remaining)),
+                FaultAction::Disconnect => errors.push((ErrorKind::NoDevice, true, remaining)),
+            }
+        }
+
+        // a transfer that's failed without reaching the device isn't performed
+        if errors.iter().any(|&(_, _, remaining)| remaining == 0) {
+            limit = 0;
+        }
+
+        for &(_, _, remaining) in errors.iter() {
+            limit = cmp::min(limit, remaining);
+        }
+
+        state.injected += injected;
+    }
+
+    if delay > Duration::from_secs(0) {
+        thread::sleep(delay);
+    }
+
+    let (res, transferred) = if errors.iter().any(|&(_, _, remaining)| remaining == 0) {
+        (Ok(()), 0)
+    }
+    else {
+        transfer(limit)
+    };
+
+    let mut state = injector.state.lock().unwrap();
+
+    for i in matched {
+        state.rules[i].bytes += transferred;
+    }
+
+    if res.is_err() {
+        return (res, transferred);
+    }
+
+    match errors.into_iter().find(|&(_, _, remaining)| transferred >= remaining) {
+        Some((kind, disconnect, _)) => {
+            state.injected += 1;
+            state.disconnected = disconnect;
+
+            (Err(Error::new(kind)), transferred)
+        },
+        None => (res, transferred),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use context::Context;
+    use error::{Error, ErrorKind};
+    use fields::TransferType;
+    use mock::{MockBackend, MockDevice};
+
+    use super::{FaultInjector, FaultRule};
+
+    fn transfer(injector: &FaultInjector, transfer_type: TransferType, endpoint: u8, len: usize) -> (Option<ErrorKind>, usize) {
+        status(super::inject(injector, transfer_type, endpoint, len, |len| (Ok(()), len)))
+    }
+
+    fn status((res, transferred): (::Result<()>, usize)) -> (Option<ErrorKind>, usize) {
+        (res.err().map(|err| err.kind()), transferred)
+    }
+
+    #[test]
+    fn it_fails_nth_matching_transfer() {
+        let injector = FaultInjector::new().rule(FaultRule::fail(ErrorKind::Pipe).transfer_type(TransferType::Bulk).endpoint(0x81).nth(3));
+
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x81, 64));
+        assert_eq!((None, 64), transfer(&injector, TransferType::Interrupt, 0x81, 64));
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x02, 64));
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x81, 64));
+        assert_eq!((Some(ErrorKind::Pipe), 0), transfer(&injector, TransferType::Bulk, 0x81, 64));
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x81, 64));
+        assert_eq!(1, injector.injected());
+    }
+
+    #[test]
+    fn it_disconnects_after_bytes() {
+        let injector = FaultInjector::new().rule(FaultRule::disconnect().endpoint(0x02).after_bytes(100));
+
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x02, 64));
+        assert!(!injector.is_disconnected());
+
+        assert_eq!((Some(ErrorKind::NoDevice), 36), transfer(&injector, TransferType::Bulk, 0x02, 64));
+        assert!(injector.is_disconnected());
+
+        assert_eq!((Some(ErrorKind::NoDevice), 0), transfer(&injector, TransferType::Control, 0x80, 8));
+    }
+
+    #[test]
+    fn it_does_not_perform_failed_transfers() {
+        let injector = FaultInjector::new().rule(FaultRule::fail(ErrorKind::Timeout));
+
+        let res = status(super::inject(&injector, TransferType::Bulk, 0x81, 64, |_| panic!("transfer performed")));
+        assert_eq!((Some(ErrorKind::Timeout), 0), res);
+    }
+
+    #[test]
+    fn it_injects_errors_without_libusb_code() {
+        let injector = FaultInjector::new().rule(FaultRule::fail(ErrorKind::ShortTransfer));
+        assert_eq!((Some(ErrorKind::ShortTransfer), 0), transfer(&injector, TransferType::Bulk, 0x81, 64));
+    }
+
+    #[test]
+    fn it_keeps_errors_of_the_transfer() {
+        let injector = FaultInjector::new().rule(FaultRule::fail(ErrorKind::Pipe).after_bytes(10));
+
+        let res = status(super::inject(&injector, TransferType::Bulk, 0x81, 64, |_| (Err(Error::new(ErrorKind::Timeout)), 4)));
+        assert_eq!((Some(ErrorKind::Timeout), 4), res);
+    }
+
+    #[test]
+    fn it_truncates_transfers() {
+        let injector = FaultInjector::new().rule(FaultRule::truncate(10).endpoint(0x81));
+
+        assert_eq!((None, 10), transfer(&injector, TransferType::Bulk, 0x81, 64));
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x82, 64));
+    }
+
+    #[test]
+    fn it_delays_transfers() {
+        let injector = FaultInjector::new().rule(FaultRule::delay(Duration::from_millis(20)));
+        let start = Instant::now();
+
+        assert_eq!((None, 64), transfer(&injector, TransferType::Bulk, 0x81, 64));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn it_injects_faults_into_device_handle_transfers() {
+        let device = MockDevice::new(0x1234, 0x5678).endpoint(0, 0x81, TransferType::Bulk, 64);
+
+        for _ in 0..3 {
+            device.push_in(0x81, vec![1, 2, 3, 4]);
+        }
+
+        let backend = MockBackend::new();
+        backend.add_device(device);
+
+        let context = Context::with_backend(backend);
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        let faults = FaultInjector::new()
+            .rule(FaultRule::fail(ErrorKind::Pipe).transfer_type(TransferType::Bulk).endpoint(0x81).nth(2))
+            .rule(FaultRule::disconnect().endpoint(0x81).after_bytes(8));
+
+        handle.set_fault_injector(faults.clone());
+
+        let mut buf = [0; 64];
+        assert_eq!(4, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap());
+        assert_eq!(ErrorKind::Pipe, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(ErrorKind::NoDevice, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(ErrorKind::NoDevice, handle.read_languages(Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(3, faults.injected());
+
+        handle.clear_fault_injector();
+        assert_eq!(1, handle.read_languages(Duration::from_secs(1)).unwrap().len());
+    }
+}
@@ -0,0 +1,360 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use device::Device;
+use device_descriptor::DeviceDescriptor;
+use device_handle::DeviceHandle;
+use error::{Error, ErrorKind};
+use fields::Version;
+use interface_descriptor::InterfaceDescriptor;
+use timeout::Timeout;
+
+/// Criteria for selecting devices.
+///
+/// A filter matches a device if the device meets every criterion that was set on the filter. A
+/// new filter has no criteria and matches every device. Filters are used to select devices with
+/// [`Context::find_devices()`](struct.Context.html#method.find_devices) and
+/// [`Context::open_first()`](struct.Context.html#method.open_first).
+///
+/// A filter can be parsed from a string in the format accepted by `lsusb -d`, i.e.,
+/// `[vendor]:[product]` with the IDs in hexadecimal, e.g., `"1d6b:0002"` or `"1d6b:"`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use libusb::{Context, DeviceFilter};
+///
+/// let context = Context::new().unwrap();
+///
+/// let filter = "1d6b:".parse::<DeviceFilter>().unwrap().interface_class(0x09);
+/// let hubs = context.find_devices(&filter).unwrap();
+/// ```
+#[derive(Debug,Default,Clone,PartialEq,Eq)]
+pub struct DeviceFilter {
+    vendor_id: Option<(u16, u16)>,
+    product_id: Option<(u16, u16)>,
+    device_class: ClassFilter,
+    interface_class: ClassFilter,
+    device_version: Option<(Version, Version)>,
+    bus_number: Option<u8>,
+    port_numbers: Option<Vec<u8>>,
+    serial_number: Option<String>,
+    serial_number_timeout: Option<Timeout>,
+}
+
+#[derive(Debug,Default,Clone,Copy,PartialEq,Eq)]
+struct ClassFilter {
+    class_code: Option<u8>,
+    sub_class_code: Option<u8>,
+    protocol_code: Option<u8>,
+}
+
+impl ClassFilter {
+    fn is_empty(&self) -> bool {
+        self.class_code.is_none() && self.sub_class_code.is_none() && self.protocol_code.is_none()
+    }
+
+    fn matches(&self, class_code: u8, sub_class_code: u8, protocol_code: u8) -> bool {
+        self.class_code.map_or(true, |code| code == class_code)
+            && self.sub_class_code.map_or(true, |code| code == sub_class_code)
+            && self.protocol_code.map_or(true, |code| code == protocol_code)
+    }
+}
+
+impl DeviceFilter {
+    /// Creates a filter that matches every device.
+    pub fn new() -> Self {
+        DeviceFilter::default()
+    }
+
+    /// Matches devices with the given vendor ID.
+    pub fn vendor_id(self, vendor_id: u16) -> Self {
+        self.vendor_id_masked(vendor_id, 0xFFFF)
+    }
+
+    /// Matches devices whose vendor ID equals `vendor_id` in the bits that are set in `mask`.
+    pub fn vendor_id_masked(mut self, vendor_id: u16, mask: u16) -> Self {
+        self.vendor_id = Some((vendor_id & mask, mask));
+        self
+    }
+
+    /// Matches devices with the given product ID.
+    pub fn product_id(self, product_id: u16) -> Self {
+        self.product_id_masked(product_id, 0xFFFF)
+    }
+
+    /// Matches devices whose product ID equals `product_id` in the bits that are set in `mask`.
+    pub fn product_id_masked(mut self, product_id: u16, mask: u16) -> Self {
+        self.product_id = Some((product_id & mask, mask));
+        self
+    }
+
+    /// Matches devices with the given device class code.
+    pub fn device_class(mut self, class_code: u8) -> Self {
+        self.device_class.class_code = Some(class_code);
+        self
+    }
+
+    /// Matches devices with the given device sub class code.
+    pub fn device_sub_class(mut self, sub_class_code: u8) -> Self {
+        self.device_class.sub_class_code = Some(sub_class_code);
+        self
+    }
+
+    /// Matches devices with the given device protocol code.
+    pub fn device_protocol(mut self, protocol_code: u8) -> Self {
+        self.device_class.protocol_code = Some(protocol_code);
+        self
+    }
+
+    /// Matches devices that have an interface with the given class code.
+    ///
+    /// The interface criteria must be met by the same interface setting, which may belong to any
+    /// of the device's configurations.
+    pub fn interface_class(mut self, class_code: u8) -> Self {
+        self.interface_class.class_code = Some(class_code);
+        self
+    }
+
+    /// Matches devices that have an interface with the given sub class code.
+    pub fn interface_sub_class(mut self, sub_class_code: u8) -> Self {
+        self.interface_class.sub_class_code = Some(sub_class_code);
+        self
+    }
+
+    /// Matches devices that have an interface with the given protocol code.
+    pub fn interface_protocol(mut self, protocol_code: u8) -> Self {
+        self.interface_class.protocol_code = Some(protocol_code);
+        self
+    }
+
+    /// Matches devices whose device version (`bcdDevice`) is between `min` and `max`, inclusive.
+    pub fn device_version(mut self, min: Version, max: Version) -> Self {
+        self.device_version = Some((min, max));
+        self
+    }
+
+    /// Matches devices that are connected to the given bus.
+    pub fn bus_number(mut self, bus_number: u8) -> Self {
+        self.bus_number = Some(bus_number);
+        self
+    }
+
+    /// Matches devices with the given port path, which is the numbers of the ports from the root
+    /// hub to the device.
+    pub fn port_numbers(mut self, port_numbers: &[u8]) -> Self {
+        self.port_numbers = Some(port_numbers.to_vec());
+        self
+    }
+
+    /// Matches devices with the given serial number.
+    ///
+    /// Matching a serial number opens the device to read its serial number string in the first
+    /// language that the device supports. The other criteria are checked first, so that only
+    /// devices that meet them are opened.
+    pub fn serial_number(mut self, serial_number: &str) -> Self {
+        self.serial_number = Some(serial_number.to_owned());
+        self
+    }
+
+    /// Sets the timeout for reading a device's serial number.
+    ///
+    /// The timeout applies to each of the requests that read the device's languages and its
+    /// serial number string. The default is one second.
+    pub fn serial_number_timeout<T: Into<Timeout>>(mut self, timeout: T) -> Self {
+        self.serial_number_timeout = Some(timeout.into());
+        self
+    }
+
+    /// Checks if a device matches the filter.
+    ///
+    /// Returns an error if the device's descriptors can't be read or if the device can't be opened
+    /// to read its serial number, e.g., `Timeout` if the serial number isn't read within the
+    /// filter's [`serial_number_timeout()`](#method.serial_number_timeout).
+    pub fn matches(&self, device: &Device) -> ::Result<bool> {
+        if self.bus_number.map_or(false, |bus_number| bus_number != device.bus_number()) {
+            return Ok(false);
+        }
+
+        if let Some(ref port_numbers) = self.port_numbers {
+            if *port_numbers != try!(device.port_numbers()) {
+                return Ok(false);
+            }
+        }
+
+        let descriptor = try!(device.device_descriptor());
+
+        if !self.matches_descriptor(&descriptor) {
+            return Ok(false);
+        }
+
+        if !self.interface_class.is_empty() && !try!(self.matches_interfaces(device, &descriptor)) {
+            return Ok(false);
+        }
+
+        match self.serial_number {
+            Some(ref serial_number) => {
+                let handle = try!(device.open());
+                let timeout = self.serial_number_timeout.unwrap_or(Timeout::After(Duration::from_secs(1)));
+                let serial = try!(read_serial_number(&handle, &descriptor, timeout));
+
+                Ok(serial.as_ref() == Some(serial_number))
+            },
+            None => Ok(true),
+        }
+    }
+
+    fn matches_descriptor(&self, descriptor: &DeviceDescriptor) -> bool {
+        let version = descriptor.device_version();
+        let version = (version.major(), version.minor(), version.sub_minor());
+
+        self.vendor_id.map_or(true, |(id, mask)| descriptor.vendor_id() & mask == id)
+            && self.product_id.map_or(true, |(id, mask)| descriptor.product_id() & mask == id)
+            && self.device_class.matches(descriptor.class_code(), descriptor.sub_class_code(), descriptor.protocol_code())
+            && self.device_version.map_or(true, |(min, max)| {
+                (min.major(), min.minor(), min.sub_minor()) <= version && version <= (max.major(), max.minor(), max.sub_minor())
+            })
+    }
+
+    fn matches_interfaces(&self, device: &Device, descriptor: &DeviceDescriptor) -> ::Result<bool> {
+        let matches = |setting: &InterfaceDescriptor| {
+            self.interface_class.matches(setting.class_code(), setting.sub_class_code(), setting.protocol_code())
+        };
+
+        for i in 0..descriptor.num_configurations() {
+            let config = try!(device.config_descriptor(i));
+
+            if config.interfaces().any(|interface| interface.descriptors().any(|setting| matches(&setting))) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+}
+
+impl FromStr for DeviceFilter {
+    type Err = Error;
+
+    /// Parses a filter in the format accepted by `lsusb -d`, i.e., `[vendor]:[product]`.
+    fn from_str(s: &str) -> ::Result<DeviceFilter> {
+        let mut parts = s.splitn(2, ':');
+
+        let (vendor, product) = match (parts.next(), parts.next()) {
+            (Some(vendor), Some(product)) => (vendor.trim(), product.trim()),
+            _ => return Err(Error::new(ErrorKind::InvalidParam)),
+        };
+
+        let parse = |id: &str| {
+            if id.len() > 4 || id.starts_with('+') {
+                return Err(Error::new(ErrorKind::InvalidParam));
+            }
+
+            u16::from_str_radix(id, 16).map_err(|_| Error::new(ErrorKind::InvalidParam))
+        };
+
+        let mut filter = DeviceFilter::new();
+
+        if !vendor.is_empty() {
+            filter = filter.vendor_id(try!(parse(vendor)));
+        }
+
+        if !product.is_empty() {
+            filter = filter.product_id(try!(parse(product)));
+        }
+
+        Ok(filter)
+    }
+}
+
+
+/// Reads a device's serial number string in the first language that the device supports.
+///
+/// Returns `None` if the device has no serial number or no languages.
+#[doc(hidden)]
+pub fn read_serial_number<T: Into<Timeout>>(handle: &DeviceHandle, descriptor: &DeviceDescriptor, timeout: T) -> ::Result<Option<String>> {
+    if descriptor.serial_number_string_index().is_none() {
+        return Ok(None);
+    }
+
+    let timeout = timeout.into();
+
+    match try!(handle.read_languages(timeout)).first() {
+        Some(&language) => handle.read_serial_number_string(language, descriptor, timeout).map(Some),
+        None => Ok(None),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use context::Context;
+    use error::ErrorKind;
+    use fields::Version;
+    use mock::MockDevice;
+    use test_helpers::context_with;
+
+    use super::DeviceFilter;
+
+    #[test]
+    fn it_parses_vendor_and_product_ids() {
+        assert_eq!(DeviceFilter::new().vendor_id(0x1d6b).product_id(0x0002), "1d6b:0002".parse().unwrap());
+        assert_eq!(DeviceFilter::new().vendor_id(0x1d6b), "1d6b:".parse().unwrap());
+        assert_eq!(DeviceFilter::new().product_id(0x2), ":2".parse().unwrap());
+        assert_eq!(DeviceFilter::new(), ":".parse().unwrap());
+    }
+
+    #[test]
+    fn it_rejects_invalid_ids() {
+        for s in ["1d6b", "1d6b0:0002", "xyz:0002", "1d6b:+2", ""].iter() {
+            assert_eq!(ErrorKind::InvalidParam, s.parse::<DeviceFilter>().unwrap_err().kind());
+        }
+    }
+
+    fn context() -> Context {
+        context_with(&[&MockDevice::new(0x1234, 0x0001).bus_number(1).port_number(2).serial_number("A1").interface(0, 0x03),
+                       &MockDevice::new(0x1234, 0x0102).bus_number(2).port_number(3).serial_number("B2").interface(0, 0x08),
+                       &MockDevice::new(0x5678, 0x0001).bus_number(2).port_number(4)])
+    }
+
+    fn find(context: &Context, filter: DeviceFilter) -> Vec<(u16, u16)> {
+        context.find_devices(&filter).unwrap().iter().map(|device| {
+            let descriptor = device.device_descriptor().unwrap();
+            (descriptor.vendor_id(), descriptor.product_id())
+        }).collect()
+    }
+
+    #[test]
+    fn it_finds_matching_devices() {
+        let context = context();
+
+        assert_eq!(3, find(&context, DeviceFilter::new()).len());
+        assert_eq!(vec![(0x1234, 0x0001), (0x1234, 0x0102)], find(&context, DeviceFilter::new().vendor_id(0x1234)));
+        assert_eq!(vec![(0x1234, 0x0102)], find(&context, DeviceFilter::new().product_id_masked(0x0100, 0xFF00)));
+        assert_eq!(vec![(0x1234, 0x0102)], find(&context, DeviceFilter::new().interface_class(0x08)));
+        assert_eq!(vec![(0x5678, 0x0001)], find(&context, DeviceFilter::new().bus_number(2).port_numbers(&[4])));
+        assert_eq!(vec![(0x1234, 0x0102)], find(&context, DeviceFilter::new().serial_number("B2")));
+        assert_eq!(3, find(&context, DeviceFilter::new().device_version(Version(1, 0, 0), Version(1, 0, 0))).len());
+        assert!(find(&context, DeviceFilter::new().device_class(0xFF)).is_empty());
+    }
+
+    #[test]
+    fn it_reads_serial_number_within_timeout() {
+        let context = context();
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+
+        assert!(DeviceFilter::new().serial_number("A1").serial_number_timeout(Duration::from_millis(100)).matches(&device).unwrap());
+        assert_eq!(ErrorKind::Timeout, DeviceFilter::new().serial_number("A1").serial_number_timeout(Instant::now()).matches(&device).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_opens_first_matching_device() {
+        let context = context();
+
+        assert!(context.open_first(&DeviceFilter::new().vendor_id(0x5678)).is_ok());
+        assert_eq!(ErrorKind::NotFound, context.open_first(&DeviceFilter::new().vendor_id(0x9999)).err().unwrap().kind());
+    }
+}
@@ -0,0 +1,494 @@
+use std::cmp;
+use std::fmt;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+use libc::c_int;
+use libusb::*;
+
+use device_handle::{self, DeviceHandle};
+use error::{self, Error, ErrorKind};
+use fields::Speed;
+use timeout::Timeout;
+use transfer::{self, Transfer};
+
+/// A service that polls interrupt IN endpoints.
+///
+/// An interrupt poller keeps a transfer queued on each of its endpoints, so that the host
+/// controller polls the endpoints at the interval given by their endpoint descriptors. Each report
+/// received from an endpoint is delivered to the callbacks that are subscribed to the endpoint.
+///
+/// The poller is driven by calling [`run()`](#method.run), which services the endpoints until the
+/// poller is stopped, or [`poll()`](#method.poll), which handles the reports that arrive within a
+/// timeout. The poller can be paused, resumed, and stopped from another thread through a
+/// [`PollerControl`](struct.PollerControl.html).
+///
+/// If an endpoint stalls, its halt condition is cleared and polling continues. Other transfer
+/// errors are retried. A stall counts as a failed transfer, and an endpoint whose transfers fail
+/// three times in a row stops polling with the endpoint's error. If the device is disconnected,
+/// polling stops and `NoDevice` is returned.
+///
+/// ## Example
+///
+/// ```no_run
+/// # fn poll(handle: &libusb::DeviceHandle) -> libusb::Result<()> {
+/// let mut poller = try!(handle.interrupt_poller(&[0x81]));
+///
+/// try!(poller.subscribe(0x81, |report| println!("report: {:?}", report)));
+///
+/// let control = poller.control();
+/// // give `control` to another thread, which calls `control.stop()` when done
+/// # control.stop();
+///
+/// try!(poller.run());
+/// # Ok(())
+/// # }
+/// ```
+pub struct InterruptPoller<'h, 'a: 'h> {
+    handle: &'h DeviceHandle<'a>,
+    endpoints: Vec<PolledEndpoint<'h>>,
+    control: PollerControl,
+}
+
+struct PolledEndpoint<'h> {
+    // declared before `buf` so that the transfer is completed before its buffer is freed
+    transfer: Transfer,
+    buf: Vec<u8>,
+    endpoint: u8,
+    period: Duration,
+    pending: bool,
+    failures: usize,
+    subscribers: Vec<Box<FnMut(&[u8]) + 'h>>,
+}
+
+impl<'h, 'a> Drop for InterruptPoller<'h, 'a> {
+    /// Cancels the queued transfers.
+    fn drop(&mut self) {
+        for endpoint in self.endpoints.iter() {
+            endpoint.transfer.cancel();
+        }
+    }
+}
+
+impl<'h, 'a> InterruptPoller<'h, 'a> {
+    /// Returns the addresses of the polled endpoints.
+    pub fn endpoints(&self) -> Vec<u8> {
+        self.endpoints.iter().map(|endpoint| endpoint.endpoint).collect()
+    }
+
+    /// Returns the polling period of an endpoint.
+    ///
+    /// The polling period is derived from the endpoint's `interval()` and the device's speed.
+    /// Returns `None` if the endpoint isn't polled.
+    pub fn polling_period(&self, endpoint: u8) -> Option<Duration> {
+        self.endpoints.iter().find(|polled| polled.endpoint == endpoint).map(|polled| polled.period)
+    }
+
+    /// Subscribes a callback to the reports of an endpoint.
+    ///
+    /// The callback is called with each report received from the endpoint, on the thread that
+    /// runs the poller.
+    ///
+    /// ## Errors
+    ///
+    /// * `UnknownEndpoint` if the endpoint isn't polled.
+    pub fn subscribe<F: FnMut(&[u8]) + 'h>(&mut self, endpoint: u8, callback: F) -> ::Result<()> {
+        match self.endpoints.iter_mut().find(|polled| polled.endpoint == endpoint) {
+            Some(polled) => {
+                polled.subscribers.push(Box::new(callback));
+                Ok(())
+            },
+            None => Err(Error::new(ErrorKind::UnknownEndpoint)),
+        }
+    }
+
+    /// Returns a control for pausing, resuming, and stopping the poller.
+    pub fn control(&self) -> PollerControl {
+        self.control.clone()
+    }
+
+    /// Services the endpoints until the poller is stopped.
+    ///
+    /// While the poller is paused, no transfers are queued and this method blocks until the poller
+    /// is resumed or stopped. A pause or stop request is noticed within the shortest polling
+    /// period of the endpoints, and at most 100 milliseconds.
+    ///
+    /// ## Errors
+    ///
+    /// * `NoDevice` if the device has been disconnected.
+    /// * The endpoint's error, e.g., `Io`, `Pipe`, or `Overflow`, if an endpoint's transfers fail
+    ///   three times in a row.
+    pub fn run(&mut self) -> ::Result<()> {
+        let wake_period = self.endpoints.iter()
+            .map(|endpoint| endpoint.period)
+            .fold(Duration::from_millis(100), cmp::min);
+
+        loop {
+            match self.control.state() {
+                PollerState::Running => try!(self.poll(wake_period)),
+                PollerState::Paused => {
+                    try!(self.cancel());
+                    self.control.wait_while_paused();
+                },
+                PollerState::Stopped => return self.cancel(),
+            }
+        }
+    }
+
+    /// Services the endpoints once, delivering the reports that arrive within `timeout`.
+    ///
+    /// Transfers are queued on endpoints that don't have one. If the poller is paused or stopped,
+    /// the queued transfers are cancelled and this method returns immediately.
+    ///
+    /// ## Errors
+    ///
+    /// * `NoDevice` if the device has been disconnected.
+    /// * The endpoint's error, e.g., `Io`, `Pipe`, or `Overflow`, if an endpoint's transfers fail
+    ///   three times in a row.
+    pub fn poll<T: Into<Timeout>>(&mut self, timeout: T) -> ::Result<()> {
+        if self.control.state() != PollerState::Running {
+            return self.cancel();
+        }
+
+        for i in 0..self.endpoints.len() {
+            if !self.endpoints[i].pending {
+                try!(self.submit(i));
+            }
+        }
+
+        transfer::handle_events(device_handle::context_raw(self.handle), timeout.into().remaining());
+
+        for i in 0..self.endpoints.len() {
+            if self.endpoints[i].pending && self.endpoints[i].transfer.is_completed() {
+                try!(self.complete(i));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn submit(&mut self, i: usize) -> ::Result<()> {
+        let handle = device_handle::as_raw(self.handle);
+        let polled = &mut self.endpoints[i];
+
+        unsafe {
+            polled.transfer.fill(handle, LIBUSB_TRANSFER_TYPE_INTERRUPT, polled.endpoint, polled.buf.as_mut_ptr(), polled.buf.len(), 0, 0);
+        }
+
+        match polled.transfer.submit() {
+            0 => {
+                polled.pending = true;
+                Ok(())
+            },
+            err => Err(error::from_libusb(err).with_endpoint(polled.endpoint)),
+        }
+    }
+
+    /// Delivers the result of an endpoint's completed transfer.
+    fn complete(&mut self, i: usize) -> ::Result<()> {
+        let handle = device_handle::as_raw(self.handle);
+        let polled = &mut self.endpoints[i];
+
+        polled.transfer.wait();
+        polled.pending = false;
+
+        match completion(polled.transfer.result(), &mut polled.failures) {
+            Completion::Deliver => {
+                let report = &polled.buf[..polled.transfer.actual_length()];
+
+                for subscriber in polled.subscribers.iter_mut() {
+                    subscriber(report);
+                }
+
+                Ok(())
+            },
+            Completion::ClearHalt => {
+                match unsafe { libusb_clear_halt(handle, polled.endpoint) } {
+                    0 => Ok(()),
+                    err => Err(error::from_libusb(err).with_endpoint(polled.endpoint)),
+                }
+            },
+            Completion::Resubmit => Ok(()),
+            Completion::Fail(err) => Err(error::from_libusb(err).with_endpoint(polled.endpoint)),
+        }
+    }
+
+    /// Cancels the queued transfers and delivers any reports that arrived before they were
+    /// cancelled.
+    fn cancel(&mut self) -> ::Result<()> {
+        for endpoint in self.endpoints.iter() {
+            if endpoint.pending {
+                endpoint.transfer.cancel();
+            }
+        }
+
+        let mut result = Ok(());
+
+        for i in 0..self.endpoints.len() {
+            if self.endpoints[i].pending {
+                if let Err(err) = self.complete(i) {
+                    result = Err(err);
+                }
+            }
+        }
+
+        result
+    }
+}
+
+
+/// Controls an [`InterruptPoller`](struct.InterruptPoller.html) from any thread.
+///
+/// Cloning a control returns a new reference to the same poller's control.
+#[derive(Clone)]
+pub struct PollerControl {
+    inner: Arc<(Mutex<PollerState>, Condvar)>,
+}
+
+#[derive(Debug,PartialEq,Eq,Clone,Copy)]
+enum PollerState {
+    Running,
+    Paused,
+    Stopped,
+}
+
+impl PollerControl {
+    /// Pauses the poller.
+    ///
+    /// While the poller is paused, its endpoints are not polled.
+    pub fn pause(&self) {
+        self.set_state(PollerState::Paused);
+    }
+
+    /// Resumes a paused poller.
+    pub fn resume(&self) {
+        self.set_state(PollerState::Running);
+    }
+
+    /// Stops the poller.
+    ///
+    /// A stopped poller can't be resumed.
+    pub fn stop(&self) {
+        self.set_state(PollerState::Stopped);
+    }
+
+    /// Indicates whether the poller is paused.
+    pub fn is_paused(&self) -> bool {
+        self.state() == PollerState::Paused
+    }
+
+    /// Indicates whether the poller is stopped.
+    pub fn is_stopped(&self) -> bool {
+        self.state() == PollerState::Stopped
+    }
+
+    fn state(&self) -> PollerState {
+        *self.inner.0.lock().unwrap()
+    }
+
+    fn set_state(&self, state: PollerState) {
+        let mut current = self.inner.0.lock().unwrap();
+
+        if *current != PollerState::Stopped {
+            *current = state;
+            self.inner.1.notify_all();
+        }
+    }
+
+    fn wait_while_paused(&self) {
+        let mut state = self.inner.0.lock().unwrap();
+
+        while *state == PollerState::Paused {
+            state = self.inner.1.wait(state).unwrap();
+        }
+    }
+}
+
+impl fmt::Debug for PollerControl {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("PollerControl")
+            .field("state", &self.state())
+            .finish()
+    }
+}
+
+
+/// The number of consecutive failed transfers after which an endpoint stops polling.
+const MAX_FAILURES: usize = 3;
+
+/// What to do with an endpoint after its transfer completes.
+#[derive(Debug,PartialEq,Eq)]
+enum Completion {
+    /// Deliver the report to the subscribers.
+    Deliver,
+
+    /// Clear the endpoint's halt condition and queue another transfer.
+    ClearHalt,
+
+    /// Queue another transfer without delivering a report.
+    Resubmit,
+
+    /// Stop polling with the given `libusb` error code.
+    Fail(c_int),
+}
+
+/// Decides what to do after a transfer completes with the given result, counting an endpoint's
+/// consecutive failures.
+fn completion(result: c_int, failures: &mut usize) -> Completion {
+    match result {
+        LIBUSB_SUCCESS => {
+            *failures = 0;
+            Completion::Deliver
+        },
+        // the transfer was cancelled by the poller
+        LIBUSB_ERROR_INTERRUPTED => Completion::Resubmit,
+        LIBUSB_ERROR_NO_DEVICE => Completion::Fail(result),
+        err => {
+            *failures += 1;
+
+            if *failures >= MAX_FAILURES {
+                Completion::Fail(err)
+            }
+            else if err == LIBUSB_ERROR_PIPE {
+                Completion::ClearHalt
+            }
+            else {
+                Completion::Resubmit
+            }
+        },
+    }
+}
+
+/// Returns the polling period of an interrupt endpoint.
+///
+/// For high-speed and SuperSpeed devices, the interval is an exponent of 125 microsecond
+/// microframes. Otherwise, the interval is a number of 1 millisecond frames.
+fn polling_period(speed: Speed, interval: u8) -> Duration {
+    match speed {
+        Speed::High | Speed::Super => {
+            let exponent = cmp::min(cmp::max(interval, 1), 16) - 1;
+            Duration::new(0, 125_000) * (1 << exponent)
+        },
+        _ => Duration::from_millis(cmp::max(interval, 1) as u64),
+    }
+}
+
+#[doc(hidden)]
+pub fn new<'h, 'a>(handle: &'h DeviceHandle<'a>, speed: Speed, endpoints: &[(u8, usize, u8)]) -> ::Result<InterruptPoller<'h, 'a>> {
+    let mut polled = Vec::with_capacity(endpoints.len());
+
+    for &(endpoint, report_size, interval) in endpoints {
+        polled.push(PolledEndpoint {
+            transfer: try!(Transfer::new(device_handle::context_raw(handle))),
+            buf: vec![0; report_size],
+            endpoint: endpoint,
+            period: polling_period(speed, interval),
+            pending: false,
+            failures: 0,
+            subscribers: Vec::new(),
+        });
+    }
+
+    Ok(InterruptPoller {
+        handle: handle,
+        endpoints: polled,
+        control: PollerControl {
+            inner: Arc::new((Mutex::new(PollerState::Running), Condvar::new())),
+        },
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use libusb::*;
+
+    use fields::Speed;
+
+    use super::{Completion, completion, polling_period};
+
+    #[test]
+    fn it_uses_frames_for_full_and_low_speed_intervals() {
+        assert_eq!(Duration::from_millis(10), polling_period(Speed::Full, 10));
+        assert_eq!(Duration::from_millis(255), polling_period(Speed::Low, 255));
+    }
+
+    #[test]
+    fn it_uses_microframe_exponent_for_high_speed_intervals() {
+        assert_eq!(Duration::new(0, 125_000), polling_period(Speed::High, 1));
+        assert_eq!(Duration::from_millis(1), polling_period(Speed::High, 4));
+        assert_eq!(Duration::from_millis(4096), polling_period(Speed::Super, 16));
+    }
+
+    #[test]
+    fn it_clamps_out_of_range_intervals() {
+        assert_eq!(Duration::from_millis(1), polling_period(Speed::Full, 0));
+        assert_eq!(Duration::new(0, 125_000), polling_period(Speed::High, 0));
+        assert_eq!(Duration::from_millis(4096), polling_period(Speed::High, 255));
+    }
+
+    #[test]
+    fn it_delivers_successful_reports_and_resets_failures() {
+        let mut failures = 2;
+
+        assert_eq!(Completion::Deliver, completion(LIBUSB_SUCCESS, &mut failures));
+        assert_eq!(0, failures);
+    }
+
+    #[test]
+    fn it_clears_halt_and_fails_on_disconnect() {
+        let mut failures = 0;
+
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+        assert_eq!(Completion::Fail(LIBUSB_ERROR_NO_DEVICE), completion(LIBUSB_ERROR_NO_DEVICE, &mut failures));
+        assert_eq!(Completion::Resubmit, completion(LIBUSB_ERROR_INTERRUPTED, &mut failures));
+        assert_eq!(1, failures);
+    }
+
+    #[test]
+    fn it_fails_after_repeated_stalls() {
+        let mut failures = 0;
+
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+        assert_eq!(Completion::Fail(LIBUSB_ERROR_PIPE), completion(LIBUSB_ERROR_PIPE, &mut failures));
+    }
+
+    #[test]
+    fn it_counts_stalls_with_other_transfer_errors() {
+        let mut failures = 0;
+
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+        assert_eq!(Completion::Resubmit, completion(LIBUSB_ERROR_IO, &mut failures));
+        assert_eq!(Completion::Fail(LIBUSB_ERROR_PIPE), completion(LIBUSB_ERROR_PIPE, &mut failures));
+
+        failures = 0;
+
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+        assert_eq!(Completion::Deliver, completion(LIBUSB_SUCCESS, &mut failures));
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+        assert_eq!(Completion::ClearHalt, completion(LIBUSB_ERROR_PIPE, &mut failures));
+    }
+
+    #[test]
+    fn it_fails_after_repeated_transfer_errors() {
+        let mut failures = 0;
+
+        assert_eq!(Completion::Resubmit, completion(LIBUSB_ERROR_IO, &mut failures));
+        assert_eq!(Completion::Resubmit, completion(LIBUSB_ERROR_OVERFLOW, &mut failures));
+        assert_eq!(Completion::Fail(LIBUSB_ERROR_IO), completion(LIBUSB_ERROR_IO, &mut failures));
+    }
+
+    #[test]
+    fn it_tolerates_intermittent_transfer_errors() {
+        let mut failures = 0;
+
+        for _ in 0..10 {
+            assert_eq!(Completion::Resubmit, completion(LIBUSB_ERROR_IO, &mut failures));
+            assert_eq!(Completion::Resubmit, completion(LIBUSB_ERROR_IO, &mut failures));
+            assert_eq!(Completion::Deliver, completion(LIBUSB_SUCCESS, &mut failures));
+        }
+    }
+}
@@ -4,13 +4,33 @@ extern crate bit_set;
 extern crate libusb_sys as libusb;
 extern crate libc;
 
+#[cfg(feature = "serde")]
+extern crate serde;
+
 pub use version::{LibraryVersion, version};
-pub use error::{Result, Error};
+pub use error::{Result, Error, ErrorKind, setlocale};
+pub use timeout::Timeout;
 
 pub use context::{Context, LogLevel};
+pub use backend::{Backend, BackendDevice, BackendHandle};
+pub use mock::{MockBackend, MockDevice};
 pub use device_list::{DeviceList, Devices};
 pub use device::Device;
+pub use device_id::DeviceId;
+pub use filter::DeviceFilter;
+pub use watcher::{DeviceWatcher, DeviceEvent};
 pub use device_handle::DeviceHandle;
+pub use endpoint_io::{EndpointReader, EndpointWriter};
+pub use transfer::{CancellationToken, TransferFlags, TransferStats};
+pub use bulk_stream::BulkStream;
+pub use bulk_sink::BulkSink;
+pub use device_buffer::DeviceBuffer;
+pub use interrupt_poller::{InterruptPoller, PollerControl};
+pub use trace::{TransferObserver, TransferRecord, SetupPacket};
+pub use metrics::{MetricsSnapshot, EndpointMetrics, LatencyHistogram};
+pub use session::{Session, SessionRecorder, ReplayBackend};
+pub use fault::{FaultInjector, FaultRule};
+pub use snapshot::DeviceSnapshot;
 
 pub use fields::{Speed, TransferType, SyncType, UsageType, Direction, RequestType, Recipient, Version, request_type};
 pub use device_descriptor::DeviceDescriptor;
@@ -27,11 +47,29 @@ mod test_helpers;
 #[macro_use]
 mod error;
 mod version;
+mod timeout;
 
+mod backend;
+mod dispatch;
+mod mock;
 mod context;
 mod device_list;
 mod device;
+mod device_id;
+mod filter;
+mod watcher;
 mod device_handle;
+mod endpoint_io;
+mod transfer;
+mod bulk_stream;
+mod bulk_sink;
+mod device_buffer;
+mod interrupt_poller;
+mod trace;
+mod metrics;
+mod session;
+mod fault;
+mod snapshot;
 
 mod fields;
 mod device_descriptor;
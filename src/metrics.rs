@@ -0,0 +1,376 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use error::ErrorKind;
+use fields::TransferType;
+
+/// Upper bounds of the latency histogram's buckets, in microseconds.
+const LATENCY_BUCKETS: [u64; 16] = [
+    100, 250, 500,
+    1_000, 2_500, 5_000,
+    10_000, 25_000, 50_000,
+    100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+    10_000_000,
+];
+
+/// A snapshot of the metrics collected by a device handle.
+///
+/// Metrics are collected by a [`DeviceHandle`](struct.DeviceHandle.html) after they are enabled
+/// with [`set_metrics_enabled()`](struct.DeviceHandle.html#method.set_metrics_enabled). They
+/// cover the control, bulk, and interrupt transfers that the handle performs, and are kept
+/// separately for each endpoint and transfer type.
+///
+/// ## Example
+///
+/// ```no_run
+/// # fn report(handle: &mut libusb::DeviceHandle) {
+/// handle.set_metrics_enabled(true);
+///
+/// // perform transfers
+///
+/// if let Some(metrics) = handle.metrics() {
+///     print!("{}", metrics.to_prometheus());
+/// }
+/// # }
+/// ```
+#[derive(Debug,Clone)]
+pub struct MetricsSnapshot {
+    endpoints: Vec<EndpointMetrics>,
+}
+
+impl MetricsSnapshot {
+    /// Returns the metrics of each endpoint and transfer type, ordered by endpoint address.
+    pub fn endpoints(&self) -> &[EndpointMetrics] {
+        &self.endpoints
+    }
+
+    /// Returns the metrics of an endpoint for one transfer type.
+    pub fn endpoint(&self, endpoint: u8, transfer_type: TransferType) -> Option<&EndpointMetrics> {
+        self.endpoints.iter().find(|metrics| metrics.endpoint == endpoint && metrics.transfer_type == transfer_type)
+    }
+
+    /// Renders the snapshot in the Prometheus text exposition format.
+    ///
+    /// Each metric is labeled with the endpoint address, e.g., `endpoint="0x81"`, and the transfer
+    /// type, e.g., `type="bulk"`. Errors are additionally labeled with the error kind, e.g.,
+    /// `kind="Pipe"`. Latencies are exported as a histogram in seconds.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP libusb_transfers_total Number of transfers that completed successfully.\n");
+        out.push_str("# TYPE libusb_transfers_total counter\n");
+        for metrics in self.endpoints.iter() {
+            writeln!(out, "libusb_transfers_total{{{}}} {}", metrics.labels(), metrics.transfers).unwrap();
+        }
+
+        out.push_str("# HELP libusb_transfer_bytes_total Number of bytes transferred.\n");
+        out.push_str("# TYPE libusb_transfer_bytes_total counter\n");
+        for metrics in self.endpoints.iter() {
+            writeln!(out, "libusb_transfer_bytes_total{{{}}} {}", metrics.labels(), metrics.bytes).unwrap();
+        }
+
+        out.push_str("# HELP libusb_transfer_errors_total Number of transfers that failed, by error kind.\n");
+        out.push_str("# TYPE libusb_transfer_errors_total counter\n");
+        for metrics in self.endpoints.iter() {
+            for &(kind, count) in metrics.errors.iter() {
+                writeln!(out, "libusb_transfer_errors_total{{{},kind=\"{:?}\"}} {}", metrics.labels(), kind, count).unwrap();
+            }
+        }
+
+        out.push_str("# HELP libusb_transfer_timeouts_total Number of transfers that timed out.\n");
+        out.push_str("# TYPE libusb_transfer_timeouts_total counter\n");
+        for metrics in self.endpoints.iter() {
+            writeln!(out, "libusb_transfer_timeouts_total{{{}}} {}", metrics.labels(), metrics.timeouts()).unwrap();
+        }
+
+        out.push_str("# HELP libusb_transfer_latency_seconds Time taken by transfers to complete.\n");
+        out.push_str("# TYPE libusb_transfer_latency_seconds histogram\n");
+        for metrics in self.endpoints.iter() {
+            let labels = metrics.labels();
+            let mut cumulative = 0;
+
+            for (bound, count) in LATENCY_BUCKETS.iter().zip(metrics.latency.counts.iter()) {
+                cumulative += *count;
+                writeln!(out, "libusb_transfer_latency_seconds_bucket{{{},le=\"{}\"}} {}", labels, seconds(*bound), cumulative).unwrap();
+            }
+
+            writeln!(out, "libusb_transfer_latency_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, metrics.latency.count).unwrap();
+            writeln!(out, "libusb_transfer_latency_seconds_sum{{{}}} {}", labels, seconds(metrics.latency.sum)).unwrap();
+            writeln!(out, "libusb_transfer_latency_seconds_count{{{}}} {}", labels, metrics.latency.count).unwrap();
+        }
+
+        out
+    }
+}
+
+
+/// Metrics of one endpoint and transfer type.
+#[derive(Debug,Clone)]
+pub struct EndpointMetrics {
+    endpoint: u8,
+    transfer_type: TransferType,
+    bytes: u64,
+    transfers: u64,
+    errors: Vec<(ErrorKind, u64)>,
+    latency: LatencyHistogram,
+}
+
+impl EndpointMetrics {
+    /// Returns the address of the endpoint.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Returns the transfer type.
+    pub fn transfer_type(&self) -> TransferType {
+        self.transfer_type
+    }
+
+    /// Returns the number of bytes transferred, including bytes transferred by failed transfers.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the number of transfers that completed successfully.
+    pub fn transfers(&self) -> u64 {
+        self.transfers
+    }
+
+    /// Returns the number of transfers that failed.
+    pub fn errors(&self) -> u64 {
+        self.errors.iter().map(|&(_, count)| count).sum()
+    }
+
+    /// Returns the number of transfers that failed with the given kind of error.
+    pub fn errors_of_kind(&self, kind: ErrorKind) -> u64 {
+        self.errors.iter().find(|&&(error_kind, _)| error_kind == kind).map_or(0, |&(_, count)| count)
+    }
+
+    /// Returns the number of transfers that timed out.
+    pub fn timeouts(&self) -> u64 {
+        self.errors_of_kind(ErrorKind::Timeout)
+    }
+
+    /// Returns the histogram of the latencies of the transfers.
+    pub fn latency(&self) -> &LatencyHistogram {
+        &self.latency
+    }
+
+    fn labels(&self) -> String {
+        let transfer_type = match self.transfer_type {
+            TransferType::Control => "control",
+            TransferType::Isochronous => "isochronous",
+            TransferType::Bulk => "bulk",
+            TransferType::Interrupt => "interrupt",
+        };
+
+        format!("endpoint=\"{:#04x}\",type=\"{}\"", self.endpoint, transfer_type)
+    }
+}
+
+
+/// A histogram of transfer latencies.
+///
+/// The histogram has buckets with upper bounds from 100 microseconds to 10 seconds. Latencies
+/// above the largest bound are only included in the total count and sum.
+#[derive(Debug,Clone)]
+pub struct LatencyHistogram {
+    counts: [u64; 16],
+    count: u64,
+    sum: u64,
+}
+
+impl LatencyHistogram {
+    /// Returns the upper bound and number of latencies of each bucket.
+    ///
+    /// The counts are not cumulative; each latency is counted in the first bucket whose upper
+    /// bound it doesn't exceed.
+    pub fn buckets(&self) -> Vec<(Duration, u64)> {
+        LATENCY_BUCKETS.iter().zip(self.counts.iter()).map(|(&bound, &count)| (micros_to_duration(bound), count)).collect()
+    }
+
+    /// Returns the number of latencies in the histogram.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Returns the sum of the latencies in the histogram.
+    pub fn sum(&self) -> Duration {
+        micros_to_duration(self.sum)
+    }
+
+    fn record(&mut self, latency: Duration) {
+        let micros = latency.as_secs() * 1_000_000 + (latency.subsec_nanos() / 1_000) as u64;
+
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&bound| micros <= bound) {
+            self.counts[i] += 1;
+        }
+
+        self.count += 1;
+        self.sum += micros;
+    }
+}
+
+fn micros_to_duration(micros: u64) -> Duration {
+    Duration::new(micros / 1_000_000, (micros % 1_000_000) as u32 * 1_000)
+}
+
+fn seconds(micros: u64) -> String {
+    if micros == 0 {
+        return "0".to_owned();
+    }
+
+    let mut text = format!("{}.{:06}", micros / 1_000_000, micros % 1_000_000);
+
+    while text.ends_with('0') {
+        text.pop();
+    }
+
+    if text.ends_with('.') {
+        text.pop();
+    }
+
+    text
+}
+
+
+#[doc(hidden)]
+pub fn new_snapshot() -> MetricsSnapshot {
+    MetricsSnapshot {
+        endpoints: Vec::new(),
+    }
+}
+
+#[doc(hidden)]
+pub fn record_transfer(snapshot: &mut MetricsSnapshot, transfer_type: TransferType, endpoint: u8, bytes: usize, error: Option<ErrorKind>, latency: Duration) {
+    let i = match snapshot.endpoints.iter().position(|metrics| metrics.endpoint == endpoint && metrics.transfer_type == transfer_type) {
+        Some(i) => i,
+        None => {
+            let i = snapshot.endpoints.iter().position(|metrics| metrics.endpoint > endpoint).unwrap_or(snapshot.endpoints.len());
+
+            snapshot.endpoints.insert(i, EndpointMetrics {
+                endpoint: endpoint,
+                transfer_type: transfer_type,
+                bytes: 0,
+                transfers: 0,
+                errors: Vec::new(),
+                latency: LatencyHistogram {
+                    counts: [0; 16],
+                    count: 0,
+                    sum: 0,
+                },
+            });
+
+            i
+        },
+    };
+
+    let metrics = &mut snapshot.endpoints[i];
+
+    metrics.bytes += bytes as u64;
+    metrics.latency.record(latency);
+
+    match error {
+        None => metrics.transfers += 1,
+        Some(kind) => {
+            match metrics.errors.iter().position(|&(error_kind, _)| error_kind == kind) {
+                Some(j) => metrics.errors[j].1 += 1,
+                None => metrics.errors.push((kind, 1)),
+            }
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use error::ErrorKind;
+    use fields::TransferType;
+
+    use super::{new_snapshot, record_transfer};
+
+    #[test]
+    fn it_counts_transfers_per_endpoint_and_type() {
+        let mut snapshot = new_snapshot();
+
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 512, None, Duration::from_millis(1));
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 100, None, Duration::from_millis(1));
+        record_transfer(&mut snapshot, TransferType::Control, 0x80, 18, None, Duration::from_millis(1));
+
+        let bulk = snapshot.endpoint(0x81, TransferType::Bulk).unwrap();
+        assert_eq!(612, bulk.bytes());
+        assert_eq!(2, bulk.transfers());
+
+        assert_eq!(1, snapshot.endpoint(0x80, TransferType::Control).unwrap().transfers());
+        assert!(snapshot.endpoint(0x81, TransferType::Interrupt).is_none());
+    }
+
+    #[test]
+    fn it_orders_endpoints_by_address() {
+        let mut snapshot = new_snapshot();
+
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 0, None, Duration::from_millis(1));
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x02, 0, None, Duration::from_millis(1));
+
+        let endpoints: Vec<u8> = snapshot.endpoints().iter().map(|metrics| metrics.endpoint()).collect();
+        assert_eq!(vec![0x02, 0x81], endpoints);
+    }
+
+    #[test]
+    fn it_counts_errors_by_kind() {
+        let mut snapshot = new_snapshot();
+
+        record_transfer(&mut snapshot, TransferType::Interrupt, 0x81, 0, Some(ErrorKind::Timeout), Duration::from_secs(1));
+        record_transfer(&mut snapshot, TransferType::Interrupt, 0x81, 0, Some(ErrorKind::Timeout), Duration::from_secs(1));
+        record_transfer(&mut snapshot, TransferType::Interrupt, 0x81, 0, Some(ErrorKind::Pipe), Duration::from_millis(1));
+
+        let metrics = snapshot.endpoint(0x81, TransferType::Interrupt).unwrap();
+        assert_eq!(0, metrics.transfers());
+        assert_eq!(3, metrics.errors());
+        assert_eq!(2, metrics.timeouts());
+        assert_eq!(1, metrics.errors_of_kind(ErrorKind::Pipe));
+    }
+
+    #[test]
+    fn it_sorts_latencies_into_buckets() {
+        let mut snapshot = new_snapshot();
+
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 0, None, Duration::new(0, 50_000));
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 0, None, Duration::from_millis(1));
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 0, None, Duration::from_secs(20));
+
+        let latency = snapshot.endpoint(0x81, TransferType::Bulk).unwrap().latency();
+        let buckets = latency.buckets();
+
+        assert_eq!((Duration::new(0, 100_000), 1), buckets[0]);
+        assert_eq!((Duration::from_millis(1), 1), buckets[3]);
+        assert_eq!(2, buckets.iter().map(|&(_, count)| count).sum::<u64>());
+        assert_eq!(3, latency.count());
+        assert_eq!(Duration::new(20, 1_050_000), latency.sum());
+    }
+
+    #[test]
+    fn it_renders_prometheus_text() {
+        let mut snapshot = new_snapshot();
+
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 512, None, Duration::new(0, 200_000));
+        record_transfer(&mut snapshot, TransferType::Bulk, 0x81, 0, Some(ErrorKind::Pipe), Duration::from_millis(3));
+
+        let text = snapshot.to_prometheus();
+
+        assert!(text.contains("# TYPE libusb_transfers_total counter\n"));
+        assert!(text.contains("libusb_transfers_total{endpoint=\"0x81\",type=\"bulk\"} 1\n"));
+        assert!(text.contains("libusb_transfer_bytes_total{endpoint=\"0x81\",type=\"bulk\"} 512\n"));
+        assert!(text.contains("libusb_transfer_errors_total{endpoint=\"0x81\",type=\"bulk\",kind=\"Pipe\"} 1\n"));
+        assert!(text.contains("libusb_transfer_timeouts_total{endpoint=\"0x81\",type=\"bulk\"} 0\n"));
+        assert!(text.contains("libusb_transfer_latency_seconds_bucket{endpoint=\"0x81\",type=\"bulk\",le=\"0.0001\"} 0\n"));
+        assert!(text.contains("libusb_transfer_latency_seconds_bucket{endpoint=\"0x81\",type=\"bulk\",le=\"0.00025\"} 1\n"));
+        assert!(text.contains("libusb_transfer_latency_seconds_bucket{endpoint=\"0x81\",type=\"bulk\",le=\"0.005\"} 2\n"));
+        assert!(text.contains("libusb_transfer_latency_seconds_bucket{endpoint=\"0x81\",type=\"bulk\",le=\"+Inf\"} 2\n"));
+        assert!(text.contains("libusb_transfer_latency_seconds_sum{endpoint=\"0x81\",type=\"bulk\"} 0.0032\n"));
+        assert!(text.contains("libusb_transfer_latency_seconds_count{endpoint=\"0x81\",type=\"bulk\"} 2\n"));
+    }
+}
@@ -0,0 +1,890 @@
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use libusb::*;
+
+use backend::{Backend, BackendDevice, BackendHandle, descriptor_request, reply_descriptor};
+use config_descriptor;
+use error::{Error, ErrorKind};
+use fields::{Speed, TransferType};
+use trace::SetupPacket;
+
+// the UTF-16 code units that fit in a string descriptor after its two-byte header
+const MAX_STRING_UNITS: usize = 126;
+
+/// A backend that provides virtual devices for testing.
+///
+/// A mock backend is passed to [`Context::with_backend()`](struct.Context.html#method.with_backend),
+/// so that the code under test uses the normal API, e.g., `Context::devices()`, `Device::open()`,
+/// and `DeviceHandle::read_bulk()`, while talking to the backend's
+/// [`MockDevice`](struct.MockDevice.html)s. Cloning a backend returns a new reference to the
+/// same backend, so that a test can keep adding and removing devices after giving the backend to
+/// a context.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use libusb::{Context, MockBackend, MockDevice, TransferType};
+///
+/// let device = MockDevice::new(0x1234, 0x5678)
+///     .product("Widget")
+///     .interface(0, 0xFF)
+///     .endpoint(0, 0x81, TransferType::Bulk, 512);
+///
+/// device.push_in(0x81, vec![1, 2, 3]);
+///
+/// let backend = MockBackend::new();
+/// backend.add_device(device.clone());
+///
+/// let context = Context::with_backend(backend);
+/// let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+/// handle.claim_interface(0).unwrap();
+///
+/// let mut buf = [0; 512];
+/// assert_eq!(3, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap());
+///
+/// handle.write_bulk(0x02, &[4, 5], Duration::from_secs(1)).unwrap();
+/// assert_eq!(vec![vec![4, 5]], device.take_out(0x02));
+/// ```
+#[derive(Clone,Default)]
+pub struct MockBackend {
+    devices: Arc<Mutex<Vec<Arc<MockDevice>>>>,
+}
+
+impl MockBackend {
+    /// Creates a backend without devices.
+    pub fn new() -> Self {
+        MockBackend::default()
+    }
+
+    /// Connects a device to the backend.
+    pub fn add_device(&self, device: MockDevice) {
+        device.state().connected = true;
+        self.devices.lock().unwrap().push(Arc::new(device));
+    }
+
+    /// Disconnects a device from the backend.
+    ///
+    /// Open handles to the device fail with `NoDevice` afterwards.
+    pub fn remove_device(&self, device: &MockDevice) {
+        device.disconnect();
+        self.devices.lock().unwrap().retain(|other| !Arc::ptr_eq(&other.inner, &device.inner));
+    }
+}
+
+impl Backend for MockBackend {
+    fn devices(&self) -> ::Result<Vec<Arc<BackendDevice>>> {
+        Ok(self.devices.lock().unwrap().iter()
+            .filter(|device| device.state().connected)
+            .map(|device| device.clone() as Arc<BackendDevice>)
+            .collect())
+    }
+}
+
+impl fmt::Debug for MockBackend {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("MockBackend")
+            .field("devices", &self.devices.lock().unwrap().len())
+            .finish()
+    }
+}
+
+
+/// A virtual device provided by a [`MockBackend`](struct.MockBackend.html).
+///
+/// A mock device is declared with builder methods. Its descriptors are generated from the
+/// declared interfaces, endpoints, and strings, or can be given as raw bytes. The device answers
+/// standard `GET_DESCRIPTOR` requests by itself and passes every other control request to the
+/// handler set with [`on_control()`](#method.on_control). Requests without a handler stall with
+/// `Pipe`, as a real device does.
+///
+/// Data for IN endpoints is queued with [`push_in()`](#method.push_in). A read from an endpoint
+/// whose queue is empty fails immediately with `Timeout`. Data written to OUT endpoints is
+/// collected and returned by [`take_out()`](#method.take_out).
+///
+/// Cloning a device returns a new reference to the same device.
+#[derive(Clone)]
+pub struct MockDevice {
+    inner: Arc<Mutex<MockState>>,
+}
+
+struct MockState {
+    bus_number: u8,
+    address: u8,
+    port_number: u8,
+    speed: Speed,
+    vendor_id: u16,
+    product_id: u16,
+    device_descriptor: Option<Vec<u8>>,
+    config_descriptors: Vec<Vec<u8>>,
+    interfaces: Vec<(u8, u8, Vec<(u8, TransferType, u16)>)>,
+    languages: Vec<u16>,
+    strings: Vec<(u8, String)>,
+    string_indexes: [u8; 3],
+    control_handler: Option<Box<FnMut(&SetupPacket, &mut [u8]) -> ::Result<usize> + Send>>,
+    in_queues: HashMap<u8, VecDeque<::Result<Vec<u8>>>>,
+    out_data: HashMap<u8, Vec<Vec<u8>>>,
+    active_config: u8,
+    claimed: Vec<u8>,
+    kernel_drivers: Vec<u8>,
+    detach_kernel_driver: bool,
+    connected: bool,
+}
+
+impl MockDevice {
+    /// Creates a device with the given vendor ID and product ID.
+    ///
+    /// The device is a full-speed USB 2.0 device on port 1 of bus 1 with address 1. It has one
+    /// configuration without interfaces, which is active.
+    pub fn new(vendor_id: u16, product_id: u16) -> Self {
+        MockDevice {
+            inner: Arc::new(Mutex::new(MockState {
+                bus_number: 1,
+                address: 1,
+                port_number: 1,
+                speed: Speed::Full,
+                vendor_id: vendor_id,
+                product_id: product_id,
+                device_descriptor: None,
+                config_descriptors: Vec::new(),
+                interfaces: Vec::new(),
+                languages: vec![0x0409],
+                strings: Vec::new(),
+                string_indexes: [0; 3],
+                control_handler: None,
+                in_queues: HashMap::new(),
+                out_data: HashMap::new(),
+                active_config: 1,
+                claimed: Vec::new(),
+                kernel_drivers: Vec::new(),
+                detach_kernel_driver: true,
+                connected: true,
+            })),
+        }
+    }
+
+    /// Sets the number of the bus that the device is connected to.
+    pub fn bus_number(self, bus_number: u8) -> Self {
+        self.state().bus_number = bus_number;
+        self
+    }
+
+    /// Sets the device's address on its bus.
+    pub fn address(self, address: u8) -> Self {
+        self.state().address = address;
+        self
+    }
+
+    /// Sets the number of the port that the device is connected to.
+    pub fn port_number(self, port_number: u8) -> Self {
+        self.state().port_number = port_number;
+        self
+    }
+
+    /// Sets the device's connection speed.
+    pub fn speed(self, speed: Speed) -> Self {
+        self.state().speed = speed;
+        self
+    }
+
+    /// Sets the manufacturer string.
+    pub fn manufacturer(self, manufacturer: &str) -> Self {
+        self.device_string(0, manufacturer)
+    }
+
+    /// Sets the product string.
+    pub fn product(self, product: &str) -> Self {
+        self.device_string(1, product)
+    }
+
+    /// Sets the serial number string.
+    pub fn serial_number(self, serial_number: &str) -> Self {
+        self.device_string(2, serial_number)
+    }
+
+    /// Sets the string descriptor with the given index.
+    ///
+    /// Strings are served in each of the device's languages.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string is longer than the 126 UTF-16 code units that fit in a string
+    /// descriptor.
+    pub fn string(self, index: u8, string: &str) -> Self {
+        assert!(string.encode_utf16().count() <= MAX_STRING_UNITS, "string descriptor {} is too long", index);
+
+        {
+            let mut state = self.state();
+
+            state.strings.retain(|&(other, _)| other != index);
+            state.strings.push((index, string.to_owned()));
+        }
+
+        self
+    }
+
+    /// Sets the language IDs that the device reports for its string descriptors.
+    ///
+    /// The default language is US English (`0x0409`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are more than the 126 language IDs that fit in a string descriptor.
+    pub fn languages(self, languages: &[u16]) -> Self {
+        assert!(languages.len() <= MAX_STRING_UNITS, "too many language IDs");
+
+        self.state().languages = languages.to_vec();
+        self
+    }
+
+    /// Adds an interface to the generated configuration.
+    pub fn interface(self, number: u8, class_code: u8) -> Self {
+        self.state().interfaces.push((number, class_code, Vec::new()));
+        self
+    }
+
+    /// Adds an endpoint to an interface of the generated configuration.
+    ///
+    /// The interface is added if it hasn't been declared yet.
+    pub fn endpoint(self, iface: u8, address: u8, transfer_type: TransferType, max_packet_size: u16) -> Self {
+        {
+            let mut state = self.state();
+
+            if !state.interfaces.iter().any(|&(number, _, _)| number == iface) {
+                state.interfaces.push((iface, 0xFF, Vec::new()));
+            }
+
+            let interface = state.interfaces.iter_mut().find(|&&mut (number, _, _)| number == iface).unwrap();
+            interface.2.push((address, transfer_type, max_packet_size));
+        }
+
+        self
+    }
+
+    /// Replaces the generated device descriptor with the given bytes.
+    pub fn device_descriptor(self, bytes: &[u8]) -> Self {
+        self.state().device_descriptor = Some(bytes.to_vec());
+        self
+    }
+
+    /// Replaces the generated configuration with the given configuration descriptors.
+    ///
+    /// Each element holds the bytes of a configuration descriptor and the descriptors that
+    /// follow it. The first configuration is made active.
+    pub fn config_descriptors(self, configs: Vec<Vec<u8>>) -> Self {
+        {
+            let mut state = self.state();
+
+            state.active_config = configs.first().and_then(|config| config.get(5)).cloned().unwrap_or(0);
+            state.config_descriptors = configs;
+        }
+
+        self
+    }
+
+    /// Sets the handler for control requests that the device doesn't answer by itself.
+    ///
+    /// The handler is called with the setup packet and a buffer. For requests that read from
+    /// the device, the handler fills the buffer and returns the number of bytes it filled. For
+    /// requests that write to the device, the buffer holds the data written by the host.
+    pub fn on_control<F>(self, handler: F) -> Self
+        where F: FnMut(&SetupPacket, &mut [u8]) -> ::Result<usize> + Send + 'static
+    {
+        self.state().control_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Attaches a kernel driver to an interface.
+    ///
+    /// The interface can't be claimed while its kernel driver is attached.
+    pub fn kernel_driver(self, iface: u8) -> Self {
+        {
+            let mut state = self.state();
+
+            if !state.kernel_drivers.contains(&iface) {
+                state.kernel_drivers.push(iface);
+            }
+        }
+
+        self
+    }
+
+    /// Sets whether kernel drivers can be detached from the device's interfaces.
+    ///
+    /// Kernel drivers can be detached by default.
+    pub fn detach_kernel_driver(self, supported: bool) -> Self {
+        self.state().detach_kernel_driver = supported;
+        self
+    }
+
+    /// Queues data to be returned by the next read from an IN endpoint.
+    pub fn push_in(&self, endpoint: u8, data: Vec<u8>) {
+        self.state().in_queues.entry(endpoint).or_insert_with(VecDeque::new).push_back(Ok(data));
+    }
+
+    /// Queues an error to be returned by the next read from an IN endpoint.
+    pub fn push_in_error(&self, endpoint: u8, kind: ErrorKind) {
+        self.state().in_queues.entry(endpoint).or_insert_with(VecDeque::new).push_back(Err(Error::new(kind)));
+    }
+
+    /// Returns and clears the data written to an OUT endpoint, one element per transfer.
+    pub fn take_out(&self, endpoint: u8) -> Vec<Vec<u8>> {
+        self.state().out_data.remove(&endpoint).unwrap_or_else(Vec::new)
+    }
+
+    /// Returns the numbers of the interfaces that are claimed.
+    pub fn claimed_interfaces(&self) -> Vec<u8> {
+        self.state().claimed.clone()
+    }
+
+    /// Disconnects the device, so that open handles fail with `NoDevice`.
+    pub fn disconnect(&self) {
+        self.state().connected = false;
+    }
+
+    fn device_string(self, i: usize, string: &str) -> Self {
+        let index = {
+            let mut state = self.state();
+
+            if state.string_indexes[i] == 0 {
+                state.string_indexes[i] = i as u8 + 1;
+            }
+
+            state.string_indexes[i]
+        };
+
+        self.string(index, string)
+    }
+
+    fn state<'a>(&'a self) -> MutexGuard<'a, MockState> {
+        self.inner.lock().unwrap()
+    }
+
+    fn connected_state<'a>(&'a self) -> ::Result<MutexGuard<'a, MockState>> {
+        let state = self.state();
+
+        if state.connected {
+            Ok(state)
+        }
+        else {
+            Err(Error::new(ErrorKind::NoDevice))
+        }
+    }
+}
+
+impl MockState {
+    fn device_descriptor(&self) -> Vec<u8> {
+        if let Some(ref bytes) = self.device_descriptor {
+            return bytes.clone();
+        }
+
+        let num_configurations = cmp::max(self.config_descriptors.len(), 1) as u8;
+
+        vec![
+            18, LIBUSB_DT_DEVICE,
+            0x00, 0x02,
+            0, 0, 0, 64,
+            self.vendor_id as u8, (self.vendor_id >> 8) as u8,
+            self.product_id as u8, (self.product_id >> 8) as u8,
+            0x00, 0x01,
+            self.string_indexes[0], self.string_indexes[1], self.string_indexes[2],
+            num_configurations,
+        ]
+    }
+
+    fn config_descriptor(&self, config_index: u8) -> ::Result<Vec<u8>> {
+        if !self.config_descriptors.is_empty() {
+            return self.config_descriptors.get(config_index as usize).cloned().ok_or(Error::new(ErrorKind::NotFound));
+        }
+
+        if config_index != 0 {
+            return Err(Error::new(ErrorKind::NotFound));
+        }
+
+        let mut bytes = vec![9, LIBUSB_DT_CONFIG, 0, 0, self.interfaces.len() as u8, 1, 0, 0x80, 50];
+
+        for &(number, class_code, ref endpoints) in self.interfaces.iter() {
+            bytes.extend_from_slice(&[9, LIBUSB_DT_INTERFACE, number, 0, endpoints.len() as u8, class_code, 0, 0, 0]);
+
+            for &(address, transfer_type, max_packet_size) in endpoints.iter() {
+                let attributes = match transfer_type {
+                    TransferType::Control => LIBUSB_TRANSFER_TYPE_CONTROL,
+                    TransferType::Isochronous => LIBUSB_TRANSFER_TYPE_ISOCHRONOUS,
+                    TransferType::Bulk => LIBUSB_TRANSFER_TYPE_BULK,
+                    TransferType::Interrupt => LIBUSB_TRANSFER_TYPE_INTERRUPT,
+                };
+
+                let interval = if transfer_type == TransferType::Interrupt { 1 } else { 0 };
+
+                bytes.extend_from_slice(&[7, LIBUSB_DT_ENDPOINT, address, attributes, max_packet_size as u8, (max_packet_size >> 8) as u8, interval]);
+            }
+        }
+
+        let total_length = bytes.len();
+        bytes[2] = total_length as u8;
+        bytes[3] = (total_length >> 8) as u8;
+
+        Ok(bytes)
+    }
+
+    fn active_config_descriptor(&self) -> ::Result<Vec<u8>> {
+        let num_configs = cmp::max(self.config_descriptors.len(), 1);
+
+        for config_index in 0..num_configs {
+            let bytes = try!(self.config_descriptor(config_index as u8));
+
+            if bytes.get(5) == Some(&self.active_config) {
+                return Ok(bytes);
+            }
+        }
+
+        Err(Error::new(ErrorKind::NotFound))
+    }
+
+    fn has_setting(&self, iface: u8, setting: u8) -> ::Result<bool> {
+        let config = try!(config_descriptor::from_bytes(&try!(self.active_config_descriptor())));
+
+        Ok(config.interfaces().any(|interface| {
+            interface.number() == iface && interface.descriptors().any(|descriptor| descriptor.setting_number() == setting)
+        }))
+    }
+
+    fn string_descriptor(&self, index: u8, language: u16) -> Option<Vec<u8>> {
+        let utf16: Vec<u16> = if index == 0 {
+            self.languages.clone()
+        }
+        else if self.languages.contains(&language) {
+            match self.strings.iter().find(|&&(other, _)| other == index) {
+                Some(&(_, ref string)) => string.encode_utf16().collect(),
+                None => return None,
+            }
+        }
+        else {
+            return None;
+        };
+
+        let mut bytes = vec![(2 + utf16.len() * 2) as u8, LIBUSB_DT_STRING];
+
+        for unit in utf16 {
+            bytes.push(unit as u8);
+            bytes.push((unit >> 8) as u8);
+        }
+
+        Some(bytes)
+    }
+
+    fn descriptor(&self, descriptor_type: u8, index: u8, language: u16) -> Option<Vec<u8>> {
+        match descriptor_type {
+            LIBUSB_DT_DEVICE => Some(self.device_descriptor()),
+            LIBUSB_DT_CONFIG => self.config_descriptor(index).ok(),
+            LIBUSB_DT_STRING => self.string_descriptor(index, language),
+            _ => None,
+        }
+    }
+}
+
+impl BackendDevice for MockDevice {
+    fn bus_number(&self) -> u8 {
+        self.state().bus_number
+    }
+
+    fn address(&self) -> u8 {
+        self.state().address
+    }
+
+    fn port_number(&self) -> u8 {
+        self.state().port_number
+    }
+
+    fn speed(&self) -> Speed {
+        self.state().speed
+    }
+
+    fn device_descriptor(&self) -> ::Result<Vec<u8>> {
+        Ok(self.state().device_descriptor())
+    }
+
+    fn config_descriptor(&self, config_index: u8) -> ::Result<Vec<u8>> {
+        self.state().config_descriptor(config_index)
+    }
+
+    fn active_config_descriptor(&self) -> ::Result<Vec<u8>> {
+        self.state().active_config_descriptor()
+    }
+
+    fn open(&self) -> ::Result<Box<BackendHandle>> {
+        drop(try!(self.connected_state()));
+
+        Ok(Box::new(MockHandle { device: self.clone() }))
+    }
+}
+
+impl fmt::Debug for MockDevice {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.state();
+
+        fmt.debug_struct("MockDevice")
+            .field("bus_number", &state.bus_number)
+            .field("address", &state.address)
+            .field("vendor_id", &state.vendor_id)
+            .field("product_id", &state.product_id)
+            .field("connected", &state.connected)
+            .finish()
+    }
+}
+
+
+struct MockHandle {
+    device: MockDevice,
+}
+
+impl BackendHandle for MockHandle {
+    fn active_configuration(&self) -> ::Result<u8> {
+        Ok(try!(self.device.connected_state()).active_config)
+    }
+
+    fn set_active_configuration(&self, config: Option<u8>) -> ::Result<()> {
+        let mut state = try!(self.device.connected_state());
+
+        match config {
+            Some(config) => {
+                let num_configs = cmp::max(state.config_descriptors.len(), 1);
+                let exists = (0..num_configs).any(|i| {
+                    state.config_descriptor(i as u8).ok().and_then(|bytes| bytes.get(5).cloned()) == Some(config)
+                });
+
+                if !exists {
+                    return Err(Error::new(ErrorKind::NotFound));
+                }
+
+                state.active_config = config;
+            },
+            None => state.active_config = 0,
+        }
+
+        state.claimed.clear();
+        Ok(())
+    }
+
+    fn reset(&self) -> ::Result<()> {
+        let mut state = try!(self.device.connected_state());
+
+        state.claimed.clear();
+        Ok(())
+    }
+
+    fn claim_interface(&self, iface: u8) -> ::Result<()> {
+        let mut state = try!(self.device.connected_state());
+
+        if !try!(state.has_setting(iface, 0)) {
+            return Err(Error::new(ErrorKind::NotFound));
+        }
+
+        if state.kernel_drivers.contains(&iface) {
+            return Err(Error::new(ErrorKind::Busy));
+        }
+
+        if !state.claimed.contains(&iface) {
+            state.claimed.push(iface);
+        }
+
+        Ok(())
+    }
+
+    fn release_interface(&self, iface: u8) -> ::Result<()> {
+        let mut state = try!(self.device.connected_state());
+
+        match state.claimed.iter().position(|&claimed| claimed == iface) {
+            Some(i) => {
+                state.claimed.remove(i);
+                Ok(())
+            },
+            None => Err(Error::new(ErrorKind::NotFound)),
+        }
+    }
+
+    fn set_alternate_setting(&self, iface: u8, setting: u8) -> ::Result<()> {
+        let state = try!(self.device.connected_state());
+
+        if !state.claimed.contains(&iface) || !try!(state.has_setting(iface, setting)) {
+            return Err(Error::new(ErrorKind::NotFound));
+        }
+
+        Ok(())
+    }
+
+    fn clear_halt(&self, _endpoint: u8) -> ::Result<()> {
+        self.device.connected_state().map(|_| ())
+    }
+
+    fn kernel_driver_active(&self, iface: u8) -> ::Result<bool> {
+        Ok(try!(self.device.connected_state()).kernel_drivers.contains(&iface))
+    }
+
+    fn supports_detach_kernel_driver(&self) -> bool {
+        self.device.state().detach_kernel_driver
+    }
+
+    fn detach_kernel_driver(&self, iface: u8) -> ::Result<()> {
+        let mut state = try!(self.device.connected_state());
+
+        if !state.detach_kernel_driver {
+            return Err(Error::new(ErrorKind::NotSupported));
+        }
+
+        match state.kernel_drivers.iter().position(|&attached| attached == iface) {
+            Some(i) => {
+                state.kernel_drivers.remove(i);
+                Ok(())
+            },
+            None => Err(Error::new(ErrorKind::NotFound)),
+        }
+    }
+
+    fn attach_kernel_driver(&self, iface: u8) -> ::Result<()> {
+        let mut state = try!(self.device.connected_state());
+
+        if !state.detach_kernel_driver {
+            return Err(Error::new(ErrorKind::NotSupported));
+        }
+
+        if state.claimed.contains(&iface) || state.kernel_drivers.contains(&iface) {
+            return Err(Error::new(ErrorKind::Busy));
+        }
+
+        state.kernel_drivers.push(iface);
+        Ok(())
+    }
+
+    fn read_control(&self, setup: &SetupPacket, buf: &mut [u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let mut state = try!(self.device.connected_state());
+
+        if let Some((descriptor_type, index, language)) = descriptor_request(setup) {
+            // other descriptor types are left to the control handler
+            if [LIBUSB_DT_DEVICE, LIBUSB_DT_CONFIG, LIBUSB_DT_STRING].contains(&descriptor_type) {
+                let bytes = state.descriptor(descriptor_type, index, language);
+
+                return reply_descriptor(bytes.as_ref().map(|bytes| &bytes[..]), buf);
+            }
+        }
+
+        match state.control_handler {
+            Some(ref mut handler) => handler(setup, buf),
+            None => Err(Error::new(ErrorKind::Pipe)),
+        }
+    }
+
+    fn write_control(&self, setup: &SetupPacket, buf: &[u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let mut state = try!(self.device.connected_state());
+        let mut data = buf.to_vec();
+
+        match state.control_handler {
+            Some(ref mut handler) => handler(setup, &mut data),
+            None => Err(Error::new(ErrorKind::Pipe)),
+        }
+    }
+
+    fn read(&self, _transfer_type: TransferType, endpoint: u8, buf: &mut [u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let mut state = try!(self.device.connected_state());
+
+        match state.in_queues.get_mut(&endpoint).and_then(|queue| queue.pop_front()) {
+            Some(Ok(data)) => {
+                if data.len() > buf.len() {
+                    return Err(Error::new(ErrorKind::Overflow));
+                }
+
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            },
+            Some(Err(err)) => Err(err),
+            None => Err(Error::new(ErrorKind::Timeout)),
+        }
+    }
+
+    fn write(&self, _transfer_type: TransferType, endpoint: u8, buf: &[u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let mut state = try!(self.device.connected_state());
+
+        state.out_data.entry(endpoint).or_insert_with(Vec::new).push(buf.to_vec());
+        Ok(buf.len())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use context::Context;
+    use error::ErrorKind;
+    use fields::{Direction, Recipient, RequestType, TransferType, request_type};
+    use language;
+    use test_helpers::context_with;
+
+    use super::{MockBackend, MockDevice};
+
+    #[test]
+    fn it_lists_devices_with_descriptors() {
+        let device = MockDevice::new(0x1234, 0x5678).bus_number(3).address(7).port_number(2).interface(0, 0x03).endpoint(0, 0x81, TransferType::Interrupt, 8);
+        let context = context_with(&[&device]);
+
+        let devices = context.devices().unwrap();
+        assert_eq!(1, devices.len());
+
+        let device = devices.iter().next().unwrap();
+        assert_eq!(3, device.bus_number());
+        assert_eq!(7, device.address());
+        assert_eq!(2, device.port_number());
+
+        let descriptor = device.device_descriptor().unwrap();
+        assert_eq!(0x1234, descriptor.vendor_id());
+        assert_eq!(0x5678, descriptor.product_id());
+
+        let config = device.active_config_descriptor().unwrap();
+        let interface = config.interfaces().next().unwrap();
+        let setting = interface.descriptors().next().unwrap();
+        let endpoint = setting.endpoint_descriptors().next().unwrap();
+
+        assert_eq!(0x03, setting.class_code());
+        assert_eq!(0x81, endpoint.address());
+        assert_eq!(TransferType::Interrupt, endpoint.transfer_type());
+        assert_eq!(8, endpoint.max_packet_size());
+    }
+
+    #[test]
+    fn it_omits_removed_devices() {
+        let device = MockDevice::new(0x1234, 0x5678);
+        let backend = MockBackend::new();
+        backend.add_device(device.clone());
+
+        let context = Context::with_backend(backend.clone());
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        backend.remove_device(&device);
+
+        assert_eq!(0, context.devices().unwrap().len());
+        assert_eq!(ErrorKind::NoDevice, handle.active_configuration().unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_reads_strings() {
+        let device = MockDevice::new(0x1234, 0x5678).manufacturer("Acme").product("Widget");
+        let context = context_with(&[&device]);
+
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        let descriptor = context.devices().unwrap().iter().next().unwrap().device_descriptor().unwrap();
+
+        let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+        assert_eq!(vec![language::from_lang_id(0x0409)], languages);
+
+        assert_eq!("Acme", handle.read_manufacturer_string(languages[0], &descriptor, Duration::from_secs(1)).unwrap());
+        assert_eq!("Widget", handle.read_product_string(languages[0], &descriptor, Duration::from_secs(1)).unwrap());
+        assert_eq!(ErrorKind::InvalidParam, handle.read_serial_number_string(languages[0], &descriptor, Duration::from_secs(1)).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_reads_longest_string() {
+        let product = "x".repeat(126);
+        let device = MockDevice::new(0x1234, 0x5678).product(&product);
+        let context = context_with(&[&device]);
+
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        let descriptor = context.devices().unwrap().iter().next().unwrap().device_descriptor().unwrap();
+        let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+
+        assert_eq!(product, handle.read_product_string(languages[0], &descriptor, Duration::from_secs(1)).unwrap());
+    }
+
+    #[test]
+    #[should_panic(expected = "string descriptor 5 is too long")]
+    fn it_rejects_strings_that_dont_fit_in_descriptor() {
+        MockDevice::new(0x1234, 0x5678).string(5, &"x".repeat(127));
+    }
+
+    #[test]
+    #[should_panic(expected = "too many language IDs")]
+    fn it_rejects_languages_that_dont_fit_in_descriptor() {
+        MockDevice::new(0x1234, 0x5678).languages(&[0x0409; 127]);
+    }
+
+    #[test]
+    fn it_passes_control_requests_to_handler() {
+        let device = MockDevice::new(0x1234, 0x5678).on_control(|setup, buf| {
+            match setup.request() {
+                0x01 => {
+                    buf[0] = 42;
+                    Ok(1)
+                },
+                0x02 => Ok(buf.len()),
+                _ => Err(::error::Error::new(ErrorKind::Pipe)),
+            }
+        });
+
+        let context = context_with(&[&device]);
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        let read_type = request_type(Direction::In, RequestType::Vendor, Recipient::Device);
+        let write_type = request_type(Direction::Out, RequestType::Vendor, Recipient::Device);
+        let mut buf = [0; 4];
+
+        assert_eq!(1, handle.read_control(read_type, 0x01, 0, 0, &mut buf, Duration::from_secs(1)).unwrap());
+        assert_eq!(42, buf[0]);
+        assert_eq!(3, handle.write_control(write_type, 0x02, 0, 0, &[1, 2, 3], Duration::from_secs(1)).unwrap());
+
+        let err = handle.read_control(read_type, 0x03, 0, 0, &mut buf, Duration::from_secs(1)).unwrap_err();
+        assert_eq!(ErrorKind::Pipe, err.kind());
+        assert_eq!(Some((read_type, 0x03)), err.request());
+    }
+
+    #[test]
+    fn it_transfers_endpoint_data() {
+        let device = MockDevice::new(0x1234, 0x5678).endpoint(0, 0x81, TransferType::Bulk, 64).endpoint(0, 0x02, TransferType::Bulk, 64);
+        let context = context_with(&[&device]);
+
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        handle.claim_interface(0).unwrap();
+        assert_eq!(vec![0], device.claimed_interfaces());
+
+        device.push_in(0x81, vec![1, 2, 3]);
+        device.push_in_error(0x81, ErrorKind::Pipe);
+
+        let mut buf = [0; 64];
+        assert_eq!(3, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap());
+        assert_eq!([1, 2, 3], buf[..3]);
+        assert_eq!(ErrorKind::Pipe, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(ErrorKind::Timeout, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+
+        assert_eq!(2, handle.write_bulk(0x02, &[4, 5], Duration::from_secs(1)).unwrap());
+        assert_eq!(vec![vec![4, 5]], device.take_out(0x02));
+        assert!(device.take_out(0x02).is_empty());
+    }
+
+    #[test]
+    fn it_validates_endpoints_against_generated_configuration() {
+        let device = MockDevice::new(0x1234, 0x5678).endpoint(0, 0x81, TransferType::Bulk, 64);
+        let context = context_with(&[&device]);
+
+        let mut handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        handle.set_endpoint_validation(true);
+
+        let mut buf = [0; 64];
+        assert_eq!(ErrorKind::InterfaceNotClaimed, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(ErrorKind::UnknownEndpoint, handle.read_bulk(0x83, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(ErrorKind::NotFound, handle.claim_interface(1).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_does_not_support_libusb_only_features() {
+        let device = MockDevice::new(0x1234, 0x5678).endpoint(0, 0x81, TransferType::Bulk, 64);
+        let context = context_with(&[&device]);
+
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+
+        let mut buf = [0; 64];
+        assert_eq!(ErrorKind::NotSupported, handle.read_stream(0x81, 1, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        assert_eq!(false, handle.kernel_driver_active(0).unwrap());
+    }
+}
@@ -0,0 +1,818 @@
+use std::cmp;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use libusb::*;
+
+use backend::{Backend, BackendDevice, BackendHandle};
+use device::{self, Device};
+use error::{self, Error, ErrorKind};
+use fields::{Speed, TransferType};
+use trace::{SetupPacket, TransferObserver, TransferRecord};
+
+const SESSION_VERSION: u32 = 1;
+
+/// A recorded interaction with a device.
+///
+/// A session holds the descriptors of a device and every control, bulk, and interrupt transfer
+/// that was performed through a `DeviceHandle` while it was recorded, including the requests that
+/// read string descriptors. Sessions are recorded with a
+/// [`SessionRecorder`](struct.SessionRecorder.html) and replayed with a
+/// [`ReplayBackend`](struct.ReplayBackend.html).
+///
+/// Sessions are saved as text files with one descriptor or transfer per line, so that they can
+/// be reviewed and kept under version control.
+#[derive(Debug,Clone,PartialEq,Eq)]
+pub struct Session {
+    bus_number: u8,
+    address: u8,
+    speed: Speed,
+    active_config: u8,
+    device_descriptor: Vec<u8>,
+    config_descriptors: Vec<Vec<u8>>,
+    transfers: Vec<RecordedTransfer>,
+}
+
+#[derive(Debug,Clone,PartialEq,Eq)]
+struct RecordedTransfer {
+    transfer_type: TransferType,
+    endpoint: u8,
+    setup: Option<SetupPacket>,
+    requested_length: usize,
+    actual_length: usize,
+    error: Option<ErrorKind>,
+    data: Vec<u8>,
+}
+
+impl Session {
+    /// Returns the number of transfers in the session.
+    pub fn num_transfers(&self) -> usize {
+        self.transfers.len()
+    }
+
+    /// Loads a session from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Session> {
+        Session::read_from(try!(File::open(path)))
+    }
+
+    /// Saves the session to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+
+        try!(self.write_to(&mut writer));
+        writer.flush()
+    }
+
+    /// Reads a session from a reader.
+    pub fn read_from<R: Read>(reader: R) -> io::Result<Session> {
+        let mut session = Session {
+            bus_number: 0,
+            address: 0,
+            speed: Speed::Unknown,
+            active_config: 0,
+            device_descriptor: Vec::new(),
+            config_descriptors: Vec::new(),
+            transfers: Vec::new(),
+        };
+
+        try!(read_records(reader, "session", SESSION_VERSION, |fields| {
+            match fields[0] {
+                "device" if fields.len() == 5 => {
+                    match (fields[1].parse(), fields[2].parse(), speed_from_name(fields[3]), fields[4].parse()) {
+                        (Ok(bus_number), Ok(address), Some(speed), Ok(active_config)) => {
+                            session.bus_number = bus_number;
+                            session.address = address;
+                            session.speed = speed;
+                            session.active_config = active_config;
+                            Some(())
+                        },
+                        _ => None,
+                    }
+                },
+                "descriptor" if fields.len() == 2 => {
+                    decode_hex(fields[1]).map(|bytes| session.device_descriptor = bytes)
+                },
+                "config" if fields.len() == 2 => {
+                    decode_hex(fields[1]).map(|bytes| session.config_descriptors.push(bytes))
+                },
+                "transfer" => {
+                    parse_transfer(&fields[1..]).map(|transfer| session.transfers.push(transfer))
+                },
+                _ => None,
+            }
+        }));
+
+        if session.device_descriptor.is_empty() {
+            return Err(invalid_data("incomplete session".to_owned()));
+        }
+
+        Ok(session)
+    }
+
+    /// Writes the session to a writer.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writeln!(writer, "# libusb session"));
+        try!(writeln!(writer, "version {}", SESSION_VERSION));
+        try!(writeln!(writer, "device {} {} {} {}", self.bus_number, self.address, speed_name(self.speed), self.active_config));
+        try!(writeln!(writer, "descriptor {}", encode_hex(&self.device_descriptor)));
+
+        for config in self.config_descriptors.iter() {
+            try!(writeln!(writer, "config {}", encode_hex(config)));
+        }
+
+        for transfer in self.transfers.iter() {
+            let setup = match transfer.setup {
+                Some(ref setup) => encode_hex(&setup_bytes(setup)),
+                None => "-".to_owned(),
+            };
+
+            let result = match transfer.error {
+                Some(kind) => format!("{:?}", kind),
+                None => "ok".to_owned(),
+            };
+
+            try!(writeln!(writer, "transfer {} 0x{:02x} {} {} {} {} {}",
+                          transfer_type_name(transfer.transfer_type),
+                          transfer.endpoint,
+                          setup,
+                          transfer.requested_length,
+                          result,
+                          transfer.actual_length,
+                          encode_hex(&transfer.data)));
+        }
+
+        Ok(())
+    }
+}
+
+
+/// Records a session with a device.
+///
+/// A recorder captures the device's descriptors when it's created. It's then set as the observer
+/// of a handle to the device with
+/// [`DeviceHandle::set_observer()`](struct.DeviceHandle.html#method.set_observer) to capture the
+/// transfers performed through the handle. Cloning a recorder returns a new reference to the same
+/// recording.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::{Context, SessionRecorder};
+///
+/// let context = Context::new().unwrap();
+/// let devices = context.devices().unwrap();
+/// let device = devices.iter().next().unwrap();
+/// let recorder = SessionRecorder::new(&device).unwrap();
+///
+/// let mut handle = device.open().unwrap();
+/// handle.set_observer(recorder.clone());
+///
+/// let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+///
+/// recorder.session().save("session.txt").unwrap();
+/// ```
+#[derive(Clone)]
+pub struct SessionRecorder {
+    session: Arc<Mutex<Session>>,
+}
+
+impl SessionRecorder {
+    /// Creates a recorder for a device and captures the device's descriptors.
+    pub fn new(device: &Device) -> ::Result<SessionRecorder> {
+        let (device_descriptor, config_descriptors, active_config) = try!(device::descriptor_bytes(device));
+
+        Ok(SessionRecorder {
+            session: Arc::new(Mutex::new(Session {
+                bus_number: device.bus_number(),
+                address: device.address(),
+                speed: device.speed(),
+                active_config: active_config,
+                device_descriptor: device_descriptor,
+                config_descriptors: config_descriptors,
+                transfers: Vec::new(),
+            })),
+        })
+    }
+
+    /// Returns the session recorded so far.
+    pub fn session(&self) -> Session {
+        self.session.lock().unwrap().clone()
+    }
+}
+
+impl TransferObserver for SessionRecorder {
+    fn on_transfer(&self, record: &TransferRecord) {
+        let transfer = RecordedTransfer {
+            transfer_type: record.transfer_type(),
+            endpoint: record.endpoint(),
+            setup: record.setup().cloned(),
+            requested_length: record.requested_length(),
+            actual_length: record.actual_length(),
+            error: record.error().map(|err| err.kind()),
+            data: record.data().unwrap_or(&[]).to_vec(),
+        };
+
+        self.session.lock().unwrap().transfers.push(transfer);
+    }
+
+    fn capture_data(&self) -> bool {
+        true
+    }
+}
+
+
+/// A backend that replays a recorded [`Session`](struct.Session.html).
+///
+/// The backend provides one device with the session's descriptors. Transfers performed through
+/// a handle to the device must match the recorded transfers in order: the transfer type, the
+/// endpoint, the setup packet, the requested length, and the data that's written must be the same.
+/// Each transfer returns the recorded data and result without waiting for its timeout. Requests
+/// that don't perform transfers, such as claiming interfaces, succeed without being checked.
+///
+/// Cloning a backend returns a new reference to the same replay.
+///
+/// # Panics
+///
+/// A transfer that doesn't match the next recorded transfer panics with a message that describes
+/// both transfers, as does a transfer after the end of the session.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::{Context, ReplayBackend};
+///
+/// let backend = ReplayBackend::load("session.txt").unwrap();
+/// let context = Context::with_backend(backend.clone());
+///
+/// let devices = context.devices().unwrap();
+/// let device = devices.iter().next().unwrap();
+/// let handle = device.open().unwrap();
+/// let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+///
+/// backend.assert_finished();
+/// ```
+#[derive(Clone)]
+pub struct ReplayBackend {
+    state: Arc<Mutex<ReplayState>>,
+    device: Arc<ReplayDevice>,
+}
+
+struct ReplayState {
+    session: Session,
+    position: usize,
+}
+
+impl ReplayBackend {
+    /// Creates a backend that replays a session.
+    pub fn new(session: Session) -> ReplayBackend {
+        let state = Arc::new(Mutex::new(ReplayState {
+            session: session,
+            position: 0,
+        }));
+
+        ReplayBackend {
+            state: state.clone(),
+            device: Arc::new(ReplayDevice { state: state }),
+        }
+    }
+
+    /// Creates a backend that replays a session from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ReplayBackend> {
+        Session::load(path).map(ReplayBackend::new)
+    }
+
+    /// Returns the number of recorded transfers that haven't been replayed.
+    pub fn remaining(&self) -> usize {
+        let state = self.state.lock().unwrap();
+
+        state.session.transfers.len() - state.position
+    }
+
+    /// Asserts that all recorded transfers have been replayed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if any recorded transfers haven't been replayed.
+    pub fn assert_finished(&self) {
+        let state = self.state.lock().unwrap();
+
+        if let Some(transfer) = state.session.transfers.get(state.position) {
+            let position = state.position;
+            let expected = describe(transfer.transfer_type, transfer.endpoint, transfer.setup.as_ref(), transfer.requested_length, Some(&transfer.data));
+            drop(state);
+
+            panic!("session replay ended early at transfer {}: expected {}", position + 1, expected);
+        }
+    }
+}
+
+impl Backend for ReplayBackend {
+    fn devices(&self) -> ::Result<Vec<Arc<BackendDevice>>> {
+        Ok(vec![self.device.clone()])
+    }
+}
+
+struct ReplayDevice {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl ReplayDevice {
+    fn with_session<T, F: FnOnce(&Session) -> T>(&self, f: F) -> T {
+        f(&self.state.lock().unwrap().session)
+    }
+}
+
+impl BackendDevice for ReplayDevice {
+    fn bus_number(&self) -> u8 {
+        self.with_session(|session| session.bus_number)
+    }
+
+    fn address(&self) -> u8 {
+        self.with_session(|session| session.address)
+    }
+
+    fn speed(&self) -> Speed {
+        self.with_session(|session| session.speed)
+    }
+
+    fn device_descriptor(&self) -> ::Result<Vec<u8>> {
+        Ok(self.with_session(|session| session.device_descriptor.clone()))
+    }
+
+    fn config_descriptor(&self, config_index: u8) -> ::Result<Vec<u8>> {
+        self.with_session(|session| session.config_descriptors.get(config_index as usize).cloned())
+            .ok_or(Error::new(ErrorKind::NotFound))
+    }
+
+    fn active_config_descriptor(&self) -> ::Result<Vec<u8>> {
+        self.with_session(|session| {
+            session.config_descriptors.iter().find(|config| config.get(5) == Some(&session.active_config)).cloned()
+        }).ok_or(Error::new(ErrorKind::NotFound))
+    }
+
+    fn open(&self) -> ::Result<Box<BackendHandle>> {
+        Ok(Box::new(ReplayHandle { state: self.state.clone() }))
+    }
+}
+
+struct ReplayHandle {
+    state: Arc<Mutex<ReplayState>>,
+}
+
+impl BackendHandle for ReplayHandle {
+    fn active_configuration(&self) -> ::Result<u8> {
+        Ok(self.state.lock().unwrap().session.active_config)
+    }
+
+    fn set_active_configuration(&self, _config: Option<u8>) -> ::Result<()> {
+        Ok(())
+    }
+
+    fn reset(&self) -> ::Result<()> {
+        Ok(())
+    }
+
+    fn claim_interface(&self, _iface: u8) -> ::Result<()> {
+        Ok(())
+    }
+
+    fn release_interface(&self, _iface: u8) -> ::Result<()> {
+        Ok(())
+    }
+
+    fn set_alternate_setting(&self, _iface: u8, _setting: u8) -> ::Result<()> {
+        Ok(())
+    }
+
+    fn clear_halt(&self, _endpoint: u8) -> ::Result<()> {
+        Ok(())
+    }
+
+    fn read_control(&self, setup: &SetupPacket, buf: &mut [u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let transfer = next_transfer(&self.state, TransferType::Control, setup.request_type() & LIBUSB_ENDPOINT_DIR_MASK, Some(setup), buf.len(), None);
+        replay_in(&transfer, buf)
+    }
+
+    fn write_control(&self, setup: &SetupPacket, buf: &[u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let transfer = next_transfer(&self.state, TransferType::Control, setup.request_type() & LIBUSB_ENDPOINT_DIR_MASK, Some(setup), buf.len(), Some(buf));
+        replay_result(&transfer)
+    }
+
+    fn read(&self, transfer_type: TransferType, endpoint: u8, buf: &mut [u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let transfer = next_transfer(&self.state, transfer_type, endpoint, None, buf.len(), None);
+        replay_in(&transfer, buf)
+    }
+
+    fn write(&self, transfer_type: TransferType, endpoint: u8, buf: &[u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let transfer = next_transfer(&self.state, transfer_type, endpoint, None, buf.len(), Some(buf));
+        replay_result(&transfer)
+    }
+}
+
+fn next_transfer(state: &Mutex<ReplayState>, transfer_type: TransferType, endpoint: u8, setup: Option<&SetupPacket>, requested_length: usize, data: Option<&[u8]>) -> RecordedTransfer {
+    let mut state = state.lock().unwrap();
+    let position = state.position;
+
+    let expected = state.session.transfers.get(position).cloned();
+
+    let matches = expected.as_ref().map_or(false, |expected| {
+        expected.transfer_type == transfer_type
+            && expected.endpoint == endpoint
+            && expected.setup.as_ref() == setup
+            && expected.requested_length == requested_length
+            && data.map_or(true, |data| data == &expected.data[..])
+    });
+
+    if !matches {
+        drop(state);
+
+        let actual = describe(transfer_type, endpoint, setup, requested_length, data);
+
+        match expected {
+            Some(expected) => {
+                let expected = describe(expected.transfer_type, expected.endpoint, expected.setup.as_ref(), expected.requested_length, Some(&expected.data));
+                panic!("session replay diverged at transfer {}: expected {}, got {}", position + 1, expected, actual);
+            },
+            None => panic!("session replay diverged at transfer {}: expected end of session, got {}", position + 1, actual),
+        }
+    }
+
+    state.position += 1;
+    expected.unwrap()
+}
+
+fn replay_in(transfer: &RecordedTransfer, buf: &mut [u8]) -> ::Result<usize> {
+    let len = cmp::min(transfer.data.len(), buf.len());
+    buf[..len].copy_from_slice(&transfer.data[..len]);
+
+    replay_result(transfer)
+}
+
+fn replay_result(transfer: &RecordedTransfer) -> ::Result<usize> {
+    match transfer.error {
+        Some(kind) => Err(Error::new(kind)),
+        None => Ok(transfer.actual_length),
+    }
+}
+
+fn describe(transfer_type: TransferType, endpoint: u8, setup: Option<&SetupPacket>, requested_length: usize, data: Option<&[u8]>) -> String {
+    let mut description = format!("{} 0x{:02x}", transfer_type_name(transfer_type).to_uppercase(), endpoint);
+
+    if let Some(setup) = setup {
+        write!(description, " {}", setup).unwrap();
+    }
+
+    write!(description, " {} bytes", requested_length).unwrap();
+
+    match data {
+        Some(data) if endpoint & LIBUSB_ENDPOINT_DIR_MASK == LIBUSB_ENDPOINT_OUT => {
+            write!(description, " [{}]", encode_hex(data)).unwrap();
+        },
+        _ => {},
+    }
+
+    description
+}
+
+fn parse_transfer(fields: &[&str]) -> Option<RecordedTransfer> {
+    if fields.len() != 7 || !fields[1].starts_with("0x") {
+        return None;
+    }
+
+    let setup = match fields[2] {
+        "-" => None,
+        setup => match decode_hex(setup) {
+            Some(ref bytes) if bytes.len() == 8 => {
+                let word = |i: usize| bytes[i] as u16 | (bytes[i + 1] as u16) << 8;
+                Some(SetupPacket::new(bytes[0], bytes[1], word(2), word(4), word(6)))
+            },
+            _ => return None,
+        },
+    };
+
+    let error = match fields[4] {
+        "ok" => None,
+        name => match error::kind_from_name(name) {
+            Some(kind) => Some(kind),
+            None => return None,
+        },
+    };
+
+    let endpoint = u8::from_str_radix(&fields[1][2..], 16).ok();
+
+    match (transfer_type_from_name(fields[0]), endpoint, fields[3].parse().ok(), fields[5].parse().ok(), decode_hex(fields[6])) {
+        (Some(transfer_type), Some(endpoint), Some(requested_length), Some(actual_length), Some(data)) => {
+            Some(RecordedTransfer {
+                transfer_type: transfer_type,
+                endpoint: endpoint,
+                setup: setup,
+                requested_length: requested_length,
+                actual_length: actual_length,
+                error: error,
+                data: data,
+            })
+        },
+        _ => None,
+    }
+}
+
+fn setup_bytes(setup: &SetupPacket) -> [u8; 8] {
+    [
+        setup.request_type(),
+        setup.request(),
+        setup.value() as u8, (setup.value() >> 8) as u8,
+        setup.index() as u8, (setup.index() >> 8) as u8,
+        setup.length() as u8, (setup.length() >> 8) as u8,
+    ]
+}
+
+fn transfer_type_name(transfer_type: TransferType) -> &'static str {
+    match transfer_type {
+        TransferType::Control => "control",
+        TransferType::Isochronous => "isochronous",
+        TransferType::Bulk => "bulk",
+        TransferType::Interrupt => "interrupt",
+    }
+}
+
+fn transfer_type_from_name(name: &str) -> Option<TransferType> {
+    match name {
+        "control" => Some(TransferType::Control),
+        "isochronous" => Some(TransferType::Isochronous),
+        "bulk" => Some(TransferType::Bulk),
+        "interrupt" => Some(TransferType::Interrupt),
+        _ => None,
+    }
+}
+
+/// Reads the records of a session or snapshot file.
+///
+/// Blank lines and comments are skipped. The first record must be `version` followed by the
+/// given version. `parse` is called with the fields of every later record and returns `None` if
+/// the record is invalid. Errors name the kind of file that is read.
+#[doc(hidden)]
+pub fn read_records<R: Read, F>(reader: R, kind: &str, version: u32, mut parse: F) -> io::Result<()>
+    where F: FnMut(&[&str]) -> Option<()>
+{
+    let mut versioned = false;
+
+    for (i, line) in BufReader::new(reader).lines().enumerate() {
+        let line = try!(line);
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+
+        if fields.is_empty() || fields[0].starts_with('#') {
+            continue;
+        }
+
+        let parsed = if versioned {
+            parse(&fields)
+        }
+        else if fields[0] == "version" {
+            match fields.get(1).and_then(|v| v.parse::<u32>().ok()) {
+                Some(v) if v == version => {
+                    versioned = true;
+                    Some(())
+                },
+                _ => return Err(invalid_data(format!("unsupported {} version on line {}", kind, i + 1))),
+            }
+        }
+        else {
+            None
+        };
+
+        if parsed.is_none() {
+            return Err(invalid_data(format!("invalid {} data on line {}: {}", kind, i + 1, line)));
+        }
+    }
+
+    if !versioned {
+        return Err(invalid_data(format!("incomplete {}", kind)));
+    }
+
+    Ok(())
+}
+
+#[doc(hidden)]
+pub fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Returns the name of a speed in session and snapshot files.
+#[doc(hidden)]
+pub fn speed_name(speed: Speed) -> &'static str {
+    match speed {
+        Speed::Unknown => "unknown",
+        Speed::Low => "low",
+        Speed::Full => "full",
+        Speed::High => "high",
+        Speed::Super => "super",
+    }
+}
+
+/// Looks up a speed by its name in session and snapshot files.
+#[doc(hidden)]
+pub fn speed_from_name(name: &str) -> Option<Speed> {
+    match name {
+        "unknown" => Some(Speed::Unknown),
+        "low" => Some(Speed::Low),
+        "full" => Some(Speed::Full),
+        "high" => Some(Speed::High),
+        "super" => Some(Speed::Super),
+        _ => None,
+    }
+}
+
+/// Encodes bytes as lowercase hexadecimal digits, or `"-"` if there are no bytes.
+#[doc(hidden)]
+pub fn encode_hex(bytes: &[u8]) -> String {
+    if bytes.is_empty() {
+        return "-".to_owned();
+    }
+
+    let mut hex = String::with_capacity(bytes.len() * 2);
+
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+
+    hex
+}
+
+/// Decodes bytes that were encoded by `encode_hex()`.
+#[doc(hidden)]
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex == "-" {
+        return Some(Vec::new());
+    }
+
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use context::Context;
+    use error::ErrorKind;
+    use fields::TransferType;
+    use mock::{MockBackend, MockDevice};
+
+    use super::{ReplayBackend, Session, SessionRecorder};
+
+    fn record() -> Session {
+        let device = MockDevice::new(0x1234, 0x5678)
+            .product("Widget")
+            .endpoint(0, 0x81, TransferType::Bulk, 64)
+            .endpoint(0, 0x02, TransferType::Bulk, 64);
+
+        device.push_in(0x81, vec![1, 2, 3]);
+
+        let backend = MockBackend::new();
+        backend.add_device(device);
+
+        let context = Context::with_backend(backend);
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+        let recorder = SessionRecorder::new(&device).unwrap();
+
+        let mut handle = device.open().unwrap();
+        handle.set_observer(recorder.clone());
+        exercise(&mut handle);
+
+        recorder.session()
+    }
+
+    fn exercise(handle: &mut ::DeviceHandle) {
+        let mut buf = [0; 64];
+
+        handle.claim_interface(0).unwrap();
+
+        let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+        assert_eq!("Widget", handle.read_string_descriptor(languages[0], 2, Duration::from_secs(1)).unwrap());
+
+        assert_eq!(3, handle.write_bulk(0x02, &[4, 5, 6], Duration::from_secs(1)).unwrap());
+        assert_eq!(3, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap());
+        assert_eq!([1, 2, 3], buf[..3]);
+        assert_eq!(ErrorKind::Timeout, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+    }
+
+    fn replay(session: Session) -> (Context, ReplayBackend) {
+        let backend = ReplayBackend::new(session);
+        (Context::with_backend(backend.clone()), backend)
+    }
+
+    #[test]
+    fn it_records_transfers() {
+        assert_eq!(5, record().num_transfers());
+    }
+
+    #[test]
+    fn it_saves_and_loads_sessions() {
+        let session = record();
+        let mut bytes = Vec::new();
+
+        session.write_to(&mut bytes).unwrap();
+
+        assert_eq!(session, Session::read_from(&bytes[..]).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_unsupported_versions() {
+        let err = Session::read_from(&b"version 2\ndescriptor 12\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("unsupported session version"));
+    }
+
+    #[test]
+    fn it_requires_version_before_other_records() {
+        let err = Session::read_from(&b"# comment\ndescriptor 12\nversion 1\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("invalid session data on line 2"));
+
+        let err = Session::read_from(&b"version 1\nversion 1\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("invalid session data on line 2"));
+
+        let err = Session::read_from(&b"# comment\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("incomplete session"));
+    }
+
+    #[test]
+    fn it_replays_sessions() {
+        let (context, backend) = replay(record());
+
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+        assert_eq!(0x1234, device.device_descriptor().unwrap().vendor_id());
+        assert_eq!(1, device.active_config_descriptor().unwrap().num_interfaces());
+
+        let mut handle = device.open().unwrap();
+        exercise(&mut handle);
+
+        assert_eq!(0, backend.remaining());
+        backend.assert_finished();
+    }
+
+    #[test]
+    fn it_compares_replayed_device_across_device_lists() {
+        let (context, _backend) = replay(record());
+
+        let first = context.devices().unwrap().iter().collect::<Vec<_>>();
+        let second = context.devices().unwrap().iter().collect::<HashSet<_>>();
+
+        assert_eq!(1, second.len());
+        assert!(second.contains(&first[0]));
+    }
+
+    #[test]
+    fn it_replays_errors_without_libusb_code() {
+        let device = MockDevice::new(0x1234, 0x5678).endpoint(0, 0x81, TransferType::Bulk, 64);
+        device.push_in_error(0x81, ErrorKind::ShortTransfer);
+
+        let backend = MockBackend::new();
+        backend.add_device(device);
+
+        let context = Context::with_backend(backend);
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+        let recorder = SessionRecorder::new(&device).unwrap();
+        let mut buf = [0; 64];
+
+        let mut handle = device.open().unwrap();
+        handle.set_observer(recorder.clone());
+        assert_eq!(ErrorKind::ShortTransfer, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+
+        let (context, backend) = replay(recorder.session());
+
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        assert_eq!(ErrorKind::ShortTransfer, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+        backend.assert_finished();
+    }
+
+    #[test]
+    #[should_panic(expected = "session replay diverged at transfer 3: expected BULK 0x02 3 bytes [040506], got BULK 0x02 3 bytes [040507]")]
+    fn it_panics_when_replay_diverges() {
+        let (context, _backend) = replay(record());
+
+        let handle = context.open_device_with_vid_pid(0x1234, 0x5678).unwrap();
+        handle.read_languages(Duration::from_secs(1)).unwrap();
+        handle.read_string_descriptor(::language::from_lang_id(0x0409), 2, Duration::from_secs(1)).unwrap();
+        handle.write_bulk(0x02, &[4, 5, 7], Duration::from_secs(1)).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "session replay ended early at transfer 1")]
+    fn it_panics_when_replay_ends_early() {
+        let (_context, backend) = replay(record());
+        backend.assert_finished();
+    }
+}
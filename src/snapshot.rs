@@ -0,0 +1,584 @@
+use std::cmp;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use libusb::*;
+
+use backend::{Backend, BackendDevice, BackendHandle, descriptor_request, reply_descriptor};
+use config_descriptor;
+use device::{self, Device};
+use device_descriptor::{self, DeviceDescriptor};
+use device_handle::DeviceHandle;
+use error::{Error, ErrorKind};
+use fields::{Speed, TransferType};
+use language::{self, Language};
+use session::{decode_hex, encode_hex, invalid_data, read_records, speed_from_name, speed_name};
+use timeout::Timeout;
+use trace::SetupPacket;
+
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// Everything that can be read about a device without changing its state.
+///
+/// A snapshot holds the device descriptor, every configuration descriptor with the interface,
+/// endpoint, and extra descriptors that follow it, the BOS descriptor, the string descriptors in
+/// each of the device's languages, and the device's bus number, address, port number, and speed.
+/// The BOS and string descriptors are kept as the raw bytes that the device returned. The device
+/// and configuration descriptors are rebuilt from the copies that `libusb` parsed, so they hold
+/// the same fields and extra descriptors but may list alternate settings in a different order.
+///
+/// Snapshots are saved as versioned text files. A snapshot is also a [`Backend`](trait.Backend.html)
+/// that provides one read-only virtual device, so that a loaded snapshot can be inspected with the
+/// normal API. The virtual device answers the standard `GET_DESCRIPTOR` requests and fails every
+/// request that would communicate with or change the state of the device with `NotSupported`.
+/// Cloning a snapshot returns a new reference to the same virtual device.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::{Context, DeviceSnapshot};
+///
+/// let context = Context::new().unwrap();
+/// let devices = context.devices().unwrap();
+/// let device = devices.iter().next().unwrap();
+///
+/// DeviceSnapshot::capture(&device, Duration::from_secs(1)).unwrap().save("device.snapshot").unwrap();
+///
+/// let offline = Context::with_backend(DeviceSnapshot::load("device.snapshot").unwrap());
+/// let devices = offline.devices().unwrap();
+/// let device = devices.iter().next().unwrap();
+/// let handle = device.open().unwrap();
+/// let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+/// ```
+#[derive(Clone)]
+pub struct DeviceSnapshot {
+    data: Arc<SnapshotData>,
+    device: Arc<SnapshotDevice>,
+}
+
+#[derive(PartialEq,Eq)]
+struct SnapshotData {
+    bus_number: u8,
+    address: u8,
+    port_number: u8,
+    speed: Speed,
+    active_config: u8,
+    device_descriptor: Vec<u8>,
+    config_descriptors: Vec<Vec<u8>>,
+    bos_descriptor: Option<Vec<u8>>,
+    strings: Vec<(u16, u8, Vec<u8>)>,
+}
+
+impl fmt::Debug for DeviceSnapshot {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("DeviceSnapshot")
+            .field("bus_number", &self.data.bus_number)
+            .field("address", &self.data.address)
+            .field("port_number", &self.data.port_number)
+            .field("speed", &self.data.speed)
+            .field("active_config", &self.data.active_config)
+            .field("device_descriptor", &self.data.device_descriptor)
+            .field("config_descriptors", &self.data.config_descriptors)
+            .field("bos_descriptor", &self.data.bos_descriptor)
+            .field("strings", &self.data.strings)
+            .finish()
+    }
+}
+
+impl PartialEq for DeviceSnapshot {
+    fn eq(&self, other: &Self) -> bool {
+        self.data == other.data
+    }
+}
+
+impl Eq for DeviceSnapshot {}
+
+impl DeviceSnapshot {
+    /// Captures a snapshot of a device.
+    ///
+    /// The device is opened to read the BOS descriptor and the string descriptors. Descriptors
+    /// that the device fails to return are left out of the snapshot. `timeout` applies to each
+    /// request.
+    pub fn capture<T: Into<Timeout>>(device: &Device, timeout: T) -> ::Result<DeviceSnapshot> {
+        let timeout = timeout.into();
+        let (device_bytes, config_descriptors, active_config) = try!(device::descriptor_bytes(device));
+        let device_descriptor = try!(device_descriptor::from_bytes(&device_bytes));
+        let handle = try!(device.open());
+
+        let mut snapshot = SnapshotData {
+            bus_number: device.bus_number(),
+            address: device.address(),
+            port_number: device.port_number(),
+            speed: device.speed(),
+            active_config: active_config,
+            device_descriptor: device_bytes,
+            config_descriptors: config_descriptors,
+            bos_descriptor: None,
+            strings: Vec::new(),
+        };
+
+        let usb_version = device_descriptor.usb_version();
+
+        if (usb_version.major(), usb_version.minor(), usb_version.sub_minor()) >= (2, 0, 1) {
+            if let Some(header) = try!(read_descriptor(&handle, LIBUSB_DT_BOS, 0, 0, 5, timeout)) {
+                if header.len() == 5 {
+                    let total_length = header[2] as usize | (header[3] as usize) << 8;
+                    snapshot.bos_descriptor = try!(read_descriptor(&handle, LIBUSB_DT_BOS, 0, 0, total_length, timeout));
+                }
+            }
+        }
+
+        let languages = match try!(read_descriptor(&handle, LIBUSB_DT_STRING, 0, 0, 255, timeout)) {
+            Some(bytes) => bytes,
+            None => return Ok(DeviceSnapshot::from_data(snapshot)),
+        };
+
+        let indexes = try!(string_indexes(&snapshot, &device_descriptor));
+        snapshot.strings.push((0, 0, languages.clone()));
+
+        for language in lang_ids(&languages) {
+            for &index in indexes.iter() {
+                if let Some(bytes) = try!(read_descriptor(&handle, LIBUSB_DT_STRING, index, language, 255, timeout)) {
+                    snapshot.strings.push((language, index, bytes));
+                }
+            }
+        }
+
+        Ok(DeviceSnapshot::from_data(snapshot))
+    }
+
+    /// Returns the number of the bus that the device was connected to.
+    pub fn bus_number(&self) -> u8 {
+        self.data.bus_number
+    }
+
+    /// Returns the device's address on the bus that it was connected to.
+    pub fn address(&self) -> u8 {
+        self.data.address
+    }
+
+    /// Returns the number of the port that the device was connected to, or 0 if it wasn't
+    /// available.
+    pub fn port_number(&self) -> u8 {
+        self.data.port_number
+    }
+
+    /// Returns the device's connection speed.
+    pub fn speed(&self) -> Speed {
+        self.data.speed
+    }
+
+    /// Returns the device descriptor.
+    pub fn device_descriptor(&self) -> ::Result<DeviceDescriptor> {
+        device_descriptor::from_bytes(&self.data.device_descriptor)
+    }
+
+    /// Returns the bytes of the BOS descriptor and the device capability descriptors that follow
+    /// it, if the device returned them.
+    pub fn bos_descriptor(&self) -> Option<&[u8]> {
+        self.data.bos_descriptor.as_ref().map(|bytes| &bytes[..])
+    }
+
+    /// Returns the languages of the device's string descriptors.
+    pub fn languages(&self) -> Vec<Language> {
+        self.data.string_descriptor(0, 0).map_or(Vec::new(), |bytes| {
+            lang_ids(bytes).into_iter().map(language::from_lang_id).collect()
+        })
+    }
+
+    /// Returns the string descriptor with the given index in the given language.
+    pub fn string(&self, language: Language, index: u8) -> Option<String> {
+        self.data.string_descriptor(language.lang_id(), index).and_then(|bytes| {
+            let utf16 = bytes.chunks(2).skip(1).map(|chunk| {
+                chunk[0] as u16 | (*chunk.get(1).unwrap_or(&0) as u16) << 8
+            }).collect::<Vec<u16>>();
+
+            String::from_utf16(&utf16).ok()
+        })
+    }
+
+    /// Loads a snapshot from a file.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<DeviceSnapshot> {
+        DeviceSnapshot::read_from(try!(File::open(path)))
+    }
+
+    /// Saves the snapshot to a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(try!(File::create(path)));
+
+        try!(self.write_to(&mut writer));
+        writer.flush()
+    }
+
+    /// Reads a snapshot from a reader.
+    pub fn read_from<R: Read>(reader: R) -> io::Result<DeviceSnapshot> {
+        let mut snapshot = SnapshotData {
+            bus_number: 0,
+            address: 0,
+            port_number: 0,
+            speed: Speed::Unknown,
+            active_config: 0,
+            device_descriptor: Vec::new(),
+            config_descriptors: Vec::new(),
+            bos_descriptor: None,
+            strings: Vec::new(),
+        };
+
+        try!(read_records(reader, "snapshot", SNAPSHOT_VERSION, |fields| {
+            match fields[0] {
+                "device" if fields.len() == 6 => {
+                    match (fields[1].parse(), fields[2].parse(), fields[3].parse(), speed_from_name(fields[4]), fields[5].parse()) {
+                        (Ok(bus_number), Ok(address), Ok(port_number), Some(speed), Ok(active_config)) => {
+                            snapshot.bus_number = bus_number;
+                            snapshot.address = address;
+                            snapshot.port_number = port_number;
+                            snapshot.speed = speed;
+                            snapshot.active_config = active_config;
+                            Some(())
+                        },
+                        _ => None,
+                    }
+                },
+                "descriptor" if fields.len() == 2 => {
+                    decode_hex(fields[1]).map(|bytes| snapshot.device_descriptor = bytes)
+                },
+                "config" if fields.len() == 2 => {
+                    decode_hex(fields[1]).map(|bytes| snapshot.config_descriptors.push(bytes))
+                },
+                "bos" if fields.len() == 2 => {
+                    decode_hex(fields[1]).map(|bytes| snapshot.bos_descriptor = Some(bytes))
+                },
+                "string" if fields.len() == 4 && fields[1].starts_with("0x") => {
+                    match (u16::from_str_radix(&fields[1][2..], 16), fields[2].parse(), decode_hex(fields[3])) {
+                        (Ok(language), Ok(index), Some(bytes)) => {
+                            snapshot.strings.push((language, index, bytes));
+                            Some(())
+                        },
+                        _ => None,
+                    }
+                },
+                _ => None,
+            }
+        }));
+
+        if snapshot.device_descriptor.is_empty() {
+            return Err(invalid_data("incomplete snapshot".to_owned()));
+        }
+
+        Ok(DeviceSnapshot::from_data(snapshot))
+    }
+
+    /// Writes the snapshot to a writer.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        try!(writeln!(writer, "# libusb device snapshot"));
+        try!(writeln!(writer, "version {}", SNAPSHOT_VERSION));
+        try!(writeln!(writer, "device {} {} {} {} {}", self.data.bus_number, self.data.address, self.data.port_number, speed_name(self.data.speed), self.data.active_config));
+        try!(writeln!(writer, "descriptor {}", encode_hex(&self.data.device_descriptor)));
+
+        for config in self.data.config_descriptors.iter() {
+            try!(writeln!(writer, "config {}", encode_hex(config)));
+        }
+
+        if let Some(ref bos) = self.data.bos_descriptor {
+            try!(writeln!(writer, "bos {}", encode_hex(bos)));
+        }
+
+        for &(language, index, ref bytes) in self.data.strings.iter() {
+            try!(writeln!(writer, "string 0x{:04x} {} {}", language, index, encode_hex(bytes)));
+        }
+
+        Ok(())
+    }
+
+    fn from_data(data: SnapshotData) -> DeviceSnapshot {
+        let data = Arc::new(data);
+
+        DeviceSnapshot {
+            data: data.clone(),
+            device: Arc::new(SnapshotDevice { snapshot: data }),
+        }
+    }
+}
+
+impl SnapshotData {
+    fn string_descriptor(&self, language: u16, index: u8) -> Option<&[u8]> {
+        self.strings.iter()
+            .find(|&&(other_language, other_index, _)| other_language == language && other_index == index)
+            .map(|&(_, _, ref bytes)| &bytes[..])
+    }
+}
+
+impl Backend for DeviceSnapshot {
+    fn devices(&self) -> ::Result<Vec<Arc<BackendDevice>>> {
+        Ok(vec![self.device.clone()])
+    }
+}
+
+struct SnapshotDevice {
+    snapshot: Arc<SnapshotData>,
+}
+
+impl BackendDevice for SnapshotDevice {
+    fn bus_number(&self) -> u8 {
+        self.snapshot.bus_number
+    }
+
+    fn address(&self) -> u8 {
+        self.snapshot.address
+    }
+
+    fn port_number(&self) -> u8 {
+        self.snapshot.port_number
+    }
+
+    fn speed(&self) -> Speed {
+        self.snapshot.speed
+    }
+
+    fn device_descriptor(&self) -> ::Result<Vec<u8>> {
+        Ok(self.snapshot.device_descriptor.clone())
+    }
+
+    fn config_descriptor(&self, config_index: u8) -> ::Result<Vec<u8>> {
+        self.snapshot.config_descriptors.get(config_index as usize).cloned().ok_or(Error::new(ErrorKind::NotFound))
+    }
+
+    fn active_config_descriptor(&self) -> ::Result<Vec<u8>> {
+        self.snapshot.config_descriptors.iter()
+            .find(|config| config.get(5) == Some(&self.snapshot.active_config))
+            .cloned()
+            .ok_or(Error::new(ErrorKind::NotFound))
+    }
+
+    fn open(&self) -> ::Result<Box<BackendHandle>> {
+        Ok(Box::new(SnapshotHandle { snapshot: self.snapshot.clone() }))
+    }
+}
+
+struct SnapshotHandle {
+    snapshot: Arc<SnapshotData>,
+}
+
+impl BackendHandle for SnapshotHandle {
+    fn active_configuration(&self) -> ::Result<u8> {
+        Ok(self.snapshot.active_config)
+    }
+
+    fn set_active_configuration(&self, _config: Option<u8>) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn reset(&self) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn claim_interface(&self, _iface: u8) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn release_interface(&self, _iface: u8) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn set_alternate_setting(&self, _iface: u8, _setting: u8) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn clear_halt(&self, _endpoint: u8) -> ::Result<()> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn read_control(&self, setup: &SetupPacket, buf: &mut [u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        let (descriptor_type, index, language) = match descriptor_request(setup) {
+            Some(request) => request,
+            None => return Err(Error::new(ErrorKind::NotSupported)),
+        };
+
+        let snapshot = &self.snapshot;
+
+        let bytes = match descriptor_type {
+            LIBUSB_DT_DEVICE => Some(&snapshot.device_descriptor[..]),
+            LIBUSB_DT_CONFIG => snapshot.config_descriptors.get(index as usize).map(|bytes| &bytes[..]),
+            LIBUSB_DT_BOS => snapshot.bos_descriptor.as_ref().map(|bytes| &bytes[..]),
+            LIBUSB_DT_STRING => snapshot.string_descriptor(language, index),
+            _ => None,
+        };
+
+        reply_descriptor(bytes, buf)
+    }
+
+    fn write_control(&self, _setup: &SetupPacket, _buf: &[u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn read(&self, _transfer_type: TransferType, _endpoint: u8, _buf: &mut [u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+
+    fn write(&self, _transfer_type: TransferType, _endpoint: u8, _buf: &[u8], _timeout: Option<Duration>) -> ::Result<usize> {
+        Err(Error::new(ErrorKind::NotSupported))
+    }
+}
+
+/// Reads a descriptor with a standard `GET_DESCRIPTOR` request.
+///
+/// Returns `None` if the device fails the request with an error other than `NoDevice`.
+fn read_descriptor(handle: &DeviceHandle, descriptor_type: u8, index: u8, language: u16, len: usize, timeout: Timeout) -> ::Result<Option<Vec<u8>>> {
+    let mut buf = vec![0; cmp::min(len, u16::max_value() as usize)];
+
+    match handle.read_control(LIBUSB_ENDPOINT_IN, LIBUSB_REQUEST_GET_DESCRIPTOR, (descriptor_type as u16) << 8 | index as u16, language, &mut buf, timeout) {
+        Ok(len) if len >= 2 && buf[1] == descriptor_type => {
+            buf.truncate(len);
+            Ok(Some(buf))
+        },
+        Ok(_) => Ok(None),
+        Err(err) => match err.kind() {
+            ErrorKind::NoDevice => Err(err),
+            _ => Ok(None),
+        },
+    }
+}
+
+/// Returns the indexes of the string descriptors that the device's descriptors refer to.
+fn string_indexes(snapshot: &SnapshotData, device_descriptor: &DeviceDescriptor) -> ::Result<Vec<u8>> {
+    let mut indexes = vec![
+        device_descriptor.manufacturer_string_index(),
+        device_descriptor.product_string_index(),
+        device_descriptor.serial_number_string_index(),
+    ];
+
+    for bytes in snapshot.config_descriptors.iter() {
+        let config = try!(config_descriptor::from_bytes(bytes));
+        indexes.push(config.description_string_index());
+
+        for interface in config.interfaces() {
+            for setting in interface.descriptors() {
+                indexes.push(setting.description_string_index());
+            }
+        }
+    }
+
+    let mut indexes = indexes.into_iter().filter_map(|index| index).collect::<Vec<_>>();
+    indexes.sort();
+    indexes.dedup();
+
+    Ok(indexes)
+}
+
+fn lang_ids(bytes: &[u8]) -> Vec<u16> {
+    bytes.chunks(2).skip(1).filter(|chunk| chunk.len() == 2).map(|chunk| chunk[0] as u16 | (chunk[1] as u16) << 8).collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use context::Context;
+    use error::ErrorKind;
+    use fields::{Speed, TransferType};
+    use language;
+    use mock::{MockBackend, MockDevice};
+
+    use super::DeviceSnapshot;
+
+    fn capture() -> DeviceSnapshot {
+        let bos = vec![5, 0x0F, 12, 0, 1, 7, 0x10, 0x02, 0x02, 0, 0, 0];
+
+        let device = MockDevice::new(0x1234, 0x5678)
+            .device_descriptor(&[18, 0x01, 0x01, 0x02, 0, 0, 0, 64, 0x34, 0x12, 0x78, 0x56, 0x00, 0x01, 1, 2, 0, 1])
+            .bus_number(2)
+            .address(9)
+            .port_number(4)
+            .speed(Speed::High)
+            .languages(&[0x0409, 0x0407])
+            .manufacturer("Acme")
+            .product("Widget")
+            .endpoint(0, 0x81, TransferType::Bulk, 512)
+            .on_control(move |setup, buf| {
+                if setup.value() >> 8 == 0x0F {
+                    let len = ::std::cmp::min(buf.len(), bos.len());
+                    buf[..len].copy_from_slice(&bos[..len]);
+                    Ok(len)
+                }
+                else {
+                    Err(::error::Error::new(ErrorKind::Pipe))
+                }
+            });
+
+        let backend = MockBackend::new();
+        backend.add_device(device);
+
+        let context = Context::with_backend(backend);
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+
+        DeviceSnapshot::capture(&device, Duration::from_secs(1)).unwrap()
+    }
+
+    #[test]
+    fn it_captures_devices() {
+        let snapshot = capture();
+
+        assert_eq!((2, 9, 4, Speed::High), (snapshot.bus_number(), snapshot.address(), snapshot.port_number(), snapshot.speed()));
+        assert_eq!(0x1234, snapshot.device_descriptor().unwrap().vendor_id());
+        assert_eq!(Some(12), snapshot.bos_descriptor().map(|bos| bos.len()));
+        assert_eq!(vec![language::from_lang_id(0x0409), language::from_lang_id(0x0407)], snapshot.languages());
+        assert_eq!(Some("Widget".to_owned()), snapshot.string(language::from_lang_id(0x0407), 2));
+        assert_eq!(None, snapshot.string(language::from_lang_id(0x0407), 3));
+    }
+
+    #[test]
+    fn it_saves_and_loads_snapshots() {
+        let snapshot = capture();
+        let mut bytes = Vec::new();
+
+        snapshot.write_to(&mut bytes).unwrap();
+
+        assert_eq!(snapshot, DeviceSnapshot::read_from(&bytes[..]).unwrap());
+    }
+
+    #[test]
+    fn it_rejects_invalid_snapshots() {
+        let err = DeviceSnapshot::read_from(&b"version 1\ndescriptor 12\nstring 409 1 -\n"[..]).unwrap_err();
+        assert!(err.to_string().contains("line 3"));
+    }
+
+    #[test]
+    fn it_provides_read_only_virtual_device() {
+        let context = Context::with_backend(capture());
+        let devices = context.devices().unwrap();
+        let device = devices.iter().next().unwrap();
+
+        assert_eq!(4, device.port_number());
+        assert_eq!(0x81, device.active_config_descriptor().unwrap().interfaces().next().unwrap()
+                   .descriptors().next().unwrap().endpoint_descriptors().next().unwrap().address());
+
+        let mut handle = device.open().unwrap();
+        let descriptor = device.device_descriptor().unwrap();
+        let languages = handle.read_languages(Duration::from_secs(1)).unwrap();
+
+        assert_eq!("Acme", handle.read_manufacturer_string(languages[0], &descriptor, Duration::from_secs(1)).unwrap());
+        assert_eq!(ErrorKind::NotSupported, handle.claim_interface(0).unwrap_err().kind());
+
+        let mut buf = [0; 64];
+        assert_eq!(ErrorKind::NotSupported, handle.read_bulk(0x81, &mut buf, Duration::from_secs(1)).unwrap_err().kind());
+    }
+
+    #[test]
+    fn it_compares_virtual_device_across_device_lists() {
+        let context = Context::with_backend(capture());
+
+        let first = context.devices().unwrap().iter().collect::<Vec<_>>();
+        let second = context.devices().unwrap().iter().collect::<HashSet<_>>();
+
+        assert_eq!(1, second.len());
+        assert!(second.contains(&first[0]));
+    }
+}
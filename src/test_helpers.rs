@@ -1,5 +1,10 @@
 pub use std::ptr;
 
+use context::Context;
+use device_handle::DeviceHandle;
+use fields::TransferType;
+use mock::{MockBackend, MockDevice};
+
 macro_rules! merge {
     ($default:expr => $($field:ident : $value:expr),*) => {
         {
@@ -162,3 +167,33 @@ macro_rules! device_descriptor {
         )
     }
 }
+
+/// Returns a mock device with bulk endpoints 0x81 and 0x02 of the given maximum packet size and
+/// interrupt endpoints 0x83 and 0x04 with a maximum packet size of 8, all on interface 0.
+pub fn mock_device(bulk_packet_size: u16) -> MockDevice {
+    MockDevice::new(0x1234, 0x5678)
+        .endpoint(0, 0x81, TransferType::Bulk, bulk_packet_size)
+        .endpoint(0, 0x02, TransferType::Bulk, bulk_packet_size)
+        .endpoint(0, 0x83, TransferType::Interrupt, 8)
+        .endpoint(0, 0x04, TransferType::Interrupt, 8)
+}
+
+/// Returns a context whose backend provides the given mock devices.
+pub fn context_with(devices: &[&MockDevice]) -> Context {
+    let backend = MockBackend::new();
+
+    for device in devices {
+        backend.add_device((*device).clone());
+    }
+
+    Context::with_backend(backend)
+}
+
+/// Opens the context's first device and claims its interface 0.
+pub fn open<'a>(context: &'a Context) -> DeviceHandle<'a> {
+    let devices = context.devices().unwrap();
+    let mut handle = devices.iter().next().unwrap().open().unwrap();
+
+    handle.claim_interface(0).unwrap();
+    handle
+}
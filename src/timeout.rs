@@ -0,0 +1,229 @@
+use std::time::{Duration, Instant};
+
+use libc::c_uint;
+
+/// A timeout for a blocking operation.
+///
+/// Every blocking method of [`DeviceHandle`](struct.DeviceHandle.html) accepts a timeout. Methods
+/// that accept a timeout also accept a `Duration`, which is converted to `Timeout::After`.
+///
+/// A relative timeout can be converted to an absolute deadline with
+/// [`to_deadline()`](#method.to_deadline), so that several operations can share one overall
+/// deadline:
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::Timeout;
+///
+/// # fn read_strings(handle: &libusb::DeviceHandle, device_desc: &libusb::DeviceDescriptor) -> libusb::Result<()> {
+/// let timeout = Timeout::After(Duration::from_secs(1)).to_deadline();
+///
+/// let languages = try!(handle.read_languages(timeout));
+///
+/// for language in languages {
+///     try!(handle.read_product_string(language, device_desc, timeout));
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug,PartialEq,Eq,Clone,Copy,Hash)]
+pub enum Timeout {
+    /// The operation waits until it completes.
+    Infinite,
+
+    /// The operation times out after the given duration.
+    After(Duration),
+
+    /// The operation times out at the given instant.
+    Until(Instant),
+}
+
+impl Timeout {
+    /// Converts a relative timeout to an absolute deadline.
+    ///
+    /// `Timeout::After(duration)` is converted to a `Timeout::Until` that expires `duration` from
+    /// now, or to `Timeout::Infinite` if `duration` is too long to be represented as an instant.
+    /// Other timeouts are returned unchanged.
+    pub fn to_deadline(&self) -> Timeout {
+        match *self {
+            Timeout::After(duration) => match Instant::now().checked_add(duration) {
+                Some(deadline) => Timeout::Until(deadline),
+                None => Timeout::Infinite,
+            },
+            timeout => timeout,
+        }
+    }
+
+    /// Returns the time remaining before the timeout expires.
+    ///
+    /// Returns `None` for an infinite timeout. Returns a zero duration if the timeout has expired.
+    pub fn remaining(&self) -> Option<Duration> {
+        match *self {
+            Timeout::Infinite => None,
+            Timeout::After(duration) => Some(duration),
+            Timeout::Until(deadline) => {
+                let now = Instant::now();
+
+                if deadline > now {
+                    Some(deadline - now)
+                }
+                else {
+                    Some(Duration::new(0, 0))
+                }
+            },
+        }
+    }
+
+    /// Indicates whether the timeout's deadline has passed.
+    ///
+    /// Relative and infinite timeouts never expire.
+    pub fn is_expired(&self) -> bool {
+        match *self {
+            Timeout::Until(deadline) => deadline <= Instant::now(),
+            _ => false,
+        }
+    }
+}
+
+impl From<Duration> for Timeout {
+    fn from(duration: Duration) -> Self {
+        Timeout::After(duration)
+    }
+}
+
+impl From<Instant> for Timeout {
+    fn from(deadline: Instant) -> Self {
+        Timeout::Until(deadline)
+    }
+}
+
+
+/// Converts a timeout to milliseconds for `libusb`.
+///
+/// `libusb` interprets a timeout of zero as "wait forever", so finite timeouts are rounded up to
+/// the next millisecond and are at least one millisecond long. Timeouts that don't fit in a
+/// `c_uint` are saturated. Returns `None` if the timeout's deadline has passed.
+#[doc(hidden)]
+pub fn to_libusb(timeout: &Timeout) -> Option<c_uint> {
+    match *timeout {
+        Timeout::Infinite => Some(0),
+        Timeout::After(duration) => Some(duration_to_millis(duration)),
+        Timeout::Until(deadline) => {
+            let now = Instant::now();
+
+            if deadline > now {
+                Some(duration_to_millis(deadline - now))
+            }
+            else {
+                None
+            }
+        },
+    }
+}
+
+fn duration_to_millis(duration: Duration) -> c_uint {
+    let sub_millis = (duration.subsec_nanos() as u64 + 999_999) / 1_000_000;
+
+    let millis = duration.as_secs()
+        .checked_mul(1000)
+        .and_then(|millis| millis.checked_add(sub_millis))
+        .unwrap_or(u64::max_value());
+
+    if millis == 0 {
+        1
+    }
+    else if millis > c_uint::max_value() as u64 {
+        c_uint::max_value()
+    }
+    else {
+        millis as c_uint
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use libc::c_uint;
+
+    use super::*;
+
+    #[test]
+    fn it_converts_infinite_timeout_to_zero() {
+        assert_eq!(Some(0), to_libusb(&Timeout::Infinite));
+    }
+
+    #[test]
+    fn it_converts_duration_to_millis() {
+        assert_eq!(Some(1500), to_libusb(&Timeout::After(Duration::from_millis(1500))));
+    }
+
+    #[test]
+    fn it_rounds_sub_millisecond_durations_up() {
+        assert_eq!(Some(1), to_libusb(&Timeout::After(Duration::new(0, 1))));
+        assert_eq!(Some(2), to_libusb(&Timeout::After(Duration::new(0, 1_000_001))));
+    }
+
+    #[test]
+    fn it_converts_zero_duration_to_shortest_timeout() {
+        assert_eq!(Some(1), to_libusb(&Timeout::After(Duration::new(0, 0))));
+    }
+
+    #[test]
+    fn it_saturates_long_durations() {
+        assert_eq!(Some(c_uint::max_value()), to_libusb(&Timeout::After(Duration::from_secs(u64::max_value()))));
+        assert_eq!(Some(c_uint::max_value()), to_libusb(&Timeout::After(Duration::from_secs(60 * 60 * 24 * 365))));
+    }
+
+    #[test]
+    fn it_converts_future_deadline_to_millis() {
+        let millis = to_libusb(&Timeout::Until(Instant::now() + Duration::from_secs(10))).unwrap();
+
+        assert!(millis > 9000 && millis <= 10000);
+    }
+
+    #[test]
+    fn it_converts_past_deadline_to_none() {
+        assert_eq!(None, to_libusb(&Timeout::Until(Instant::now())));
+    }
+
+    #[test]
+    fn it_converts_duration_into_relative_timeout() {
+        assert_eq!(Timeout::After(Duration::from_secs(1)), Timeout::from(Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn it_converts_relative_timeout_to_deadline() {
+        let before = Instant::now();
+        let timeout = Timeout::After(Duration::from_secs(1)).to_deadline();
+
+        match timeout {
+            Timeout::Until(deadline) => assert!(deadline >= before + Duration::from_secs(1)),
+            _ => panic!("expected a deadline: {:?}", timeout),
+        }
+    }
+
+    #[test]
+    fn it_converts_overlong_relative_timeout_to_infinite_deadline() {
+        assert_eq!(Timeout::Infinite, Timeout::After(Duration::from_secs(u64::max_value())).to_deadline());
+    }
+
+    #[test]
+    fn it_keeps_infinite_timeout_when_converting_to_deadline() {
+        assert_eq!(Timeout::Infinite, Timeout::Infinite.to_deadline());
+    }
+
+    #[test]
+    fn it_has_no_remaining_time_for_infinite_timeout() {
+        assert_eq!(None, Timeout::Infinite.remaining());
+    }
+
+    #[test]
+    fn it_has_zero_remaining_time_after_deadline() {
+        let deadline = Instant::now();
+
+        assert_eq!(Some(Duration::new(0, 0)), Timeout::Until(deadline).remaining());
+        assert!(Timeout::Until(deadline).is_expired());
+    }
+}
@@ -0,0 +1,410 @@
+use std::fmt;
+use std::time::Duration;
+
+use libusb::*;
+
+use error::Error;
+use fields::{Direction, TransferType};
+
+/// An observer of the transfers made through a device handle.
+///
+/// An observer is installed with
+/// [`DeviceHandle::set_observer()`](struct.DeviceHandle.html#method.set_observer). It's called
+/// after each control, bulk, and interrupt transfer that the handle performs, on the thread that
+/// performed the transfer, with a [`TransferRecord`](struct.TransferRecord.html) that describes
+/// the transfer.
+///
+/// Transfers that are queued by [`BulkStream`](struct.BulkStream.html),
+/// [`BulkSink`](struct.BulkSink.html), and [`InterruptPoller`](struct.InterruptPoller.html) are
+/// not observed.
+///
+/// ## Example
+///
+/// ```no_run
+/// struct Logger;
+///
+/// impl libusb::TransferObserver for Logger {
+///     fn on_transfer(&self, record: &libusb::TransferRecord) {
+///         println!("{}", record);
+///     }
+///
+///     fn capture_data(&self) -> bool {
+///         true
+///     }
+/// }
+///
+/// # fn trace(handle: &mut libusb::DeviceHandle) {
+/// handle.set_observer(Logger);
+/// # }
+/// ```
+pub trait TransferObserver: Send + Sync {
+    /// Called after a transfer completes or fails.
+    fn on_transfer(&self, record: &TransferRecord);
+
+    /// Indicates whether records should include the transferred data.
+    ///
+    /// The default implementation returns `false`.
+    fn capture_data(&self) -> bool {
+        false
+    }
+}
+
+/// A record of a transfer that was observed by a [`TransferObserver`](trait.TransferObserver.html).
+///
+/// A record is displayed as one line that describes the transfer, e.g.:
+///
+/// ```text
+/// IN  0x80 CONTROL GET_DESCRIPTOR(STRING, idx 2, lang 0x0409) 18/255 bytes OK 0.412ms
+/// OUT 0x02 BULK 0/512 bytes Pipe 1.030ms
+/// ```
+pub struct TransferRecord<'d> {
+    transfer_type: TransferType,
+    endpoint: u8,
+    setup: Option<SetupPacket>,
+    requested_length: usize,
+    actual_length: usize,
+    error: Option<Error>,
+    latency: Duration,
+    data: Option<&'d [u8]>,
+}
+
+impl<'d> TransferRecord<'d> {
+    /// Returns the type of the transfer.
+    pub fn transfer_type(&self) -> TransferType {
+        self.transfer_type
+    }
+
+    /// Returns the address of the endpoint.
+    ///
+    /// For control transfers, this is the address of the default control endpoint in the
+    /// direction of the transfer.
+    pub fn endpoint(&self) -> u8 {
+        self.endpoint
+    }
+
+    /// Returns the direction of the transfer.
+    pub fn direction(&self) -> Direction {
+        direction(self.endpoint)
+    }
+
+    /// Returns the setup packet of a control transfer.
+    pub fn setup(&self) -> Option<&SetupPacket> {
+        self.setup.as_ref()
+    }
+
+    /// Returns the number of bytes that were requested to be transferred.
+    pub fn requested_length(&self) -> usize {
+        self.requested_length
+    }
+
+    /// Returns the number of bytes that were transferred.
+    pub fn actual_length(&self) -> usize {
+        self.actual_length
+    }
+
+    /// Returns the error that the transfer failed with.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+
+    /// Returns the time that the transfer took to complete.
+    pub fn latency(&self) -> Duration {
+        self.latency
+    }
+
+    /// Returns the transferred data.
+    ///
+    /// For IN transfers, this is the data that was received. For OUT transfers, this is the data
+    /// that was requested to be sent. Returns `None` unless the observer captures data.
+    pub fn data(&self) -> Option<&'d [u8]> {
+        self.data
+    }
+}
+
+impl<'d> fmt::Display for TransferRecord<'d> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let direction = match self.direction() {
+            Direction::In => "IN ",
+            Direction::Out => "OUT",
+        };
+
+        let transfer_type = match self.transfer_type {
+            TransferType::Control => "CONTROL",
+            TransferType::Isochronous => "ISOCHRONOUS",
+            TransferType::Bulk => "BULK",
+            TransferType::Interrupt => "INTERRUPT",
+        };
+
+        try!(write!(fmt, "{} {:#04x} {}", direction, self.endpoint, transfer_type));
+
+        if let Some(ref setup) = self.setup {
+            try!(write!(fmt, " {}", setup));
+        }
+
+        try!(write!(fmt, " {}/{} bytes", self.actual_length, self.requested_length));
+
+        match self.error {
+            Some(ref err) => try!(write!(fmt, " {:?}", err.kind())),
+            None => try!(fmt.write_str(" OK")),
+        }
+
+        let micros = self.latency.as_secs() * 1_000_000 + (self.latency.subsec_nanos() / 1_000) as u64;
+        try!(write!(fmt, " {}.{:03}ms", micros / 1_000, micros % 1_000));
+
+        if let Some(data) = self.data {
+            try!(fmt.write_str(" ["));
+
+            for (i, byte) in data.iter().enumerate() {
+                if i > 0 {
+                    try!(fmt.write_str(" "));
+                }
+
+                try!(write!(fmt, "{:02x}", byte));
+            }
+
+            try!(fmt.write_str("]"));
+        }
+
+        Ok(())
+    }
+}
+
+
+/// The setup packet of a control transfer.
+///
+/// A setup packet is displayed as a decoded request. Standard requests are decoded by name,
+/// e.g., `GET_DESCRIPTOR(STRING, idx 2, lang 0x0409)` or `SET_CONFIGURATION(1)`. Class, vendor,
+/// and reserved requests are displayed with their raw fields.
+#[derive(Debug,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct SetupPacket {
+    request_type: u8,
+    request: u8,
+    value: u16,
+    index: u16,
+    length: u16,
+}
+
+impl SetupPacket {
+    /// Creates a setup packet from its fields.
+    pub fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Self {
+        SetupPacket {
+            request_type: request_type,
+            request: request,
+            value: value,
+            index: index,
+            length: length,
+        }
+    }
+
+    /// Returns the setup packet's `bmRequestType` field.
+    pub fn request_type(&self) -> u8 {
+        self.request_type
+    }
+
+    /// Returns the setup packet's `bRequest` field.
+    pub fn request(&self) -> u8 {
+        self.request
+    }
+
+    /// Returns the setup packet's `wValue` field.
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    /// Returns the setup packet's `wIndex` field.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    /// Returns the setup packet's `wLength` field.
+    pub fn length(&self) -> u16 {
+        self.length
+    }
+
+    /// Returns the direction of the request's data stage.
+    pub fn direction(&self) -> Direction {
+        direction(self.request_type)
+    }
+
+    fn fmt_standard(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let recipient = self.request_type & 0x1F;
+
+        match self.request {
+            LIBUSB_REQUEST_GET_STATUS => {
+                try!(fmt.write_str("GET_STATUS("));
+                try!(self.fmt_recipient(fmt, recipient));
+                fmt.write_str(")")
+            },
+            LIBUSB_REQUEST_CLEAR_FEATURE | LIBUSB_REQUEST_SET_FEATURE => {
+                let name = if self.request == LIBUSB_REQUEST_SET_FEATURE { "SET_FEATURE" } else { "CLEAR_FEATURE" };
+
+                try!(write!(fmt, "{}(", name));
+
+                match (recipient, self.value) {
+                    (LIBUSB_RECIPIENT_ENDPOINT, 0) => try!(fmt.write_str("ENDPOINT_HALT, ")),
+                    (LIBUSB_RECIPIENT_DEVICE, 1) => try!(fmt.write_str("DEVICE_REMOTE_WAKEUP, ")),
+                    (LIBUSB_RECIPIENT_DEVICE, 2) => try!(fmt.write_str("TEST_MODE, ")),
+                    (_, feature) => try!(write!(fmt, "feature {}, ", feature)),
+                }
+
+                try!(self.fmt_recipient(fmt, recipient));
+                fmt.write_str(")")
+            },
+            LIBUSB_REQUEST_SET_ADDRESS => write!(fmt, "SET_ADDRESS({})", self.value),
+            LIBUSB_REQUEST_GET_DESCRIPTOR | LIBUSB_REQUEST_SET_DESCRIPTOR => {
+                let name = if self.request == LIBUSB_REQUEST_GET_DESCRIPTOR { "GET_DESCRIPTOR" } else { "SET_DESCRIPTOR" };
+                let descriptor_type = (self.value >> 8) as u8;
+
+                try!(write!(fmt, "{}(", name));
+
+                match descriptor_type_name(descriptor_type) {
+                    Some(type_name) => try!(fmt.write_str(type_name)),
+                    None => try!(write!(fmt, "{:#04x}", descriptor_type)),
+                }
+
+                try!(write!(fmt, ", idx {}", self.value & 0xFF));
+
+                if descriptor_type == LIBUSB_DT_STRING && self.value & 0xFF != 0 {
+                    try!(write!(fmt, ", lang {:#06x}", self.index));
+                }
+
+                fmt.write_str(")")
+            },
+            LIBUSB_REQUEST_GET_CONFIGURATION => fmt.write_str("GET_CONFIGURATION()"),
+            LIBUSB_REQUEST_SET_CONFIGURATION => write!(fmt, "SET_CONFIGURATION({})", self.value),
+            LIBUSB_REQUEST_GET_INTERFACE => write!(fmt, "GET_INTERFACE(iface {})", self.index),
+            LIBUSB_REQUEST_SET_INTERFACE => write!(fmt, "SET_INTERFACE(iface {}, alt {})", self.index, self.value),
+            LIBUSB_REQUEST_SYNCH_FRAME => write!(fmt, "SYNCH_FRAME(endpoint {:#04x})", self.index),
+            _ => self.fmt_raw(fmt, "STANDARD"),
+        }
+    }
+
+    fn fmt_recipient(&self, fmt: &mut fmt::Formatter, recipient: u8) -> fmt::Result {
+        match recipient {
+            LIBUSB_RECIPIENT_DEVICE => fmt.write_str("DEVICE"),
+            LIBUSB_RECIPIENT_INTERFACE => write!(fmt, "INTERFACE {}", self.index),
+            LIBUSB_RECIPIENT_ENDPOINT => write!(fmt, "ENDPOINT {:#04x}", self.index),
+            _ => fmt.write_str("OTHER"),
+        }
+    }
+
+    fn fmt_raw(&self, fmt: &mut fmt::Formatter, request_type: &str) -> fmt::Result {
+        write!(fmt, "{}(request {:#04x}, value {:#06x}, index {:#06x})", request_type, self.request, self.value, self.index)
+    }
+}
+
+impl fmt::Display for SetupPacket {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self.request_type & (0x03 << 5) {
+            LIBUSB_REQUEST_TYPE_STANDARD => self.fmt_standard(fmt),
+            LIBUSB_REQUEST_TYPE_CLASS => self.fmt_raw(fmt, "CLASS"),
+            LIBUSB_REQUEST_TYPE_VENDOR => self.fmt_raw(fmt, "VENDOR"),
+            _ => self.fmt_raw(fmt, "RESERVED"),
+        }
+    }
+}
+
+fn direction(address: u8) -> Direction {
+    match address & LIBUSB_ENDPOINT_DIR_MASK {
+        LIBUSB_ENDPOINT_IN => Direction::In,
+        _ => Direction::Out,
+    }
+}
+
+fn descriptor_type_name(descriptor_type: u8) -> Option<&'static str> {
+    match descriptor_type {
+        0x01 => Some("DEVICE"),
+        0x02 => Some("CONFIGURATION"),
+        0x03 => Some("STRING"),
+        0x04 => Some("INTERFACE"),
+        0x05 => Some("ENDPOINT"),
+        0x06 => Some("DEVICE_QUALIFIER"),
+        0x07 => Some("OTHER_SPEED_CONFIGURATION"),
+        0x08 => Some("INTERFACE_POWER"),
+        0x09 => Some("OTG"),
+        0x0A => Some("DEBUG"),
+        0x0B => Some("INTERFACE_ASSOCIATION"),
+        0x0F => Some("BOS"),
+        0x10 => Some("DEVICE_CAPABILITY"),
+        0x21 => Some("HID"),
+        0x22 => Some("REPORT"),
+        0x23 => Some("PHYSICAL"),
+        0x29 => Some("HUB"),
+        0x2A => Some("SUPERSPEED_HUB"),
+        0x30 => Some("SS_ENDPOINT_COMPANION"),
+        _ => None,
+    }
+}
+
+
+#[doc(hidden)]
+pub fn new_record<'d>(transfer_type: TransferType, endpoint: u8, setup: Option<SetupPacket>, requested_length: usize, actual_length: usize, error: Option<Error>, latency: Duration, data: Option<&'d [u8]>) -> TransferRecord<'d> {
+    TransferRecord {
+        transfer_type: transfer_type,
+        endpoint: endpoint,
+        setup: setup,
+        requested_length: requested_length,
+        actual_length: actual_length,
+        error: error,
+        latency: latency,
+        data: data,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use error::{Error, ErrorKind};
+    use fields::TransferType;
+
+    use super::{SetupPacket, new_record};
+
+    #[test]
+    fn it_decodes_get_string_descriptor() {
+        assert_eq!("GET_DESCRIPTOR(STRING, idx 2, lang 0x0409)", SetupPacket::new(0x80, 0x06, 0x0302, 0x0409, 255).to_string());
+    }
+
+    #[test]
+    fn it_decodes_get_descriptor_without_language() {
+        assert_eq!("GET_DESCRIPTOR(DEVICE, idx 0)", SetupPacket::new(0x80, 0x06, 0x0100, 0, 18).to_string());
+        assert_eq!("GET_DESCRIPTOR(STRING, idx 0)", SetupPacket::new(0x80, 0x06, 0x0300, 0, 255).to_string());
+        assert_eq!("GET_DESCRIPTOR(0x42, idx 1)", SetupPacket::new(0x80, 0x06, 0x4201, 0, 8).to_string());
+    }
+
+    #[test]
+    fn it_decodes_configuration_and_interface_requests() {
+        assert_eq!("SET_CONFIGURATION(1)", SetupPacket::new(0x00, 0x09, 1, 0, 0).to_string());
+        assert_eq!("SET_INTERFACE(iface 2, alt 1)", SetupPacket::new(0x01, 0x0B, 1, 2, 0).to_string());
+    }
+
+    #[test]
+    fn it_decodes_feature_requests() {
+        assert_eq!("CLEAR_FEATURE(ENDPOINT_HALT, ENDPOINT 0x81)", SetupPacket::new(0x02, 0x01, 0, 0x81, 0).to_string());
+        assert_eq!("SET_FEATURE(DEVICE_REMOTE_WAKEUP, DEVICE)", SetupPacket::new(0x00, 0x03, 1, 0, 0).to_string());
+        assert_eq!("GET_STATUS(INTERFACE 1)", SetupPacket::new(0x81, 0x00, 0, 1, 2).to_string());
+    }
+
+    #[test]
+    fn it_displays_raw_fields_of_vendor_and_class_requests() {
+        assert_eq!("VENDOR(request 0x01, value 0x1234, index 0x0000)", SetupPacket::new(0xC0, 0x01, 0x1234, 0, 64).to_string());
+        assert_eq!("CLASS(request 0x09, value 0x0200, index 0x0001)", SetupPacket::new(0x21, 0x09, 0x0200, 1, 8).to_string());
+    }
+
+    #[test]
+    fn it_displays_control_record() {
+        let setup = SetupPacket::new(0x80, 0x06, 0x0302, 0x0409, 255);
+        let record = new_record(TransferType::Control, 0x80, Some(setup), 255, 18, None, Duration::new(0, 412_000), None);
+
+        assert_eq!("IN  0x80 CONTROL GET_DESCRIPTOR(STRING, idx 2, lang 0x0409) 18/255 bytes OK 0.412ms", record.to_string());
+    }
+
+    #[test]
+    fn it_displays_failed_record_with_data() {
+        let data = [0x01, 0xAB];
+        let record = new_record(TransferType::Bulk, 0x02, None, 2, 0, Some(Error::new(ErrorKind::Pipe)), Duration::from_millis(12), Some(&data));
+
+        assert_eq!("OUT 0x02 BULK 0/2 bytes Pipe 12.000ms [01 ab]", record.to_string());
+    }
+}
@@ -0,0 +1,614 @@
+use std::fmt;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::{c_int, c_uint, c_uchar, c_void, timeval};
+use libusb::*;
+
+use error::{Error, ErrorKind};
+use timeout::Timeout;
+
+/// A token that cancels blocking transfers from another thread.
+///
+/// A cancellation token is passed to the cancellable transfer methods of
+/// [`DeviceHandle`](struct.DeviceHandle.html), such as
+/// [`read_bulk_cancellable()`](struct.DeviceHandle.html#method.read_bulk_cancellable). Cloning a
+/// token returns a new reference to the same token, so that one clone can be given to the thread
+/// that performs the transfer and another clone can be used to cancel it.
+///
+/// Calling [`cancel()`](#method.cancel) aborts every transfer that is in flight with the token and
+/// causes any later transfer with the token to fail immediately. A cancelled transfer returns
+/// an error of kind `ErrorKind::Interrupted`.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::thread;
+/// use std::time::Duration;
+///
+/// let token = libusb::CancellationToken::new();
+/// let canceller = token.clone();
+///
+/// thread::spawn(move || {
+///     thread::sleep(Duration::from_secs(1));
+///     canceller.cancel();
+/// });
+///
+/// # fn read(handle: &libusb::DeviceHandle, token: &libusb::CancellationToken) {
+/// let mut buf = [0u8; 64];
+///
+/// match handle.read_bulk_cancellable(0x81, &mut buf, libusb::Timeout::Infinite, token) {
+///     Ok(n) => println!("read {} bytes", n),
+///     Err(ref err) if err.kind() == libusb::ErrorKind::Interrupted => println!("cancelled"),
+///     Err(err) => println!("error: {}", err),
+/// }
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct CancellationToken {
+    inner: Arc<Cancellation>,
+}
+
+struct Cancellation {
+    cancelled: AtomicBool,
+    transfers: Mutex<Vec<usize>>,
+}
+
+impl CancellationToken {
+    /// Creates a new cancellation token.
+    pub fn new() -> Self {
+        CancellationToken {
+            inner: Arc::new(Cancellation {
+                cancelled: AtomicBool::new(false),
+                transfers: Mutex::new(Vec::new()),
+            }),
+        }
+    }
+
+    /// Cancels the transfers that use this token.
+    ///
+    /// Transfers that are in flight are aborted. The threads that are blocked on them return
+    /// promptly. Transfers that are started with the token after it has been cancelled fail
+    /// immediately.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+
+        let transfers = self.inner.transfers.lock().unwrap();
+
+        for &transfer in transfers.iter() {
+            unsafe {
+                libusb_cancel_transfer(transfer as *mut libusb_transfer);
+            }
+        }
+    }
+
+    /// Indicates whether the token has been cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+impl Default for CancellationToken {
+    fn default() -> Self {
+        CancellationToken::new()
+    }
+}
+
+impl fmt::Debug for CancellationToken {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.debug_struct("CancellationToken")
+            .field("cancelled", &self.is_cancelled())
+            .finish()
+    }
+}
+
+
+/// Statistics for a stream of transfers.
+///
+/// Statistics are collected by [`BulkStream`](struct.BulkStream.html) and
+/// [`BulkSink`](struct.BulkSink.html).
+#[derive(Debug,Clone,Copy)]
+pub struct TransferStats {
+    bytes: u64,
+    transfers: u64,
+    errors: u64,
+    overruns: u64,
+    started: Instant,
+}
+
+impl TransferStats {
+    /// Returns the number of bytes transferred.
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns the number of transfers that completed successfully.
+    pub fn transfers(&self) -> u64 {
+        self.transfers
+    }
+
+    /// Returns the number of transfers that failed.
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Returns the number of overruns.
+    ///
+    /// An overrun occurs when every queued transfer has completed before the application handles
+    /// the next one, which leaves the endpoint without a pending transfer.
+    pub fn overruns(&self) -> u64 {
+        self.overruns
+    }
+
+    /// Returns the time elapsed since the stream was started.
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Returns the average throughput since the stream was started, in bytes per second.
+    pub fn throughput(&self) -> f64 {
+        let elapsed = self.elapsed();
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        if secs > 0.0 {
+            self.bytes as f64 / secs
+        }
+        else {
+            0.0
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn new_stats() -> TransferStats {
+    TransferStats {
+        bytes: 0,
+        transfers: 0,
+        errors: 0,
+        overruns: 0,
+        started: Instant::now(),
+    }
+}
+
+#[doc(hidden)]
+pub fn record_transfer(stats: &mut TransferStats, bytes: usize) {
+    stats.bytes += bytes as u64;
+    stats.transfers += 1;
+}
+
+#[doc(hidden)]
+pub fn record_error(stats: &mut TransferStats) {
+    stats.errors += 1;
+}
+
+#[doc(hidden)]
+pub fn record_overrun(stats: &mut TransferStats) {
+    stats.overruns += 1;
+}
+
+
+/// Options for a bulk or interrupt transfer.
+///
+/// Flags are passed to the transfer methods of [`DeviceHandle`](struct.DeviceHandle.html) that
+/// accept them, such as [`read_bulk_with_flags()`](struct.DeviceHandle.html#method.read_bulk_with_flags),
+/// and can be set on [`BulkStream`](struct.BulkStream.html) and [`BulkSink`](struct.BulkSink.html).
+/// The default flags match `libusb`'s default behavior.
+///
+/// `libusb`'s flags for freeing a transfer's buffer are not exposed, because buffers are always
+/// owned by the application.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use libusb::TransferFlags;
+///
+/// # fn read(handle: &libusb::DeviceHandle) -> libusb::Result<()> {
+/// let flags = TransferFlags::new().short_not_ok(true);
+/// let mut buf = [0u8; 512];
+///
+/// match handle.read_bulk_with_flags(0x81, &mut buf, Duration::from_secs(1), flags) {
+///     Ok(n) => println!("read {} bytes", n),
+///     Err(ref err) if err.kind() == libusb::ErrorKind::ShortTransfer => println!("short read"),
+///     Err(err) => return Err(err),
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug,Default,Clone,Copy,PartialEq,Eq,Hash)]
+pub struct TransferFlags {
+    short_not_ok: bool,
+    add_zero_packet: bool,
+}
+
+impl TransferFlags {
+    /// Returns the default flags.
+    pub fn new() -> Self {
+        TransferFlags::default()
+    }
+
+    /// Sets whether a short read is treated as an error.
+    ///
+    /// When set, a transfer from an IN endpoint that receives less data than the size of its
+    /// buffer fails with `ErrorKind::ShortTransfer`.
+    pub fn short_not_ok(mut self, short_not_ok: bool) -> Self {
+        self.short_not_ok = short_not_ok;
+        self
+    }
+
+    /// Sets whether a transfer ends with a zero-length packet.
+    ///
+    /// When set, a transfer to an OUT endpoint whose length is a multiple of the endpoint's
+    /// maximum packet size is followed by a zero-length packet. This flag is only supported on
+    /// some platforms, such as Linux. Other platforms fail the transfer with
+    /// `ErrorKind::NotSupported`.
+    pub fn add_zero_packet(mut self, add_zero_packet: bool) -> Self {
+        self.add_zero_packet = add_zero_packet;
+        self
+    }
+
+    /// Indicates whether a short read is treated as an error.
+    pub fn is_short_not_ok(&self) -> bool {
+        self.short_not_ok
+    }
+
+    /// Indicates whether a transfer ends with a zero-length packet.
+    pub fn is_add_zero_packet(&self) -> bool {
+        self.add_zero_packet
+    }
+}
+
+#[doc(hidden)]
+pub fn flags_to_libusb(flags: &TransferFlags) -> u8 {
+    let mut bits = 0;
+
+    if flags.short_not_ok {
+        bits |= LIBUSB_TRANSFER_SHORT_NOT_OK;
+    }
+
+    if flags.add_zero_packet {
+        bits |= LIBUSB_TRANSFER_ADD_ZERO_PACKET;
+    }
+
+    bits
+}
+
+/// Converts the error of a failed transfer to the error reported to the caller.
+///
+/// `libusb` reports a short read with `SHORT_NOT_OK` as a generic transfer error, which is
+/// distinguished from other errors by the transfer's length.
+#[doc(hidden)]
+pub fn transfer_error(flags: &TransferFlags, err: Error, actual_length: usize, length: usize) -> Error {
+    if flags.short_not_ok && err.kind() == ErrorKind::Io && actual_length < length {
+        Error::new(ErrorKind::ShortTransfer)
+    }
+    else {
+        err
+    }
+}
+
+
+/// An asynchronous transfer.
+///
+/// A transfer is filled with the parameters of a bulk or interrupt transfer, submitted, and then
+/// completed by handling events on the context. Dropping a transfer that is in flight cancels it
+/// and waits for it to complete, because `libusb` may still write to the transfer's buffer.
+#[doc(hidden)]
+pub struct Transfer {
+    ptr: *mut libusb_transfer,
+    completed: Box<c_int>,
+    context: *mut libusb_context,
+    in_flight: bool,
+}
+
+unsafe impl Send for Transfer {}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        if self.in_flight {
+            self.cancel();
+            self.wait();
+        }
+
+        unsafe {
+            libusb_free_transfer(self.ptr);
+        }
+    }
+}
+
+impl Transfer {
+    /// Allocates a transfer for a device on the given context.
+    pub fn new(context: *mut libusb_context) -> ::Result<Transfer> {
+        let ptr = unsafe { libusb_alloc_transfer(0) };
+
+        if ptr.is_null() {
+            return Err(Error::new(ErrorKind::NoMem));
+        }
+
+        Ok(Transfer {
+            ptr: ptr,
+            completed: Box::new(1),
+            context: context,
+            in_flight: false,
+        })
+    }
+
+    /// Fills the transfer's parameters.
+    ///
+    /// The caller must ensure that `buf` remains valid for `len` bytes until the transfer
+    /// completes.
+    pub unsafe fn fill(&mut self, handle: *mut libusb_device_handle, transfer_type: c_uchar, endpoint: u8, buf: *mut c_uchar, len: usize, timeout_ms: c_uint, flags: u8) {
+        debug_assert!(!self.in_flight);
+
+        let transfer = &mut *self.ptr;
+
+        transfer.dev_handle = handle;
+        transfer.flags = flags;
+        transfer.endpoint = endpoint;
+        transfer.transfer_type = transfer_type;
+        transfer.timeout = timeout_ms;
+        transfer.length = len as c_int;
+        transfer.actual_length = 0;
+        transfer.callback = transfer_callback;
+        transfer.user_data = &mut *self.completed as *mut c_int as *mut c_void;
+        transfer.buffer = buf;
+        transfer.num_iso_packets = 0;
+    }
+
+    /// Sets the USB 3 bulk stream ID of a bulk transfer.
+    ///
+    /// Must be called after `fill()`, since it changes the transfer's type to a bulk stream
+    /// transfer.
+    pub fn set_stream_id(&mut self, stream_id: u32) {
+        debug_assert!(!self.in_flight);
+
+        unsafe {
+            libusb_transfer_set_stream_id(self.ptr, stream_id);
+        }
+    }
+
+    /// Submits the transfer.
+    ///
+    /// Returns a `libusb` error code.
+    pub fn submit(&mut self) -> c_int {
+        *self.completed = 0;
+
+        let res = unsafe { libusb_submit_transfer(self.ptr) };
+
+        if res == 0 {
+            self.in_flight = true;
+        }
+        else {
+            *self.completed = 1;
+        }
+
+        res
+    }
+
+    /// Submits the transfer unless the cancellation token has been cancelled.
+    ///
+    /// The transfer is registered with the token, so that cancelling the token cancels the
+    /// transfer. It must be unregistered with `unregister()` once it completes.
+    pub fn submit_cancellable(&mut self, cancel: &CancellationToken) -> c_int {
+        let mut transfers = cancel.inner.transfers.lock().unwrap();
+
+        if cancel.is_cancelled() {
+            return LIBUSB_ERROR_INTERRUPTED;
+        }
+
+        // submitting while holding the lock ensures that cancel() can't miss the transfer
+        let res = self.submit();
+
+        if res == 0 {
+            transfers.push(self.ptr as usize);
+        }
+
+        res
+    }
+
+    /// Unregisters the transfer from a cancellation token.
+    pub fn unregister(&self, cancel: &CancellationToken) {
+        let mut transfers = cancel.inner.transfers.lock().unwrap();
+
+        transfers.retain(|&transfer| transfer != self.ptr as usize);
+    }
+
+    /// Requests cancellation of the transfer.
+    ///
+    /// The transfer is not complete until its callback has run.
+    pub fn cancel(&self) {
+        if self.in_flight {
+            unsafe {
+                libusb_cancel_transfer(self.ptr);
+            }
+        }
+    }
+
+    /// Indicates whether the transfer has completed.
+    pub fn is_completed(&self) -> bool {
+        unsafe { ptr::read_volatile(&*self.completed) != 0 }
+    }
+
+    /// Handles events until the transfer completes.
+    pub fn wait(&mut self) {
+        self.wait_timeout(Timeout::Infinite);
+    }
+
+    /// Handles events until the transfer completes or the timeout expires.
+    ///
+    /// Returns `true` if the transfer completed.
+    pub fn wait_timeout(&mut self, timeout: Timeout) -> bool {
+        let timeout = timeout.to_deadline();
+
+        while !self.is_completed() {
+            let completed: *mut c_int = &mut *self.completed;
+
+            let res = match timeout.remaining() {
+                None => unsafe {
+                    libusb_handle_events_completed(self.context, completed)
+                },
+                Some(remaining) => {
+                    if remaining == Duration::new(0, 0) {
+                        return false;
+                    }
+
+                    let tv = to_timeval(remaining);
+
+                    unsafe {
+                        libusb_handle_events_timeout_completed(self.context, &tv, completed)
+                    }
+                },
+            };
+
+            if res < 0 && res != LIBUSB_ERROR_INTERRUPTED {
+                // keep handling events, since the transfer must complete before it can be freed
+                self.cancel();
+            }
+        }
+
+        self.in_flight = false;
+        true
+    }
+
+    /// Returns the transfer's result as a `libusb` error code.
+    pub fn result(&self) -> c_int {
+        status_to_libusb(unsafe { (*self.ptr).status })
+    }
+
+    /// Returns the number of bytes that were transferred.
+    pub fn actual_length(&self) -> usize {
+        unsafe { (*self.ptr).actual_length as usize }
+    }
+}
+
+/// Handles any pending events on a context without blocking.
+#[doc(hidden)]
+pub fn poll_events(context: *mut libusb_context) {
+    handle_events(context, Some(Duration::new(0, 0)));
+}
+
+/// Handles events on a context, blocking up to `timeout` if no events are pending. A timeout of
+/// `None` blocks until an event is handled.
+///
+/// Returns a `libusb` error code.
+#[doc(hidden)]
+pub fn handle_events(context: *mut libusb_context, timeout: Option<Duration>) -> c_int {
+    match timeout {
+        Some(timeout) => {
+            let tv = to_timeval(timeout);
+
+            unsafe {
+                libusb_handle_events_timeout_completed(context, &tv, ptr::null_mut())
+            }
+        },
+        None => unsafe {
+            libusb_handle_events_completed(context, ptr::null_mut())
+        },
+    }
+}
+
+// the fields of `timeval` are `c_long` on Windows and `time_t` and `suseconds_t` elsewhere
+fn to_timeval(duration: Duration) -> timeval {
+    timeval {
+        tv_sec: duration.as_secs() as _,
+        tv_usec: (duration.subsec_nanos() / 1000) as _,
+    }
+}
+
+extern "C" fn transfer_callback(transfer: *mut libusb_transfer) {
+    unsafe {
+        let completed = (*transfer).user_data as *mut c_int;
+        ptr::write_volatile(completed, 1);
+    }
+}
+
+/// Converts a transfer status to the equivalent `libusb` error code.
+fn status_to_libusb(status: c_int) -> c_int {
+    match status {
+        LIBUSB_TRANSFER_COMPLETED => LIBUSB_SUCCESS,
+        LIBUSB_TRANSFER_TIMED_OUT => LIBUSB_ERROR_TIMEOUT,
+        LIBUSB_TRANSFER_CANCELLED => LIBUSB_ERROR_INTERRUPTED,
+        LIBUSB_TRANSFER_STALL     => LIBUSB_ERROR_PIPE,
+        LIBUSB_TRANSFER_NO_DEVICE => LIBUSB_ERROR_NO_DEVICE,
+        LIBUSB_TRANSFER_OVERFLOW  => LIBUSB_ERROR_OVERFLOW,
+        LIBUSB_TRANSFER_ERROR | _ => LIBUSB_ERROR_IO,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use libusb::*;
+
+    use error::{self, ErrorKind};
+
+    use super::{CancellationToken, TransferFlags, flags_to_libusb, status_to_libusb, transfer_error};
+
+    #[test]
+    fn it_converts_completed_status_to_success() {
+        assert_eq!(LIBUSB_SUCCESS, status_to_libusb(LIBUSB_TRANSFER_COMPLETED));
+    }
+
+    #[test]
+    fn it_converts_error_statuses_to_error_codes() {
+        assert_eq!(LIBUSB_ERROR_TIMEOUT,     status_to_libusb(LIBUSB_TRANSFER_TIMED_OUT));
+        assert_eq!(LIBUSB_ERROR_INTERRUPTED, status_to_libusb(LIBUSB_TRANSFER_CANCELLED));
+        assert_eq!(LIBUSB_ERROR_PIPE,        status_to_libusb(LIBUSB_TRANSFER_STALL));
+        assert_eq!(LIBUSB_ERROR_NO_DEVICE,   status_to_libusb(LIBUSB_TRANSFER_NO_DEVICE));
+        assert_eq!(LIBUSB_ERROR_OVERFLOW,    status_to_libusb(LIBUSB_TRANSFER_OVERFLOW));
+        assert_eq!(LIBUSB_ERROR_IO,          status_to_libusb(LIBUSB_TRANSFER_ERROR));
+    }
+
+    #[test]
+    fn it_creates_tokens_that_are_not_cancelled() {
+        assert!(!CancellationToken::new().is_cancelled());
+    }
+
+    #[test]
+    fn it_cancels_all_clones_of_a_token() {
+        let token = CancellationToken::new();
+        let clone = token.clone();
+
+        clone.cancel();
+
+        assert!(token.is_cancelled());
+    }
+
+    #[test]
+    fn it_has_no_flags_by_default() {
+        assert_eq!(0, flags_to_libusb(&TransferFlags::new()));
+    }
+
+    #[test]
+    fn it_converts_flags_to_libusb_flags() {
+        assert_eq!(LIBUSB_TRANSFER_SHORT_NOT_OK, flags_to_libusb(&TransferFlags::new().short_not_ok(true)));
+        assert_eq!(LIBUSB_TRANSFER_ADD_ZERO_PACKET, flags_to_libusb(&TransferFlags::new().add_zero_packet(true)));
+        assert_eq!(LIBUSB_TRANSFER_SHORT_NOT_OK | LIBUSB_TRANSFER_ADD_ZERO_PACKET, flags_to_libusb(&TransferFlags::new().short_not_ok(true).add_zero_packet(true)));
+    }
+
+    #[test]
+    fn it_reports_short_read_as_short_transfer() {
+        match transfer_error(&TransferFlags::new().short_not_ok(true), error::from_libusb(LIBUSB_ERROR_IO), 10, 64).kind() {
+            ErrorKind::ShortTransfer => (),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_reports_io_error_without_short_not_ok_as_io_error() {
+        match transfer_error(&TransferFlags::new(), error::from_libusb(LIBUSB_ERROR_IO), 10, 64).kind() {
+            ErrorKind::Io => (),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn it_reports_io_error_after_full_transfer_as_io_error() {
+        match transfer_error(&TransferFlags::new().short_not_ok(true), error::from_libusb(LIBUSB_ERROR_IO), 64, 64).kind() {
+            ErrorKind::Io => (),
+            other => panic!("unexpected error: {:?}", other),
+        }
+    }
+}
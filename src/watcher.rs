@@ -0,0 +1,343 @@
+use std::cmp;
+use std::collections::VecDeque;
+use std::mem;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use context::Context;
+use device::{self, Device};
+use error::{Error, ErrorKind};
+use fields::Version;
+use timeout::Timeout;
+
+/// A change in the set of connected devices reported by a
+/// [`DeviceWatcher`](struct.DeviceWatcher.html).
+pub enum DeviceEvent<'a> {
+    /// A device was connected.
+    Added(Device<'a>),
+
+    /// A device was disconnected.
+    ///
+    /// The device can't be opened anymore, but its bus number, address, and device descriptor can
+    /// still be read.
+    Removed(Device<'a>),
+}
+
+impl<'a> DeviceEvent<'a> {
+    /// Returns the device that was connected or disconnected.
+    pub fn device(&self) -> &Device<'a> {
+        match *self {
+            DeviceEvent::Added(ref device) => device,
+            DeviceEvent::Removed(ref device) => device,
+        }
+    }
+}
+
+/// A watcher that reports devices as they are connected and disconnected.
+///
+/// The watcher doesn't rely on hotplug support. Instead, it lists the context's devices
+/// periodically and compares each list to the devices that it has already reported. Devices are
+/// identified by their bus number, port numbers, and the vendor ID, product ID, device version,
+/// and class code of their device descriptors, so that a device that re-enumerates at a new
+/// address on the same port is recognized as the same device.
+///
+/// The devices that are connected when the watcher lists devices for the first time are not
+/// reported. A debounce interval can be set so that a change is only reported once it has lasted
+/// for the whole interval, which hides devices that disconnect and reconnect quickly, e.g., when
+/// they reset.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// # fn watch(context: &libusb::Context) -> libusb::Result<()> {
+/// let watcher = libusb::DeviceWatcher::new(context)
+///     .interval(Duration::from_millis(250))
+///     .debounce(Duration::from_secs(1));
+///
+/// for event in watcher {
+///     match try!(event) {
+///         libusb::DeviceEvent::Added(device) => println!("added: bus {}", device.bus_number()),
+///         libusb::DeviceEvent::Removed(device) => println!("removed: bus {}", device.bus_number()),
+///     }
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct DeviceWatcher<'a> {
+    context: &'a Context,
+    interval: Duration,
+    debounce: Duration,
+    known: Option<Vec<Entry<'a>>>,
+    pending: Vec<Change<'a>>,
+    events: VecDeque<DeviceEvent<'a>>,
+}
+
+struct Entry<'a> {
+    key: DeviceKey,
+    device: Device<'a>,
+}
+
+/// A change that hasn't lasted for the debounce interval yet. Added devices carry the device.
+struct Change<'a> {
+    key: DeviceKey,
+    device: Option<Device<'a>>,
+    since: Instant,
+}
+
+#[derive(PartialEq,Eq,Clone)]
+struct DeviceKey {
+    bus_number: u8,
+    port_numbers: Vec<u8>,
+    vendor_id: u16,
+    product_id: u16,
+    device_version: Version,
+    class_code: u8,
+}
+
+impl<'a> DeviceWatcher<'a> {
+    /// Creates a watcher for the devices of a context.
+    ///
+    /// By default, devices are listed once per second and changes are reported without debouncing.
+    pub fn new(context: &'a Context) -> Self {
+        DeviceWatcher {
+            context: context,
+            interval: Duration::from_secs(1),
+            debounce: Duration::new(0, 0),
+            known: None,
+            pending: Vec::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Sets how often devices are listed while waiting for events.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets how long a device must stay connected or disconnected before the change is reported.
+    pub fn debounce(mut self, debounce: Duration) -> Self {
+        self.debounce = debounce;
+        self
+    }
+
+    /// Lists devices once and returns the events that are ready to be reported.
+    ///
+    /// This method doesn't block, so it can be called from an application's own polling loop.
+    pub fn poll(&mut self) -> ::Result<Vec<DeviceEvent<'a>>> {
+        try!(self.update());
+
+        Ok(self.events.drain(..).collect())
+    }
+
+    /// Waits for the next event, listing devices at the watcher's interval.
+    ///
+    /// ## Errors
+    ///
+    /// * `Timeout` if no device is connected or disconnected before the timeout expires.
+    pub fn next_event<T: Into<Timeout>>(&mut self, timeout: T) -> ::Result<DeviceEvent<'a>> {
+        let deadline = timeout.into().to_deadline();
+
+        if let Some(event) = self.events.pop_front() {
+            return Ok(event);
+        }
+
+        loop {
+            try!(self.update());
+
+            if let Some(event) = self.events.pop_front() {
+                return Ok(event);
+            }
+
+            if deadline.is_expired() {
+                return Err(Error::new(ErrorKind::Timeout));
+            }
+
+            thread::sleep(deadline.remaining().map_or(self.interval, |remaining| cmp::min(remaining, self.interval)));
+        }
+    }
+
+    fn update(&mut self) -> ::Result<()> {
+        let now = Instant::now();
+        let devices = try!(self.context.devices());
+
+        let mut current: Vec<Entry<'a>> = devices.iter().filter_map(|device| {
+            device_key(&device).map(|key| Entry { key: key, device: device })
+        }).collect();
+
+        let mut known = match self.known.take() {
+            Some(known) => known,
+            None => {
+                self.known = Some(current);
+                return Ok(());
+            },
+        };
+
+        // pair up known and current devices, leaving the devices that were removed or added
+        let mut removed = Vec::new();
+
+        for entry in known.iter() {
+            match current.iter().position(|other| other.key == entry.key) {
+                Some(position) => { current.remove(position); },
+                None => removed.push(entry.key.clone()),
+            }
+        }
+
+        // pending changes that aren't observed again have been reverted and are dropped
+        let mut pending = mem::replace(&mut self.pending, Vec::new());
+        let mut changes = Vec::new();
+
+        for key in removed {
+            let since = take_pending(&mut pending, &key, false).unwrap_or(now);
+
+            changes.push(Change { key: key, device: None, since: since });
+        }
+
+        for entry in current {
+            let since = take_pending(&mut pending, &entry.key, true).unwrap_or(now);
+
+            changes.push(Change { key: entry.key, device: Some(entry.device), since: since });
+        }
+
+        for change in changes {
+            if now.duration_since(change.since) < self.debounce {
+                self.pending.push(change);
+                continue;
+            }
+
+            match change.device {
+                Some(device) => {
+                    known.push(Entry { key: change.key, device: device::duplicate(&device) });
+                    self.events.push_back(DeviceEvent::Added(device));
+                },
+                None => {
+                    if let Some(position) = known.iter().position(|entry| entry.key == change.key) {
+                        let entry = known.remove(position);
+                        self.events.push_back(DeviceEvent::Removed(entry.device));
+                    }
+                },
+            }
+        }
+
+        self.known = Some(known);
+
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for DeviceWatcher<'a> {
+    type Item = ::Result<DeviceEvent<'a>>;
+
+    /// Waits for the next event without a timeout. The iterator never ends.
+    fn next(&mut self) -> Option<::Result<DeviceEvent<'a>>> {
+        Some(self.next_event(Timeout::Infinite))
+    }
+}
+
+fn device_key(device: &Device) -> Option<DeviceKey> {
+    let descriptor = match device.device_descriptor() {
+        Ok(descriptor) => descriptor,
+        Err(_) => return None,
+    };
+
+    Some(DeviceKey {
+        bus_number: device.bus_number(),
+        port_numbers: device.port_numbers().unwrap_or(Vec::new()),
+        vendor_id: descriptor.vendor_id(),
+        product_id: descriptor.product_id(),
+        device_version: descriptor.device_version(),
+        class_code: descriptor.class_code(),
+    })
+}
+
+/// Removes a pending change and returns when it was first observed.
+fn take_pending(pending: &mut Vec<Change>, key: &DeviceKey, added: bool) -> Option<Instant> {
+    pending.iter()
+        .position(|change| change.key == *key && change.device.is_some() == added)
+        .map(|position| pending.remove(position).since)
+}
+
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use context::Context;
+    use error::ErrorKind;
+    use mock::{MockBackend, MockDevice};
+
+    use super::{DeviceWatcher, DeviceEvent};
+
+    fn describe(events: Vec<DeviceEvent>) -> Vec<(bool, u16)> {
+        events.iter().map(|event| {
+            let added = match *event {
+                DeviceEvent::Added(_) => true,
+                DeviceEvent::Removed(_) => false,
+            };
+
+            (added, event.device().device_descriptor().unwrap().product_id())
+        }).collect()
+    }
+
+    #[test]
+    fn it_reports_added_and_removed_devices() {
+        let backend = MockBackend::new();
+        let first = MockDevice::new(0x1234, 0x0001).port_number(1);
+        backend.add_device(first.clone());
+        let context = Context::with_backend(backend.clone());
+        let mut watcher = DeviceWatcher::new(&context);
+
+        assert!(watcher.poll().unwrap().is_empty());
+
+        backend.add_device(MockDevice::new(0x1234, 0x0002).port_number(2));
+        assert_eq!(vec![(true, 0x0002)], describe(watcher.poll().unwrap()));
+
+        backend.remove_device(&first);
+        assert_eq!(vec![(false, 0x0001)], describe(watcher.poll().unwrap()));
+        assert!(watcher.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn it_debounces_devices_that_reconnect() {
+        let backend = MockBackend::new();
+        let device = MockDevice::new(0x1234, 0x0001).port_number(1).address(1);
+        backend.add_device(device.clone());
+        let context = Context::with_backend(backend.clone());
+        let mut watcher = DeviceWatcher::new(&context).debounce(Duration::from_millis(30));
+
+        assert!(watcher.poll().unwrap().is_empty());
+
+        backend.remove_device(&device);
+        assert!(watcher.poll().unwrap().is_empty());
+
+        backend.add_device(MockDevice::new(0x1234, 0x0001).port_number(1).address(2));
+        thread::sleep(Duration::from_millis(40));
+        assert!(watcher.poll().unwrap().is_empty());
+
+        backend.add_device(MockDevice::new(0x1234, 0x0002).port_number(2));
+        assert!(watcher.poll().unwrap().is_empty());
+        thread::sleep(Duration::from_millis(40));
+        assert_eq!(vec![(true, 0x0002)], describe(watcher.poll().unwrap()));
+    }
+
+    #[test]
+    fn it_waits_for_next_event() {
+        let backend = MockBackend::new();
+        let context = Context::with_backend(backend.clone());
+        let mut watcher = DeviceWatcher::new(&context).interval(Duration::from_millis(5));
+
+        assert_eq!(ErrorKind::Timeout, watcher.next_event(Duration::from_millis(10)).err().unwrap().kind());
+
+        let arrival = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            backend.add_device(MockDevice::new(0x1234, 0x0001));
+        });
+
+        let event = watcher.next().unwrap().unwrap();
+        assert_eq!(vec![(true, 0x0001)], describe(vec![event]));
+        arrival.join().unwrap();
+    }
+}